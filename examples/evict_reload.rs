@@ -0,0 +1,87 @@
+/*!
+ * Build an eviction set for a userspace address and watch it get evicted.
+ *
+ * `cargo run -r --example evict_reload [evset size]`
+ */
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+use {
+    pacman::cache,
+    pacman::evset,
+    pacman::memory,
+    pacman::pacmankit::PacmanKitConnection,
+    pacman::primitives::AddressTranslation,
+    pacman::qos::{set_core, CoreKind},
+    pacman::timer,
+};
+
+/// How many reloads to average over
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+const NUM_TRIALS : usize = 64;
+
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+fn main() {
+    let evset_size : usize = match std::env::args().nth(1) {
+        Some(arg) => arg.parse().expect("the eviction set size should be a number"),
+        None => cache::L2_WAYS * 2,
+    };
+
+    unsafe {
+        if !set_core(CoreKind::PCORE) {
+            println!("Error setting CPU affinity!");
+            return;
+        }
+
+        let handle = match PacmanKitConnection::init() {
+            Ok(v) => v,
+            Err(err) => {
+                println!("{}", err);
+                return;
+            }
+        };
+
+        let memory_region = match memory::alloc_memory_region() {
+            Ok(v) => v,
+            Err(err) => {
+                println!("Error creating memory region! Error is {}", err);
+                return;
+            }
+        };
+        memory::init_memory(memory_region);
+
+        // The target lives outside of the region the eviction set is drawn from
+        let target_page = vec![0x41u8; cache::PAGE_SIZE];
+        let target = &target_page[0] as *const u8 as u64;
+        let target_pa = handle.user_virt_to_phys(target).unwrap();
+        println!("Target is 0x{:X} (PA 0x{:X}, L2 set {})", target, target_pa, cache::get_cache_set_m1(target_pa));
+
+        let candidates = evset::data_pevset(target, target_pa, memory_region);
+        println!("Found {} conflicts.", candidates.len());
+        let chosen = match evset::choose_evset(&candidates, evset_size, "the eviction set") {
+            Ok(v) => v,
+            Err(err) => {
+                println!("{}", err);
+                return;
+            }
+        };
+
+        let mut hit_total = 0;
+        let mut evicted_total = 0;
+        for _ in 0..NUM_TRIALS {
+            timer::time_access(target);
+            hit_total += timer::time_access(target);
+
+            for addr in &chosen {
+                timer::time_access(*addr);
+            }
+            evicted_total += timer::time_access(target);
+        }
+
+        println!("Reload without eviction: {} cycles", hit_total / NUM_TRIALS as u64);
+        println!("Reload after evicting with {} addresses: {} cycles", chosen.len(), evicted_total / NUM_TRIALS as u64);
+    }
+}
+
+#[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
+fn main() {
+    println!("This example needs Apple silicon running macOS");
+}
@@ -0,0 +1,53 @@
+/*!
+ * Sign a kernel pointer with PacmanKit and pick its PAC apart with the `pac` helpers.
+ *
+ * `cargo run -r --example forge_pac`
+ */
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+use {
+    pacman::pac::*,
+    pacman::pacmankit::PacmanKitConnection,
+    pacman::primitives::*,
+};
+
+/// Any salt works, this is just the one the vtable entries in `attacks::pacman` use
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+const SALT : u64 = 0xa7d5;
+
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+fn main() {
+    unsafe {
+        let handle = match PacmanKitConnection::init() {
+            Ok(v) => v,
+            Err(err) => {
+                println!("{}", err);
+                return;
+            }
+        };
+
+        let target = handle.leak_win().unwrap() | PAC_BITMASK;
+        let salt = pacman::attacks::pacman::get_salt(handle.get_handle_loc().unwrap(), SALT);
+        let signed = handle.forge_sign_inst(target, salt).unwrap();
+        let pac = extract_pac(signed);
+
+        println!("win() is at           0x{:X}", target);
+        println!("Signed it is          0x{:X} (pac is 0x{:X})", signed, pac);
+        println!("Is a kernel pointer?  {}", is_kernel_pointer(signed));
+
+        // Re-encoding the PAC onto the raw pointer gets back the signed pointer
+        assert_eq!(encode_pac(pac, target), signed);
+
+        // And it is one of the NUM_PACS candidates a bruteforce would try
+        let position = iterate_pacs(target).position(|guess| guess == signed).unwrap();
+        println!("Bruteforce would find it after {} of {} guesses", position + 1, NUM_PACS);
+
+        // PacmanKit agrees that it authenticates
+        let authed = handle.forge_auth_inst(signed, salt).unwrap();
+        println!("Authenticated it is   0x{:X}", authed);
+    }
+}
+
+#[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
+fn main() {
+    println!("This example needs Apple silicon running macOS");
+}
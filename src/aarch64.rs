@@ -0,0 +1,433 @@
+/*!
+ * Decoding the AArch64 instructions PACMAN gadgets are made of, and encoding them back.
+ *
+ * Only what the gadget scanner (`kernelcache::gadgets`) needs to follow is decoded: branches, the pointer
+ * authentication instructions, loads and stores, and the moves and compares that build discriminators and
+ * set flags. Everything else is `Inst::Other`.
+ *
+ * `Inst::encode` is the other way around, for generating code to execute (see `payload`). Moves, compares and
+ * arithmetic are encoded as their 64 bit forms.
+ *
+ * Registers are numbered 0-30, and 31 is `xzr` or `sp` (whichever the instruction uses).
+ */
+use crate::kernelcache::macho::PtrAuthKey;
+
+/// The zero register (or the stack pointer)
+pub const XZR : u8 = 31;
+
+/// The link register
+pub const LR : u8 = 30;
+
+/// `ret`
+pub const RET : u32 = 0xD65F03C0;
+
+/// `nop`
+pub const NOP : u32 = 0xD503201F;
+
+/// `isb`
+pub const ISB : u32 = 0xD5033FDF;
+
+/// `dsb sy`
+pub const DSB_SY : u32 = 0xD5033F9F;
+
+/**
+ * A condition code (of `b.cond`).
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Cond(pub u8);
+
+impl Cond {
+    /// The condition's assembly name
+    pub fn name(&self) -> &'static str {
+        return ["eq", "ne", "hs", "lo", "mi", "pl", "vs", "vc", "hi", "ls", "ge", "lt", "gt", "le", "al", "nv"][(self.0 & 0xF) as usize];
+    }
+}
+
+/**
+ * A decoded instruction. Branch offsets are in bytes from the instruction itself.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Inst {
+    /// `b` (or `bl` if `link`)
+    B { offset: i64, link: bool },
+
+    /// `b.cond`
+    BCond { cond: Cond, offset: i64 },
+
+    /// `cbz`/ `cbnz`
+    Cbz { rt: u8, nonzero: bool, offset: i64 },
+
+    /// `tbz`/ `tbnz`
+    Tbz { rt: u8, bit: u8, nonzero: bool, offset: i64 },
+
+    /// `br`/ `blr` (`blr` if `link`)
+    Br { rn: u8, link: bool },
+
+    /// `braa`/ `brab`/ `blraa`/ `blrab` and their zero modifier forms (`modifier` is `None`)
+    BrAuth { key: PtrAuthKey, rn: u8, modifier: Option<u8>, link: bool },
+
+    /// `ret` (or `retaa`/ `retab`, with `key`)
+    Ret { rn: u8, key: Option<PtrAuthKey> },
+
+    /// `pacia`/ `pacib`/ `pacda`/ `pacdb` and their zero modifier forms (`modifier` is `None`)
+    Pac { key: PtrAuthKey, rd: u8, modifier: Option<u8> },
+
+    /// `autia`/ `autib`/ `autda`/ `autdb` and their zero modifier forms (`modifier` is `None`)
+    Aut { key: PtrAuthKey, rd: u8, modifier: Option<u8> },
+
+    /// `ldr`/ `ldur` (and the narrower loads) of `size` bytes from `[rn, #offset]`
+    Ldr { rt: u8, rn: u8, offset: i64, size: u8 },
+
+    /// `ldr` of `size` bytes from `[rn, rm]`
+    LdrReg { rt: u8, rn: u8, rm: u8, size: u8 },
+
+    /// `str`/ `stur` (and the narrower stores) of `size` bytes to `[rn, #offset]`
+    Str { rt: u8, rn: u8, offset: i64, size: u8 },
+
+    /// `movz`/ `movk` (`keep` for `movk`) of `imm` shifted left by `shift`
+    Mov { rd: u8, imm: u16, shift: u8, keep: bool },
+
+    /// `mov xd, xm` (`orr xd, xzr, xm`)
+    MovReg { rd: u8, rm: u8 },
+
+    /// `add`/ `sub` (`sub` if `negative`) of an immediate. `add xd, sp, #0` is `mov xd, sp`.
+    AddImm { rd: u8, rn: u8, imm: u64, negative: bool },
+
+    /// `cmp` of `rn` and a register (`subs xzr, rn, rm`)
+    CmpReg { rn: u8, rm: u8 },
+
+    /// `cmp` of `rn` and an immediate (`subs xzr, rn, #imm`)
+    CmpImm { rn: u8, imm: u64 },
+
+    /// `nop`, `isb` or `dsb`
+    Barrier,
+
+    /// Anything else
+    Other(u32),
+}
+
+/// `bits` bits of `word` starting at `lo`
+fn field(word: u32, lo: u32, bits: u32) -> u32 {
+    return (word >> lo) & ((1 << bits) - 1);
+}
+
+/// The `bits` bit two's complement `val`
+fn sign_extend(val: u32, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    return ((val as i64) << shift) >> shift;
+}
+
+/**
+ * Decode one instruction.
+ */
+pub fn decode(word: u32) -> Inst {
+    let rd = field(word, 0, 5) as u8;
+    let rn = field(word, 5, 5) as u8;
+    let rm = field(word, 16, 5) as u8;
+
+    if word & 0x7C000000 == 0x14000000 {
+        return Inst::B { offset: sign_extend(field(word, 0, 26), 26) * 4, link: word >> 31 != 0 };
+    }
+    if word & 0xFF000010 == 0x54000000 {
+        return Inst::BCond { cond: Cond(field(word, 0, 4) as u8), offset: sign_extend(field(word, 5, 19), 19) * 4 };
+    }
+    if word & 0x7E000000 == 0x34000000 {
+        return Inst::Cbz { rt: rd, nonzero: field(word, 24, 1) != 0, offset: sign_extend(field(word, 5, 19), 19) * 4 };
+    }
+    if word & 0x7E000000 == 0x36000000 {
+        return Inst::Tbz {
+            rt: rd,
+            bit: (field(word, 31, 1) << 5 | field(word, 19, 5)) as u8,
+            nonzero: field(word, 24, 1) != 0,
+            offset: sign_extend(field(word, 5, 14), 14) * 4,
+        };
+    }
+
+    // Unconditional branches to registers
+    if word & 0xFFDFFC1F == 0xD61F0000 {
+        return Inst::Br { rn, link: field(word, 21, 1) != 0 };
+    }
+    if word & 0xFFDFF800 == 0xD71F0800 {
+        let key = if field(word, 10, 1) != 0 { PtrAuthKey::IB } else { PtrAuthKey::IA };
+        return Inst::BrAuth { key, rn, modifier: Some(rd), link: field(word, 21, 1) != 0 };
+    }
+    if word & 0xFFDFF81F == 0xD61F081F {
+        let key = if field(word, 10, 1) != 0 { PtrAuthKey::IB } else { PtrAuthKey::IA };
+        return Inst::BrAuth { key, rn, modifier: None, link: field(word, 21, 1) != 0 };
+    }
+    if word & 0xFFFFFC1F == 0xD65F0000 {
+        return Inst::Ret { rn, key: None };
+    }
+    if word == 0xD65F0BFF || word == 0xD65F0FFF {
+        let key = if field(word, 10, 1) != 0 { PtrAuthKey::IB } else { PtrAuthKey::IA };
+        return Inst::Ret { rn: 30, key: Some(key) };
+    }
+
+    // pac*/ aut*
+    if word & 0xFFFFC000 == 0xDAC10000 {
+        let opcode = field(word, 10, 6);
+        let key = PtrAuthKey::from_bits(opcode as u64);
+        let zero = opcode & 0x8 != 0;
+        if zero && rn != XZR {
+            return Inst::Other(word);
+        }
+        let modifier = if zero { None } else { Some(rn) };
+        return match opcode & 0x34 {
+            0x00 => Inst::Pac { key, rd, modifier },
+            0x04 => Inst::Aut { key, rd, modifier },
+            _ => Inst::Other(word),
+        };
+    }
+
+    // Loads and stores (integer registers)
+    let size = 1u8 << field(word, 30, 2);
+    if word & 0x3F000000 == 0x39000000 {
+        let offset = field(word, 10, 12) as i64 * size as i64;
+        return match field(word, 22, 2) {
+            0b00 => Inst::Str { rt: rd, rn, offset, size },
+            0b01 => Inst::Ldr { rt: rd, rn, offset, size },
+            _ => Inst::Other(word),
+        };
+    }
+    if word & 0x3F200C00 == 0x38000000 {
+        let offset = sign_extend(field(word, 12, 9), 9);
+        return match field(word, 22, 2) {
+            0b00 => Inst::Str { rt: rd, rn, offset, size },
+            0b01 => Inst::Ldr { rt: rd, rn, offset, size },
+            _ => Inst::Other(word),
+        };
+    }
+    if word & 0x3FE00C00 == 0x38600800 {
+        return Inst::LdrReg { rt: rd, rn, rm, size };
+    }
+
+    // Moves
+    if word & 0x1F800000 == 0x12800000 {
+        let opc = field(word, 29, 2);
+        let imm = field(word, 5, 16) as u16;
+        let shift = (field(word, 21, 2) * 16) as u8;
+        return match opc {
+            0b10 => Inst::Mov { rd, imm, shift, keep: false },
+            0b11 => Inst::Mov { rd, imm, shift, keep: true },
+            _ => Inst::Other(word),
+        };
+    }
+    if word & 0x7FE0FFE0 == 0x2A0003E0 {
+        return Inst::MovReg { rd, rm };
+    }
+
+    // Arithmetic and compares
+    if word & 0x1F800000 == 0x11000000 {
+        let imm = (field(word, 10, 12) as u64) << (field(word, 22, 1) * 12);
+        let negative = field(word, 30, 1) != 0;
+        if field(word, 29, 1) != 0 {
+            if negative && rd == XZR {
+                return Inst::CmpImm { rn, imm };
+            }
+            return Inst::Other(word);
+        }
+        return Inst::AddImm { rd, rn, imm, negative };
+    }
+    if word & 0x7F200000 == 0x6B000000 && rd == XZR && field(word, 10, 6) == 0 {
+        return Inst::CmpReg { rn, rm };
+    }
+
+    if word == NOP || word == ISB || word & 0xFFFFF0FF == 0xD503309F {
+        return Inst::Barrier;
+    }
+
+    return Inst::Other(word);
+}
+
+impl Inst {
+    /**
+     * The register this instruction writes (if it writes one).
+     *
+     * Instructions that aren't decoded are assumed to write the register in bits 0-4, like most data
+     * processing instructions do, so nothing is assumed to survive them.
+     */
+    pub fn dest(&self) -> Option<u8> {
+        let rd = match *self {
+            Inst::Pac { rd, .. } | Inst::Aut { rd, .. } => rd,
+            Inst::Ldr { rt, .. } | Inst::LdrReg { rt, .. } => rt,
+            Inst::Mov { rd, .. } | Inst::MovReg { rd, .. } | Inst::AddImm { rd, .. } => rd,
+            Inst::B { link: true, .. } | Inst::Br { link: true, .. } | Inst::BrAuth { link: true, .. } => 30,
+            Inst::Other(word) => (word & 0x1F) as u8,
+            _ => return None,
+        };
+        if rd == XZR {
+            return None;
+        }
+        return Some(rd);
+    }
+
+    /// Is this a conditional branch?
+    pub fn is_conditional_branch(&self) -> bool {
+        return matches!(self, Inst::BCond { .. } | Inst::Cbz { .. } | Inst::Tbz { .. });
+    }
+
+    /// Does execution never continue with the next instruction?
+    pub fn ends_block(&self) -> bool {
+        return matches!(self, Inst::B { link: false, .. } | Inst::Br { link: false, .. } | Inst::BrAuth { link: false, .. } | Inst::Ret { .. });
+    }
+
+    /// Is this a call (`bl`, `blr`, `blraa`, ...)?
+    pub fn is_call(&self) -> bool {
+        return matches!(self, Inst::B { link: true, .. } | Inst::Br { link: true, .. } | Inst::BrAuth { link: true, .. });
+    }
+
+    /// Where a (conditional or not) direct branch at `addr` goes
+    pub fn branch_target(&self, addr: u64) -> Option<u64> {
+        return match *self {
+            Inst::B { offset, .. } | Inst::BCond { offset, .. } | Inst::Cbz { offset, .. } | Inst::Tbz { offset, .. } => Some(addr.wrapping_add(offset as u64)),
+            _ => None,
+        };
+    }
+
+    /**
+     * Encode this instruction (the inverse of `decode`).
+     *
+     * # Return Value
+     * The instruction word, or None if it can't be encoded: a branch offset that isn't 4 byte aligned or is out
+     * of range, an immediate or load/ store offset that doesn't fit, or a `Barrier` (which doesn't say which one,
+     * use `NOP`, `ISB` or `DSB_SY`).
+     */
+    pub fn encode(&self) -> Option<u32> {
+        let word = match *self {
+            Inst::B { offset, link } => 0x14000000 | (link as u32) << 31 | branch_imm(offset, 26)?,
+            Inst::BCond { cond, offset } => 0x54000000 | branch_imm(offset, 19)? << 5 | (cond.0 & 0xF) as u32,
+            Inst::Cbz { rt, nonzero, offset } => 0xB4000000 | (nonzero as u32) << 24 | branch_imm(offset, 19)? << 5 | reg(rt),
+            Inst::Tbz { rt, bit, nonzero, offset } => {
+                if bit >= 64 {
+                    return None;
+                }
+                0x36000000 | ((bit >> 5) as u32) << 31 | (nonzero as u32) << 24 | ((bit & 0x1F) as u32) << 19 | branch_imm(offset, 14)? << 5 | reg(rt)
+            },
+
+            Inst::Br { rn, link } => 0xD61F0000 | (link as u32) << 21 | reg(rn) << 5,
+            Inst::BrAuth { key, rn, modifier, link } => {
+                let key_b = match key {
+                    PtrAuthKey::IA => 0,
+                    PtrAuthKey::IB => 1,
+                    _ => return None,
+                };
+                let base = match modifier {
+                    Some(rm) => 0xD71F0800 | reg(rm),
+                    None => 0xD61F081F,
+                };
+                base | (link as u32) << 21 | key_b << 10 | reg(rn) << 5
+            },
+            Inst::Ret { rn, key: None } => 0xD65F0000 | reg(rn) << 5,
+            Inst::Ret { rn: LR, key: Some(PtrAuthKey::IA) } => 0xD65F0BFF,
+            Inst::Ret { rn: LR, key: Some(PtrAuthKey::IB) } => 0xD65F0FFF,
+            Inst::Ret { .. } => return None,
+
+            Inst::Pac { key, rd, modifier } => pac_aut(0x00, key, rd, modifier),
+            Inst::Aut { key, rd, modifier } => pac_aut(0x04, key, rd, modifier),
+
+            Inst::Ldr { rt, rn, offset, size } => load_store(0b01, rt, rn, offset, size)?,
+            Inst::Str { rt, rn, offset, size } => load_store(0b00, rt, rn, offset, size)?,
+            Inst::LdrReg { rt, rn, rm, size } => 0x38606800 | size_bits(size)? << 30 | reg(rm) << 16 | reg(rn) << 5 | reg(rt),
+
+            Inst::Mov { rd, imm, shift, keep } => {
+                if shift % 16 != 0 || shift > 48 {
+                    return None;
+                }
+                (if keep { 0xF2800000 } else { 0xD2800000 }) | ((shift / 16) as u32) << 21 | (imm as u32) << 5 | reg(rd)
+            },
+            Inst::MovReg { rd, rm } => 0xAA0003E0 | reg(rm) << 16 | reg(rd),
+            Inst::AddImm { rd, rn, imm, negative } => 0x91000000 | (negative as u32) << 30 | add_imm(imm)? | reg(rn) << 5 | reg(rd),
+            Inst::CmpReg { rn, rm } => 0xEB00001F | reg(rm) << 16 | reg(rn) << 5,
+            Inst::CmpImm { rn, imm } => 0xF100001F | add_imm(imm)? | reg(rn) << 5,
+
+            Inst::Barrier => return None,
+            Inst::Other(word) => word,
+        };
+        return Some(word);
+    }
+}
+
+/// Register number `r` (as a 5 bit field)
+fn reg(r: u8) -> u32 {
+    return (r & 0x1F) as u32;
+}
+
+/// A byte offset as the `bits` bit word offset of a branch (if it's aligned and in range)
+fn branch_imm(offset: i64, bits: u32) -> Option<u32> {
+    let words = offset / 4;
+    if offset % 4 != 0 || words < -(1 << (bits - 1)) || words >= 1 << (bits - 1) {
+        return None;
+    }
+    return Some((words as u32) & ((1 << bits) - 1));
+}
+
+/// `pac*` (`op` 0) or `aut*` (`op` 4) with `key`
+fn pac_aut(op: u32, key: PtrAuthKey, rd: u8, modifier: Option<u8>) -> u32 {
+    let opcode = op | key as u32;
+    return match modifier {
+        Some(rn) => 0xDAC10000 | opcode << 10 | reg(rn) << 5 | reg(rd),
+        None => 0xDAC10000 | (opcode | 0x8) << 10 | reg(XZR) << 5 | reg(rd),
+    };
+}
+
+/// The 2 bit size field of a `size` byte load or store
+fn size_bits(size: u8) -> Option<u32> {
+    return match size {
+        1 => Some(0),
+        2 => Some(1),
+        4 => Some(2),
+        8 => Some(3),
+        _ => None,
+    };
+}
+
+/// `ldr`/ `str` (`opc` 1 or 0) at `[rn, #offset]`, scaled if it can be and unscaled (`ldur`/ `stur`) otherwise
+fn load_store(opc: u32, rt: u8, rn: u8, offset: i64, size: u8) -> Option<u32> {
+    let size_field = size_bits(size)? << 30 | opc << 22 | reg(rn) << 5 | reg(rt);
+    let scaled = offset / size as i64;
+    if offset >= 0 && offset % size as i64 == 0 && scaled < 1 << 12 {
+        return Some(0x39000000 | size_field | (scaled as u32) << 10);
+    }
+    if (-256..256).contains(&offset) {
+        return Some(0x38000000 | size_field | ((offset as u32) & 0x1FF) << 12);
+    }
+    return None;
+}
+
+/// The 12 bit immediate (shifted by 12 if it has to be) of an `add`/ `sub`/ `cmp`
+fn add_imm(imm: u64) -> Option<u32> {
+    if imm < 1 << 12 {
+        return Some((imm as u32) << 10);
+    }
+    if imm & 0xFFF == 0 && imm < 1 << 24 {
+        return Some(1 << 22 | ((imm >> 12) as u32) << 10);
+    }
+    return None;
+}
+
+/**
+ * Encode every instruction of `insts` into little endian bytes (see `Inst::encode`).
+ *
+ * # Return Value
+ * The code, or None if any instruction can't be encoded.
+ */
+pub fn encode_all(insts: &[Inst]) -> Option<Vec<u8>> {
+    let mut code = Vec::with_capacity(insts.len() * 4);
+    for inst in insts {
+        code.extend_from_slice(&inst.encode()?.to_le_bytes());
+    }
+    return Some(code);
+}
+
+/**
+ * Decode every instruction of `code` (which starts at `addr`).
+ *
+ * # Return Value
+ * Each instruction's address and decoding.
+ */
+pub fn decode_all(code: &[u8], addr: u64) -> Vec<(u64, Inst)> {
+    return code.chunks_exact(4)
+        .enumerate()
+        .map(|(i, word)| (addr + i as u64 * 4, decode(u32::from_le_bytes(word.try_into().unwrap()))))
+        .collect();
+}
@@ -11,5 +11,14 @@ pub mod prime_probe;
 // Spectre testbed for testing speculative execution in the kernel
 pub mod spectre;
 
+// Spectre-v1 harness sweeping training schedules, LIMIT evictions and measurement oracles
+pub mod spectre_sweep;
+
+// How much work fits in the speculation window after a mispredicted bounds check
+pub mod spec_window;
+
 // The actual PACMAN attack code itself
 pub mod pacman;
+
+// Hardware-independent decision logic for the PACMAN attack (shared by live runs and trace replay)
+pub mod decision;
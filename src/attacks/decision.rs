@@ -0,0 +1,238 @@
+/*!
+ * The decision logic of the PACMAN attack, separated from the measurement code.
+ *
+ * Nothing in here touches hardware, so it can be driven by live measurements (see `pacman.rs`)
+ * or by a recorded trace (see `trace.rs`).
+ */
+
+/**
+ * Classify a single prime+probe measurement: count how many probe latencies were cache misses.
+ *
+ * # Arguments
+ * * `times`: The probe latencies of the eviction set.
+ * * `miss_latency`: Anything strictly above this many cycles is a miss.
+ */
+pub fn count_misses(times: &[u64], miss_latency: u64) -> u64 {
+    return times.iter().filter(|t| **t > miss_latency).count() as u64;
+}
+
+/**
+ * Summary statistics over a set of miss counts.
+ *
+ * `min` and `max` skip the two most extreme values on either end (the very first and last few
+ * samples are often outliers), which is what the original result printing reported.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SampleStats {
+    /// Third smallest miss count
+    pub min: u64,
+
+    /// Median miss count
+    pub median: u64,
+
+    /// Second largest miss count
+    pub max: u64,
+
+    /// Average miss count
+    pub avg: f64,
+
+    /// Sum of every miss count
+    pub total: u64,
+}
+
+impl SampleStats {
+    /**
+     * Compute statistics over `samples` (which do not need to be sorted).
+     * Returns `None` if there are no samples.
+     */
+    pub fn from_samples(samples: &[u64]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+
+        let mut sorted = samples.to_vec();
+        sorted.sort();
+
+        let total : u64 = sorted.iter().sum();
+        let len = sorted.len();
+
+        return Some(Self {
+            min: sorted[2.min(len - 1)],
+            median: sorted[len / 2],
+            max: sorted[len.saturating_sub(2)],
+            avg: total as f64 / len as f64,
+            total,
+        });
+    }
+}
+
+/**
+ * Which statistic of a guess' miss counts the bruteforce decides on.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DecisionStatistic {
+    /// `SampleStats::avg` (what the attack has always used)
+    Average,
+
+    /// `SampleStats::median` (ignores a few noisy measurements entirely)
+    Median,
+
+    /// `SampleStats::min` (a guess has to miss in nearly every measurement)
+    Min,
+}
+
+impl DecisionStatistic {
+    /// Every statistic
+    pub const ALL : [DecisionStatistic; 3] = [DecisionStatistic::Average, DecisionStatistic::Median, DecisionStatistic::Min];
+
+    /// Pick this statistic out of `stats`
+    pub fn of(&self, stats: &SampleStats) -> f64 {
+        return match self {
+            DecisionStatistic::Average => stats.avg,
+            DecisionStatistic::Median => stats.median as f64,
+            DecisionStatistic::Min => stats.min as f64,
+        };
+    }
+}
+
+/**
+ * The bruteforce decision algorithm.
+ *
+ * 1. The first guess sets the baseline: a guess is "significant" when its average miss count is
+ *    at least `how_far_above_average` above the first guess' average (capped at the eviction set size).
+ * 2. Every guess within half of `how_far_above_average` of the significant level is a potential match.
+ * 3. Potential matches are measured again (many more times) and inspected. Those that reach the
+ *    significant level are final matches, and the final match with the most misses in total wins.
+ */
+#[derive(Clone, Debug)]
+pub struct BruteforceDecider {
+    /// Size of the eviction set (the most misses a single measurement can have)
+    evset_size: usize,
+
+    /// How far above the first run's average does a run have to be to get marked "significant"
+    how_far_above_average: f64,
+
+    /// What the "average" of a guess is (`DecisionStatistic::Average` unless picked with `with_statistic`)
+    statistic: DecisionStatistic,
+
+    /// Average number of misses a guess needs to be significant (set by the first guess)
+    threshold: Option<f64>,
+
+    /// Was the threshold capped to the eviction set size?
+    threshold_capped: bool,
+
+    /// Guesses that passed the scan
+    potential_matches: Vec<u64>,
+
+    /// (total misses, guess) for every potential match that passed inspection
+    final_matches: Vec<(u64, u64)>,
+}
+
+impl BruteforceDecider {
+    /// Start deciding for an eviction set of `evset_size` addresses
+    pub fn new(evset_size: usize, how_far_above_average: f64) -> Self {
+        return Self::with_statistic(evset_size, how_far_above_average, DecisionStatistic::Average);
+    }
+
+    /// Same as `new`, but comparing guesses by `statistic` instead of their average
+    pub fn with_statistic(evset_size: usize, how_far_above_average: f64, statistic: DecisionStatistic) -> Self {
+        return Self {
+            evset_size,
+            how_far_above_average,
+            statistic,
+            threshold: None,
+            threshold_capped: false,
+            potential_matches: Vec::new(),
+            final_matches: Vec::new(),
+        };
+    }
+
+    /**
+     * Feed the miss counts of one guess from the scan phase.
+     *
+     * # Return Value
+     * Returns true if `guess` is now a potential match.
+     */
+    pub fn scan(&mut self, guess: u64, samples: &[u64]) -> bool {
+        let stats = match SampleStats::from_samples(samples) {
+            Some(v) => v,
+            None => return false,
+        };
+
+        let threshold = match self.threshold {
+            Some(v) => v,
+            None => {
+                // @TODO: while the observed average isn't great, regenerate eviction set :)
+                let mut threshold = self.statistic.of(&stats) + self.how_far_above_average;
+                if threshold > self.evset_size as f64 {
+                    threshold = self.evset_size as f64;
+                    self.threshold_capped = true;
+                }
+                self.threshold = Some(threshold);
+                threshold
+            }
+        };
+
+        if self.statistic.of(&stats) >= threshold - (self.how_far_above_average / 2.0) {
+            self.potential_matches.push(guess);
+            return true;
+        }
+
+        return false;
+    }
+
+    /**
+     * Feed the miss counts of a potential match from the inspection phase.
+     *
+     * # Return Value
+     * Returns true if `guess` is now a final match.
+     */
+    pub fn inspect(&mut self, guess: u64, samples: &[u64]) -> bool {
+        let stats = match SampleStats::from_samples(samples) {
+            Some(v) => v,
+            None => return false,
+        };
+
+        let threshold = match self.threshold {
+            Some(v) => v,
+            None => return false,
+        };
+
+        if self.statistic.of(&stats) >= threshold {
+            self.final_matches.push((stats.total, guess));
+            return true;
+        }
+
+        return false;
+    }
+
+    /// The average number of misses a guess needs to be significant (once the first guess was scanned)
+    pub fn threshold(&self) -> Option<f64> {
+        return self.threshold;
+    }
+
+    /// Did the first guess ask for an impossibly high average (so it was rounded down)?
+    pub fn threshold_capped(&self) -> bool {
+        return self.threshold_capped;
+    }
+
+    /// Guesses that passed the scan phase, in the order they were scanned
+    pub fn potential_matches(&self) -> &[u64] {
+        return &self.potential_matches;
+    }
+
+    /// (total misses, guess) for every guess that passed inspection, in the order they were inspected
+    pub fn final_matches(&self) -> &[(u64, u64)] {
+        return &self.final_matches;
+    }
+
+    /**
+     * Pick the final answer: the final match with the most misses.
+     * Returns `None` if nothing passed inspection.
+     */
+    pub fn decide(&self) -> Option<u64> {
+        let mut final_matches = self.final_matches.clone();
+        final_matches.sort_by_key(|k| k.0);
+        return final_matches.last().map(|k| k.1);
+    }
+}
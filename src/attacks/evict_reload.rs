@@ -1,9 +1,22 @@
 /*!
  * Generate latency traces for graphing and precise timer metrics using evict+reload.
  */
-use crate::*;
-use rand::thread_rng;
-use rand::prelude::SliceRandom;
+use crate::evset;
+use serde::{Serialize, Deserialize};
+
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+use {
+    crate::error::PacmanError,
+    crate::guard::KernelAllocation,
+    crate::cache,
+    crate::evset::{data_kpevset, data_pevset, inst_pevset},
+    crate::pac::PAC_BITMASK,
+    crate::primitives::*,
+    crate::results::{ExperimentRecord, ResultsSink},
+    crate::timer,
+    rand::thread_rng,
+    rand::prelude::SliceRandom,
+};
 
 /// Number of different eviction set sizes to try (each trial == a different eviction size)
 pub const TRIALS : usize = 256;
@@ -14,6 +27,51 @@ pub const NUM_RETRIALS : usize = 25;
 /// How many times to repeat a trial before reporting a latency
 pub const TRIAL_REPEAT : usize = 12;
 
+/**
+ * Runtime parameters for the evict+reload experiments.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EvictReloadParams {
+    /// Largest eviction set size to try. `None` means `TRIALS` for the userspace experiments and
+    /// every conflict found for the kernel experiments.
+    pub max_evset_size: Option<usize>,
+
+    /// Number of times to try a random collection of addresses from the potential eviction set for a given size
+    pub num_retrials: usize,
+
+    /// How many times to repeat a trial before reporting a latency
+    pub trial_repeat: usize,
+}
+
+/// The parameters used when nothing is overridden
+pub const DEFAULT_PARAMS : EvictReloadParams = EvictReloadParams {
+    max_evset_size: None,
+    num_retrials: NUM_RETRIALS,
+    trial_repeat: TRIAL_REPEAT,
+};
+
+impl EvictReloadParams {
+    /**
+     * Check these parameters make sense.
+     * Returns a description of the first problem found.
+     */
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(max_evset_size) = self.max_evset_size {
+            if max_evset_size == 0 || max_evset_size > evset::EVSET_SIZE_MAX {
+                return Err(format!("max_evset_size must be between 1 and {} (got {})", evset::EVSET_SIZE_MAX, max_evset_size));
+            }
+        }
+        if self.num_retrials == 0 {
+            return Err("num_retrials must be at least 1".to_string());
+        }
+        if self.trial_repeat == 0 {
+            return Err("trial_repeat must be at least 1".to_string());
+        }
+        return Ok(());
+    }
+}
+
 /**
  * Evict+Reload for data accesses.
  *
@@ -29,21 +87,15 @@ pub const TRIAL_REPEAT : usize = 12;
  *            reload test addr
  *            record reload latency
  *
- *    print(average(reload latencies for a given trial size))
+ *    record(average(reload latencies for a given trial size))
  * ```
  */
-pub unsafe fn data_evict_reload(shared_mem: &mut [u8]) {
-    let handle = match PacmanKitConnection::init() {
-        Some(v) => v,
-        None => panic!("Couldn't connect to PacmanKit"),
-    };
-
-    let mut kernel_mmap_va = match handle.kernel_mmap() {
-        Ok(v) => v,
-        Err(err) => panic!("Couldn't call IOMalloc in the kernel!"),
-    };
-
-    let kernel_mmap_pa = handle.kernel_virt_to_phys(kernel_mmap_va).unwrap();
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub unsafe fn data_evict_reload<K: KernelPrimitives>(handle: &K, shared_mem: &mut [u8], params: &EvictReloadParams, sink: &mut dyn ResultsSink) -> Result<(), PacmanError> {
+    let kernel_memory = KernelAllocation::new(handle)?;
+    let kernel_mmap_va = kernel_memory.addr();
+
+    let kernel_mmap_pa = handle.kernel_virt_to_phys(kernel_mmap_va)?;
     let target_set = cache::get_cache_set_m1(kernel_mmap_pa);
     println!("Kernel mmap VA: 0x{:X}\n            PA: 0x{:X}\n", kernel_mmap_va, kernel_mmap_pa);
     println!("Generating eviction set to match address with L2 set {}...", target_set);
@@ -52,10 +104,9 @@ pub unsafe fn data_evict_reload(shared_mem: &mut [u8]) {
     println!("Found {} conflicts.", evset.len());
 
     // Evict + Reload
-    for num_test_addrs in 0..TRIALS {
-        let mut averages = [0; NUM_RETRIALS];
-        let mut trial_accumulator = 0;
-        for retrial_idx in 0..NUM_RETRIALS {
+    for num_test_addrs in 0..params.max_evset_size.unwrap_or(TRIALS) {
+        let mut averages = vec![0; params.num_retrials];
+        for retrial_idx in 0..params.num_retrials {
             // Chose an eviction set of size `num_test_addrs`...
             let mut chosen : Vec<u64> = evset.choose_multiple(&mut rand::thread_rng(), num_test_addrs + 1).into_iter().cloned().collect();
             let evict_me = chosen.pop().unwrap();
@@ -66,10 +117,10 @@ pub unsafe fn data_evict_reload(shared_mem: &mut [u8]) {
             let mut indexes : Vec<usize> = (0..chosen.len()).collect();
             indexes.shuffle(&mut thread_rng());
 
-            let mut measurements = [0; TRIAL_REPEAT];
+            let mut measurements = vec![0; params.trial_repeat];
 
-            for cur_trial in 0..TRIAL_REPEAT+1 {
-                let init_read_time = timer::time_access(evict_me);
+            for cur_trial in 0..params.trial_repeat+1 {
+                let _init_read_time = timer::time_access(evict_me);
 
                 for i in 0..num_test_addrs {
                     timer::time_access(chosen[indexes[i]]);
@@ -84,21 +135,21 @@ pub unsafe fn data_evict_reload(shared_mem: &mut [u8]) {
             }
 
             let mut average : u64 = 0;
-            for i in 0..TRIAL_REPEAT {
+            for i in 0..params.trial_repeat {
                 average += measurements[i];
             }
-            average /= (TRIAL_REPEAT as u64);
-            trial_accumulator += average;
+            average /= params.trial_repeat as u64;
             averages[retrial_idx] = average;
         }
-        print!("'{}': [", num_test_addrs);
-
-        for i in averages {
-            print!("{},", i);
-        }
-
-        println!("],");
+        sink.record(
+            &ExperimentRecord::new("data_evict_reload")
+                .param("num_test_addrs", num_test_addrs)
+                .param("trial_repeat", params.trial_repeat)
+                .samples(&averages)
+        )?;
     }
+
+    return Ok(());
 }
 
 /**
@@ -116,21 +167,15 @@ pub unsafe fn data_evict_reload(shared_mem: &mut [u8]) {
  *            execute (again) test addr
  *            record execute latency
  *
- *    print(average(execute latencies for a given trial size))
+ *    record(average(execute latencies for a given trial size))
  * ```
  */
- pub unsafe fn inst_evict_reload(shared_mem: &mut [u8]) {
-    let handle = match PacmanKitConnection::init() {
-        Some(v) => v,
-        None => panic!("Couldn't connect to PacmanKit"),
-    };
-
-    let mut kernel_mmap_va = match handle.kernel_mmap() {
-        Ok(v) => v,
-        Err(err) => panic!("Couldn't call IOMalloc in the kernel!"),
-    };
-
-    let kernel_mmap_pa = handle.kernel_virt_to_phys(kernel_mmap_va).unwrap();
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+ pub unsafe fn inst_evict_reload<K: KernelPrimitives>(handle: &K, shared_mem: &mut [u8], params: &EvictReloadParams, sink: &mut dyn ResultsSink) -> Result<(), PacmanError> {
+    let kernel_memory = KernelAllocation::new(handle)?;
+    let kernel_mmap_va = kernel_memory.addr();
+
+    let kernel_mmap_pa = handle.kernel_virt_to_phys(kernel_mmap_va)?;
     let target_set = cache::get_cache_set_m1(kernel_mmap_pa);
     println!("Kernel mmap VA: 0x{:X}\n            PA: 0x{:X}\n", kernel_mmap_va, kernel_mmap_pa);
     println!("Generating eviction set to match address with L2 set {}...", target_set);
@@ -139,10 +184,9 @@ pub unsafe fn data_evict_reload(shared_mem: &mut [u8]) {
     println!("Found {} conflicts.", evset.len());
 
     // Evict + Reload
-    for num_test_addrs in 0..TRIALS {
-        let mut averages = [0; NUM_RETRIALS];
-        let mut trial_accumulator = 0;
-        for retrial_idx in 0..NUM_RETRIALS {
+    for num_test_addrs in 0..params.max_evset_size.unwrap_or(TRIALS) {
+        let mut averages = vec![0; params.num_retrials];
+        for retrial_idx in 0..params.num_retrials {
             // init_memory(shared_mem);
 
             // Chose an eviction set of size `num_test_addrs`...
@@ -155,10 +199,10 @@ pub unsafe fn data_evict_reload(shared_mem: &mut [u8]) {
             let mut indexes : Vec<usize> = (0..chosen.len()).collect();
             indexes.shuffle(&mut thread_rng());
 
-            let mut measurements = [0; TRIAL_REPEAT];
+            let mut measurements = vec![0; params.trial_repeat];
 
-            for cur_trial in 0..TRIAL_REPEAT+1 {
-                let init_read_time = timer::time_exec(evict_me);
+            for cur_trial in 0..params.trial_repeat+1 {
+                let _init_read_time = timer::time_exec(evict_me);
 
                 for i in 0..num_test_addrs {
                     timer::time_exec(chosen[indexes[i]]);
@@ -173,21 +217,21 @@ pub unsafe fn data_evict_reload(shared_mem: &mut [u8]) {
             }
 
             let mut average : u64 = 0;
-            for i in 0..TRIAL_REPEAT {
+            for i in 0..params.trial_repeat {
                 average += measurements[i];
             }
-            average /= (TRIAL_REPEAT as u64);
-            trial_accumulator += average;
+            average /= params.trial_repeat as u64;
             averages[retrial_idx] = average;
         }
-        print!("'{}': [", num_test_addrs);
-
-        for i in averages {
-            print!("{},", i);
-        }
-
-        println!("],");
+        sink.record(
+            &ExperimentRecord::new("inst_evict_reload")
+                .param("num_test_addrs", num_test_addrs)
+                .param("trial_repeat", params.trial_repeat)
+                .samples(&averages)
+        )?;
     }
+
+    return Ok(());
 }
 
 /**
@@ -195,35 +239,30 @@ pub unsafe fn data_evict_reload(shared_mem: &mut [u8]) {
  *
  * Keep this in sync with `data_evict_reload`!
  */
- pub unsafe fn data_evict_reload_kernel(shared_mem: &mut [u8]) {
-    let handle = match PacmanKitConnection::init() {
-        Some(v) => v,
-        None => panic!("Couldn't connect to PacmanKit"),
-    };
-
-    let kernel_target_va = handle.leak_limit_location().unwrap();
-    // let kernel_target_va = handle.kernel_mmap().unwrap();
-    let kernel_target_pa = handle.kernel_virt_to_phys(kernel_target_va).unwrap();
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+ pub unsafe fn data_evict_reload_kernel<K: KernelPrimitives>(handle: &K, shared_mem: &mut [u8], params: &EvictReloadParams, sink: &mut dyn ResultsSink) -> Result<(), PacmanError> {
+    let kernel_target_va = handle.leak_limit_location()?;
+    // let kernel_target_va = handle.kernel_mmap()?;
+    let kernel_target_pa = handle.kernel_virt_to_phys(kernel_target_va)?;
 
     let target_set = cache::get_cache_set_m1(kernel_target_pa);
-    println!("Kernel target VA: 0x{:X}\n              PA: 0x{:X}\n              Contents: 0x{:X}\n", kernel_target_va, kernel_target_pa, handle.kernel_read(kernel_target_va).unwrap());
+    println!("Kernel target VA: 0x{:X}\n              PA: 0x{:X}\n              Contents: 0x{:X}\n", kernel_target_va, kernel_target_pa, handle.kernel_read(kernel_target_va)?);
     println!("Generating eviction set to match address with L2 set {}...", target_set);
 
     let evset = data_pevset(kernel_target_va, kernel_target_pa, shared_mem);
     println!("Found {} conflicts.", evset.len());
 
     // Evict + Reload
-    for num_test_addrs in 0..evset.len() {
-        let mut averages = [0; NUM_RETRIALS];
-        let mut trial_accumulator = 0;
-        for retrial_idx in 0..NUM_RETRIALS {
+    for num_test_addrs in 0..params.max_evset_size.unwrap_or(evset.len()).min(evset.len()) {
+        let mut averages = vec![0; params.num_retrials];
+        for retrial_idx in 0..params.num_retrials {
             // Chose an eviction set of size `num_test_addrs`...
             let mut chosen : Vec<u64> = evset.choose_multiple(&mut rand::thread_rng(), num_test_addrs + 1).into_iter().cloned().collect();
 
             // For the user mode version, we use something from the evset as our reload target
             // Since we're using the kernel, we can ignore this popped value
             // Keep it here to ensure the index math lines up with the user version of this method, though.
-            let ignore_this = chosen.pop().unwrap();
+            let _ignore_this = chosen.pop().unwrap();
 
             // ...and access them in a random order
             // Don't do pointer chasing as the DMP can predict that
@@ -231,16 +270,16 @@ pub unsafe fn data_evict_reload(shared_mem: &mut [u8]) {
             let mut indexes : Vec<usize> = (0..chosen.len()).collect();
             indexes.shuffle(&mut thread_rng());
 
-            let mut measurements = [0; TRIAL_REPEAT];
+            let mut measurements = vec![0; params.trial_repeat];
 
-            for cur_trial in 0..TRIAL_REPEAT+1 {
-                let init_read_time = handle.kernel_read_for_timing(kernel_target_va, true).unwrap();
+            for cur_trial in 0..params.trial_repeat+1 {
+                let _init_read_time = handle.kernel_read_for_timing(kernel_target_va, true)?;
 
                 for i in 0..num_test_addrs {
                     timer::time_access(chosen[indexes[i]]);
                 }
 
-                let reload_time = handle.kernel_read_for_timing(kernel_target_va, true).unwrap();
+                let reload_time = handle.kernel_read_for_timing(kernel_target_va, true)?;
 
                 // Skip the very first trial as its latency is always way too high
                 if cur_trial != 0 {
@@ -249,21 +288,21 @@ pub unsafe fn data_evict_reload(shared_mem: &mut [u8]) {
             }
 
             let mut average : u64 = 0;
-            for i in 0..TRIAL_REPEAT {
+            for i in 0..params.trial_repeat {
                 average += measurements[i];
             }
-            average /= (TRIAL_REPEAT as u64);
-            trial_accumulator += average;
+            average /= params.trial_repeat as u64;
             averages[retrial_idx] = average;
         }
-        print!("'{}': [", num_test_addrs);
-
-        for i in averages {
-            print!("{},", i);
-        }
-
-        println!("],");
+        sink.record(
+            &ExperimentRecord::new("data_evict_reload_kernel")
+                .param("num_test_addrs", num_test_addrs)
+                .param("trial_repeat", params.trial_repeat)
+                .samples(&averages)
+        )?;
     }
+
+    return Ok(());
 }
 
 /**
@@ -271,16 +310,12 @@ pub unsafe fn data_evict_reload(shared_mem: &mut [u8]) {
  *
  * Keep this in sync with `inst_evict_reload`!
  */
- pub unsafe fn inst_evict_reload_kernel(shared_mem: &mut [u8]) {
-    let handle = match PacmanKitConnection::init() {
-        Some(v) => v,
-        None => panic!("Couldn't connect to PacmanKit"),
-    };
-
-    let kernel_method_va = handle.leak_retpoline().unwrap() | PAC_BITMASK; // + 0x30C0;
-    // let kernel_method_va = handle.get_kernel_base().unwrap() + attacks::pacman::INST_TARGET_OFFSET;
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+ pub unsafe fn inst_evict_reload_kernel<K: KernelPrimitives>(handle: &K, shared_mem: &mut [u8], params: &EvictReloadParams, sink: &mut dyn ResultsSink) -> Result<(), PacmanError> {
+    let kernel_method_va = handle.leak_retpoline()? | PAC_BITMASK; // + 0x30C0;
+    // let kernel_method_va = handle.get_kernel_base()? + 0x26a497c; // profile::INST_TARGET
 
-    let kernel_method_pa = handle.kernel_virt_to_phys(kernel_method_va).unwrap();
+    let kernel_method_pa = handle.kernel_virt_to_phys(kernel_method_va)?;
     let target_set = cache::get_cache_set_m1(kernel_method_pa);
     println!("Kernel mmap VA: 0x{:X}\n            PA: 0x{:X}\n", kernel_method_va, kernel_method_pa);
     println!("Generating eviction set to match address with L2 set {}...", target_set);
@@ -289,10 +324,9 @@ pub unsafe fn data_evict_reload(shared_mem: &mut [u8]) {
     println!("Found {} conflicts.", evset.len());
 
     // Evict + Reload
-    for num_test_addrs in 0..TRIALS {
-        let mut averages = [0; NUM_RETRIALS];
-        let mut trial_accumulator = 0;
-        for retrial_idx in 0..NUM_RETRIALS {
+    for num_test_addrs in 0..params.max_evset_size.unwrap_or(TRIALS) {
+        let mut averages = vec![0; params.num_retrials];
+        for retrial_idx in 0..params.num_retrials {
             // init_memory(shared_mem);
 
             // Chose an eviction set of size `num_test_addrs`...
@@ -301,7 +335,7 @@ pub unsafe fn data_evict_reload(shared_mem: &mut [u8]) {
             // For the user mode version, we use something from the evset as our reload target
             // Since we're using the kernel, we can ignore this popped value
             // Keep it here to ensure the index math lines up with the user version of this method, though.
-            let ignore_this = chosen.pop().unwrap();
+            let _ignore_this = chosen.pop().unwrap();
 
             // ...and access them in a random order
             // Don't do pointer chasing as the DMP can predict that
@@ -309,16 +343,16 @@ pub unsafe fn data_evict_reload(shared_mem: &mut [u8]) {
             let mut indexes : Vec<usize> = (0..chosen.len()).collect();
             indexes.shuffle(&mut thread_rng());
 
-            let mut measurements = [0; TRIAL_REPEAT];
+            let mut measurements = vec![0; params.trial_repeat];
 
-            for cur_trial in 0..TRIAL_REPEAT+1 {
-                let init_read_time = handle.kernel_exec_for_timing(kernel_method_va, true).unwrap();
+            for cur_trial in 0..params.trial_repeat+1 {
+                let _init_read_time = handle.kernel_exec_for_timing(kernel_method_va, true)?;
 
                 for i in 0..num_test_addrs {
                     timer::time_exec(chosen[indexes[i]]);
                 }
 
-                let reload_time = handle.kernel_exec_for_timing(kernel_method_va, true).unwrap();
+                let reload_time = handle.kernel_exec_for_timing(kernel_method_va, true)?;
 
                 // Skip the very first trial as its latency is always way too high
                 if cur_trial != 0 {
@@ -327,21 +361,21 @@ pub unsafe fn data_evict_reload(shared_mem: &mut [u8]) {
             }
 
             let mut average : u64 = 0;
-            for i in 0..TRIAL_REPEAT {
+            for i in 0..params.trial_repeat {
                 average += measurements[i];
             }
-            average /= (TRIAL_REPEAT as u64);
-            trial_accumulator += average;
+            average /= params.trial_repeat as u64;
             averages[retrial_idx] = average;
         }
-        print!("'{}': [", num_test_addrs);
-
-        for i in averages {
-            print!("{},", i);
-        }
-
-        println!("],");
+        sink.record(
+            &ExperimentRecord::new("inst_evict_reload_kernel")
+                .param("num_test_addrs", num_test_addrs)
+                .param("trial_repeat", params.trial_repeat)
+                .samples(&averages)
+        )?;
     }
+
+    return Ok(());
 }
 
 /**
@@ -351,44 +385,40 @@ pub unsafe fn data_evict_reload(shared_mem: &mut [u8]) {
  *
  * Keep this in sync with `data_evict_reload`!
  */
- pub unsafe fn data_evict_reload_kernel_kernel_evset(shared_mem: &mut [u8]) {
-    let handle = match PacmanKitConnection::init() {
-        Some(v) => v,
-        None => panic!("Couldn't connect to PacmanKit"),
-    };
-
-    let kernel_limit_addr = handle.leak_limit_location().unwrap();
-    let kernel_mmap_addr = handle.kernel_mmap().unwrap();
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+ pub unsafe fn data_evict_reload_kernel_kernel_evset<K: KernelPrimitives>(handle: &K, _shared_mem: &mut [u8], params: &EvictReloadParams, sink: &mut dyn ResultsSink) -> Result<(), PacmanError> {
+    let kernel_limit_addr = handle.leak_limit_location()?;
+    let kernel_memory = KernelAllocation::new(handle)?;
+    let kernel_mmap_addr = kernel_memory.addr();
     let kernel_target_va = kernel_limit_addr;
     // let kernel_target_va = kernel_mmap_addr;
-    let kernel_target_pa = handle.kernel_virt_to_phys(kernel_target_va).unwrap();
+    let kernel_target_pa = handle.kernel_virt_to_phys(kernel_target_va)?;
 
     let target_set = cache::get_cache_set_m1(kernel_target_pa);
-    println!("Kernel target VA: 0x{:X}\n              PA: 0x{:X}\n              Contents: 0x{:X}\n", kernel_target_va, kernel_target_pa, handle.kernel_read(kernel_target_va).unwrap());
+    println!("Kernel target VA: 0x{:X}\n              PA: 0x{:X}\n              Contents: 0x{:X}\n", kernel_target_va, kernel_target_pa, handle.kernel_read(kernel_target_va)?);
     println!("Generating eviction set to match address with L2 set {}...", target_set);
 
     // let evset = data_pevset(kernel_target_va, kernel_target_pa, shared_mem);
-    let evset = data_kpevset(kernel_target_va, kernel_target_pa, kernel_mmap_addr, 0xC000 * cache::PAGE_SIZE);
+    let evset = data_kpevset(handle, kernel_target_va, kernel_target_pa, kernel_mmap_addr, 0xC000 * cache::PAGE_SIZE)?;
     println!("Found {} conflicts.", evset.len());
 
     for i in 0..evset.len() {
         if i > 100 {break;}
         let print_va = evset[i];
-        println!("0x{:X}\t=>\t0x{:X}", print_va, handle.kernel_virt_to_phys(print_va).unwrap());
+        println!("0x{:X}\t=>\t0x{:X}", print_va, handle.kernel_virt_to_phys(print_va)?);
     }
 
     // Evict + Reload
-    for num_test_addrs in 0..evset.len() {
-        let mut averages = [0; NUM_RETRIALS];
-        let mut trial_accumulator = 0;
-        for retrial_idx in 0..NUM_RETRIALS {
+    for num_test_addrs in 0..params.max_evset_size.unwrap_or(evset.len()).min(evset.len()) {
+        let mut averages = vec![0; params.num_retrials];
+        for retrial_idx in 0..params.num_retrials {
             // Chose an eviction set of size `num_test_addrs`...
             let mut chosen : Vec<u64> = evset.choose_multiple(&mut rand::thread_rng(), num_test_addrs + 1).into_iter().cloned().collect();
 
             // For the user mode version, we use something from the evset as our reload target
             // Since we're using the kernel, we can ignore this popped value
             // Keep it here to ensure the index math lines up with the user version of this method, though.
-            let ignore_this = chosen.pop().unwrap();
+            let _ignore_this = chosen.pop().unwrap();
 
             // ...and access them in a random order
             // Don't do pointer chasing as the DMP can predict that
@@ -396,21 +426,21 @@ pub unsafe fn data_evict_reload(shared_mem: &mut [u8]) {
             let mut indexes : Vec<usize> = (0..chosen.len()).collect();
             indexes.shuffle(&mut thread_rng());
 
-            let mut measurements = [0; TRIAL_REPEAT];
+            let mut measurements = vec![0; params.trial_repeat];
 
-            for cur_trial in 0..TRIAL_REPEAT+1 {
-                let init_read_time = handle.kernel_read_for_timing(kernel_target_va, true).unwrap();
+            for cur_trial in 0..params.trial_repeat+1 {
+                let _init_read_time = handle.kernel_read_for_timing(kernel_target_va, true)?;
 
                 for i in 0..num_test_addrs {
                     // timer::time_access(chosen[indexes[i]]);
-                    handle.kernel_read_for_timing(chosen[indexes[i]], true).unwrap();
+                    handle.kernel_read_for_timing(chosen[indexes[i]], true)?;
                 }
 
                 // Flush the entire cache:
                 // init_memory(shared_mem);
                 // flush_cache(shared_mem);
 
-                let reload_time = handle.kernel_read_for_timing(kernel_target_va, true).unwrap();
+                let reload_time = handle.kernel_read_for_timing(kernel_target_va, true)?;
 
                 // Skip the very first trial as its latency is always way too high
                 if cur_trial != 0 {
@@ -419,19 +449,19 @@ pub unsafe fn data_evict_reload(shared_mem: &mut [u8]) {
             }
 
             let mut average : u64 = 0;
-            for i in 0..TRIAL_REPEAT {
+            for i in 0..params.trial_repeat {
                 average += measurements[i];
             }
-            average /= (TRIAL_REPEAT as u64);
-            trial_accumulator += average;
+            average /= params.trial_repeat as u64;
             averages[retrial_idx] = average;
         }
-        print!("'{}': [", num_test_addrs);
-
-        for i in averages {
-            print!("{},", i);
-        }
-
-        println!("],");
+        sink.record(
+            &ExperimentRecord::new("data_evict_reload_kernel_kernel_evset")
+                .param("num_test_addrs", num_test_addrs)
+                .param("trial_repeat", params.trial_repeat)
+                .samples(&averages)
+        )?;
     }
+
+    return Ok(());
 }
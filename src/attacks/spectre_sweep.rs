@@ -0,0 +1,500 @@
+/*!
+ * A Spectre-v1 harness: how often does mistraining one of PacmanKit's bounds checks on `LIMIT` actually
+ * open a speculation window?
+ *
+ * Each trial runs the same steps as the PACMAN experiments, with every choice they hard code made a
+ * parameter (the `spectre` experiments are single settings of it, see `attacks::spectre`):
+ * 1. Train the bounds check with a `TrainingSchedule` (how many in-bounds calls, and any out-of-bounds ones
+ *    mixed in).
+ * 2. Evict `LIMIT` so the bounds check has to wait for memory (a `ConditionEviction`).
+ * 3. Evict the target by priming an eviction set for it.
+ * 4. Call the `SpectreGadget` out of bounds on the target.
+ * 5. Ask an `Oracle` whether the target was touched.
+ *
+ * `spectre_sweep` runs every combination of the settings in a `SweepConfig` and reports how many trials of
+ * each speculated. Nothing here needs the hardware: against the simulated kernel it exercises the simulated
+ * predictor (see `sim::kernel`).
+ */
+use std::fmt;
+use std::str::FromStr;
+use serde::{Serialize, Deserialize};
+use crate::attacks::decision::count_misses;
+use crate::attacks::pacman::find_victim_objects;
+use crate::cache;
+use crate::error::PacmanError;
+use crate::evset;
+use crate::guard::KernelAllocation;
+use crate::pac::PAC_BITMASK;
+use crate::profile::{self, OffsetProfile};
+use crate::primitives::*;
+use crate::results::{ExperimentRecord, ResultsSink};
+use crate::timer;
+
+/// The out of bounds index the PACMAN experiments call the gadget with (anything at or above `LIMIT` works)
+pub const ATTACK_IDX : u64 = 10000;
+
+/// How far into the retpoline region the exec gadget's target is by default (a page away from the `ret` it's
+/// trained on, and inside the region on every PacmanKit build)
+pub const EXEC_TARGET_OFFSET : u64 = 0x4000;
+
+/// Trials per setting
+pub const SWEEP_NUM_TRIALS : usize = 64;
+
+/// How many addresses to prime the target with
+pub const SWEEP_TARGET_EVSET_SIZE : usize = 12;
+
+/// Latency above which a probe (or the kernel's timing of the target) is a miss. 42 for MSR timers, 110 for multithreaded.
+pub const SWEEP_MISS_LATENCY : u64 = 42;
+
+/**
+ * Which of PacmanKit's bounds checked gadgets to mistrain.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpectreGadget {
+    /// `kernel_read_for_spectre`: a load from a PacmanKit allocation (trained on another line of it)
+    Read,
+
+    /// `kernel_exec_for_spectre`: a branch into the kext's retpoline region (trained on its first `ret`)
+    Exec,
+
+    /// PacmanKitService's externalMethod: the virtual call PACMAN attacks (its footprint is on the vtable)
+    ServiceRoutine,
+}
+
+impl fmt::Display for SpectreGadget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", match self {
+            SpectreGadget::Read => "read",
+            SpectreGadget::Exec => "exec",
+            SpectreGadget::ServiceRoutine => "service_routine",
+        });
+    }
+}
+
+/**
+ * The order of the calls that train a bounds check before the attack.
+ *
+ * Written as `consecutive:<count>`, `interleaved:<count>:<every>` or `alternating:<count>:<run>`.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum TrainingSchedule {
+    /// `count` in-bounds calls
+    Consecutive { count: usize },
+
+    /// `count` in-bounds calls, with an out-of-bounds one after every `every` of them
+    Interleaved { count: usize, every: usize },
+
+    /// Runs of `run` in-bounds calls and `run` out-of-bounds calls, until there have been `count` in-bounds
+    /// calls (ending on a run of in-bounds calls)
+    Alternating { count: usize, run: usize },
+}
+
+impl TrainingSchedule {
+    /**
+     * The training calls in order: true for in-bounds, false for out-of-bounds.
+     */
+    pub fn calls(&self) -> Vec<bool> {
+        let (count, in_run, out_run) = match *self {
+            TrainingSchedule::Consecutive { count } => (count, count.max(1), 0),
+            TrainingSchedule::Interleaved { count, every } => (count, every, 1),
+            TrainingSchedule::Alternating { count, run } => (count, run, run),
+        };
+
+        let mut calls = Vec::new();
+        let mut in_bounds = 0;
+        while in_bounds < count {
+            let run = in_run.min(count - in_bounds);
+            calls.extend(std::iter::repeat_n(true, run));
+            in_bounds += run;
+            if in_bounds < count {
+                calls.extend(std::iter::repeat_n(false, out_run));
+            }
+        }
+        return calls;
+    }
+}
+
+impl fmt::Display for TrainingSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match *self {
+            TrainingSchedule::Consecutive { count } => write!(f, "consecutive:{}", count),
+            TrainingSchedule::Interleaved { count, every } => write!(f, "interleaved:{}:{}", count, every),
+            TrainingSchedule::Alternating { count, run } => write!(f, "alternating:{}:{}", count, run),
+        };
+    }
+}
+
+impl FromStr for TrainingSchedule {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let fields : Vec<&str> = text.trim().split(':').collect();
+        let numbers = fields[1..].iter()
+            .map(|field| field.parse::<usize>().map_err(|err| format!("Bad number in training schedule {}: {}", text, err)))
+            .collect::<Result<Vec<usize>, String>>()?;
+        return match (fields[0], &numbers[..]) {
+            ("consecutive", &[count]) => Ok(TrainingSchedule::Consecutive { count }),
+            ("interleaved", &[count, every]) if every > 0 => Ok(TrainingSchedule::Interleaved { count, every }),
+            ("alternating", &[count, run]) if run > 0 => Ok(TrainingSchedule::Alternating { count, run }),
+            _ => Err(format!("{} isn't consecutive:<count>, interleaved:<count>:<every> or alternating:<count>:<run> (with every and run at least 1)", text)),
+        };
+    }
+}
+
+impl TryFrom<String> for TrainingSchedule {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        return text.parse();
+    }
+}
+
+impl From<TrainingSchedule> for String {
+    fn from(schedule: TrainingSchedule) -> Self {
+        return schedule.to_string();
+    }
+}
+
+/**
+ * How `LIMIT` is evicted before the attack, so the bounds check waits on memory.
+ *
+ * Written as `none`, `evset:<size>` or `flush`.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum ConditionEviction {
+    /// Leave `LIMIT` cached (the control: nothing should speculate)
+    None,
+
+    /// Access `size` addresses congruent with `LIMIT` (what the spectre and PACMAN experiments do)
+    Evset { size: usize },
+
+    /// Walk an L2 sized buffer
+    Flush,
+}
+
+impl fmt::Display for ConditionEviction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match *self {
+            ConditionEviction::None => write!(f, "none"),
+            ConditionEviction::Evset { size } => write!(f, "evset:{}", size),
+            ConditionEviction::Flush => write!(f, "flush"),
+        };
+    }
+}
+
+impl FromStr for ConditionEviction {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        return match text.trim().split_once(':') {
+            None if text.trim() == "none" => Ok(ConditionEviction::None),
+            None if text.trim() == "flush" => Ok(ConditionEviction::Flush),
+            Some(("evset", size)) => match size.parse() {
+                Ok(size) if size > 0 && size <= evset::EVSET_SIZE_MAX => Ok(ConditionEviction::Evset { size }),
+                _ => Err(format!("Bad eviction set size in {} (must be between 1 and {})", text, evset::EVSET_SIZE_MAX)),
+            },
+            _ => Err(format!("{} isn't none, evset:<size> or flush", text)),
+        };
+    }
+}
+
+impl TryFrom<String> for ConditionEviction {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        return text.parse();
+    }
+}
+
+impl From<ConditionEviction> for String {
+    fn from(eviction: ConditionEviction) -> Self {
+        return eviction.to_string();
+    }
+}
+
+/**
+ * How to tell whether the attack touched the target.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Oracle {
+    /// Time the target from the kernel (`kernel_read_for_timing`/ `kernel_exec_for_timing`): a hit means it was touched
+    KernelTiming,
+
+    /// Probe the target's eviction set from userspace: any miss means it was touched
+    PrimeProbe,
+}
+
+impl fmt::Display for Oracle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", match self {
+            Oracle::KernelTiming => "kernel_timing",
+            Oracle::PrimeProbe => "prime_probe",
+        });
+    }
+}
+
+/**
+ * The settings to sweep (every combination is run) and the parameters shared by all of them.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SweepConfig {
+    /// How many trials to run for each setting?
+    pub num_trials: usize,
+
+    /// The out of bounds index to attack with (and for the out-of-bounds training calls)
+    pub attack_idx: u64,
+
+    /// How many addresses to prime the target with?
+    pub target_evset_size: usize,
+
+    /// Latency above which the target (or a probed address) missed
+    pub miss_latency: u64,
+
+    /// How far into the kext's retpoline region the exec gadget's target is (has to be inside the region)
+    pub exec_target_offset: u64,
+
+    pub gadgets: Vec<SpectreGadget>,
+    pub schedules: Vec<TrainingSchedule>,
+    pub evictions: Vec<ConditionEviction>,
+    pub oracles: Vec<Oracle>,
+}
+
+impl Default for SweepConfig {
+    fn default() -> Self {
+        return SweepConfig {
+            num_trials: SWEEP_NUM_TRIALS,
+            attack_idx: ATTACK_IDX,
+            target_evset_size: SWEEP_TARGET_EVSET_SIZE,
+            miss_latency: SWEEP_MISS_LATENCY,
+            exec_target_offset: EXEC_TARGET_OFFSET,
+            gadgets: vec![SpectreGadget::Read, SpectreGadget::Exec, SpectreGadget::ServiceRoutine],
+            schedules: vec![
+                TrainingSchedule::Consecutive { count: 1 },
+                TrainingSchedule::Consecutive { count: 4 },
+                TrainingSchedule::Consecutive { count: 16 },
+                TrainingSchedule::Consecutive { count: 64 },
+                TrainingSchedule::Interleaved { count: 64, every: 4 },
+                TrainingSchedule::Alternating { count: 64, run: 1 },
+            ],
+            evictions: vec![ConditionEviction::None, ConditionEviction::Evset { size: 50 }],
+            oracles: vec![Oracle::KernelTiming, Oracle::PrimeProbe],
+        };
+    }
+}
+
+impl SweepConfig {
+    /**
+     * Check these parameters make sense.
+     * Returns a description of the first problem found.
+     */
+    pub fn validate(&self) -> Result<(), String> {
+        if self.num_trials == 0 {
+            return Err("num_trials must be at least 1".to_string());
+        }
+        if self.target_evset_size == 0 || self.target_evset_size > evset::EVSET_SIZE_MAX {
+            return Err(format!("target_evset_size must be between 1 and {} (got {})", evset::EVSET_SIZE_MAX, self.target_evset_size));
+        }
+        if self.exec_target_offset == 0 || !self.exec_target_offset.is_multiple_of(4) {
+            return Err(format!("exec_target_offset must be a nonzero multiple of 4 (got 0x{:x})", self.exec_target_offset));
+        }
+        if self.gadgets.is_empty() || self.schedules.is_empty() || self.evictions.is_empty() || self.oracles.is_empty() {
+            return Err("gadgets, schedules, evictions and oracles each need at least one setting".to_string());
+        }
+        return Ok(());
+    }
+}
+
+/**
+ * How one setting of the sweep did.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct SweepResult {
+    pub gadget: SpectreGadget,
+    pub schedule: TrainingSchedule,
+    pub eviction: ConditionEviction,
+    pub oracle: Oracle,
+
+    /// What the oracle measured each trial (the target's latency, or how many probes missed)
+    pub samples: Vec<u64>,
+
+    /// How many trials touched the target
+    pub speculated: usize,
+}
+
+impl SweepResult {
+    /// The fraction of trials that touched the target
+    pub fn success_rate(&self) -> f64 {
+        return self.speculated as f64 / self.samples.len() as f64;
+    }
+}
+
+impl fmt::Display for SweepResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{} trained {} with LIMIT evicted by {}, measured by {}: {}/{} speculated ({:.0}%)",
+            self.gadget, self.schedule, self.eviction, self.oracle, self.speculated, self.samples.len(), self.success_rate() * 100.0);
+    }
+}
+
+/// A gadget's training and target addresses
+struct GadgetTarget {
+    train: u64,
+    target: u64,
+}
+
+impl SpectreGadget {
+    /// Is the target executed (rather than loaded)?
+    pub fn is_inst(&self) -> bool {
+        return *self == SpectreGadget::Exec;
+    }
+
+    /// Where to train and attack (`kernel_memory` is a PacmanKit allocation for the read gadget, `profile` says
+    /// where the service routine's object is)
+    unsafe fn target<K: KernelPrimitives>(&self, handle: &K, kernel_memory: u64, config: &SweepConfig, profile: Option<&OffsetProfile>) -> Result<GadgetTarget, PacmanError> {
+        return Ok(match self {
+            SpectreGadget::Read => GadgetTarget { train: kernel_memory, target: kernel_memory + 0x100C80 },
+            SpectreGadget::Exec => {
+                let retpoline = handle.leak_retpoline()? | PAC_BITMASK;
+                GadgetTarget { train: retpoline, target: retpoline + config.exec_target_offset }
+            },
+            SpectreGadget::ServiceRoutine => {
+                let profile = profile.ok_or(PacmanError::MissingOffset(profile::PACMANKIT_HELPER))?;
+                let (_user_client, _victim_object, victim_vtable, _victim_vtable_entry) = find_victim_objects(handle, profile)?;
+                GadgetTarget { train: victim_vtable | PAC_BITMASK, target: victim_vtable | PAC_BITMASK }
+            },
+        });
+    }
+
+    /// Call the gadget on `addr` with index `idx`
+    unsafe fn call<K: KernelPrimitives>(&self, handle: &K, addr: u64, idx: u64) -> Result<(), PacmanError> {
+        match self {
+            SpectreGadget::Read => handle.kernel_read_for_spectre(addr, idx)?,
+            SpectreGadget::Exec => handle.kernel_exec_for_spectre(addr, idx)?,
+            SpectreGadget::ServiceRoutine => { handle.call_service_routine(idx, 0, 0, 0, 0, 0)?; },
+        }
+        return Ok(());
+    }
+
+    /// Time the gadget's target from the kernel
+    unsafe fn time_target<K: KernelPrimitives>(&self, handle: &K, target: u64) -> Result<u64, PacmanError> {
+        if self.is_inst() {
+            return handle.kernel_exec_for_timing(target, true);
+        }
+        return handle.kernel_read_for_timing(target, true);
+    }
+}
+
+/**
+ * Run every combination of the settings in `config`, recording each one to `sink` as a `spectre_sweep` record.
+ *
+ * # Arguments
+ * * `handle`: The PacmanKit connection whose gadgets are attacked.
+ * * `memory_region`: Where to draw eviction sets (and the buffer `ConditionEviction::Flush` walks) from.
+ * * `profile`: The offsets of the running kernel (only the service routine gadget needs them, it attacks PacmanKit's
+ *   helper object).
+ *
+ * # Return Value
+ * How each setting did (in the order they ran), or the first kernel operation (or results write) that failed.
+ */
+pub unsafe fn spectre_sweep<K: KernelPrimitives>(handle: &K, memory_region: &mut [u8], config: &SweepConfig, profile: Option<&OffsetProfile>, sink: &mut dyn ResultsSink) -> Result<Vec<SweepResult>, PacmanError> {
+    let kernel_memory = KernelAllocation::new(handle)?;
+    let limit_va = handle.leak_limit_location()?;
+    let limit_pa = handle.kernel_virt_to_phys(limit_va)?;
+    let limit_candidates = evset::data_pevset(limit_va, limit_pa, memory_region);
+    let flush_buffer = &memory_region[0] as *const u8 as u64;
+
+    let mut results = Vec::new();
+    for gadget in &config.gadgets {
+        let target = gadget.target(handle, kernel_memory.addr(), config, profile)?;
+        let target_pa = handle.kernel_virt_to_phys(target.target)?;
+        let target_candidates = if gadget.is_inst() {
+            evset::inst_pevset(target.target, target_pa, memory_region)
+        }
+        else {
+            evset::data_pevset(target.target, target_pa, memory_region)
+        };
+        let target_evset = evset::choose_evset(&target_candidates, config.target_evset_size, "the spectre target eviction set")?;
+        let touch : unsafe fn(u64) -> u64 = if gadget.is_inst() { timer::time_exec } else { timer::time_access };
+
+        for schedule in &config.schedules {
+            let calls = schedule.calls();
+            for eviction in &config.evictions {
+                let limit_evset = match eviction {
+                    ConditionEviction::Evset { size } => evset::choose_evset(&limit_candidates, *size, "the LIMIT eviction set")?,
+                    _ => Vec::new(),
+                };
+
+                for oracle in &config.oracles {
+                    let mut samples = vec![0u64; config.num_trials];
+                    let mut speculated = 0;
+                    let mut times = vec![0u64; target_evset.len()];
+
+                    for sample in samples.iter_mut() {
+                        // 1. Train the bounds check
+                        for in_bounds in &calls {
+                            let idx = if *in_bounds { 0 } else { config.attack_idx };
+                            gadget.call(handle, target.train, idx)?;
+                        }
+
+                        // 2. Evict LIMIT
+                        match eviction {
+                            ConditionEviction::None => {},
+                            ConditionEviction::Evset { .. } => for addr in &limit_evset {
+                                timer::time_access(*addr);
+                            },
+                            ConditionEviction::Flush => for offset in (0..cache::L2_SIZE as u64).step_by(cache::L2_LINESIZE) {
+                                timer::time_access(flush_buffer + offset);
+                            },
+                        }
+
+                        // 3. Evict the target (this primes it for the prime+probe oracle)
+                        for addr in &target_evset {
+                            touch(*addr);
+                        }
+
+                        // 4. Attack
+                        gadget.call(handle, target.target, config.attack_idx)?;
+
+                        // 5. Was the target touched?
+                        let touched = match oracle {
+                            Oracle::KernelTiming => {
+                                *sample = gadget.time_target(handle, target.target)?;
+                                *sample <= config.miss_latency
+                            },
+                            Oracle::PrimeProbe => {
+                                for (time, addr) in times.iter_mut().zip(&target_evset).rev() {
+                                    *time = touch(*addr);
+                                }
+                                *sample = count_misses(&times, config.miss_latency);
+                                *sample > 0
+                            },
+                        };
+                        speculated += touched as usize;
+                    }
+
+                    let result = SweepResult { gadget: *gadget, schedule: *schedule, eviction: *eviction, oracle: *oracle, samples, speculated };
+                    sink.record(
+                        &ExperimentRecord::new("spectre_sweep")
+                            .param("gadget", gadget.to_string())
+                            .param("schedule", schedule.to_string())
+                            .param("training_calls", calls.len())
+                            .param("eviction", eviction.to_string())
+                            .param("oracle", oracle.to_string())
+                            .param("attack_idx", config.attack_idx)
+                            .param("miss_latency", config.miss_latency)
+                            .param("speculated", result.speculated)
+                            .param("success_rate", result.success_rate())
+                            .samples(&result.samples)
+                    )?;
+                    results.push(result);
+                }
+            }
+        }
+    }
+
+    return Ok(results);
+}
@@ -0,0 +1,258 @@
+/*!
+ * Queueing kernel reads, writes and gadget calls to submit in one round-trip.
+ *
+ * Every PacmanKit operation is an `IOConnectCallScalarMethod`, and the PACMAN hot loop makes a lot of
+ * them (a write, `TRAINING_CALLS` training calls and another write per guess). Each one is a syscall that
+ * runs kernel code we don't control and pollutes the caches and branch predictor we're measuring.
+ * A `Batch` queues them up and `KernelBatch::run_batch` sends the whole queue to the kext's
+ * `PacmanKitOp::Batch` selector as a single structure input.
+ *
+ * The structure input is `BATCH_ENTRY_SIZE` bytes per operation (little endian u64s):
+ *
+ * ```text
+ * selector | times | arg (x6, unused ones are 0)
+ * ```
+ *
+ * and the structure output is `BATCH_RESULT_SIZE` bytes per operation: its `kern_return_t` (as a u64) and
+ * its output (the last repetition's, 0 for operations without one). The kext runs the operations in order
+ * and stops at the first one that fails. A failing `CallServiceRoutine` doesn't stop it (its return value
+ * is ignored, just like `call_service_routine` ignores it).
+ *
+ * Anything that can't run batches (an older kext, a remote server) runs each operation as its own scalar
+ * call instead (see `run_each`), so a batch always does the same thing, only slower. Recorded sessions
+ * record batches as they were sent, and replay them the same way.
+ */
+use crate::error::PacmanError;
+use crate::pacmankit::{KernReturn, PacmanKitOp, KERN_SUCCESS};
+use crate::primitives::{KernelGadgets, KernelMemory};
+
+/// Bytes per operation in the structure input
+pub const BATCH_ENTRY_SIZE : usize = 64;
+
+/// Bytes per operation in the structure output
+pub const BATCH_RESULT_SIZE : usize = 16;
+
+/// Most operations sent in one call (keeps the structure input within the 4096 bytes IOKit passes inline).
+/// Longer batches are split up.
+pub const MAX_BATCH_OPS : usize = 4096 / BATCH_ENTRY_SIZE;
+
+/**
+ * One operation in a `Batch`: the batchable `PacmanKitOp`s and their arguments.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BatchOp {
+    /// Read a u64 from kernel memory (`kernel_read`)
+    Read { addr: u64 },
+
+    /// Write a u64 to kernel memory (`kernel_write`)
+    Write { addr: u64, val: u64 },
+
+    /// Run the kernel data spectre gadget (`kernel_read_for_spectre`)
+    ReadForSpectre { addr: u64, idx: u64 },
+
+    /// Run the kernel inst spectre gadget (`kernel_exec_for_spectre`)
+    ExecForSpectre { addr: u64, idx: u64 },
+
+    /// Call the PacmanKitService externalMethod (`call_service_routine`)
+    CallServiceRoutine { args: [u64; 6] },
+}
+
+impl BatchOp {
+    /// The selector this operation runs
+    pub fn op(&self) -> PacmanKitOp {
+        return match self {
+            BatchOp::Read { .. } => PacmanKitOp::Read,
+            BatchOp::Write { .. } => PacmanKitOp::Write,
+            BatchOp::ReadForSpectre { .. } => PacmanKitOp::ReadForSpectre,
+            BatchOp::ExecForSpectre { .. } => PacmanKitOp::ExecForSpectre,
+            BatchOp::CallServiceRoutine { .. } => PacmanKitOp::CallServiceRoutine,
+        };
+    }
+
+    /// The selector's scalar inputs
+    pub fn args(&self) -> Vec<u64> {
+        return match *self {
+            BatchOp::Read { addr } => vec![addr],
+            BatchOp::Write { addr, val } => vec![addr, val],
+            BatchOp::ReadForSpectre { addr, idx } => vec![addr, idx],
+            BatchOp::ExecForSpectre { addr, idx } => vec![addr, idx],
+            BatchOp::CallServiceRoutine { args } => args.to_vec(),
+        };
+    }
+
+    /// How many scalar outputs the selector has (0 or 1)
+    pub fn num_outputs(&self) -> usize {
+        return match self {
+            BatchOp::Read { .. } | BatchOp::CallServiceRoutine { .. } => 1,
+            _ => 0,
+        };
+    }
+
+    /// Does a failure stop the batch?
+    pub fn stops_on_failure(&self) -> bool {
+        return !matches!(self, BatchOp::CallServiceRoutine { .. });
+    }
+
+    /// The operation with `selector` and `args` (if it's batchable)
+    pub fn from_parts(selector: u64, args: &[u64; 6]) -> Option<Self> {
+        let op = PacmanKitOp::from_selector(u32::try_from(selector).ok()?)?;
+        return match op {
+            PacmanKitOp::Read => Some(BatchOp::Read { addr: args[0] }),
+            PacmanKitOp::Write => Some(BatchOp::Write { addr: args[0], val: args[1] }),
+            PacmanKitOp::ReadForSpectre => Some(BatchOp::ReadForSpectre { addr: args[0], idx: args[1] }),
+            PacmanKitOp::ExecForSpectre => Some(BatchOp::ExecForSpectre { addr: args[0], idx: args[1] }),
+            PacmanKitOp::CallServiceRoutine => Some(BatchOp::CallServiceRoutine { args: *args }),
+            _ => None,
+        };
+    }
+}
+
+/**
+ * A queue of operations (each run some number of times) to submit together.
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Batch {
+    ops: Vec<(BatchOp, u64)>,
+}
+
+impl Batch {
+    /// An empty batch
+    pub fn new() -> Self {
+        return Batch { ops: Vec::new() };
+    }
+
+    /// Queue `op` to run `times` times in a row
+    pub fn push(&mut self, op: BatchOp, times: u64) -> &mut Self {
+        self.ops.push((op, times));
+        return self;
+    }
+
+    /// Queue a kernel read
+    pub fn read(&mut self, addr: u64) -> &mut Self {
+        return self.push(BatchOp::Read { addr }, 1);
+    }
+
+    /// Queue a kernel write
+    pub fn write(&mut self, addr: u64, val: u64) -> &mut Self {
+        return self.push(BatchOp::Write { addr, val }, 1);
+    }
+
+    /// Queue `times` calls to PacmanKitService's externalMethod
+    pub fn call_service_routine(&mut self, args: [u64; 6], times: u64) -> &mut Self {
+        return self.push(BatchOp::CallServiceRoutine { args }, times);
+    }
+
+    /// Queue everything in `other` (after what's already queued)
+    pub fn extend(&mut self, other: &Batch) -> &mut Self {
+        self.ops.extend_from_slice(&other.ops);
+        return self;
+    }
+
+    /// The queued operations and how many times each runs
+    pub fn ops(&self) -> &[(BatchOp, u64)] {
+        return &self.ops;
+    }
+
+    /// How many operations are queued
+    pub fn len(&self) -> usize {
+        return self.ops.len();
+    }
+
+    /// Is nothing queued?
+    pub fn is_empty(&self) -> bool {
+        return self.ops.is_empty();
+    }
+
+    /// This batch split into batches of at most `MAX_BATCH_OPS` operations
+    pub fn chunks(&self) -> Vec<Batch> {
+        return self.ops.chunks(MAX_BATCH_OPS).map(|ops| Batch { ops: ops.to_vec() }).collect();
+    }
+
+    /// The structure input for `PacmanKitOp::Batch`
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.ops.len() * BATCH_ENTRY_SIZE);
+        for (op, times) in &self.ops {
+            let mut args = [0u64; 6];
+            for (slot, arg) in args.iter_mut().zip(op.args()) {
+                *slot = arg;
+            }
+            bytes.extend_from_slice(&(op.op() as u64).to_le_bytes());
+            bytes.extend_from_slice(&times.to_le_bytes());
+            for arg in args {
+                bytes.extend_from_slice(&arg.to_le_bytes());
+            }
+        }
+        return bytes;
+    }
+
+    /**
+     * Parse a structure input made by `encode` (what the kext does with it).
+     *
+     * # Return Value
+     * Returns `None` if `bytes` isn't a whole number of entries or an entry isn't a batchable operation.
+     */
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if !bytes.len().is_multiple_of(BATCH_ENTRY_SIZE) {
+            return None;
+        }
+        let mut batch = Batch::new();
+        for entry in bytes.chunks(BATCH_ENTRY_SIZE) {
+            let words : Vec<u64> = entry.chunks(8).map(|word| u64::from_le_bytes(word.try_into().unwrap())).collect();
+            let mut args = [0u64; 6];
+            args.copy_from_slice(&words[2..]);
+            batch.push(BatchOp::from_parts(words[0], &args)?, words[1]);
+        }
+        return Some(batch);
+    }
+
+    /**
+     * Turn the structure output of running this batch into each operation's output.
+     *
+     * # Return Value
+     * Each operation's output, or the first operation that failed.
+     */
+    pub fn results(&self, output: &[u8]) -> Result<Vec<u64>, PacmanError> {
+        let mut results = Vec::with_capacity(self.ops.len());
+        for ((op, _), result) in self.ops.iter().zip(output.chunks(BATCH_RESULT_SIZE)) {
+            let kret = u64::from_le_bytes(result[..8].try_into().unwrap()) as KernReturn;
+            if KERN_SUCCESS != kret && op.stops_on_failure() {
+                return Err(PacmanError::OperationFailed { op: op.op(), kret });
+            }
+            results.push(u64::from_le_bytes(result[8..].try_into().unwrap()));
+        }
+        return Ok(results);
+    }
+}
+
+/**
+ * Write one operation's result into a structure output (what the kext does after running it).
+ */
+pub fn write_result(output: &mut [u8], index: usize, kret: KernReturn, value: u64) {
+    let result = &mut output[index * BATCH_RESULT_SIZE..(index + 1) * BATCH_RESULT_SIZE];
+    result[..8].copy_from_slice(&(kret as u64).to_le_bytes());
+    result[8..].copy_from_slice(&value.to_le_bytes());
+}
+
+/**
+ * Run `batch` one scalar call at a time (for backends that can't run batches).
+ *
+ * # Return Value
+ * Each operation's output (0 for operations without one), or the first operation that failed.
+ */
+pub unsafe fn run_each<K: KernelMemory + KernelGadgets + ?Sized>(handle: &K, batch: &Batch) -> Result<Vec<u64>, PacmanError> {
+    let mut results = Vec::with_capacity(batch.len());
+    for (op, times) in batch.ops() {
+        let mut result = 0;
+        for _ in 0..*times {
+            result = match *op {
+                BatchOp::Read { addr } => handle.kernel_read(addr)?,
+                BatchOp::Write { addr, val } => handle.kernel_write(addr, val).map(|_| 0)?,
+                BatchOp::ReadForSpectre { addr, idx } => handle.kernel_read_for_spectre(addr, idx).map(|_| 0)?,
+                BatchOp::ExecForSpectre { addr, idx } => handle.kernel_exec_for_spectre(addr, idx).map(|_| 0)?,
+                BatchOp::CallServiceRoutine { args } => handle.call_service_routine(args[0], args[1], args[2], args[3], args[4], args[5]).unwrap_or(0),
+            };
+        }
+        results.push(result);
+    }
+    return Ok(results);
+}
@@ -0,0 +1,84 @@
+/*!
+ * A stand-in PacmanKit server: answers `remote` requests from a simulated kernel, or proxies them to the
+ * PacmanKit kext on this machine (a lab Mac).
+ *
+ * `cargo run -r --bin pacmankit_server -- --listen unix:/tmp/pacmankit.sock --seed 1`
+ */
+// The repo style is `return x;` everywhere
+#![allow(clippy::needless_return)]
+
+use std::sync::{Arc, Mutex};
+use clap::{Parser, ValueEnum};
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+use pacman::pacmankit::PacmanKitConnection;
+use pacman::remote::{self, Address, Listener};
+use pacman::sim::kernel::{self, SimulatedKernel};
+use pacman::sim::noise;
+
+/// Default address to listen on
+pub const LISTEN_ADDRESS : &str = "127.0.0.1:7878";
+
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+enum Backend {
+    /// A simulated kernel (shared by every client)
+    Sim,
+
+    /// The PacmanKit kext on this machine (Apple silicon running macOS only)
+    Kext,
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "pacmankit_server", about = "Answer PacmanKit requests over a socket")]
+struct ServerCli {
+    /// Where to listen (unix:<path> or <host>:<port>)
+    #[arg(long, default_value = LISTEN_ADDRESS)]
+    listen: Address,
+
+    /// What answers the requests
+    #[arg(long, default_value = "sim")]
+    backend: Backend,
+
+    /// Seed of the simulated kernel
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// How much realistic noise to add to the simulated kernel (see `sim::noise::REALISTIC`, 0 for none)
+    #[arg(long, default_value_t = 0.0)]
+    noise: f64,
+}
+
+/// Open a connection to the kext for every client
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+fn serve_kext(listener: Listener) -> ! {
+    remote::serve(listener, || unsafe { PacmanKitConnection::init() });
+}
+
+/// There's no kext to proxy to here
+#[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
+fn serve_kext(_listener: Listener) -> ! {
+    println!("Proxying to PacmanKit needs Apple silicon running macOS. Use --backend sim on this machine.");
+    std::process::exit(1);
+}
+
+pub fn main() {
+    let cli = ServerCli::parse();
+
+    let listener = match Listener::bind(&cli.listen) {
+        Ok(v) => v,
+        Err(err) => {
+            println!("Couldn't listen on {}! Error is {}", cli.listen, err);
+            std::process::exit(1);
+        }
+    };
+    println!("Listening on {}", listener.address().map(|addr| addr.to_string()).unwrap_or(cli.listen.to_string()));
+
+    match cli.backend {
+        Backend::Sim => {
+            let kernel = Arc::new(Mutex::new(SimulatedKernel::new(cli.seed)));
+            kernel.lock().unwrap().set_noise(noise::REALISTIC.scaled(cli.noise));
+            println!("Simulated kernel base is 0x{:X}", kernel.lock().unwrap().kernel_base());
+            remote::serve(listener, move || kernel::connect(&kernel));
+        },
+        Backend::Kext => serve_kext(listener),
+    }
+}
@@ -2,16 +2,25 @@
  * Routines for interacting with addresses.
  */
 
+/// L1 dCache associativity
 pub const L1D_WAYS : usize = 8;
+/// Number of L1 dCache sets
 pub const L1D_SETS : usize = 256;
+/// L1 dCache line size in bytes
 pub const L1D_LINESIZE : usize = 64;
 
+/// L1 iCache associativity
 pub const L1I_WAYS : usize = 6;
+/// Number of L1 iCache sets
 pub const L1I_SETS : usize = 512;
+/// L1 iCache line size in bytes
 pub const L1I_LINESIZE : usize = 64;
 
+/// L2 associativity
 pub const L2_WAYS : usize = 12;
+/// Number of L2 sets
 pub const L2_SETS : usize = 8192;
+/// L2 line size in bytes
 pub const L2_LINESIZE : usize = 128;
 
 /// sysctl -a | grep "l2"
@@ -50,7 +59,7 @@ There are 2^6 == 64 different cache sets contained within a page.
 pub fn get_cache_tag_generic(addr: u64) -> u64 {
     let set_shift = (L2_SETS as f64).log2().ceil().round() as u64;
     let offset_shift = (L2_LINESIZE as f64).log2().ceil().round() as u64;
-    return (addr >> (set_shift + offset_shift));
+    return addr >> (set_shift + offset_shift);
 }
 
 /// Returns the L2 set index of a given physical address
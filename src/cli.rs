@@ -0,0 +1,355 @@
+/*!
+ * Command line interface for picking and tuning an experiment.
+ *
+ * Every subcommand takes the same tuning flags. They are applied on top of the `AttackConfig` (loaded
+ * with `--config`, or the defaults): each experiment maps them onto its own parameters (see the `*_params`
+ * methods on `Options`) and ignores the ones that don't apply to it.
+ */
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use pacman::timer::TimerBackend;
+use pacman::attacks::evict_reload::EvictReloadParams;
+use pacman::attacks::prime_probe::PrimeProbeParams;
+use pacman::attacks::spectre::SpectreParams;
+use pacman::attacks::spectre_sweep::{SpectreGadget, SweepConfig};
+use pacman::attacks::spec_window::WindowConfig;
+use pacman::covert::channel::ChannelConfig;
+use pacman::attacks::pacman::GadgetParams;
+use pacman::config::AttackConfig;
+use pacman::remote::Address;
+use pacman::kernelcache::symbols::SymbolicAddress;
+
+/// Default location for experiment results
+pub const RESULTS_PATH : &str = "results.jsonl";
+
+/// Default location for raw PACMAN trial traces
+pub const TRACE_PATH : &str = "trace.bin";
+
+/// Parse an address (hex, with or without `0x`)
+fn parse_hex(text: &str) -> Result<u64, String> {
+    let digits = text.strip_prefix("0x").unwrap_or(text);
+    return u64::from_str_radix(digits, 16).map_err(|err| format!("{} isn't a hex address: {}", text, err));
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "pacman", about = "PACMAN except in rust!")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+
+    #[command(flatten)]
+    pub options: Options,
+}
+
+/**
+ * Flags shared by every subcommand.
+ */
+#[derive(Args, Clone, Debug)]
+pub struct Options {
+    /// TOML file to load experiment parameters from (flags below take priority over it)
+    #[arg(long, global = true)]
+    pub config: Option<String>,
+
+    /// Eviction set size (prime+probe/ PACMAN/ spectre sweep target/ covert receiver), largest eviction set to try (evict+reload) or LIMIT eviction set size (spectre)
+    #[arg(long, global = true)]
+    pub evset_size: Option<usize>,
+
+    /// Probe latency above which an access counts as a cache miss
+    #[arg(long, global = true)]
+    pub miss_latency: Option<u64>,
+
+    /// How far above the first guess' average miss count a guess has to be to be significant (bruteforce)
+    #[arg(long, global = true)]
+    pub how_far_above_average: Option<f64>,
+
+    /// How many times to repeat each trial (training calls for spectre and spec-window)
+    #[arg(long, global = true)]
+    pub iterations: Option<usize>,
+
+    /// How many trials to run (retrials per eviction set size for evict+reload)
+    #[arg(long, global = true)]
+    pub trials: Option<usize>,
+
+    /// How many times to repeat each potential match when bruteforcing
+    #[arg(long, global = true)]
+    pub final_iterations: Option<usize>,
+
+    /// Run kernel operations on a PacmanKit server (unix:<path> or <host>:<port>) instead of the local kext
+    #[arg(long, global = true)]
+    pub remote: Option<Address>,
+
+    /// Record every PacmanKit call (selectors, inputs, outputs and return codes) to this session file
+    #[arg(long, global = true)]
+    pub record_calls: Option<String>,
+
+    /// Answer PacmanKit calls from a recorded session file instead of the kernel
+    #[arg(long, global = true, conflicts_with_all = ["remote", "record_calls"])]
+    pub replay_calls: Option<String>,
+
+    /// Offset profile file to look the running OS build up in (defaults to the built in offsets.toml)
+    #[arg(long, global = true)]
+    pub profiles: Option<String>,
+
+    /// Kernelcache of the running kernel, to resolve kext and symbol relative addresses (like inst_target) against
+    #[arg(long, global = true)]
+    pub kernelcache: Option<String>,
+
+    /// Timer backend to measure with (msr or multithread)
+    #[arg(long, global = true, default_value = "msr")]
+    pub timer: TimerBackend,
+
+    /// Where to write experiment results (.csv for CSV, anything else for JSON Lines)
+    #[arg(long, short, global = true, default_value = RESULTS_PATH)]
+    pub output: String,
+
+    /// Where to record raw PACMAN trials
+    #[arg(long, global = true, default_value = TRACE_PATH)]
+    pub trace: String,
+
+    /// Don't record raw PACMAN trials
+    #[arg(long, global = true)]
+    pub no_trace: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+pub enum Kind {
+    Data,
+    Inst,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+pub enum PacmanMode {
+    /// Tell a correct PAC apart from an incorrect one
+    Differentiate,
+
+    /// Find the correct PAC out of every possible PAC
+    Bruteforce,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum Command {
+    /// Evict+Reload latency sweep over eviction set sizes
+    EvictReload {
+        kind: Kind,
+
+        /// Reload a kernel address instead of a userspace one
+        #[arg(long)]
+        kernel: bool,
+
+        /// Evict the kernel address with an eviction set in kernel memory (data only)
+        #[arg(long, requires = "kernel")]
+        kernel_evset: bool,
+    },
+
+    /// Prime+Probe testbed for determining effective prime+probe parameters
+    PrimeProbe {
+        #[arg(default_value = "data")]
+        kind: Kind,
+
+        /// Chain the inst eviction set into one code path: prime it with one call, probe it line by line (inst only)
+        #[arg(long)]
+        chain: bool,
+    },
+
+    /// Mistrain PacmanKit's read (data) or exec (inst) gadget: one setting of spectre-sweep (see [spectre] in the config)
+    Spectre {
+        #[arg(default_value = "data")]
+        kind: Kind,
+
+        /// Mistrain a userspace load instead of PacmanKit (data only)
+        #[arg(long)]
+        user: bool,
+    },
+
+    /// Sweep Spectre-v1 training schedules, LIMIT evictions and oracles over PacmanKit's gadgets (see [spectre_sweep] in the config)
+    SpectreSweep,
+
+    /// Measure how many dependent ALU ops or loads fit in the speculation window, per core type and LIMIT source (see [spec_window] in the config)
+    SpecWindow,
+
+    /// Send a random message over a cross-thread L2 covert channel and report its bit rate and error rates (runs against the simulated kernel off a Mac)
+    Covert,
+
+    /// The PACMAN attack against a single gadget
+    Pacman {
+        mode: PacmanMode,
+        kind: Kind,
+    },
+
+    /// Forge a vtable pointer and then a vtable entry
+    EndToEnd,
+
+    /// Attack a real system call (memorystatus_available_memory)
+    Real,
+
+    /// Report timer and cache latencies of this machine
+    PlatformInfo,
+
+    /// Replay a recorded trace through the bruteforce decision logic (doesn't touch hardware)
+    Replay {
+        /// The trace file to replay
+        trace: String,
+    },
+
+    /// List the kexts, segments, symbols and signed pointers of a kernelcache (doesn't touch hardware)
+    Kernelcache {
+        /// The (decompressed) kernelcache file
+        path: String,
+
+        /// List this many PACMAN gadgets, best first
+        #[arg(long)]
+        gadgets: Option<usize>,
+
+        /// Count the signed pointers by key and diversity constant
+        #[arg(long)]
+        signed_pointers: bool,
+
+        /// List the signed pointers stored in this symbol (eg. a vtable) with their keys and salts
+        #[arg(long)]
+        symbol: Option<String>,
+
+        /// Resolve these addresses (base+0x.., <kext>+0x.., <symbol>+0x.. or <kext>!<symbol>+0x..)
+        #[arg(long)]
+        resolve: Vec<SymbolicAddress>,
+
+        /// Describe these addresses relative to the closest symbol or kext
+        #[arg(long, value_parser = parse_hex)]
+        symbolicate: Vec<u64>,
+
+        /// A leaked kernel base: resolve to (and symbolicate) addresses in the running kernel, after the KASLR slide
+        #[arg(long, value_parser = parse_hex)]
+        kernel_base: Option<u64>,
+    },
+}
+
+impl Options {
+    /// The trace file to record to (if any)
+    #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+    pub fn trace_path(&self) -> Option<&str> {
+        if self.no_trace {
+            return None;
+        }
+        return Some(&self.trace);
+    }
+
+    /**
+     * Load the config file (if one was given) and apply the tuning flags on top of it.
+     */
+    pub fn load_config(&self) -> Result<AttackConfig, String> {
+        let config = match &self.config {
+            Some(path) => AttackConfig::load(path).map_err(|err| format!("{}: {}", path, err))?,
+            None => AttackConfig::default(),
+        };
+        return Ok(self.apply(&config));
+    }
+
+    /**
+     * Override every experiment's parameters in `config` with the tuning flags.
+     */
+    pub fn apply(&self, config: &AttackConfig) -> AttackConfig {
+        let mut config = config.clone();
+        config.evict_reload = self.evict_reload_params(config.evict_reload);
+        config.prime_probe.data = self.prime_probe_params(config.prime_probe.data);
+        config.prime_probe.inst = self.prime_probe_params(config.prime_probe.inst);
+        config.spectre.data = self.spectre_params(config.spectre.data);
+        config.spectre.inst = self.spectre_params(config.spectre.inst);
+        config.spectre_sweep = self.sweep_config(&config.spectre_sweep);
+        config.spec_window = self.window_config(&config.spec_window);
+        config.covert = self.channel_config(&config.covert);
+        config.pacman.data = self.gadget_params(config.pacman.data);
+        config.pacman.inst = self.gadget_params(config.pacman.inst);
+        config.pacman.real = self.gadget_params(config.pacman.real);
+        return config;
+    }
+
+    pub fn evict_reload_params(&self, defaults: EvictReloadParams) -> EvictReloadParams {
+        return EvictReloadParams {
+            max_evset_size: self.evset_size.or(defaults.max_evset_size),
+            num_retrials: self.trials.unwrap_or(defaults.num_retrials),
+            trial_repeat: self.iterations.unwrap_or(defaults.trial_repeat),
+        };
+    }
+
+    pub fn prime_probe_params(&self, defaults: PrimeProbeParams) -> PrimeProbeParams {
+        return PrimeProbeParams {
+            evset_size: self.evset_size.unwrap_or(defaults.evset_size),
+            miss_latency: self.miss_latency.unwrap_or(defaults.miss_latency),
+            num_iters: self.iterations.unwrap_or(defaults.num_iters),
+            num_trials: self.trials.unwrap_or(defaults.num_trials),
+        };
+    }
+
+    pub fn spectre_params(&self, defaults: SpectreParams) -> SpectreParams {
+        return SpectreParams {
+            num_trials: self.trials.unwrap_or(defaults.num_trials),
+            limit_evset_size: self.evset_size.unwrap_or(defaults.limit_evset_size),
+            training_calls: self.iterations.unwrap_or(defaults.training_calls),
+        };
+    }
+
+    pub fn sweep_config(&self, defaults: &SweepConfig) -> SweepConfig {
+        return SweepConfig {
+            num_trials: self.trials.unwrap_or(defaults.num_trials),
+            target_evset_size: self.evset_size.unwrap_or(defaults.target_evset_size),
+            miss_latency: self.miss_latency.unwrap_or(defaults.miss_latency),
+            ..defaults.clone()
+        };
+    }
+
+    pub fn window_config(&self, defaults: &WindowConfig) -> WindowConfig {
+        return WindowConfig {
+            num_trials: self.trials.unwrap_or(defaults.num_trials),
+            training_calls: self.iterations.unwrap_or(defaults.training_calls),
+            evset_size: self.evset_size.unwrap_or(defaults.evset_size),
+            miss_latency: self.miss_latency.unwrap_or(defaults.miss_latency),
+            ..defaults.clone()
+        };
+    }
+
+    pub fn channel_config(&self, defaults: &ChannelConfig) -> ChannelConfig {
+        return ChannelConfig {
+            receiver_evset_size: self.evset_size.unwrap_or(defaults.receiver_evset_size),
+            miss_latency: self.miss_latency.unwrap_or(defaults.miss_latency),
+            ..defaults.clone()
+        };
+    }
+
+    pub fn gadget_params(&self, defaults: GadgetParams) -> GadgetParams {
+        return GadgetParams {
+            evset_size: self.evset_size.unwrap_or(defaults.evset_size),
+            miss_latency: self.miss_latency.unwrap_or(defaults.miss_latency),
+            num_iters: self.iterations.unwrap_or(defaults.num_iters),
+            num_trials: self.trials.unwrap_or(defaults.num_trials),
+            num_final_iters: self.final_iterations.unwrap_or(defaults.num_final_iters),
+            how_far_above_average: self.how_far_above_average.unwrap_or(defaults.how_far_above_average),
+        };
+    }
+}
+
+impl Command {
+    /**
+     * Check the flags and parameters this command uses make sense.
+     * Only the sections of `config` the command actually runs with are checked.
+     */
+    pub fn validate(&self, config: &AttackConfig) -> Result<(), String> {
+        return match *self {
+            Command::EvictReload { kind: Kind::Inst, kernel_evset: true, .. } => Err("--kernel-evset is only supported for data evict+reload".to_string()),
+            Command::PrimeProbe { kind: Kind::Data, chain: true } => Err("--chain is only supported for inst prime+probe".to_string()),
+            Command::Spectre { kind: Kind::Inst, user: true } => Err("--user is only supported for data spectre".to_string()),
+            Command::Spectre { kind: Kind::Data, user: true } => Ok(()),
+            Command::EvictReload { .. } => config.evict_reload.validate().map_err(|err| format!("evict_reload: {}", err)),
+            Command::PrimeProbe { kind: Kind::Data, .. } => config.prime_probe.data.validate().map_err(|err| format!("prime_probe.data: {}", err)),
+            Command::PrimeProbe { kind: Kind::Inst, .. } => config.prime_probe.inst.validate().map_err(|err| format!("prime_probe.inst: {}", err)),
+            Command::Spectre { kind: Kind::Data, .. } => config.spectre.data.validate()
+                .and_then(|_| config.spectre.data.sweep(SpectreGadget::Read, &config.spectre_sweep).validate())
+                .map_err(|err| format!("spectre.data: {}", err)),
+            Command::Spectre { kind: Kind::Inst, .. } => config.spectre.inst.validate()
+                .and_then(|_| config.spectre.inst.sweep(SpectreGadget::Exec, &config.spectre_sweep).validate())
+                .map_err(|err| format!("spectre.inst: {}", err)),
+            Command::SpectreSweep => config.spectre_sweep.validate().map_err(|err| format!("spectre_sweep: {}", err)),
+            Command::SpecWindow => config.spec_window.validate().map_err(|err| format!("spec_window: {}", err)),
+            Command::Covert => config.covert.validate().map_err(|err| format!("covert: {}", err)),
+            Command::Pacman { .. } | Command::EndToEnd | Command::Real => config.pacman.validate(),
+            Command::PlatformInfo | Command::Replay { .. } | Command::Kernelcache { .. } => Ok(()),
+        };
+    }
+}
@@ -0,0 +1,130 @@
+/*!
+ * Experiment configuration.
+ *
+ * Every experiment parameter lives in an `AttackConfig`. The defaults are the tuned constants in each
+ * attack module; a TOML file only needs to list the parameters it changes, eg.
+ * ```toml
+ * [pacman]
+ * limit_evset_size = 16
+ *
+ * [pacman.data]
+ * miss_latency = 70
+ * ```
+ * Command line flags are applied on top of the file (see `cli::Options::apply`).
+ */
+use crate::attacks::evict_reload::{self, EvictReloadParams};
+use crate::attacks::prime_probe::{self, PrimeProbeParams};
+use crate::attacks::spectre::{self, SpectreParams};
+use crate::attacks::spectre_sweep::SweepConfig;
+use crate::attacks::spec_window::WindowConfig;
+use crate::covert::channel::ChannelConfig;
+use crate::attacks::pacman::PacmanConfig;
+use serde::{Serialize, Deserialize};
+
+/**
+ * Parameters for the data and inst prime+probe experiments.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrimeProbeConfig {
+    pub data: PrimeProbeParams,
+    pub inst: PrimeProbeParams,
+}
+
+/**
+ * Parameters for the kernel spectre experiments.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpectreConfig {
+    pub data: SpectreParams,
+    pub inst: SpectreParams,
+}
+
+/**
+ * Parameters for every experiment.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AttackConfig {
+    pub evict_reload: EvictReloadParams,
+    pub prime_probe: PrimeProbeConfig,
+    pub spectre: SpectreConfig,
+    pub spectre_sweep: SweepConfig,
+    pub spec_window: WindowConfig,
+    pub covert: ChannelConfig,
+    pub pacman: PacmanConfig,
+}
+
+impl Default for AttackConfig {
+    fn default() -> Self {
+        return Self {
+            evict_reload: evict_reload::DEFAULT_PARAMS,
+            prime_probe: PrimeProbeConfig {
+                data: prime_probe::DATA_PARAMS,
+                inst: prime_probe::INST_PARAMS,
+            },
+            spectre: SpectreConfig {
+                data: spectre::DATA_KERNEL_PARAMS,
+                inst: spectre::INST_KERNEL_PARAMS,
+            },
+            spectre_sweep: SweepConfig::default(),
+            spec_window: WindowConfig::default(),
+            covert: ChannelConfig::default(),
+            pacman: PacmanConfig::default(),
+        };
+    }
+}
+
+/// Recursively overwrite the entries of `base` with the ones in `overrides`
+fn merge(base: &mut toml::Value, overrides: toml::Value) {
+    match (base, overrides) {
+        (toml::Value::Table(base), toml::Value::Table(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => { base.insert(key, value); },
+                }
+            }
+        },
+        (base, overrides) => *base = overrides,
+    }
+}
+
+impl AttackConfig {
+    /**
+     * Parse a TOML config. Anything not mentioned keeps its default value.
+     * Unknown sections or parameters are an error (they're probably typos).
+     */
+    pub fn from_toml(text: &str) -> Result<Self, String> {
+        let overrides : toml::Value = toml::from_str(text).map_err(|err| err.to_string())?;
+        let mut config = toml::Value::try_from(AttackConfig::default()).map_err(|err| err.to_string())?;
+        merge(&mut config, overrides);
+        return config.try_into().map_err(|err: toml::de::Error| err.to_string());
+    }
+
+    /**
+     * Load a TOML config from a file.
+     */
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+        return AttackConfig::from_toml(&text);
+    }
+
+    /**
+     * Check every experiment's parameters make sense.
+     * Returns a description of the first problem found.
+     */
+    pub fn validate(&self) -> Result<(), String> {
+        self.evict_reload.validate().map_err(|err| format!("evict_reload: {}", err))?;
+        self.prime_probe.data.validate().map_err(|err| format!("prime_probe.data: {}", err))?;
+        self.prime_probe.inst.validate().map_err(|err| format!("prime_probe.inst: {}", err))?;
+        self.spectre.data.validate().map_err(|err| format!("spectre.data: {}", err))?;
+        self.spectre.inst.validate().map_err(|err| format!("spectre.inst: {}", err))?;
+        self.spectre_sweep.validate().map_err(|err| format!("spectre_sweep: {}", err))?;
+        self.spec_window.validate().map_err(|err| format!("spec_window: {}", err))?;
+        self.covert.validate().map_err(|err| format!("covert: {}", err))?;
+        self.pacman.validate()?;
+        return Ok(());
+    }
+}
@@ -5,8 +5,10 @@
  * as all measurements include a serializing sync barrier instruction!)
  */
 
-use crate::*;
 
+use crate::qos::{set_core, CoreKind};
+use core::arch::asm;
+use core::ptr::read_volatile;
 /**
  * A globally visible counter that can be sampled to get a rough measurement of how far time has passed.
  *
@@ -30,7 +32,7 @@ pub unsafe fn counter_thread() {
             "str x0, [{cnt_addr}]",
             "add x0, x0, 1",
             "b 1b",
-            cnt_addr = in(reg) &mut counter::CTR as *mut u64 as u64,
+            cnt_addr = in(reg) core::ptr::addr_of_mut!(CTR) as u64,
         }
     }
 }
@@ -46,7 +48,7 @@ pub unsafe fn read_counter() -> u64 {
     asm!{
         "isb"
     }
-    let retval = read_volatile(&CTR);
+    let retval = read_volatile(core::ptr::addr_of!(CTR));
     asm!{
         "isb"
     }
@@ -0,0 +1,16 @@
+/*!
+ * A cross-thread covert channel through one L2 set, built out of the prime+probe primitives.
+ *
+ * Getting a message through it end to end needs working timers, congruent eviction set addresses and a
+ * sensible miss latency, so it doubles as a health check for all three. It only needs `timer`, so it runs
+ * against the simulated kernel (and so on any machine) as well as on a Mac.
+ */
+
+// Hamming and repetition codes
+pub mod ecc;
+
+// Sync words, headers and checksums
+pub mod frame;
+
+// The sender and receiver, and bit rate/ error rate reporting
+pub mod channel;
@@ -0,0 +1,374 @@
+/*!
+ * The covert channel itself: a sender thread and a receiver sharing one L2 set.
+ *
+ * Time is cut into slots of `slot_micros`, counted from a start time both sides agree on. For a 1 the sender
+ * spends the slot loading lines congruent with the set (more of them than the set has ways), for a 0 it
+ * stays idle. The receiver primes the set with lines of its own and spends the middle half of every slot
+ * probing them: a slot is a 1 if enough of its probes missed. The bits are frames (see `frame`), so the
+ * receiver doesn't need to know where the message starts, and everything is timed with `timer` so the same
+ * code runs against the simulated kernel's cache.
+ */
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::{Serialize, Deserialize};
+use rand::Rng;
+use crate::attacks::decision::count_misses;
+use crate::cache;
+use crate::covert::ecc::Ecc;
+use crate::covert::frame::{self, Deframed, Deframer};
+use crate::error::PacmanError;
+use crate::evset;
+use crate::results::{ExperimentRecord, ResultsSink};
+use crate::sim;
+use crate::timer;
+
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+use crate::qos::{set_core, CoreKind};
+
+/// Idle slots before the first frame (so the sender thread is up and the receiver has primed)
+pub const LEAD_SLOTS : u32 = 8;
+
+/// Idle slots between frames
+pub const FRAME_GAP : usize = 4;
+
+/// Below this much time to wait, spin instead of sleeping
+const SPIN_WAIT : Duration = Duration::from_micros(200);
+
+/// How long each bit is sent for
+pub const COVERT_SLOT_MICROS : u64 = 1000;
+
+/// How many lines the sender loads for a 1 (more than the L2 has ways)
+pub const COVERT_SENDER_EVSET_SIZE : usize = cache::L2_WAYS + 4;
+
+/// How many lines the receiver primes (a whole set)
+pub const COVERT_RECEIVER_EVSET_SIZE : usize = cache::L2_WAYS;
+
+/// Probe latency above which a line missed
+pub const COVERT_MISS_LATENCY : u64 = 40;
+
+/// Fraction of a slot's probes that have to miss for it to be a 1
+pub const COVERT_BUSY_THRESHOLD : f64 = 0.2;
+
+/// Bytes in the test message
+pub const COVERT_MESSAGE_SIZE : usize = 32;
+
+/// Payload bytes per frame
+pub const COVERT_FRAME_SIZE : usize = 8;
+
+/**
+ * Parameters for the covert channel.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelConfig {
+    /// How long each bit is sent for
+    pub slot_micros: u64,
+
+    /// How many lines the sender loads for a 1
+    pub sender_evset_size: usize,
+
+    /// How many lines the receiver primes and probes
+    pub receiver_evset_size: usize,
+
+    /// Probe latency above which a line missed
+    pub miss_latency: u64,
+
+    /// Fraction of a slot's probes that have to miss for it to be a 1
+    pub busy_threshold: f64,
+
+    /// Bytes in the (random) test message
+    pub message_size: usize,
+
+    /// Payload bytes per frame
+    pub frame_size: usize,
+
+    /// How frame headers and bodies are coded
+    pub ecc: Ecc,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        return ChannelConfig {
+            slot_micros: COVERT_SLOT_MICROS,
+            sender_evset_size: COVERT_SENDER_EVSET_SIZE,
+            receiver_evset_size: COVERT_RECEIVER_EVSET_SIZE,
+            miss_latency: COVERT_MISS_LATENCY,
+            busy_threshold: COVERT_BUSY_THRESHOLD,
+            message_size: COVERT_MESSAGE_SIZE,
+            frame_size: COVERT_FRAME_SIZE,
+            ecc: Ecc::Hamming,
+        };
+    }
+}
+
+impl ChannelConfig {
+    /**
+     * Check these parameters make sense.
+     * Returns a description of the first problem found.
+     */
+    pub fn validate(&self) -> Result<(), String> {
+        if self.slot_micros < 4 {
+            return Err(format!("slot_micros must be at least 4 (got {})", self.slot_micros));
+        }
+        if self.sender_evset_size == 0 || self.sender_evset_size > evset::EVSET_SIZE_MAX {
+            return Err(format!("sender_evset_size must be between 1 and {} (got {})", evset::EVSET_SIZE_MAX, self.sender_evset_size));
+        }
+        if self.receiver_evset_size == 0 || self.receiver_evset_size > evset::EVSET_SIZE_MAX {
+            return Err(format!("receiver_evset_size must be between 1 and {} (got {})", evset::EVSET_SIZE_MAX, self.receiver_evset_size));
+        }
+        if !(self.busy_threshold > 0.0 && self.busy_threshold <= 1.0) {
+            return Err(format!("busy_threshold must be above 0 and at most 1 (got {})", self.busy_threshold));
+        }
+        if self.frame_size == 0 || self.frame_size > frame::MAX_PAYLOAD {
+            return Err(format!("frame_size must be between 1 and {} (got {})", frame::MAX_PAYLOAD, self.frame_size));
+        }
+        if self.message_size == 0 || self.message_size.div_ceil(self.frame_size) > 256 {
+            return Err(format!("message_size must be between 1 and 256 frames of frame_size bytes (got {})", self.message_size));
+        }
+        return Ok(());
+    }
+
+    /// How long each bit is sent for
+    pub fn slot(&self) -> Duration {
+        return Duration::from_micros(self.slot_micros);
+    }
+}
+
+/**
+ * How a message went over the channel.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelReport {
+    /// How many slots (raw bits) were sent, including sync words, ECC and gaps
+    pub slots: usize,
+
+    /// How many slots the receiver heard wrong
+    pub raw_bit_errors: usize,
+
+    pub frames_sent: usize,
+
+    /// Frames received with a good checksum
+    pub frames_received: usize,
+
+    /// Frames received with a bad checksum
+    pub bad_frames: usize,
+
+    /// Coded bits the ECC corrected
+    pub corrected_bits: usize,
+
+    /// Bits in the message
+    pub message_bits: usize,
+
+    /// Message bits that didn't make it (wrong, or in a frame that was lost)
+    pub message_bit_errors: usize,
+
+    /// How long sending took
+    pub elapsed: Duration,
+}
+
+impl ChannelReport {
+    /// Slots per second
+    pub fn raw_bit_rate(&self) -> f64 {
+        return self.slots as f64 / self.elapsed.as_secs_f64();
+    }
+
+    /// Correct message bits per second
+    pub fn goodput(&self) -> f64 {
+        return (self.message_bits - self.message_bit_errors) as f64 / self.elapsed.as_secs_f64();
+    }
+
+    /// Bit error rate of the channel (before framing and ECC)
+    pub fn raw_bit_error_rate(&self) -> f64 {
+        return self.raw_bit_errors as f64 / self.slots as f64;
+    }
+
+    /// Bit error rate of the message (after framing and ECC)
+    pub fn bit_error_rate(&self) -> f64 {
+        return self.message_bit_errors as f64 / self.message_bits as f64;
+    }
+}
+
+impl fmt::Display for ChannelReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Sent {} slots in {:.3}s: {:.1} bits/s raw, {:.1} bits/s of message", self.slots, self.elapsed.as_secs_f64(), self.raw_bit_rate(), self.goodput())?;
+        writeln!(f, "Raw bit error rate {:.4} ({} slots wrong, {} bits corrected)", self.raw_bit_error_rate(), self.raw_bit_errors, self.corrected_bits)?;
+        return write!(f, "{}/{} frames received ({} bad checksums), message bit error rate {:.4}", self.frames_received, self.frames_sent, self.bad_frames, self.bit_error_rate());
+    }
+}
+
+/// Wait for `deadline`: sleep most of the way, spin the rest
+fn wait_until(deadline: Instant) {
+    let now = Instant::now();
+    if deadline > now + SPIN_WAIT {
+        thread::sleep(deadline - now - SPIN_WAIT);
+    }
+    while Instant::now() < deadline {
+        std::hint::spin_loop();
+    }
+}
+
+/**
+ * The sender: for every 1 in `bits`, load `lines` over and over for the whole slot.
+ * Loads go through `timer::time_access` so the simulated cache sees them too.
+ * Both sides yield after every pass over their lines, so they still interleave if they share a core.
+ */
+fn send(lines: &[u64], bits: &[bool], start: Instant, slot: Duration) {
+    for (i, bit) in bits.iter().enumerate() {
+        let slot_end = start + slot * (i as u32 + 1);
+        if !*bit {
+            wait_until(slot_end);
+            continue;
+        }
+        wait_until(slot_end - slot);
+        while Instant::now() < slot_end {
+            for line in lines {
+                unsafe { timer::time_access(*line); }
+            }
+            thread::yield_now();
+        }
+    }
+}
+
+/**
+ * The receiver: prime+probe `lines` through the middle half of each of `num_slots` slots.
+ *
+ * # Return Value
+ * The percentage of probes that saw a miss in each slot.
+ */
+fn receive(lines: &[u64], num_slots: usize, start: Instant, slot: Duration, miss_latency: u64) -> Vec<u64> {
+    let mut times = vec![0u64; lines.len()];
+    let mut busy = Vec::with_capacity(num_slots);
+
+    for i in 0..num_slots {
+        let slot_start = start + slot * i as u32;
+        wait_until(slot_start + slot / 4);
+        let window_end = slot_start + slot * 3 / 4;
+
+        // Prime, then every probe primes for the next one
+        for line in lines {
+            unsafe { timer::time_access(*line); }
+        }
+        let mut probes = 0;
+        let mut missed = 0;
+        while Instant::now() < window_end {
+            for (time, line) in times.iter_mut().zip(lines).rev() {
+                *time = unsafe { timer::time_access(*line) };
+            }
+            probes += 1;
+            missed += (count_misses(&times, miss_latency) > 0) as u64;
+            thread::yield_now();
+        }
+        busy.push(missed * 100 / probes.max(1));
+    }
+    return busy;
+}
+
+/**
+ * Send a random `config.message_size` byte message from a sender thread to this thread over the channel,
+ * and record how it went to `sink` as a `covert_channel` record.
+ *
+ * The channel's lines are an eviction set out of `memory_region` (see `evset::data_pevset`) for a random L2 line,
+ * split between the receiver and the sender. Against the simulated kernel, the sender thread runs on the
+ * kernel installed on this thread (see `sim::kernel::install`).
+ *
+ * # Return Value
+ * How the message went, or the error if `memory_region` is too small for the channel's lines, the sender
+ * thread couldn't get onto a P core or panicked, or the record couldn't be written.
+ */
+pub fn covert_channel(memory_region: &mut [u8], config: &ChannelConfig, sink: &mut dyn ResultsSink) -> Result<ChannelReport, PacmanError> {
+    let num_lines = config.receiver_evset_size + config.sender_evset_size;
+    let target = (rand::thread_rng().gen_range(0..evset::STRIDE / cache::L2_LINESIZE) * cache::L2_LINESIZE) as u64;
+    let lines = evset::choose_evset(&evset::data_pevset(target, target, memory_region), num_lines, "the covert channel")?;
+    let (receiver_lines, sender_lines) = lines.split_at(config.receiver_evset_size);
+    let sender_lines = sender_lines.to_vec();
+
+    // Frame the message
+    let mut message = vec![0u8; config.message_size];
+    rand::thread_rng().fill(&mut message[..]);
+    let chunks : Vec<&[u8]> = message.chunks(config.frame_size).collect();
+    let mut bits = Vec::new();
+    for (seq, chunk) in chunks.iter().enumerate() {
+        bits.extend(frame::encode_frame(seq as u8, chunk, config.ecc));
+        bits.extend(std::iter::repeat_n(false, FRAME_GAP));
+    }
+
+    // Start both sides on the same slots
+    let slot = config.slot();
+    let start = Instant::now() + slot * LEAD_SLOTS;
+    let installed = sim::kernel::installed();
+    let sent = bits.clone();
+    let sender = thread::spawn(move || -> Result<(), PacmanError> {
+        if let Some(kernel) = &installed {
+            sim::kernel::install(kernel);
+        }
+        #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+        unsafe {
+            if !set_core(CoreKind::PCORE) {
+                return Err(PacmanError::CoreAffinity("covert channel sender"));
+            }
+        }
+        send(&sender_lines, &sent, start, slot);
+        return Ok(());
+    });
+    let busy = receive(receiver_lines, bits.len(), start, slot, config.miss_latency);
+    let elapsed = Instant::now() - start;
+    sender.join().map_err(|_| PacmanError::ThreadPanicked("covert channel sender"))??;
+
+    // Deframe what was heard
+    let heard : Vec<bool> = busy.iter().map(|busy| *busy as f64 >= config.busy_threshold * 100.0).collect();
+    let mut deframer = Deframer::new(config.ecc);
+    let mut received : Vec<Option<Vec<u8>>> = vec![None; chunks.len()];
+    let mut bad_frames = 0;
+    let mut corrected_bits = 0;
+    for bit in &heard {
+        match deframer.push(*bit) {
+            Some(Deframed::Frame(frame)) if (frame.seq as usize) < chunks.len() => {
+                corrected_bits += frame.corrected;
+                received[frame.seq as usize] = Some(frame.payload);
+            },
+            Some(_) => bad_frames += 1,
+            None => {},
+        }
+    }
+
+    // Compare it to what was sent
+    let mut message_bit_errors = 0;
+    for (chunk, payload) in chunks.iter().zip(&received) {
+        message_bit_errors += match payload {
+            Some(payload) if payload.len() == chunk.len() => {
+                frame::bytes_to_bits(chunk).iter().zip(frame::bytes_to_bits(payload)).filter(|(sent, heard)| **sent != *heard).count()
+            },
+            _ => chunk.len() * 8,
+        };
+    }
+
+    let report = ChannelReport {
+        slots: bits.len(),
+        raw_bit_errors: bits.iter().zip(&heard).filter(|(sent, heard)| sent != heard).count(),
+        frames_sent: chunks.len(),
+        frames_received: received.iter().filter(|payload| payload.is_some()).count(),
+        bad_frames,
+        corrected_bits,
+        message_bits: message.len() * 8,
+        message_bit_errors,
+        elapsed,
+    };
+
+    sink.record(
+        &ExperimentRecord::new("covert_channel")
+            .param("slot_micros", config.slot_micros)
+            .param("ecc", config.ecc.to_string())
+            .param("frame_size", config.frame_size)
+            .param("message_size", config.message_size)
+            .param("frames_sent", report.frames_sent)
+            .param("frames_received", report.frames_received)
+            .param("raw_bit_rate", report.raw_bit_rate())
+            .param("goodput", report.goodput())
+            .param("raw_bit_error_rate", report.raw_bit_error_rate())
+            .param("bit_error_rate", report.bit_error_rate())
+            .samples(&busy)
+    )?;
+
+    return Ok(report);
+}
@@ -0,0 +1,123 @@
+/*!
+ * Error correcting codes for the covert channel's frames.
+ *
+ * * `none`: Every bit is sent as is.
+ * * `repeat:<n>`: Every bit is sent `n` times (`n` odd) and decoded by majority vote.
+ * * `hamming`: Hamming(7,4), every nibble is sent as 7 bits and any one bit error in them is corrected.
+ */
+use std::fmt;
+use std::str::FromStr;
+use serde::{Serialize, Deserialize};
+
+/**
+ * How frame headers and bodies are coded.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Ecc {
+    None,
+    Repetition { n: usize },
+    Hamming,
+}
+
+impl Ecc {
+    /// How many bits `data_bits` bits are coded into
+    pub fn coded_len(&self, data_bits: usize) -> usize {
+        return match self {
+            Ecc::None => data_bits,
+            Ecc::Repetition { n } => data_bits * n,
+            Ecc::Hamming => data_bits.div_ceil(4) * 7,
+        };
+    }
+
+    /**
+     * Code `bits` (padded with zeroes to a whole number of nibbles for `hamming`).
+     */
+    pub fn encode(&self, bits: &[bool]) -> Vec<bool> {
+        return match self {
+            Ecc::None => bits.to_vec(),
+            Ecc::Repetition { n } => bits.iter().flat_map(|bit| std::iter::repeat_n(*bit, *n)).collect(),
+            Ecc::Hamming => bits.chunks(4).flat_map(|nibble| {
+                let d = |i: usize| *nibble.get(i).unwrap_or(&false);
+                [d(0) ^ d(1) ^ d(3), d(0) ^ d(2) ^ d(3), d(0), d(1) ^ d(2) ^ d(3), d(1), d(2), d(3)]
+            }).collect(),
+        };
+    }
+
+    /**
+     * Decode `coded` (`coded_len(..)` bits).
+     *
+     * # Return Value
+     * The decoded bits, and how many coded bits were corrected.
+     */
+    pub fn decode(&self, coded: &[bool]) -> (Vec<bool>, usize) {
+        return match self {
+            Ecc::None => (coded.to_vec(), 0),
+            Ecc::Repetition { n } => {
+                let mut corrected = 0;
+                let bits = coded.chunks(*n).map(|copies| {
+                    let ones = copies.iter().filter(|bit| **bit).count();
+                    let bit = 2 * ones > copies.len();
+                    corrected += if bit { copies.len() - ones } else { ones };
+                    bit
+                }).collect();
+                (bits, corrected)
+            },
+            Ecc::Hamming => {
+                let mut corrected = 0;
+                let mut bits = Vec::with_capacity(coded.len() / 7 * 4);
+                for codeword in coded.chunks_exact(7) {
+                    let mut codeword : [bool; 7] = codeword.try_into().unwrap();
+                    // Position (1 indexed) of the flipped bit, 0 if there isn't one
+                    let syndrome = (1..=7).filter(|position| codeword[position - 1]).fold(0, |syndrome, position| syndrome ^ position);
+                    if syndrome != 0 {
+                        codeword[syndrome - 1] = !codeword[syndrome - 1];
+                        corrected += 1;
+                    }
+                    bits.extend([codeword[2], codeword[4], codeword[5], codeword[6]]);
+                }
+                (bits, corrected)
+            },
+        };
+    }
+}
+
+impl fmt::Display for Ecc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            Ecc::None => write!(f, "none"),
+            Ecc::Repetition { n } => write!(f, "repeat:{}", n),
+            Ecc::Hamming => write!(f, "hamming"),
+        };
+    }
+}
+
+impl FromStr for Ecc {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        return match text.trim().split_once(':') {
+            None if text.trim() == "none" => Ok(Ecc::None),
+            None if text.trim() == "hamming" => Ok(Ecc::Hamming),
+            Some(("repeat", n)) => match n.parse::<usize>() {
+                Ok(n) if n % 2 == 1 => Ok(Ecc::Repetition { n }),
+                _ => Err(format!("Bad repetition count in {} (must be odd)", text)),
+            },
+            _ => Err(format!("{} isn't none, repeat:<n> or hamming", text)),
+        };
+    }
+}
+
+impl TryFrom<String> for Ecc {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        return text.parse();
+    }
+}
+
+impl From<Ecc> for String {
+    fn from(ecc: Ecc) -> Self {
+        return ecc.to_string();
+    }
+}
@@ -0,0 +1,161 @@
+/*!
+ * Framing for the covert channel.
+ *
+ * A frame is `SYNC` (sent uncoded, so the receiver can find the start of a frame in whatever it's been
+ * hearing), then a header (the frame's sequence number and payload length) and a body (the payload and its
+ * CRC-8), each coded with the channel's `Ecc`. Bytes are sent most significant bit first.
+ */
+use crate::covert::ecc::Ecc;
+
+/// The 13 bit Barker code (its shifts barely match it, so it's easy to find in a stream of bits)
+pub const SYNC : [bool; 13] = [true, true, true, true, true, false, false, true, true, false, true, false, true];
+
+/// How many bits of `SYNC` can be wrong for it to still count as found
+pub const SYNC_TOLERANCE : usize = 1;
+
+/// The most payload bytes a frame can carry
+pub const MAX_PAYLOAD : usize = 255;
+
+/// Bytes in a frame header (sequence number and payload length)
+pub const HEADER_SIZE : usize = 2;
+
+/// CRC-8 polynomial (x^8 + x^2 + x + 1)
+const CRC8_POLY : u8 = 0x07;
+
+/**
+ * CRC-8 of `bytes`.
+ */
+pub fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ CRC8_POLY } else { crc << 1 };
+        }
+    }
+    return crc;
+}
+
+/// `bytes` as bits, most significant first
+pub fn bytes_to_bits(bytes: &[u8]) -> Vec<bool> {
+    return bytes.iter().flat_map(|byte| (0..8).rev().map(move |bit| (byte >> bit) & 1 == 1)).collect();
+}
+
+/// `bits` (most significant first) as bytes, dropping any bits after the last whole byte
+pub fn bits_to_bytes(bits: &[bool]) -> Vec<u8> {
+    return bits.chunks_exact(8).map(|byte| byte.iter().fold(0, |acc, bit| (acc << 1) | *bit as u8)).collect();
+}
+
+/**
+ * The bits to send for frame `seq` carrying `payload`.
+ * Panics if `payload` is longer than `MAX_PAYLOAD`.
+ */
+pub fn encode_frame(seq: u8, payload: &[u8], ecc: Ecc) -> Vec<bool> {
+    assert!(payload.len() <= MAX_PAYLOAD, "Frames carry at most {} bytes (got {})", MAX_PAYLOAD, payload.len());
+    let mut body = payload.to_vec();
+    body.push(crc8(payload));
+
+    let mut bits = SYNC.to_vec();
+    bits.extend(ecc.encode(&bytes_to_bits(&[seq, payload.len() as u8])));
+    bits.extend(ecc.encode(&bytes_to_bits(&body)));
+    return bits;
+}
+
+/**
+ * A frame that came through with a good checksum.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub seq: u8,
+    pub payload: Vec<u8>,
+
+    /// How many coded bits the ECC corrected
+    pub corrected: usize,
+}
+
+/**
+ * What `Deframer::push` found at the end of a frame.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Deframed {
+    Frame(Frame),
+
+    /// The body didn't match its checksum (the header's sequence number, if it's to be believed)
+    BadChecksum { seq: u8 },
+}
+
+/// Where the deframer is in a frame
+#[derive(Clone, Debug)]
+enum State {
+    /// Looking for `SYNC` in the last bits heard
+    Hunting,
+
+    /// Collecting the coded header
+    Header,
+
+    /// Collecting the coded body (the payload length and ECC corrections come from the header)
+    Body { seq: u8, len: usize, corrected: usize },
+}
+
+/**
+ * Pulls frames out of a stream of received bits, one bit at a time.
+ */
+#[derive(Clone, Debug)]
+pub struct Deframer {
+    ecc: Ecc,
+    state: State,
+    bits: Vec<bool>,
+}
+
+impl Deframer {
+    pub fn new(ecc: Ecc) -> Self {
+        return Deframer { ecc, state: State::Hunting, bits: Vec::new() };
+    }
+
+    /**
+     * Take in the next received bit.
+     *
+     * # Return Value
+     * The frame that bit ended (if it ended one).
+     */
+    pub fn push(&mut self, bit: bool) -> Option<Deframed> {
+        self.bits.push(bit);
+
+        match self.state {
+            State::Hunting => {
+                if self.bits.len() > SYNC.len() {
+                    self.bits.remove(0);
+                }
+                let errors = self.bits.iter().zip(SYNC.iter()).filter(|(heard, sync)| heard != sync).count();
+                if self.bits.len() == SYNC.len() && errors <= SYNC_TOLERANCE {
+                    self.state = State::Header;
+                    self.bits.clear();
+                }
+            },
+            State::Header => {
+                if self.bits.len() == self.ecc.coded_len(8 * HEADER_SIZE) {
+                    let (header, corrected) = self.ecc.decode(&self.bits);
+                    let header = bits_to_bytes(&header);
+                    self.state = State::Body { seq: header[0], len: header[1] as usize, corrected };
+                    self.bits.clear();
+                }
+            },
+            State::Body { seq, len, corrected } => {
+                if self.bits.len() == self.ecc.coded_len(8 * (len + 1)) {
+                    let (body, body_corrected) = self.ecc.decode(&self.bits);
+                    let mut payload = bits_to_bytes(&body);
+                    payload.truncate(len + 1);
+                    let crc = payload.pop().unwrap();
+
+                    self.state = State::Hunting;
+                    self.bits.clear();
+                    if crc != crc8(&payload) {
+                        return Some(Deframed::BadChecksum { seq });
+                    }
+                    return Some(Deframed::Frame(Frame { seq, payload, corrected: corrected + body_corrected }));
+                }
+            },
+        }
+        return None;
+    }
+}
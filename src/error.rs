@@ -0,0 +1,110 @@
+/*!
+ * What can go wrong talking to PacmanKit (or mach), or working out where things are in the kernel.
+ *
+ * Nothing in the library prints when a kernel operation fails. It returns a `PacmanError` describing the
+ * failure instead, and whoever is running the experiment decides what to say about it.
+ */
+use std::fmt;
+use crate::pacmankit::{error_string, KernReturn, PacmanKitOp};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PacmanError {
+    /// The PacmanKit IOService isn't registered (the kext isn't loaded, or there's no simulated kernel installed)
+    ServiceNotFound,
+
+    /// PacmanKit is there, but opening an IOUserClient on it failed
+    OpenFailed(KernReturn),
+
+    /// A PacmanKit operation failed
+    OperationFailed { op: PacmanKitOp, kret: KernReturn },
+
+    /// A mach call (named by `call`) failed
+    Mach { call: &'static str, kret: KernReturn },
+
+    /// The PacmanKit server couldn't be reached (see `remote`)
+    Unreachable(std::io::ErrorKind),
+
+    /// The recorded session being replayed never opened this many connections (see `session`)
+    NotRecorded(u32),
+
+    /// The offset profile for this OS build doesn't have the named offset (see `profile`)
+    MissingOffset(&'static str),
+
+    /// The named symbolic address couldn't be resolved against the kernelcache (see `kernelcache::symbols`)
+    Unresolved { name: &'static str, kind: std::io::ErrorKind },
+
+    /// A bruteforce didn't find a PAC for the named pointer
+    PacNotFound(&'static str),
+
+    /// A bruteforce found the wrong PAC for the named pointer (caught before it was used and panicked the kernel)
+    WrongPac(&'static str),
+
+    /// Writing results or a trace failed (the experiment stops rather than carry on without them)
+    Io(std::io::ErrorKind),
+
+    /// An experiment took no samples to decide anything from
+    NoSamples,
+
+    /// Fewer addresses were found for the named eviction set than it needs
+    TooFewCandidates { name: &'static str, size: usize, found: usize },
+
+    /// The named thread an experiment started panicked
+    ThreadPanicked(&'static str),
+
+    /// The named thread an experiment started couldn't be moved onto a P core
+    CoreAffinity(&'static str),
+}
+
+impl PacmanError {
+    /// The `kern_return_t` behind the failure (if there is one)
+    pub fn kret(&self) -> Option<KernReturn> {
+        return match *self {
+            PacmanError::ServiceNotFound => None,
+            PacmanError::OpenFailed(kret) => Some(kret),
+            PacmanError::OperationFailed { kret, .. } => Some(kret),
+            PacmanError::Mach { kret, .. } => Some(kret),
+            PacmanError::Unreachable(_) => None,
+            PacmanError::NotRecorded(_) => None,
+            PacmanError::MissingOffset(_) => None,
+            PacmanError::Unresolved { .. } => None,
+            PacmanError::PacNotFound(_) => None,
+            PacmanError::WrongPac(_) => None,
+            PacmanError::Io(_) => None,
+            PacmanError::NoSamples => None,
+            PacmanError::TooFewCandidates { .. } => None,
+            PacmanError::ThreadPanicked(_) => None,
+            PacmanError::CoreAffinity(_) => None,
+        };
+    }
+}
+
+impl fmt::Display for PacmanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match *self {
+            PacmanError::ServiceNotFound => write!(f, "Couldn't find PacmanKit. Did you remember to install it?"),
+            PacmanError::OpenFailed(kret) => write!(f, "Couldn't connect to PacmanKit: {} (0x{:X})", error_string(kret), kret),
+            PacmanError::OperationFailed { op, kret } => write!(f, "PacmanKit {:?} failed: {} (0x{:X})", op, error_string(kret), kret),
+            PacmanError::Mach { call, kret } => write!(f, "{} failed: {} (0x{:X})", call, error_string(kret), kret),
+            PacmanError::Unreachable(kind) => write!(f, "Couldn't connect to the PacmanKit server: {}", kind),
+            PacmanError::NotRecorded(id) => write!(f, "The recorded session didn't open connection {}", id),
+            PacmanError::MissingOffset(name) => write!(f, "The offset profile for this OS build has no {}", name),
+            PacmanError::Unresolved { name, kind: std::io::ErrorKind::NotFound } => write!(f, "The kext or symbol {} is relative to isn't in the kernelcache", name),
+            PacmanError::Unresolved { name, .. } => write!(f, "Resolving {} needs the kernelcache (pass --kernelcache)", name),
+            PacmanError::PacNotFound(what) => write!(f, "Couldn't find the PAC of the {}", what),
+            PacmanError::WrongPac(what) => write!(f, "Found the wrong PAC for the {} (stopped before using it)", what),
+            PacmanError::Io(kind) => write!(f, "Couldn't record results: {}", kind),
+            PacmanError::NoSamples => write!(f, "No samples were taken (are the iteration counts 0?)"),
+            PacmanError::TooFewCandidates { name, size, found } => write!(f, "Asked for {} addresses in {}, but only {} candidates were found", size, name, found),
+            PacmanError::ThreadPanicked(name) => write!(f, "The {} thread panicked", name),
+            PacmanError::CoreAffinity(name) => write!(f, "Couldn't move the {} thread onto a P core", name),
+        };
+    }
+}
+
+impl From<std::io::Error> for PacmanError {
+    fn from(err: std::io::Error) -> Self {
+        return PacmanError::Io(err.kind());
+    }
+}
+
+impl std::error::Error for PacmanError {}
@@ -2,42 +2,69 @@
  * Generate eviction sets for data / inst pointers
  */
 
-use crate::*;
+use std::collections::BTreeMap;
+use crate::aarch64::RET;
+use crate::cache::{self, L1D_LINESIZE, L1D_SETS, L1D_WAYS, L1I_LINESIZE, L1I_SETS, L1I_WAYS};
+use crate::payload::Payload;
+use rand::thread_rng;
 use rand::prelude::SliceRandom;
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+use crate::primitives::AddressTranslation;
+use crate::error::PacmanError;
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+use crate::retpoline;
 
 /// What stride do we take between virtual addresses to generate out evset candidates?
 /// If this is a large power of two multiple of page size, it will increase the likelihood of TLB conflicts.
 /// To eliminate TLB conflicts, make this a large power of 2 + 1 multiple of page size.
 pub const STRIDE : usize = 4096 * cache::PAGE_SIZE;
 
+/// The most candidates an eviction set will ever be built out of
 pub const EVSET_SIZE_MAX : usize = 1024;
 
+/// Where in each line of an `InstChain` the `ret` to probe it with is (past the longest jump to the next line, `payload::JUMP_MAX_SIZE`)
+pub const CHAIN_PROBE_OFFSET : u64 = 0x20;
+
+/**
+ * Randomly pick `size` addresses out of an eviction set's candidates.
+ *
+ * # Arguments
+ * * `candidates`: Every address found for the eviction set (eg. from `data_pevset`).
+ * * `size`: How many addresses to pick.
+ * * `name`: What the eviction set is for (used when reporting errors).
+ *
+ * # Return Value
+ * The addresses picked, or `PacmanError::TooFewCandidates` if there are fewer than `size` candidates to pick from.
+ */
+pub fn choose_evset(candidates: &[u64], size: usize, name: &'static str) -> Result<Vec<u64>, PacmanError> {
+    if candidates.len() < size {
+        return Err(PacmanError::TooFewCandidates { name, size, found: candidates.len() });
+    }
+    return Ok(candidates.choose_multiple(&mut rand::thread_rng(), size).cloned().collect());
+}
+
 /**
  * Create a data eviction set within a kernel memory region for a given physical address.
  *
  * `target_paddr` should be a physical address.
  *
  * # Arguments
+ * * `handle`: Translates addresses in `kernel_memory` to physical addresses (eg. a `PacmanKitConnection`).
  * * `target_vaddr`: The virtual address to create an eviction set for.
  * * `target_paddr`: The physical address corresponding to `target_vaddr`.
- *                   (can't just compute this as we don't know which address space the target vaddr comes from).
+ *   (can't just compute this as we don't know which address space the target vaddr comes from).
  * * `kernel_memory`: The kernel memory region to draw addresses from for the eviction set.
  * * `kernel_memory_size`: The size of `kernel_memory`.
  *
  * # Return Value
- * A vector of addresses within `kernel_memory` that will contend with `target_paddr`.
+ * A vector of addresses within `kernel_memory` that will contend with `target_paddr`, or the error from translating them.
  */
- pub unsafe fn data_kpevset(target_vaddr: u64, target_paddr: u64, kernel_memory: u64, kernel_memory_size: usize) -> Vec<u64> {
-    let handle = match PacmanKitConnection::init() {
-        Some(v) => v,
-        None => panic!("Couldn't connect to PacmanKit"),
-    };
-
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+ pub unsafe fn data_kpevset<K: AddressTranslation>(handle: &K, target_vaddr: u64, target_paddr: u64, kernel_memory: u64, kernel_memory_size: usize) -> Result<Vec<u64>, PacmanError> {
     let mut vec = Vec::new();
     let target_l2_set = cache::get_cache_set_m1(target_paddr);
 
     // Offset applies to virtual addresses
-    let memory_region_addr = kernel_memory as usize;
 
     for i in (0..kernel_memory_size).step_by(128) {
         let idx = i;
@@ -47,7 +74,7 @@ pub const EVSET_SIZE_MAX : usize = 1024;
         }
 
         let cur_va = kernel_memory + idx as u64;
-        let cur_pa = handle.kernel_virt_to_phys(cur_va).unwrap();
+        let cur_pa = handle.kernel_virt_to_phys(cur_va)?;
         // if cache::get_cache_set_m1(cur_va) == cache::get_cache_set_m1(target_vaddr) {
             if cache::get_cache_set_m1(cur_pa) == target_l2_set {
                 vec.push(cur_va);
@@ -62,7 +89,7 @@ pub const EVSET_SIZE_MAX : usize = 1024;
         }
     }
 
-    return vec;
+    return Ok(vec);
 }
 
 /**
@@ -73,34 +100,28 @@ pub const EVSET_SIZE_MAX : usize = 1024;
  * # Arguments
  * * `target_vaddr`: The virtual address to create an eviction set for.
  * * `target_paddr`: The physical address corresponding to `target_vaddr`.
- *                   (can't just compute this as we don't know which address space the target vaddr comes from).
+ *   (can't just compute this as we don't know which address space the target vaddr comes from).
  * * `memory_region`: The region to draw addresses from for the eviction set.
  *
  * # Return Value
  * A vector of addresses within `memory_region` that will contend with `target_paddr`.
  */
-pub unsafe fn data_pevset(target_vaddr: u64, target_paddr: u64, memory_region: &mut [u8]) -> Vec<u64> {
-    let handle = match PacmanKitConnection::init() {
-        Some(v) => v,
-        None => panic!("Couldn't connect to PacmanKit"),
-    };
-
+pub fn data_pevset(target_vaddr: u64, target_paddr: u64, memory_region: &mut [u8]) -> Vec<u64> {
     let mut vec = Vec::new();
-    let target_l2_set = cache::get_cache_set_m1(target_paddr);
+    let _target_l2_set = cache::get_cache_set_m1(target_paddr);
 
     // Offset applies to virtual addresses
-    let memory_region_addr = memory_region.as_ptr() as usize;
     let offset = (target_vaddr as usize) & cache::TLB_OFFSET_MASK;
 
-    for i in (0..memory_region.len()).step_by(evset::STRIDE) {
+    for i in (0..memory_region.len()).step_by(STRIDE) {
         let idx = i + offset;
-        if idx > memory_region.len() { break; }
+        if idx >= memory_region.len() { break; }
 
         if vec.len() >= EVSET_SIZE_MAX { break; }
 
         let cur_va = &memory_region[idx] as *const u8 as u64;
 
-        // Uncomment this to use physical translation:
+        // Uncomment this to use physical translation (needs an `AddressTranslation` as `handle`):
         // For now we do NOT use physical translation as we don't need it
         // let cur_pa = handle.user_virt_to_phys(cur_va).unwrap();
         // if cache::get_cache_set_m1(cur_pa) == target_l2_set {
@@ -126,7 +147,7 @@ pub unsafe fn data_pevset(target_vaddr: u64, target_paddr: u64, memory_region: &
  * # Arguments
  * * `target_vaddr`: The virtual address to create an eviction set for.
  * * `target_paddr`: The physical address corresponding to `target_vaddr`.
- *                   (can't just compute this as we don't know which address space the target vaddr comes from).
+ *   (can't just compute this as we don't know which address space the target vaddr comes from).
  *
  * # Return Value
  * A vector of addresses within `memory_region` that will contend with `target_paddr`.
@@ -134,6 +155,7 @@ pub unsafe fn data_pevset(target_vaddr: u64, target_paddr: u64, memory_region: &
  * # Side Effects
  * Will make parts of memory_region executable, and fill them with instructions to execute.
  */
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
 pub unsafe fn inst_pevset(target_vaddr: u64, target_paddr: u64, memory_region: &mut [u8]) -> Vec<u64> {
     let evset = data_pevset(target_vaddr, target_paddr, memory_region);
 
@@ -145,6 +167,90 @@ pub unsafe fn inst_pevset(target_vaddr: u64, target_paddr: u64, memory_region: &
     return evset;
 }
 
+/**
+ * Create an instruction eviction set within a memory region for a given physical address.
+ *
+ * Nothing in `memory_region` can be executed here, so this is just `data_pevset`
+ * (the simulated timers "execute" the addresses themselves, see `sim::kernel`).
+ */
+#[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
+pub unsafe fn inst_pevset(target_vaddr: u64, target_paddr: u64, memory_region: &mut [u8]) -> Vec<u64> {
+    return data_pevset(target_vaddr, target_paddr, memory_region);
+}
+
+/**
+ * An instruction eviction set as one code path: the start of each line jumps to the next line, and the last
+ * line returns. Calling `entry` executes every line in the set (priming all of it in one call, without a
+ * timer read and barriers around each line or any loads on the data side), and each line has a `ret` of its
+ * own at `CHAIN_PROBE_OFFSET` to probe just that line with.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct InstChain {
+    /// The start of each line, in the order the chain goes through them
+    pub lines: Vec<u64>,
+}
+
+impl InstChain {
+    /**
+     * Chain the lines of the addresses in `evset` (eg. chosen out of `inst_pevset`) together, in the order given.
+     *
+     * # Panics
+     * Panics if `evset` is empty, or two of its addresses are in the same line (the chain would loop forever).
+     */
+    pub fn new(evset: &[u64]) -> Self {
+        let lines : Vec<u64> = evset.iter().map(|addr| addr & !(L1I_LINESIZE as u64 - 1)).collect();
+        let mut sorted = lines.clone();
+        sorted.sort();
+        sorted.dedup();
+        if lines.is_empty() || sorted.len() != lines.len() {
+            panic!("An instruction chain needs distinct lines to go through, got {} lines ({} distinct)", lines.len(), sorted.len());
+        }
+        return InstChain { lines };
+    }
+
+    /// Where to call to prime every line
+    pub fn entry(&self) -> u64 {
+        return self.lines[0];
+    }
+
+    /// Where to call to probe line `i` on its own
+    pub fn probe(&self, i: usize) -> u64 {
+        return self.lines[i] + CHAIN_PROBE_OFFSET;
+    }
+
+    /**
+     * The code for every page the chain goes through (all `ret`s apart from the jumps).
+     */
+    pub fn payloads(&self) -> Vec<Payload> {
+        let mut pages : BTreeMap<u64, Payload> = BTreeMap::new();
+        for (i, line) in self.lines.iter().enumerate() {
+            let page = line & !(cache::PAGE_OFFSET_MASK as u64);
+            let payload = pages.entry(page).or_insert_with(|| Payload::new(page, cache::PAGE_SIZE));
+            match self.lines.get(i + 1) {
+                Some(next) => { payload.jump(*line, *next); },
+                None => payload.emit_word(*line, RET),
+            }
+        }
+        return pages.into_values().collect();
+    }
+}
+
+/**
+ * Make the chosen addresses of an instruction eviction set into an `InstChain`.
+ *
+ * # Side Effects
+ * Will make the pages of `chosen` executable, and fill them with the chain. `retpoline::mk_payload`
+ * invalidates the instruction cache over them, so the `ret`s they held before don't get primed instead.
+ */
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub unsafe fn inst_chain_evset(chosen: &[u64]) -> Result<InstChain, PacmanError> {
+    let chain = InstChain::new(chosen);
+    for payload in chain.payloads() {
+        retpoline::mk_payload(&payload)?;
+    }
+    return Ok(chain);
+}
+
 /**
  * Create an eviction set for a given data address within a memory region.
  *
@@ -158,8 +264,8 @@ pub unsafe fn inst_pevset(target_vaddr: u64, target_paddr: u64, memory_region: &
  * A vector of addresses within `memory_region`.
  */
 pub fn data_evset(addr: *const u8, memory_region: &mut [u8]) -> Vec<*mut u8> {
+    let _ptr = addr as u64;
     let mut vec = Vec::new();
-    let ptr = addr as u64;
     let memory_region_base = (&memory_region[0] as *const u8) as u64;
 
     for i in 0..L1D_WAYS {
@@ -194,8 +300,8 @@ pub fn data_evset(addr: *const u8, memory_region: &mut [u8]) -> Vec<*mut u8> {
  * A vector of addresses within `memory_region`.
  */
 pub fn inst_evset(addr: *const u32, memory_region: &mut [u32]) -> Vec<*mut u32> {
+    let _ptr = addr as u64;
     let mut vec = Vec::new();
-    let ptr = addr as u64;
     let memory_region_base = (&memory_region[0] as *const u32) as u64;
 
     for i in 0..L1I_WAYS {
@@ -0,0 +1,148 @@
+/*!
+ * Guards that put the kernel back the way we found it.
+ *
+ * The attacks allocate kernel memory for their fake vtables and write guesses into real kernel objects.
+ * If an experiment bails out halfway (an error, or a panic) those objects are left pointing at guesses
+ * (or at freed memory), and the next thing to use them panics the kernel. `KernelAllocation` frees its
+ * memory and `KernelRestore` writes back every value it overwrote when they are dropped, whether that's
+ * at the end of the experiment, on an early `?` return or while unwinding.
+ *
+ * Declare the allocation before the restore guard for objects that will point into it, so the objects are
+ * restored before the memory they point to goes away (locals are dropped in reverse order).
+ */
+use crate::error::PacmanError;
+use crate::pac::PAC_BITMASK;
+use crate::pacmankit;
+use crate::primitives::KernelMemory;
+
+/**
+ * Kernel memory from `kernel_mmap`, freed with `kernel_free` when dropped.
+ *
+ * PacmanKit only keeps track of the last allocation (that's the one `kernel_free` frees), so only have one
+ * of these at a time.
+ */
+pub struct KernelAllocation<'a, K: KernelMemory> {
+    handle: &'a K,
+    addr: u64,
+    freed: bool,
+}
+
+impl<'a, K: KernelMemory> KernelAllocation<'a, K> {
+    /**
+     * Allocate kernel memory with `handle`.
+     */
+    pub unsafe fn new(handle: &'a K) -> Result<Self, PacmanError> {
+        let addr = handle.kernel_mmap()?;
+        return Ok(KernelAllocation { handle, addr, freed: false });
+    }
+
+    /// Where the memory is (as `kernel_mmap` returned it)
+    pub fn addr(&self) -> u64 {
+        return self.addr;
+    }
+
+    /**
+     * Free the memory now, returning the error if it couldn't be freed (dropping the guard ignores it).
+     */
+    pub unsafe fn free(mut self) -> Result<(), PacmanError> {
+        self.freed = true;
+        return pacmankit::retry(|| self.handle.kernel_free());
+    }
+}
+
+impl<'a, K: KernelMemory> Drop for KernelAllocation<'a, K> {
+    fn drop(&mut self) {
+        if !self.freed {
+            // Nothing more we can do if this fails
+            let _ = unsafe { pacmankit::retry(|| self.handle.kernel_free()) };
+        }
+    }
+}
+
+/**
+ * Remembers the original value of every kernel address written through it, and writes them all back
+ * (most recently saved first) when dropped.
+ *
+ * Addresses are compared with their PAC bits set, so `holder` and `holder | PAC_BITMASK` are the same holder.
+ */
+pub struct KernelRestore<'a, K: KernelMemory> {
+    handle: &'a K,
+
+    /// (address, original value) in the order they were saved
+    saved: Vec<(u64, u64)>,
+}
+
+impl<'a, K: KernelMemory> KernelRestore<'a, K> {
+    /**
+     * A guard with nothing to restore yet.
+     */
+    pub fn new(handle: &'a K) -> Self {
+        return KernelRestore { handle, saved: Vec::new() };
+    }
+
+    /// The value `addr` will be restored to (if it's been saved)
+    pub fn original(&self, addr: u64) -> Option<u64> {
+        let addr = addr | PAC_BITMASK;
+        return self.saved.iter().find(|(saved, _)| *saved == addr).map(|(_, original)| *original);
+    }
+
+    /**
+     * Read `addr` and remember it as its original value (if it isn't already saved).
+     *
+     * # Return Value
+     * The value `addr` will be restored to.
+     */
+    pub unsafe fn save(&mut self, addr: u64) -> Result<u64, PacmanError> {
+        if let Some(original) = self.original(addr) {
+            return Ok(original);
+        }
+        let addr = addr | PAC_BITMASK;
+        let original = pacmankit::retry(|| self.handle.kernel_read(addr))?;
+        self.saved.push((addr, original));
+        return Ok(original);
+    }
+
+    /**
+     * Remember `original` as the value to restore `addr` to without reading it (if it isn't already saved).
+     * For holders whose original value the caller already knows.
+     */
+    pub fn save_value(&mut self, addr: u64, original: u64) {
+        if self.original(addr).is_none() {
+            self.saved.push((addr | PAC_BITMASK, original));
+        }
+    }
+
+    /**
+     * Write `val` to `addr`, saving its original value first.
+     */
+    pub unsafe fn write(&mut self, addr: u64, val: u64) -> Result<(), PacmanError> {
+        self.save(addr)?;
+        return pacmankit::retry(|| self.handle.kernel_write(addr | PAC_BITMASK, val));
+    }
+
+    /**
+     * Write every original value back now, returning the first error (dropping the guard ignores them).
+     * Every address is tried even if an earlier one fails.
+     */
+    pub unsafe fn restore(mut self) -> Result<(), PacmanError> {
+        return self.restore_all();
+    }
+
+    unsafe fn restore_all(&mut self) -> Result<(), PacmanError> {
+        let mut result = Ok(());
+        while let Some((addr, original)) = self.saved.pop() {
+            let restored = pacmankit::retry(|| self.handle.kernel_write(addr, original));
+            if result.is_ok() {
+                result = restored;
+            }
+        }
+        return result;
+    }
+}
+
+impl<'a, K: KernelMemory> Drop for KernelRestore<'a, K> {
+    fn drop(&mut self) {
+        // Nothing more we can do if this fails
+        let _ = unsafe { self.restore_all() };
+    }
+}
@@ -0,0 +1,15 @@
+/*!
+ * Finding targets in a kernelcache offline, without PacmanKit or a Mac.
+ */
+
+// Parsing MH_FILESET kernelcaches (segments, kexts, symbols and chained fixups)
+pub mod macho;
+
+// Finding PACMAN gadgets in kernel code
+pub mod gadgets;
+
+// The signed pointers in a kernelcache (from its authenticated chained fixups)
+pub mod pointers;
+
+// Symbolic kernel addresses (kext/ symbol relative) and the KASLR slide
+pub mod symbols;
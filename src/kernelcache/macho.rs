@@ -0,0 +1,583 @@
+/*!
+ * A Mach-O parser that understands the MH_FILESET kernelcaches macOS boots on Apple silicon.
+ *
+ * A kernelcache is one Mach-O (`MH_FILESET`) containing the kernel and every kext. Each of them is an
+ * `LC_FILESET_ENTRY` pointing at its own Mach-O header further into the file, with its own segments
+ * and symbols. File offsets are always relative to the start of the whole file (entries share the
+ * kernelcache's `__LINKEDIT`), so a `MachO` keeps the whole file around and parses entries out of it.
+ *
+ * Pointers in the kernelcache's data segments are stored as chained fixups (`LC_DYLD_CHAINED_FIXUPS`):
+ * each one is an offset from the start of the kernelcache, and the signed ones say which key signs them
+ * and with what diversity. `chained_fixups` walks every chain and returns what each pointer will be once
+ * the kernel has slid and signed it.
+ *
+ * Nothing here needs a Mac: point it at a kernelcache file (eg. `/System/Library/KernelCollections/` after
+ * decompressing it) on any machine.
+ */
+use std::io;
+use crate::attacks::pacman::get_salt;
+
+/// `MH_MAGIC_64`
+pub const MH_MAGIC_64 : u32 = 0xFEEDFACF;
+
+/// A standalone kernel (or executable)
+pub const MH_EXECUTE : u32 = 0x2;
+/// A kext
+pub const MH_KEXT_BUNDLE : u32 = 0xB;
+/// A kernelcache (a set of Mach-Os, see `FilesetEntry`)
+pub const MH_FILESET : u32 = 0xC;
+
+/// `LC_SYMTAB`
+pub const LC_SYMTAB : u32 = 0x2;
+/// `LC_SEGMENT_64`
+pub const LC_SEGMENT_64 : u32 = 0x19;
+/// `LC_DYLD_CHAINED_FIXUPS`
+pub const LC_DYLD_CHAINED_FIXUPS : u32 = 0x80000034;
+/// `LC_FILESET_ENTRY`
+pub const LC_FILESET_ENTRY : u32 = 0x80000035;
+
+/// arm64e userspace pointers (8 byte stride, rebase targets are addresses)
+pub const DYLD_CHAINED_PTR_ARM64E : u16 = 1;
+/// arm64e kernel pointers (4 byte stride, targets are offsets from the start of the image)
+pub const DYLD_CHAINED_PTR_ARM64E_KERNEL : u16 = 7;
+/// Kernelcache pointers (4 byte stride, targets are offsets from the start of the kernelcache)
+pub const DYLD_CHAINED_PTR_64_KERNEL_CACHE : u16 = 8;
+
+/// A page of a segment with no fixups
+pub const DYLD_CHAINED_PTR_START_NONE : u16 = 0xFFFF;
+/// A page with more than one chain (only used by 32 bit formats)
+pub const DYLD_CHAINED_PTR_START_MULTI : u16 = 0x8000;
+
+/// Symbol type bits of `n_type`
+const N_TYPE : u8 = 0x0E;
+/// A symbol defined in a section
+const N_SECT : u8 = 0x0E;
+/// Debugging symbols
+const N_STAB : u8 = 0xE0;
+
+/// Size of `mach_header_64`
+const MACH_HEADER_SIZE : usize = 32;
+
+fn invalid_data(msg: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, msg);
+}
+
+/// A little endian u16 at `offset` in `data`
+fn read_u16(data: &[u8], offset: usize) -> io::Result<u16> {
+    let bytes = data.get(offset..offset + 2).ok_or_else(|| invalid_data("Truncated Mach-O"))?;
+    return Ok(u16::from_le_bytes(bytes.try_into().unwrap()));
+}
+
+/// A little endian u32 at `offset` in `data`
+fn read_u32(data: &[u8], offset: usize) -> io::Result<u32> {
+    let bytes = data.get(offset..offset + 4).ok_or_else(|| invalid_data("Truncated Mach-O"))?;
+    return Ok(u32::from_le_bytes(bytes.try_into().unwrap()));
+}
+
+/// A little endian u64 at `offset` in `data`
+fn read_u64(data: &[u8], offset: usize) -> io::Result<u64> {
+    let bytes = data.get(offset..offset + 8).ok_or_else(|| invalid_data("Truncated Mach-O"))?;
+    return Ok(u64::from_le_bytes(bytes.try_into().unwrap()));
+}
+
+/// A NUL terminated (or `max_len` long) string at `offset` in `data`
+fn read_cstr(data: &[u8], offset: usize, max_len: usize) -> io::Result<String> {
+    let bytes = data.get(offset..).ok_or_else(|| invalid_data("Truncated Mach-O"))?;
+    let bytes = &bytes[..max_len.min(bytes.len())];
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    return Ok(String::from_utf8_lossy(&bytes[..len]).into_owned());
+}
+
+/**
+ * A section of a segment (`section_64`).
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Section {
+    pub segname: String,
+    pub sectname: String,
+    pub addr: u64,
+    pub size: u64,
+
+    /// File offset of the section's contents (0 for zero fill sections)
+    pub offset: u32,
+}
+
+/**
+ * A segment (`segment_command_64`) and its sections.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+    pub name: String,
+    pub vmaddr: u64,
+    pub vmsize: u64,
+    pub fileoff: u64,
+    pub filesize: u64,
+
+    /// `VM_PROT_*` bits the segment is mapped with
+    pub initprot: u32,
+    pub sections: Vec<Section>,
+}
+
+impl Segment {
+    /// Is `addr` within this segment?
+    pub fn contains(&self, addr: u64) -> bool {
+        return addr >= self.vmaddr && addr - self.vmaddr < self.vmsize;
+    }
+
+    /// Is the segment mapped executable?
+    pub fn is_executable(&self) -> bool {
+        return self.initprot & 0x4 != 0;
+    }
+}
+
+/**
+ * One Mach-O in a kernelcache (`fileset_entry_command`): the kernel or a kext.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilesetEntry {
+    /// The bundle id (`com.apple.kernel`, `com.apple.iokit.IOSCSIArchitectureModelFamily`, ...)
+    pub id: String,
+    pub vmaddr: u64,
+
+    /// File offset of the entry's Mach-O header
+    pub fileoff: u64,
+}
+
+/**
+ * A symbol defined in a section.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u64,
+
+    /// Is it visible outside its Mach-O?
+    pub external: bool,
+}
+
+/**
+ * The pointer authentication key a pointer is signed with.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PtrAuthKey {
+    IA,
+    IB,
+    DA,
+    DB,
+}
+
+impl PtrAuthKey {
+    /// The key with the 2 bit number chained fixups use
+    pub fn from_bits(bits: u64) -> Self {
+        return match bits & 0b11 {
+            0 => PtrAuthKey::IA,
+            1 => PtrAuthKey::IB,
+            2 => PtrAuthKey::DA,
+            _ => PtrAuthKey::DB,
+        };
+    }
+}
+
+/**
+ * How a pointer is signed: its key and its salt (the diversity, blended with the pointer's address if `addr_div`).
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PtrAuth {
+    pub key: PtrAuthKey,
+    pub diversity: u16,
+    pub addr_div: bool,
+}
+
+impl PtrAuth {
+    /// The salt for the pointer stored at `location` (the same blend as `attacks::pacman::get_salt`)
+    pub fn salt(&self, location: u64) -> u64 {
+        if self.addr_div {
+            return get_salt(location, self.diversity as u64);
+        }
+        return self.diversity as u64;
+    }
+}
+
+/**
+ * A pointer in the kernelcache, as the kernel will see it before sliding.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Fixup {
+    /// Where the pointer is stored
+    pub location: u64,
+
+    /// What it points to
+    pub target: u64,
+
+    /// How it is signed (`None` for plain pointers)
+    pub auth: Option<PtrAuth>,
+}
+
+/**
+ * A parsed Mach-O (a kernelcache, or one of the Mach-Os in it).
+ */
+#[derive(Clone, Debug)]
+pub struct MachO<'a> {
+    /// The whole file
+    data: &'a [u8],
+
+    /// File offset of this Mach-O's header
+    pub offset: usize,
+
+    pub cputype: u32,
+    pub filetype: u32,
+    pub segments: Vec<Segment>,
+
+    /// The Mach-Os in a kernelcache (empty for anything else)
+    pub fileset_entries: Vec<FilesetEntry>,
+
+    /// `LC_SYMTAB`'s symoff, nsyms, stroff and strsize
+    symtab: Option<(u32, u32, u32, u32)>,
+
+    /// `LC_DYLD_CHAINED_FIXUPS`'s dataoff
+    chained_fixups: Option<u32>,
+}
+
+impl<'a> MachO<'a> {
+    /**
+     * Parse the Mach-O at the start of `data`.
+     */
+    pub fn parse(data: &'a [u8]) -> io::Result<Self> {
+        return MachO::parse_at(data, 0);
+    }
+
+    /**
+     * Parse the Mach-O whose header is `offset` bytes into `data` (file offsets in it are still relative
+     * to the start of `data`, like they are in a kernelcache).
+     */
+    pub fn parse_at(data: &'a [u8], offset: usize) -> io::Result<Self> {
+        if read_u32(data, offset)? != MH_MAGIC_64 {
+            return Err(invalid_data("Not a 64 bit Mach-O (is the kernelcache still compressed?)"));
+        }
+        let cputype = read_u32(data, offset + 4)?;
+        let filetype = read_u32(data, offset + 12)?;
+        let ncmds = read_u32(data, offset + 16)?;
+
+        let mut macho = MachO {
+            data,
+            offset,
+            cputype,
+            filetype,
+            segments: Vec::new(),
+            fileset_entries: Vec::new(),
+            symtab: None,
+            chained_fixups: None,
+        };
+
+        let mut cmd_offset = offset + MACH_HEADER_SIZE;
+        for _ in 0..ncmds {
+            let cmd = read_u32(data, cmd_offset)?;
+            let cmdsize = read_u32(data, cmd_offset + 4)? as usize;
+            if cmdsize < 8 {
+                return Err(invalid_data("Malformed load command"));
+            }
+
+            match cmd {
+                LC_SEGMENT_64 => macho.segments.push(MachO::parse_segment(data, cmd_offset)?),
+                LC_FILESET_ENTRY => {
+                    let name_offset = read_u32(data, cmd_offset + 24)? as usize;
+                    macho.fileset_entries.push(FilesetEntry {
+                        id: read_cstr(data, cmd_offset + name_offset, cmdsize.saturating_sub(name_offset))?,
+                        vmaddr: read_u64(data, cmd_offset + 8)?,
+                        fileoff: read_u64(data, cmd_offset + 16)?,
+                    });
+                },
+                LC_SYMTAB => {
+                    macho.symtab = Some((
+                        read_u32(data, cmd_offset + 8)?,
+                        read_u32(data, cmd_offset + 12)?,
+                        read_u32(data, cmd_offset + 16)?,
+                        read_u32(data, cmd_offset + 20)?,
+                    ));
+                },
+                LC_DYLD_CHAINED_FIXUPS => macho.chained_fixups = Some(read_u32(data, cmd_offset + 8)?),
+                _ => {},
+            }
+
+            cmd_offset += cmdsize;
+        }
+
+        return Ok(macho);
+    }
+
+    /// Parse the `segment_command_64` at `offset`
+    fn parse_segment(data: &[u8], offset: usize) -> io::Result<Segment> {
+        let name = read_cstr(data, offset + 8, 16)?;
+        let nsects = read_u32(data, offset + 64)?;
+
+        let mut sections = Vec::new();
+        for i in 0..nsects as usize {
+            let sect = offset + 72 + i * 80;
+            sections.push(Section {
+                sectname: read_cstr(data, sect, 16)?,
+                segname: read_cstr(data, sect + 16, 16)?,
+                addr: read_u64(data, sect + 32)?,
+                size: read_u64(data, sect + 40)?,
+                offset: read_u32(data, sect + 48)?,
+            });
+        }
+
+        return Ok(Segment {
+            name,
+            vmaddr: read_u64(data, offset + 24)?,
+            vmsize: read_u64(data, offset + 32)?,
+            fileoff: read_u64(data, offset + 40)?,
+            filesize: read_u64(data, offset + 48)?,
+            initprot: read_u32(data, offset + 60)?,
+            sections,
+        });
+    }
+
+    /// The segment called `name`
+    pub fn segment(&self, name: &str) -> Option<&Segment> {
+        return self.segments.iter().find(|seg| seg.name == name);
+    }
+
+    /// The section `sectname` of segment `segname`
+    pub fn section(&self, segname: &str, sectname: &str) -> Option<&Section> {
+        return self.segment(segname)?.sections.iter().find(|sect| sect.sectname == sectname);
+    }
+
+    /// The segment containing `addr`
+    pub fn segment_containing(&self, addr: u64) -> Option<&Segment> {
+        return self.segments.iter().find(|seg| seg.contains(addr));
+    }
+
+    /**
+     * The lowest address mapped from the file (where `__TEXT` and the header are). Chained fixup
+     * targets are offsets from here.
+     */
+    pub fn base_address(&self) -> u64 {
+        return self.segments.iter()
+            .filter(|seg| seg.filesize > 0)
+            .map(|seg| seg.vmaddr)
+            .min()
+            .unwrap_or(0);
+    }
+
+    /// The fileset entry with bundle id `id`
+    pub fn fileset_entry(&self, id: &str) -> Option<&FilesetEntry> {
+        return self.fileset_entries.iter().find(|entry| entry.id == id);
+    }
+
+    /**
+     * Parse the Mach-O of the fileset entry with bundle id `id` (eg. `com.apple.kernel`).
+     */
+    pub fn entry(&self, id: &str) -> io::Result<MachO<'a>> {
+        let entry = match self.fileset_entry(id) {
+            Some(v) => v,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("No fileset entry called {}", id))),
+        };
+        return MachO::parse_at(self.data, entry.fileoff as usize);
+    }
+
+    /**
+     * The fileset entry whose segments contain `addr` (which kext an address is in).
+     */
+    pub fn entry_containing(&self, addr: u64) -> Option<(&FilesetEntry, MachO<'a>)> {
+        for entry in &self.fileset_entries {
+            if let Ok(macho) = MachO::parse_at(self.data, entry.fileoff as usize) {
+                if macho.segment_containing(addr).is_some() {
+                    return Some((entry, macho));
+                }
+            }
+        }
+        return None;
+    }
+
+    /// The file offset `addr` is loaded from (if it's backed by the file)
+    pub fn vm_to_offset(&self, addr: u64) -> Option<usize> {
+        let seg = self.segment_containing(addr)?;
+        let delta = addr - seg.vmaddr;
+        if delta >= seg.filesize {
+            return None;
+        }
+        return Some((seg.fileoff + delta) as usize);
+    }
+
+    /// The address file offset `offset` is loaded at
+    pub fn offset_to_vm(&self, offset: usize) -> Option<u64> {
+        let offset = offset as u64;
+        let seg = self.segments.iter().find(|seg| offset >= seg.fileoff && offset - seg.fileoff < seg.filesize)?;
+        return Some(seg.vmaddr + (offset - seg.fileoff));
+    }
+
+    /// `len` bytes of the file at `addr`
+    pub fn bytes_at(&self, addr: u64, len: usize) -> Option<&'a [u8]> {
+        let offset = self.vm_to_offset(addr)?;
+        let seg = self.segment_containing(addr)?;
+        if addr + len as u64 > seg.vmaddr + seg.filesize {
+            return None;
+        }
+        return self.data.get(offset..offset + len);
+    }
+
+    /// The u64 stored at `addr` (raw, so a chained fixup if it's a pointer)
+    pub fn read_u64(&self, addr: u64) -> Option<u64> {
+        return Some(u64::from_le_bytes(self.bytes_at(addr, 8)?.try_into().unwrap()));
+    }
+
+    /// The u32 stored at `addr` (eg. an instruction)
+    pub fn read_u32(&self, addr: u64) -> Option<u32> {
+        return Some(u32::from_le_bytes(self.bytes_at(addr, 4)?.try_into().unwrap()));
+    }
+
+    /**
+     * Every symbol defined in a section (debugging symbols are skipped).
+     *
+     * In a kernelcache the symbols live in the entries (`entry("com.apple.kernel")?.symbols()`), not the fileset.
+     */
+    pub fn symbols(&self) -> io::Result<Vec<Symbol>> {
+        let (symoff, nsyms, stroff, strsize) = match self.symtab {
+            Some(v) => v,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut symbols = Vec::new();
+        for i in 0..nsyms as usize {
+            let nlist = symoff as usize + i * 16;
+            let strx = read_u32(self.data, nlist)? as usize;
+            let n_type = *self.data.get(nlist + 4).ok_or_else(|| invalid_data("Truncated Mach-O"))?;
+            if n_type & N_STAB != 0 || n_type & N_TYPE != N_SECT {
+                continue;
+            }
+            if strx >= strsize as usize {
+                return Err(invalid_data("Symbol name out of the string table"));
+            }
+            symbols.push(Symbol {
+                name: read_cstr(self.data, stroff as usize + strx, strsize as usize - strx)?,
+                addr: read_u64(self.data, nlist + 8)?,
+                external: n_type & 0x1 != 0,
+            });
+        }
+        return Ok(symbols);
+    }
+
+    /// The address of the symbol called `name` (if it's defined here)
+    pub fn symbol(&self, name: &str) -> io::Result<Option<u64>> {
+        return Ok(self.symbols()?.into_iter().find(|sym| sym.name == name).map(|sym| sym.addr));
+    }
+
+    /**
+     * Walk every chain of `LC_DYLD_CHAINED_FIXUPS`.
+     *
+     * In a kernelcache the fixups of every entry are in the fileset's load command.
+     *
+     * # Return Value
+     * Every pointer that gets fixed up (empty if there are no chained fixups), or why the chains couldn't be
+     * walked (a format other than `DYLD_CHAINED_PTR_ARM64E`, `DYLD_CHAINED_PTR_ARM64E_KERNEL` or
+     * `DYLD_CHAINED_PTR_64_KERNEL_CACHE`, a bind, or a chain leading out of the file).
+     */
+    pub fn chained_fixups(&self) -> io::Result<Vec<Fixup>> {
+        let header = match self.chained_fixups {
+            Some(v) => v as usize,
+            None => return Ok(Vec::new()),
+        };
+        if read_u32(self.data, header)? != 0 {
+            return Err(invalid_data("Unsupported chained fixups version"));
+        }
+
+        let base = self.base_address();
+        let starts = header + read_u32(self.data, header + 4)? as usize;
+        let seg_count = read_u32(self.data, starts)? as usize;
+
+        let mut fixups = Vec::new();
+        for i in 0..seg_count {
+            let seg_info_offset = read_u32(self.data, starts + 4 + i * 4)? as usize;
+            if seg_info_offset == 0 {
+                continue;
+            }
+            let seg_info = starts + seg_info_offset;
+            let page_size = read_u16(self.data, seg_info + 4)? as u64;
+            let pointer_format = read_u16(self.data, seg_info + 6)?;
+            let segment_offset = read_u64(self.data, seg_info + 8)?;
+            let page_count = read_u16(self.data, seg_info + 20)? as usize;
+
+            for page in 0..page_count {
+                let start = read_u16(self.data, seg_info + 22 + page * 2)?;
+                if start == DYLD_CHAINED_PTR_START_NONE {
+                    continue;
+                }
+                if start & DYLD_CHAINED_PTR_START_MULTI != 0 {
+                    return Err(invalid_data("Pages with more than one chain aren't supported"));
+                }
+
+                let mut location = base + segment_offset + page as u64 * page_size + start as u64;
+                loop {
+                    let raw = self.read_u64(location).ok_or_else(|| invalid_data("Chained fixup outside of the file"))?;
+                    let (fixup, next, stride) = decode_chained_ptr(pointer_format, base, location, raw)?;
+                    fixups.push(fixup);
+                    if next == 0 {
+                        break;
+                    }
+                    location += next * stride;
+                }
+            }
+        }
+        return Ok(fixups);
+    }
+}
+
+/**
+ * Decode the chained pointer `raw` stored at `location`.
+ *
+ * # Return Value
+ * The fixup, how many strides away the next one in the chain is (0 for the end of the chain) and the stride.
+ */
+pub fn decode_chained_ptr(pointer_format: u16, base: u64, location: u64, raw: u64) -> io::Result<(Fixup, u64, u64)> {
+    let bits = |lo: u32, width: u32| (raw >> lo) & ((1u64 << width) - 1);
+
+    return match pointer_format {
+        DYLD_CHAINED_PTR_ARM64E | DYLD_CHAINED_PTR_ARM64E_KERNEL => {
+            let stride = if pointer_format == DYLD_CHAINED_PTR_ARM64E { 8 } else { 4 };
+            let is_auth = bits(63, 1) != 0;
+            if bits(62, 1) != 0 {
+                return Err(invalid_data("Chained binds aren't supported"));
+            }
+
+            let fixup = if is_auth {
+                Fixup {
+                    location,
+                    target: base + bits(0, 32),
+                    auth: Some(PtrAuth {
+                        key: PtrAuthKey::from_bits(bits(49, 2)),
+                        diversity: bits(32, 16) as u16,
+                        addr_div: bits(48, 1) != 0,
+                    }),
+                }
+            }
+            else {
+                // Userspace rebases are addresses, kernel ones are offsets
+                let target = bits(0, 43) | (bits(43, 8) << 56);
+                Fixup {
+                    location,
+                    target: if pointer_format == DYLD_CHAINED_PTR_ARM64E { target } else { base + target },
+                    auth: None,
+                }
+            };
+            Ok((fixup, bits(51, 11), stride))
+        },
+
+        DYLD_CHAINED_PTR_64_KERNEL_CACHE => {
+            let fixup = Fixup {
+                location,
+                target: base + bits(0, 30),
+                auth: if bits(63, 1) != 0 {
+                    Some(PtrAuth {
+                        key: PtrAuthKey::from_bits(bits(49, 2)),
+                        diversity: bits(32, 16) as u16,
+                        addr_div: bits(48, 1) != 0,
+                    })
+                } else { None },
+            };
+            Ok((fixup, bits(51, 12), 4))
+        },
+
+        _ => Err(invalid_data(&format!("Unsupported chained pointer format {}", pointer_format))),
+    };
+}
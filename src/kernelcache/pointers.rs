@@ -0,0 +1,137 @@
+/*!
+ * An inventory of the signed pointers in a kernelcache.
+ *
+ * Every pointer the kernel signs at boot is an authenticated chained fixup, which records the key it's signed
+ * with and its discriminator: a 16 bit diversity constant, blended with the pointer's own address if it's
+ * address diversified. That's exactly what forging a PAC for the same slot needs (see `SignedPointer::salt`,
+ * which is `get_salt(location, diversity)` for address diversified pointers), so the salt constants of
+ * vtable entries and other holders can be read off the kernelcache instead of out of the disassembly.
+ *
+ * Each pointer is attributed to the kext it's stored in and the symbol before it (a vtable, usually), so
+ * the slots of one object can be picked out by name.
+ */
+use std::fmt;
+use std::io;
+use std::collections::HashMap;
+use crate::kernelcache::macho::{MachO, PtrAuth, PtrAuthKey};
+
+/**
+ * A pointer the kernel signs when it boots.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct SignedPointer {
+    /// Where the pointer is stored (its holder)
+    pub location: u64,
+
+    /// What it points to
+    pub target: u64,
+
+    pub key: PtrAuthKey,
+
+    /// Is the discriminator blended with `location`?
+    pub addr_div: bool,
+
+    /// The 16 bit diversity constant
+    pub diversity: u16,
+
+    /// The bundle id of the kext `location` is in (empty if it isn't in any)
+    pub kext: String,
+
+    /// The closest symbol at or before `location` in that kext, and how far past it `location` is
+    pub symbol: Option<(String, u64)>,
+}
+
+impl SignedPointer {
+    /// How it's signed
+    pub fn auth(&self) -> PtrAuth {
+        return PtrAuth { key: self.key, diversity: self.diversity, addr_div: self.addr_div };
+    }
+
+    /// The salt the kernel signs it with (at `location` before any KASLR slide)
+    pub fn salt(&self) -> u64 {
+        return self.auth().salt(self.location);
+    }
+}
+
+impl fmt::Display for SignedPointer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:X}", self.location)?;
+        if let Some((name, offset)) = &self.symbol {
+            write!(f, " ({}+0x{:X})", name, offset)?;
+        }
+        return write!(f, " -> 0x{:X}: {:?} key, diversity 0x{:04x}{}", self.target, self.key, self.diversity,
+            if self.addr_div { " blended with the address" } else { "" });
+    }
+}
+
+/**
+ * List every signed pointer in `kernelcache`.
+ *
+ * # Return Value
+ * The signed pointers in the order they are stored, or why the chained fixups couldn't be walked.
+ */
+pub fn signed_pointers(kernelcache: &MachO) -> io::Result<Vec<SignedPointer>> {
+    // Each kext's address range and symbols (sorted by address)
+    let mut kexts = Vec::new();
+    for entry in &kernelcache.fileset_entries {
+        let macho = kernelcache.entry(&entry.id)?;
+        let mut symbols = macho.symbols()?;
+        symbols.sort_by_key(|sym| sym.addr);
+        kexts.push((entry.id.clone(), macho, symbols));
+    }
+
+    let mut pointers = Vec::new();
+    for fixup in kernelcache.chained_fixups()? {
+        let auth = match fixup.auth {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let mut kext = String::new();
+        let mut symbol = None;
+        if let Some((id, _, symbols)) = kexts.iter().find(|(_, macho, _)| macho.segment_containing(fixup.location).is_some()) {
+            kext = id.clone();
+            let before = symbols.partition_point(|sym| sym.addr <= fixup.location);
+            if before > 0 {
+                let sym = &symbols[before - 1];
+                symbol = Some((sym.name.clone(), fixup.location - sym.addr));
+            }
+        }
+
+        pointers.push(SignedPointer {
+            location: fixup.location,
+            target: fixup.target,
+            key: auth.key,
+            addr_div: auth.addr_div,
+            diversity: auth.diversity,
+            kext,
+            symbol,
+        });
+    }
+    return Ok(pointers);
+}
+
+/**
+ * The signed pointers stored in symbol `name` (eg. the entries of a vtable), in the order they are stored.
+ */
+pub fn pointers_in<'a>(pointers: &'a [SignedPointer], name: &str) -> Vec<&'a SignedPointer> {
+    return pointers.iter()
+        .filter(|ptr| ptr.symbol.as_ref().is_some_and(|(sym, _)| sym == name))
+        .collect();
+}
+
+/**
+ * How many signed pointers use each (key, diversity) pair.
+ *
+ * # Return Value
+ * Each pair and how many pointers use it, most used first.
+ */
+pub fn diversities(pointers: &[SignedPointer]) -> Vec<((PtrAuthKey, u16), usize)> {
+    let mut counts : HashMap<(PtrAuthKey, u16), usize> = HashMap::new();
+    for ptr in pointers {
+        *counts.entry((ptr.key, ptr.diversity)).or_default() += 1;
+    }
+    let mut counts : Vec<((PtrAuthKey, u16), usize)> = counts.into_iter().collect();
+    counts.sort_by_key(|((key, diversity), count)| (std::cmp::Reverse(*count), *key as u8, *diversity));
+    return counts;
+}
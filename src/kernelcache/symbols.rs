@@ -0,0 +1,266 @@
+/*!
+ * Resolving symbolic kernel addresses, and sliding them to where the running kernel put them.
+ *
+ * Kernel addresses are written relative to something that survives a rebuild better than a raw offset:
+ * * `base+0x26a497c`: an offset from the kernel base (the kernelcache's Mach-O header).
+ * * `IOSCSIArchitectureModelFamily+0x1234`: an offset from a kext's Mach-O header (the kext's bundle id,
+ *   or just its last component).
+ * * `_proc_task+0x10`, or `com.apple.kernel!_proc_task+0x10` to pick the kext: an offset from a symbol.
+ *
+ * `KernelSymbols` resolves them against a kernelcache (offline, so on any machine), to addresses before the
+ * KASLR slide. The slide is how far the running kernel's base is from the kernelcache's: leak the base (with
+ * `KernelLeaks::get_kernel_base`, since `kas_info` needs an entitlement we don't have) and `KernelSymbols::live`
+ * resolves straight to addresses in the running kernel.
+ */
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use serde::{Serialize, Deserialize};
+use crate::kernelcache::macho::MachO;
+
+/// What a `SymbolicAddress` is an offset from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Anchor {
+    /// The kernel base (`base`)
+    KernelBase,
+
+    /// A kext (by bundle id or its last component), or failing that a symbol in any kext
+    Name(String),
+
+    /// A symbol in a specific kext (`kext!symbol`)
+    KextSymbol { kext: String, symbol: String },
+}
+
+/**
+ * A kernel address written relative to the kernel base, a kext or a symbol.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct SymbolicAddress {
+    pub anchor: Anchor,
+    pub offset: i64,
+}
+
+impl SymbolicAddress {
+    /// `offset` bytes from the kernel base
+    pub fn base(offset: u64) -> Self {
+        return SymbolicAddress { anchor: Anchor::KernelBase, offset: offset as i64 };
+    }
+
+    /// The offset from the kernel base, if that's what this is relative to (no kernelcache needed)
+    pub fn base_offset(&self) -> Option<u64> {
+        return match self.anchor {
+            Anchor::KernelBase => Some(self.offset as u64),
+            _ => None,
+        };
+    }
+
+    /**
+     * The offset from the kernel base, resolving against `symbols` if this isn't relative to the base already.
+     */
+    pub fn offset_from_base(&self, symbols: Option<&KernelSymbols>) -> io::Result<u64> {
+        if let Some(offset) = self.base_offset() {
+            return Ok(offset);
+        }
+        return match symbols {
+            Some(symbols) => Ok(symbols.resolve(self)? - symbols.base_address()),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Resolving {} needs a kernelcache", self))),
+        };
+    }
+}
+
+impl fmt::Display for SymbolicAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.anchor {
+            Anchor::KernelBase => write!(f, "base")?,
+            Anchor::Name(name) => write!(f, "{}", name)?,
+            Anchor::KextSymbol { kext, symbol } => write!(f, "{}!{}", kext, symbol)?,
+        }
+        if self.offset > 0 {
+            write!(f, "+0x{:x}", self.offset)?;
+        }
+        else if self.offset < 0 {
+            write!(f, "-0x{:x}", self.offset.unsigned_abs())?;
+        }
+        return Ok(());
+    }
+}
+
+impl FromStr for SymbolicAddress {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+        let (anchor, offset) = match text.rfind(['+', '-']).filter(|i| text[i + 1..].starts_with("0x")) {
+            Some(i) => {
+                let magnitude = i64::from_str_radix(&text[i + 3..], 16).map_err(|err| format!("Bad offset in {}: {}", text, err))?;
+                (&text[..i], if text[i..].starts_with('-') { -magnitude } else { magnitude })
+            },
+            None => (text, 0),
+        };
+
+        let anchor = match anchor.split_once('!') {
+            _ if anchor.is_empty() => return Err(format!("{} doesn't say what it's relative to", text)),
+            _ if anchor == "base" => Anchor::KernelBase,
+            Some((kext, symbol)) => Anchor::KextSymbol { kext: kext.to_string(), symbol: symbol.to_string() },
+            None => Anchor::Name(anchor.to_string()),
+        };
+        return Ok(SymbolicAddress { anchor, offset });
+    }
+}
+
+impl TryFrom<String> for SymbolicAddress {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        return text.parse();
+    }
+}
+
+impl From<SymbolicAddress> for String {
+    fn from(addr: SymbolicAddress) -> Self {
+        return addr.to_string();
+    }
+}
+
+/// A kext's bundle id and the address ranges of its segments
+#[derive(Clone, Debug)]
+struct Kext {
+    id: String,
+    vmaddr: u64,
+    ranges: Vec<(u64, u64)>,
+}
+
+/**
+ * The kexts and symbols of a kernelcache (before the KASLR slide).
+ */
+#[derive(Clone, Debug)]
+pub struct KernelSymbols {
+    base: u64,
+    kexts: Vec<Kext>,
+
+    /// (address, kext index, name), sorted by address
+    symbols: Vec<(u64, usize, String)>,
+}
+
+fn not_found(msg: String) -> io::Error {
+    return io::Error::new(io::ErrorKind::NotFound, msg);
+}
+
+impl KernelSymbols {
+    /**
+     * Collect the kexts and symbols of `kernelcache` (a fileset, or a standalone kernel).
+     */
+    pub fn from_kernelcache(kernelcache: &MachO) -> io::Result<Self> {
+        let mut machos = Vec::new();
+        for entry in &kernelcache.fileset_entries {
+            machos.push((entry.id.clone(), entry.vmaddr, kernelcache.entry(&entry.id)?));
+        }
+        if machos.is_empty() {
+            machos.push((String::new(), kernelcache.base_address(), kernelcache.clone()));
+        }
+
+        let mut kexts = Vec::new();
+        let mut symbols = Vec::new();
+        for (index, (id, vmaddr, macho)) in machos.into_iter().enumerate() {
+            symbols.extend(macho.symbols()?.into_iter().map(|sym| (sym.addr, index, sym.name)));
+            let ranges = macho.segments.iter().map(|seg| (seg.vmaddr, seg.vmaddr + seg.vmsize)).collect();
+            kexts.push(Kext { id, vmaddr, ranges });
+        }
+        symbols.sort();
+        return Ok(KernelSymbols { base: kernelcache.base_address(), kexts, symbols });
+    }
+
+    /// The kernel base before the slide
+    pub fn base_address(&self) -> u64 {
+        return self.base;
+    }
+
+    /// The kext with bundle id `name`, or whose bundle id ends in `.name`
+    fn kext(&self, name: &str) -> Option<usize> {
+        return self.kexts.iter().position(|kext| kext.id == name || kext.id.rsplit('.').next() == Some(name));
+    }
+
+    /// The address of symbol `name` (in kext `kext`, or any kext)
+    fn symbol(&self, kext: Option<usize>, name: &str) -> Option<u64> {
+        return self.symbols.iter()
+            .find(|(_, index, sym)| sym == name && kext.is_none_or(|kext| kext == *index))
+            .map(|(addr, _, _)| *addr);
+    }
+
+    /**
+     * Where `addr` is in the kernelcache (before the slide).
+     */
+    pub fn resolve(&self, addr: &SymbolicAddress) -> io::Result<u64> {
+        let anchor = match &addr.anchor {
+            Anchor::KernelBase => self.base,
+            Anchor::Name(name) => match self.kext(name) {
+                Some(kext) => self.kexts[kext].vmaddr,
+                None => self.symbol(None, name).ok_or_else(|| not_found(format!("No kext or symbol called {}", name)))?,
+            },
+            Anchor::KextSymbol { kext, symbol } => {
+                let index = self.kext(kext).ok_or_else(|| not_found(format!("No kext called {}", kext)))?;
+                self.symbol(Some(index), symbol).ok_or_else(|| not_found(format!("{} has no symbol called {}", kext, symbol)))?
+            },
+        };
+        return Ok(anchor.wrapping_add(addr.offset as u64));
+    }
+
+    /**
+     * Describe `addr` (before the slide): relative to the closest symbol before it in the same kext, or to
+     * the kext, or to the kernel base if it isn't in any kext.
+     */
+    pub fn symbolicate(&self, addr: u64) -> SymbolicAddress {
+        let kext = match self.kexts.iter().position(|kext| kext.ranges.iter().any(|(start, end)| addr >= *start && addr < *end)) {
+            Some(v) => v,
+            None => return SymbolicAddress { anchor: Anchor::KernelBase, offset: addr.wrapping_sub(self.base) as i64 },
+        };
+
+        let before = self.symbols.partition_point(|(sym_addr, _, _)| *sym_addr <= addr);
+        if let Some((sym_addr, _, name)) = self.symbols[..before].iter().rev().find(|(_, index, _)| *index == kext) {
+            let anchor = Anchor::KextSymbol { kext: self.kexts[kext].id.clone(), symbol: name.clone() };
+            return SymbolicAddress { anchor, offset: (addr - sym_addr) as i64 };
+        }
+        return SymbolicAddress { anchor: Anchor::Name(self.kexts[kext].id.clone()), offset: addr.wrapping_sub(self.kexts[kext].vmaddr) as i64 };
+    }
+
+    /**
+     * The KASLR slide of a running kernel whose base (its Mach-O header) is at `kernel_base`.
+     */
+    pub fn slide(&self, kernel_base: u64) -> u64 {
+        return kernel_base.wrapping_sub(self.base);
+    }
+
+    /**
+     * These symbols in a running kernel whose base is at `kernel_base`.
+     */
+    pub fn live(&self, kernel_base: u64) -> LiveKernel<'_> {
+        return LiveKernel { symbols: self, slide: self.slide(kernel_base) };
+    }
+}
+
+/**
+ * A kernelcache's symbols, slid to where a running kernel has them.
+ */
+#[derive(Copy, Clone, Debug)]
+pub struct LiveKernel<'a> {
+    pub symbols: &'a KernelSymbols,
+    pub slide: u64,
+}
+
+impl<'a> LiveKernel<'a> {
+    /// Where `addr` is in the running kernel
+    pub fn resolve(&self, addr: &SymbolicAddress) -> io::Result<u64> {
+        return Ok(self.symbols.resolve(addr)?.wrapping_add(self.slide));
+    }
+
+    /// Where the running kernel's `addr` is in the kernelcache
+    pub fn unslide(&self, addr: u64) -> u64 {
+        return addr.wrapping_sub(self.slide);
+    }
+
+    /// Describe the running kernel's `addr` (see `KernelSymbols::symbolicate`)
+    pub fn symbolicate(&self, addr: u64) -> SymbolicAddress {
+        return self.symbols.symbolicate(self.unslide(addr));
+    }
+}
@@ -0,0 +1,76 @@
+/*!
+ * PACMAN except in rust!
+ *
+ * The building blocks of the PACMAN attack on Apple M1, usable from other tools:
+ * * `pac`: Pulling apart, forging and iterating over pointer authentication codes.
+ * * `cache`: M1 cache geometry and address to set/ tag math.
+ * * `evset`: Building eviction sets for data and instruction addresses.
+ * * `timer`: Timing accesses, stores and executions (with a selectable backend).
+ * * `primitives`: What the attacks need from the kernel (read/ write, translation, leaks, gadgets and a PAC oracle).
+ * * `batch`: Queueing kernel operations to run in one round-trip (`primitives::KernelBatch`).
+ * * `error`: `PacmanError`, what every kernel operation returns when it fails.
+ * * `profile`: Kernel struct offsets, PAC discriminators and gadget offsets for each OS build.
+ * * `guard`: Guards that free kernel memory and restore overwritten kernel objects when dropped (even on a panic).
+ * * `pacmankit`: A connection to the PacmanKit kext for poking at the kernel (implements `primitives`).
+ * * `remote`: PacmanKit over a Unix or TCP socket (a client connection, and a server for a simulated kernel or the kext).
+ * * `sim`: A simulated kernel with PacmanKit loaded (and a PACMAN gadget), to connect to and time against instead of the real one.
+ * * `kernelcache`: Parsing kernelcaches (kexts, segments, symbols and chained fixups), finding PACMAN gadgets and signed pointers in them, and resolving symbolic kernel addresses (sliding them by KASLR), offline on any machine.
+ * * `aarch64`: Decoding the AArch64 instructions PACMAN gadgets are made of, and encoding them.
+ * * `payload`: Generating code for instruction cache and branch predictor eviction (sleds, branch chains, set aligned lines).
+ * * `attacks`: Evict+reload, prime+probe, spectre and PACMAN experiments built out of the above.
+ * * `covert`: A cross-thread L2 covert channel (framed, error corrected) that checks the timers and eviction sets end to end.
+ *
+ * Experiments record their measurements to a `results::ResultsSink`, PACMAN trials can be recorded and
+ * replayed with `trace` (and every PacmanKit call with `session`), and every experiment parameter can be
+ * loaded from a `config::AttackConfig`.
+ *
+ * Anything that touches the hardware only exists on Apple silicon running macOS (and needs PacmanKit
+ * loaded, see the README). The rest (PAC encoding, cache math, eviction set construction, decision
+ * logic, traces, results, configs, and the covert channel and the PACMAN attack itself against the simulated
+ * kernel) builds and is tested everywhere.
+ */
+
+// The repo style is `return x;` everywhere
+#![allow(clippy::needless_return)]
+// The `unsafe fn`s poke at the kernel or the hardware, there's nothing more specific to say about each one
+#![allow(clippy::missing_safety_doc)]
+
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub mod libdarwin;
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub mod qos;
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub mod retpoline;
+pub mod tests;
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub mod counter;
+pub mod evset;
+pub mod primitives;
+pub mod batch;
+pub mod error;
+pub mod guard;
+pub mod pacmankit;
+pub mod remote;
+pub mod session;
+pub mod cache;
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub mod memory;
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub mod msr;
+pub mod attacks;
+pub mod pac;
+pub mod results;
+pub mod trace;
+pub mod config;
+pub mod profile;
+pub mod sim;
+pub mod kernelcache;
+pub mod aarch64;
+pub mod payload;
+pub mod covert;
+
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub mod timer_msr;
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub mod timer_multithread;
+pub mod timer;
@@ -6,3 +6,4 @@ pub mod iokit;
 pub mod mach;
 pub mod kernel_rw;
 pub mod crandom;
+pub mod sysctl;
@@ -3,7 +3,7 @@
  */
 use std::ffi::c_void;
 use std::os::raw::c_char;
-use crate::mach;
+use crate::libdarwin::mach;
 use mach::{KernReturn, MachPort};
 
 pub type IOService = MachPort;
@@ -27,6 +27,14 @@ extern "C" {
         output: *mut u64,
         outputCnt: *mut u32
     ) -> KernReturn;
+    pub fn IOConnectCallStructMethod(
+        connection: IOConnect,
+        selector: u32,
+        inputStruct: *const c_void,
+        inputStructCnt: usize,
+        outputStruct: *mut c_void,
+        outputStructCnt: *mut usize
+    ) -> KernReturn;
     pub fn IOServiceClose(connect: IOConnect) -> KernReturn;
     pub fn IOObjectRelease(connect: IOConnect) -> KernReturn;
 }
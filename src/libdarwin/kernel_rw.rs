@@ -3,8 +3,9 @@
  *
  * DEPRECATED in favor of the PacmanKit kext (see pacmankit.rs).
  */
-use crate::*;
 
+use crate::libdarwin::mach::*;
+use core::arch::asm;
 pub const KERNEL_PID : u64 = 0;
 
 pub const SYS_KAS_INFO : u64 = 439u64;
@@ -108,6 +108,15 @@ extern "C" {
      * Returns a pointer to a C string containing the error string.
      */
     pub fn mach_error_string(err: KernReturn) -> *const std::os::raw::c_char;
+
+    /**
+     * Invalidate the instruction cache for a range of memory (after writing code into it).
+     *
+     * # Arguments
+     * * `start`: The first address to invalidate.
+     * * `len`: How many bytes to invalidate?
+     */
+    pub fn sys_icache_invalidate(start: *const u8, len: usize);
 }
 
 /**
@@ -0,0 +1,39 @@
+/*!
+ * FFI Bindings to sysctl, for finding out what OS build and machine we're on.
+ */
+use std::ffi::{c_void, CString};
+use std::os::raw::c_char;
+
+#[link(name = "system")]
+extern "C" {
+    /**
+     * Read (or write) the sysctl called `name`.
+     *
+     * # Return Value
+     * Returns 0 on success, or -1 (and sets errno) on failure.
+     */
+    pub fn sysctlbyname(name: *const c_char, oldp: *mut c_void, oldlenp: *mut usize, newp: *const c_void, newlen: usize) -> i32;
+}
+
+/**
+ * Read a string sysctl (eg. `kern.osversion` or `hw.model`).
+ *
+ * # Return Value
+ * The string, or `None` if there's no such sysctl.
+ */
+pub fn sysctl_string(name: &str) -> Option<String> {
+    let name = CString::new(name).ok()?;
+    unsafe {
+        let mut len = 0;
+        if sysctlbyname(name.as_ptr(), core::ptr::null_mut(), &mut len, core::ptr::null(), 0) != 0 {
+            return None;
+        }
+        let mut buf = vec![0u8; len];
+        if sysctlbyname(name.as_ptr(), buf.as_mut_ptr() as *mut c_void, &mut len, core::ptr::null(), 0) != 0 {
+            return None;
+        }
+        buf.truncate(len);
+        let end = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+        return Some(String::from_utf8_lossy(&buf[..end]).into_owned());
+    }
+}
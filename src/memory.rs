@@ -0,0 +1,64 @@
+/*!
+ * The memory region experiments build their eviction sets in, and helpers for getting the caches into a known state.
+ */
+use crate::cache;
+use crate::evset;
+use crate::pac::PAC_BITMASK;
+use crate::timer;
+use crate::libdarwin::mach::*;
+use std::ffi::CStr;
+
+/// How many bytes of memory should we create?
+pub const MEM_REGION_SIZE : usize = 0x40000000000usize;
+
+/**
+ * Allocate a `MEM_REGION_SIZE` memory region for experiments to use.
+ *
+ * # Return Value
+ * Returns the region on success, or the mach error string on failure.
+ */
+pub unsafe fn alloc_memory_region() -> Result<&'static mut [u8], String> {
+    let mut loc : *mut u8 = 0 as *mut u8;
+    let kret = mach_vm_allocate(
+        mach_task_self(),
+        &mut loc,
+        MEM_REGION_SIZE,
+        VM_FLAGS_ANYWHERE
+    );
+
+    if KERN_SUCCESS != kret {
+        let err_str = CStr::from_ptr(mach_error_string(kret));
+        return Err(format!("{} ({:?})", kret, err_str));
+    }
+
+    return Ok(core::slice::from_raw_parts_mut(loc, MEM_REGION_SIZE));
+}
+
+/// Touch the first `evset::EVSET_SIZE_MAX` eviction set candidates so they are all mapped in
+pub unsafe fn init_memory(memory_region: &mut [u8]) {
+    let mut iter = 0;
+    for i in (0..memory_region.len()).step_by(evset::STRIDE) {
+        if iter >= evset::EVSET_SIZE_MAX { break; }
+        core::ptr::write_volatile(&mut memory_region[i], 0x41);
+        core::ptr::read_volatile(&memory_region[i]);
+        iter+=1;
+    }
+}
+
+/// Flush the entire L2 cache
+pub unsafe fn flush_cache(memory_region: &mut [u8]) {
+    for i in (0..cache::L2_SIZE).step_by(cache::L1D_LINESIZE) {
+        core::ptr::write_volatile(&mut memory_region[i], 0x41);
+        core::ptr::read_volatile(&memory_region[i]);
+    }
+}
+
+/// Flush the L1 iCache
+/// The provided address `retpoline` should be a cache::L1I_SIZE region filled with `ret`s.
+#[allow(non_snake_case)]
+pub unsafe fn flush_iCache(retpoline: u64) {
+    let retpoline_unsigned = retpoline & (!PAC_BITMASK);
+    for i in (0..cache::L2_SIZE).step_by(cache::L1I_LINESIZE) {
+        timer::time_exec(retpoline_unsigned + i as u64);
+    }
+}
@@ -19,7 +19,8 @@ According to page D5-2656 of the ARM manual:
 "The PAC field is Xn[63:56, 54:bottom_PAC_bit]."
 */
 
-type PAC = u16;
+/// A pointer authentication code (16 bits on M1)
+pub type PAC = u16;
 
 /// Mask a kernel address with this value to eliminate the PAC
 pub const PAC_BITMASK : u64 = 0xFFFF800000000000u64;
@@ -27,6 +28,7 @@ pub const PAC_BITMASK : u64 = 0xFFFF800000000000u64;
 /// Number of possible PACs (2^16 on M1)
 pub const NUM_PACS : usize = 0xFFFFusize;
 
+/// The largest possible PAC
 pub const MAX_PAC : PAC = 0xFFFF;
 
 /**
@@ -46,7 +48,7 @@ pub fn encode_pac(pac: PAC, pointer: u64) -> u64 {
     let pac_lower = (pac as u64 & 0x0FF) << 47;
     let pac_upper = ((pac as u64 >> 8) & 0x0FF) << 56;
 
-    let pac_kernel_bit = if is_kernel_pointer(pointer) {(1 << 55)} else {0};
+    let pac_kernel_bit = if is_kernel_pointer(pointer) {1 << 55} else {0};
 
     return (pointer & (!PAC_BITMASK)) | pac_lower | pac_upper | pac_kernel_bit;
 }
@@ -87,7 +89,7 @@ impl Iterator for PACIterator {
         }
         else {
             // Configure next PAC
-            self.cur_pac = self.cur_pac + 1;
+            self.cur_pac += 1;
         }
 
         return Some(encode_pac(old_pac, self.addr));
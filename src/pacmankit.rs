@@ -1,13 +1,44 @@
 /*!
  * Shared information between the user attack and the PacmanKit kext.
+ *
+ * A `PacmanKitConnection` runs every operation through a `PacmanKitBackend`. On Apple silicon running
+ * macOS that is the kext itself (see `PacmanKitConnection::init`). Anywhere else, connections can be
+ * made to a simulated kernel instead (see `sim::kernel`).
+ *
+ * The operations themselves are the `primitives` traits, which `PacmanKitConnection` implements.
  */
-use std::ffi::{CStr, CString};
-use crate::iokit::*;
-use crate::mach::*;
+use crate::batch::{self, Batch, BATCH_RESULT_SIZE};
+use crate::error::PacmanError;
+use crate::primitives::*;
+use crate::sim;
 use crate::timer;
-
-/// Offset in bytes within a PacmanUser IOUserClient to the helper field
-pub const PACMANKIT_TO_HELPER : u64 = 0xE0;
+use std::cell::Cell;
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+use {
+    crate::libdarwin::iokit::*,
+    crate::libdarwin::mach::{mach_error_string, mach_task_self},
+    std::ffi::{CStr, CString},
+};
+
+/// `kern_return_t` (the same type as `mach::KernReturn`, but available everywhere)
+pub type KernReturn = i32;
+
+/// The operation succeeded
+pub const KERN_SUCCESS : KernReturn = 0;
+/// The address isn't mapped
+pub const KERN_INVALID_ADDRESS : KernReturn = 1;
+/// An argument was out of range
+pub const KERN_INVALID_ARGUMENT : KernReturn = 4;
+/// The operation failed for some other reason
+pub const KERN_FAILURE : KernReturn = 5;
+/// Out of memory
+pub const KERN_RESOURCE_SHORTAGE : KernReturn = 6;
+/// `MACH_SEND_INVALID_DEST` (the other end of the connection is gone)
+pub const MACH_SEND_INVALID_DEST : KernReturn = 0x10000003;
+/// `kIOReturnBadArgument` (what IOKit says about a selector the kext doesn't have)
+pub const KIO_RETURN_BAD_ARGUMENT : KernReturn = 0xE00002C2u32 as KernReturn;
+/// `kIOReturnUnsupported` (the backend can't run structure methods)
+pub const KIO_RETURN_UNSUPPORTED : KernReturn = 0xE00002C7u32 as KernReturn;
 
 /**
  * The operations supported by the PacmanKit kext.
@@ -15,273 +46,416 @@ pub const PACMANKIT_TO_HELPER : u64 = 0xE0;
  * Each of these is an ::externalMethod selector.
  */
 #[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum PacmanKitOp {
+    /// Where is the kernel mach-o?
     KernelBase        = 0x00,
+    /// Read a u64 from kernel memory
     Read              = 0x01,
+    /// Write a u64 to kernel memory
     Write             = 0x02,
+    /// Translate a kernel virtual address to a physical address
     KernelVirt2Phys   = 0x03,
+    /// Translate a user virtual address to a physical address
     UserVirt2Phys     = 0x04,
+    /// Where is our IOUserClient in kernel memory?
     IOUserClientLeak  = 0x05,
+    /// Allocate a kernel buffer
     GimmeMemory       = 0x06,
+    /// Free the kernel buffer
     FreeMemory        = 0x07,
+    /// Print the timer registers to the kernel log
     TellMeRegs        = 0x08,
+    /// Time a load from a kernel address
     ReadForTiming     = 0x09,
+    /// Time executing a kernel address
     ExecForTiming     = 0x0A,
+    /// Leak addresses of interest (`retpoline`, `LIMIT`, `win`)
     LeakMethod        = 0x0B,
+    /// Run the kernel data spectre gadget
     ReadForSpectre    = 0x0C,
+    /// Run the kernel inst spectre gadget
     ExecForSpectre    = 0x0D,
+    /// Call the PacmanKitService externalMethod
     CallServiceRoutine= 0x0E,
+    /// Sign a data pointer (PACDA)
     ForgeSignData     = 0x0F,
+    /// Authenticate a data pointer (AUTDA)
     ForgeAuthData     = 0x10,
+    /// Sign an instruction pointer (PACIA)
     ForgeSignInst     = 0x11,
+    /// Authenticate an instruction pointer (AUTIA)
     ForgeAuthInst     = 0x12,
+    /// Where is our proc struct in kernel memory?
     LeakCurProc       = 0x13,
+    /// Run a queue of operations (structure input, see `batch`)
+    Batch             = 0x14,
+}
+
+impl PacmanKitOp {
+    /// Every operation, in selector order
+    pub const ALL : [PacmanKitOp; 21] = [
+        PacmanKitOp::KernelBase, PacmanKitOp::Read, PacmanKitOp::Write, PacmanKitOp::KernelVirt2Phys,
+        PacmanKitOp::UserVirt2Phys, PacmanKitOp::IOUserClientLeak, PacmanKitOp::GimmeMemory, PacmanKitOp::FreeMemory,
+        PacmanKitOp::TellMeRegs, PacmanKitOp::ReadForTiming, PacmanKitOp::ExecForTiming, PacmanKitOp::LeakMethod,
+        PacmanKitOp::ReadForSpectre, PacmanKitOp::ExecForSpectre, PacmanKitOp::CallServiceRoutine, PacmanKitOp::ForgeSignData,
+        PacmanKitOp::ForgeAuthData, PacmanKitOp::ForgeSignInst, PacmanKitOp::ForgeAuthInst, PacmanKitOp::LeakCurProc,
+        PacmanKitOp::Batch,
+    ];
+
+    /// The operation with ::externalMethod selector `selector` (if there is one)
+    pub fn from_selector(selector: u32) -> Option<Self> {
+        return PacmanKitOp::ALL.get(selector as usize).copied();
+    }
+}
+
+/**
+ * Describe a `kern_return_t` (`mach_error_string` on macOS).
+ */
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub fn error_string(kret: KernReturn) -> String {
+    unsafe {
+        return CStr::from_ptr(mach_error_string(kret)).to_string_lossy().into_owned();
+    }
+}
+
+/**
+ * Describe a `kern_return_t` (the `mach_error_string` messages of the codes the simulator uses).
+ */
+#[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
+pub fn error_string(kret: KernReturn) -> String {
+    return match kret {
+        KERN_SUCCESS => "(os/kern) successful".to_string(),
+        KERN_INVALID_ADDRESS => "(os/kern) invalid address".to_string(),
+        KERN_INVALID_ARGUMENT => "(os/kern) invalid argument".to_string(),
+        KERN_FAILURE => "(os/kern) failure".to_string(),
+        KERN_RESOURCE_SHORTAGE => "(os/kern) resource shortage".to_string(),
+        MACH_SEND_INVALID_DEST => "(ipc/send) invalid destination port".to_string(),
+        KIO_RETURN_BAD_ARGUMENT => "(iokit/common) invalid argument".to_string(),
+        KIO_RETURN_UNSUPPORTED => "(iokit/common) unsupported function".to_string(),
+        _ => format!("unknown error code 0x{:X}", kret),
+    };
+}
+
+/// How many more times `retry` tries a PacmanKit call after it fails
+pub const KERNEL_CALL_RETRIES : usize = 8;
+
+/**
+ * Run a PacmanKit call until it succeeds, trying at most `KERNEL_CALL_RETRIES` more times after the first failure.
+ * For calls that are safe to repeat (every failure is reported by the call itself).
+ *
+ * # Return Value
+ * Returns the first success, or the last failure.
+ */
+pub fn retry<T>(mut call: impl FnMut() -> Result<T, PacmanError>) -> Result<T, PacmanError> {
+    let mut result = call();
+    for _ in 0..KERNEL_CALL_RETRIES {
+        if result.is_ok() {
+            break;
+        }
+        result = call();
+    }
+    return result;
+}
+
+/**
+ * Something that can run `PacmanKitOp`s: the kext itself, or a stand-in for it.
+ */
+pub trait PacmanKitBackend {
+    /**
+     * Run one `::externalMethod` selector (the equivalent of `IOConnectCallScalarMethod`).
+     *
+     * # Arguments
+     * * `op`: The selector to run.
+     * * `args`: The scalar inputs.
+     * * `output`: Where to put the scalar outputs. Its length is the number of outputs expected.
+     *
+     * # Return Value
+     * Returns the `kern_return_t` of the call.
+     */
+    fn call(&self, op: PacmanKitOp, args: &[u64], output: &mut [u64]) -> KernReturn;
+
+    /**
+     * Run one `::externalMethod` selector with structure input and output (the equivalent of
+     * `IOConnectCallStructMethod`). Only `PacmanKitOp::Batch` takes structures.
+     *
+     * Backends that can't run it don't have to implement this: the default says `KIO_RETURN_UNSUPPORTED`,
+     * and batches are run one scalar call at a time instead.
+     *
+     * # Return Value
+     * Returns the `kern_return_t` of the call.
+     */
+    fn call_struct(&self, _op: PacmanKitOp, _input: &[u8], _output: &mut [u8]) -> KernReturn {
+        return KIO_RETURN_UNSUPPORTED;
+    }
+}
+
+/**
+ * The PacmanKit kext, reached through its IOUserClient.
+ */
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+struct IOKitBackend(IOConnect, IOService);
+
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+impl PacmanKitBackend for IOKitBackend {
+    fn call(&self, op: PacmanKitOp, args: &[u64], output: &mut [u64]) -> KernReturn {
+        let mut output_cnt = output.len() as u32;
+        unsafe {
+            return IOConnectCallScalarMethod(
+                self.0,
+                op as u32,
+                if args.is_empty() { core::ptr::null() } else { args.as_ptr() },
+                args.len() as u32,
+                if output.is_empty() { core::ptr::null_mut() } else { output.as_mut_ptr() },
+                if output.is_empty() { core::ptr::null_mut() } else { &mut output_cnt },
+            );
+        }
+    }
+
+    fn call_struct(&self, op: PacmanKitOp, input: &[u8], output: &mut [u8]) -> KernReturn {
+        let mut output_size = output.len();
+        unsafe {
+            return IOConnectCallStructMethod(
+                self.0,
+                op as u32,
+                input.as_ptr() as *const core::ffi::c_void,
+                input.len(),
+                output.as_mut_ptr() as *mut core::ffi::c_void,
+                &mut output_size,
+            );
+        }
+    }
+}
+
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+impl Drop for IOKitBackend {
+    /**
+     * Clean up our IOKit connection.
+     */
+    fn drop(&mut self) {
+        unsafe {
+            IOServiceClose(self.0);
+            IOObjectRelease(self.1);
+        }
+    }
 }
 
 /**
- * An object representing a connection to the PacmanKit IOUserClient in the PacmanKit kext.
+ * An object representing a connection to the PacmanKit IOUserClient in the PacmanKit kext
+ * (or in a stand-in for it).
  *
  * This can be used to run all the operations provided by the PacmanKit kext.
+ * (The `Cell` says whether to still try running batches with `PacmanKitOp::Batch`.)
  */
-pub struct PacmanKitConnection(IOConnect, IOService);
+pub struct PacmanKitConnection(Box<dyn PacmanKitBackend>, Cell<bool>);
+
+/// A connection is a backend too (eg. for `remote` servers to run requests on)
+impl PacmanKitBackend for PacmanKitConnection {
+    fn call(&self, op: PacmanKitOp, args: &[u64], output: &mut [u64]) -> KernReturn {
+        return self.0.call(op, args, output);
+    }
+
+    fn call_struct(&self, op: PacmanKitOp, input: &[u8], output: &mut [u8]) -> KernReturn {
+        return self.0.call_struct(op, input, output);
+    }
+}
 
 impl PacmanKitConnection {
     /**
      * Create a new PacmanKitConnection.
      * This opens a new IOUserClient and may fail.
      *
+     * If a simulated kernel is installed on this thread (see `sim::kernel::install`), connects to that instead.
+     *
      * # Return Value
-     * Returns the `kern_return_t` error on failure, a valid PacmanKitConnection on success.
+     * Returns a valid PacmanKitConnection on success, and why PacmanKit couldn't be opened on failure.
      */
-    pub unsafe fn init() -> Option<Self> {
-        let mut kret : KernReturn;
-        let mut name : IOName = [0;128];
+    #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+    pub unsafe fn init() -> Result<Self, PacmanError> {
+        if let Some(kernel) = sim::kernel::installed() {
+            return sim::kernel::connect(&kernel);
+        }
+
         let mut handle : IOConnect = 0;
         let service_name = CString::new("PacmanKit").unwrap();
         let serv = IOServiceGetMatchingService(kIOMainPortDefault, IOServiceMatching(service_name.as_ptr()));
         if IO_OBJECT_NULL == serv {
-            println!("Couldn't find PacmanKit. Did you remember to install it?");
-            return None;
+            return Err(PacmanError::ServiceNotFound);
         }
 
-        IORegistryEntryGetName(serv, &mut name);
-        kret = IOServiceOpen(serv, mach_task_self(), 0, &mut handle);
+        let kret = IOServiceOpen(serv, mach_task_self(), 0, &mut handle);
         if KERN_SUCCESS != kret {
-            println!("Couldn't connect to IOService {:?} (error {:?}", CStr::from_ptr(&name as *const _), CStr::from_ptr(mach_error_string(kret)));
             IOObjectRelease(serv);
-            return None;
+            return Err(PacmanError::OpenFailed(kret));
         }
 
-        return Some(Self(
+        return Ok(Self(Box::new(IOKitBackend(
             handle,
             serv
-        ));
+        )), Cell::new(true)));
     }
 
     /**
-     * Returns the kernel base address (pointer to the macho header of the kernelcache).
+     * Connect to the simulated kernel installed on this thread (there's no PacmanKit to find here).
+     *
+     * # Return Value
+     * Returns `PacmanError::ServiceNotFound` if no simulated kernel is installed (see `sim::kernel::install`).
      */
-    pub unsafe fn get_kernel_base(&self) -> Result<u64, KernReturn> {
-        let mut kaslr_base = 0;
-        let mut output_cnt = 1;
-        let kret = IOConnectCallScalarMethod(
-            self.0,
-            PacmanKitOp::KernelBase as u32,
-            core::ptr::null(),
-            0,
-            &mut kaslr_base,
-            &mut output_cnt
-        );
-
-        if kret != KERN_SUCCESS {
-            println!("Couldn't leak kernel base! (error {:?})", CStr::from_ptr(mach_error_string(kret)));
-            return Err(kret);
-        }
-
-        return Ok(kaslr_base);
+    #[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
+    pub unsafe fn init() -> Result<Self, PacmanError> {
+        return match sim::kernel::installed() {
+            Some(kernel) => sim::kernel::connect(&kernel),
+            None => Err(PacmanError::ServiceNotFound),
+        };
     }
 
     /**
-     * Read a u64 from kernel virtual memory.
+     * Create a PacmanKitConnection that runs every operation on `backend`.
      */
-    pub unsafe fn kernel_read(&self, addr: u64) -> Result<u64, KernReturn> {
-        let mut output_cnt = 1;
-        let mut read_out = 0;
-        let kret = IOConnectCallScalarMethod(
-            self.0,
-            PacmanKitOp::Read as u32,
-            &addr,
-            1,
-            &mut read_out,
-            &mut output_cnt
-        );
+    pub fn with_backend(backend: Box<dyn PacmanKitBackend>) -> Self {
+        return Self(backend, Cell::new(true));
+    }
 
+    /**
+     * Run `op`, turning a failed `kern_return_t` into a `PacmanError`.
+     */
+    fn run(&self, op: PacmanKitOp, args: &[u64], output: &mut [u64]) -> Result<(), PacmanError> {
+        let kret = self.0.call(op, args, output);
         if KERN_SUCCESS != kret {
-            println!("Couldn't read from kernel memory (error {:?})", CStr::from_ptr(mach_error_string(kret)));
-            return Err(kret);
+            return Err(PacmanError::OperationFailed { op, kret });
         }
+        return Ok(());
+    }
+
+    /**
+     * Print the timer control registers (see `PacmanKitOp::TellMeRegs`).
+     */
+    pub unsafe fn list_timer_regs(&self) -> Result<(), PacmanError> {
+        let mut rval : [u64; 2] = [0, 0];
+        self.run(PacmanKitOp::TellMeRegs, &[], &mut rval)?;
+
+        println!("PMCR0 is 0x{:X}", rval[0]);
+        println!("CNTKCTL_EL1 is 0x{:X}", rval[1]);
 
-        return Ok(read_out);
+        return Ok(());
+    }
+}
+
+
+impl KernelMemory for PacmanKitConnection {
+    /**
+     * Read a u64 from kernel virtual memory.
+     */
+    unsafe fn kernel_read(&self, addr: u64) -> Result<u64, PacmanError> {
+        let mut read_out = [0; 1];
+        self.run(PacmanKitOp::Read, &[addr], &mut read_out)?;
+
+        return Ok(read_out[0]);
     }
 
     /**
      * Write a u64 into kernel memory.
      */
-    pub unsafe fn kernel_write(&self, addr: u64, val: u64) -> Result<(), KernReturn> {
+    unsafe fn kernel_write(&self, addr: u64, val: u64) -> Result<(), PacmanError> {
         let args : [u64; 2] = [addr, val];
-        let kret = IOConnectCallScalarMethod(
-            self.0,
-            PacmanKitOp::Write as u32,
-            args.as_ptr(),
-            2,
-            core::ptr::null_mut(),
-            core::ptr::null_mut()
-        );
+        self.run(PacmanKitOp::Write, &args, &mut [])?;
 
-        if KERN_SUCCESS != kret {
-            println!("Couldn't write to kernel memory (error {:?})", CStr::from_ptr(mach_error_string(kret)));
-            return Err(kret);
-        }
+        return Ok(());
+    }
+
+    /**
+     * Returns a pointer to a kernel memory region mmap'ed by IOMallocAligned to a page size.
+     */
+    unsafe fn kernel_mmap(&self) -> Result<u64, PacmanError> {
+        let mut mmap_ptr = [0; 1];
+        self.run(PacmanKitOp::GimmeMemory, &[], &mut mmap_ptr)?;
+
+        return Ok(mmap_ptr[0]);
+    }
+
+    /**
+     * Frees memory allocated by kernel_mmap.
+     */
+     unsafe fn kernel_free(&self) -> Result<(), PacmanError> {
+        self.run(PacmanKitOp::FreeMemory, &[], &mut [])?;
 
         return Ok(());
     }
+}
 
+impl AddressTranslation for PacmanKitConnection {
     /**
      * Translate a kernel virtual address to its physical address.
      */
-    pub unsafe fn kernel_virt_to_phys(&self, addr: u64) -> Result<u64, KernReturn> {
-        let mut output_cnt = 1;
-        let mut translate_out = 0;
-        let kret = IOConnectCallScalarMethod(
-            self.0,
-            PacmanKitOp::KernelVirt2Phys as u32,
-            &addr,
-            1,
-            &mut translate_out,
-            &mut output_cnt
-        );
-
-        if KERN_SUCCESS != kret {
-            println!("Couldn't translate kernel address (error {:?})", CStr::from_ptr(mach_error_string(kret)));
-            return Err(kret);
-        }
+    unsafe fn kernel_virt_to_phys(&self, addr: u64) -> Result<u64, PacmanError> {
+        let mut translate_out = [0; 1];
+        self.run(PacmanKitOp::KernelVirt2Phys, &[addr], &mut translate_out)?;
 
-        return Ok(translate_out);
+        return Ok(translate_out[0]);
     }
 
     /**
      * Translate a user virtual address to its physical address.
      */
-    pub unsafe fn user_virt_to_phys(&self, addr: u64) -> Result<u64, KernReturn> {
-        let mut output_cnt = 1;
-        let mut translate_out = 0;
-        let kret = IOConnectCallScalarMethod(
-            self.0,
-            PacmanKitOp::UserVirt2Phys as u32,
-            &addr,
-            1,
-            &mut translate_out,
-            &mut output_cnt
-        );
+    unsafe fn user_virt_to_phys(&self, addr: u64) -> Result<u64, PacmanError> {
+        let mut translate_out = [0; 1];
+        self.run(PacmanKitOp::UserVirt2Phys, &[addr], &mut translate_out)?;
 
-        if KERN_SUCCESS != kret {
-            println!("Couldn't translate user address (error {:?})", CStr::from_ptr(mach_error_string(kret)));
-            return Err(kret);
-        }
-
-        return Ok(translate_out);
+        return Ok(translate_out[0]);
     }
+}
 
+impl KernelLeaks for PacmanKitConnection {
     /**
-     * Returns a pointer to this IOUserClient in the kernel.
+     * Returns the kernel base address (pointer to the macho header of the kernelcache).
      */
-    pub unsafe fn get_handle_loc(&self) -> Result<u64, KernReturn> {
-        let mut handle_loc = 0;
-        let mut output_cnt = 1;
-        let kret = IOConnectCallScalarMethod(
-            self.0,
-            PacmanKitOp::IOUserClientLeak as u32,
-            core::ptr::null(),
-            0,
-            &mut handle_loc,
-            &mut output_cnt
-        );
+    unsafe fn get_kernel_base(&self) -> Result<u64, PacmanError> {
+        let mut kaslr_base = [0; 1];
+        self.run(PacmanKitOp::KernelBase, &[], &mut kaslr_base)?;
 
-        if KERN_SUCCESS != kret {
-            println!("Couldn't get IOConnect virtual address (error {:?})", CStr::from_ptr(mach_error_string(kret)));
-            return Err(kret);
-        }
-
-        return Ok(handle_loc);
+        return Ok(kaslr_base[0]);
     }
 
     /**
-     * Returns a pointer to a kernel memory region mmap'ed by IOMallocAligned to a page size.
+     * Returns a pointer to this IOUserClient in the kernel.
      */
-    pub unsafe fn kernel_mmap(&self) -> Result<u64, KernReturn> {
-        let mut mmap_ptr = 0;
-        let mut output_cnt = 1;
-        let kret = IOConnectCallScalarMethod(
-            self.0,
-            PacmanKitOp::GimmeMemory as u32,
-            core::ptr::null(),
-            0,
-            &mut mmap_ptr,
-            &mut output_cnt
-        );
+    unsafe fn get_handle_loc(&self) -> Result<u64, PacmanError> {
+        let mut handle_loc = [0; 1];
+        self.run(PacmanKitOp::IOUserClientLeak, &[], &mut handle_loc)?;
 
-        if KERN_SUCCESS != kret {
-            println!("Couldn't get kernel mmap (error {:?})", CStr::from_ptr(mach_error_string(kret)));
-            return Err(kret);
-        }
-
-        return Ok(mmap_ptr);
+        return Ok(handle_loc[0]);
     }
 
     /**
-     * Frees memory allocated by kernel_mmap.
+     * Returns a pointer to a kernel method that just runs `ret`.
      */
-     pub unsafe fn kernel_free(&self) -> Result<(), KernReturn> {
-        let kret = IOConnectCallScalarMethod(
-            self.0,
-            PacmanKitOp::FreeMemory as u32,
-            core::ptr::null(),
-            0,
-            core::ptr::null_mut(),
-            core::ptr::null_mut()
-        );
-
-        if KERN_SUCCESS != kret {
-            println!("Couldn't get kernel mmap (error {:?})", CStr::from_ptr(mach_error_string(kret)));
-            return Err(kret);
-        }
+     unsafe fn leak_retpoline(&self) -> Result<u64, PacmanError> {
+        let mut method_leak_ptr : [u64; 3] = [0; 3];
+        self.run(PacmanKitOp::LeakMethod, &[], &mut method_leak_ptr)?;
 
-        return Ok(());
+        return Ok(method_leak_ptr[0]);
     }
 
-    pub unsafe fn list_timer_regs(&self) {
-        let mut rval : [u64; 2] = [0, 0];
-        let mut num_args : u32 = 2;
-        let kret = IOConnectCallScalarMethod(
-            self.0,
-            PacmanKitOp::TellMeRegs as u32,
-            core::ptr::null(),
-            0,
-            rval.as_mut_ptr(),
-            &mut num_args as *mut _,
-        );
+    /**
+     * Returns a pointer to the `LIMIT` variable in the PacmanKit kext.
+     */
+     unsafe fn leak_limit_location(&self) -> Result<u64, PacmanError> {
+        let mut method_leak_ptr : [u64; 3] = [0; 3];
+        self.run(PacmanKitOp::LeakMethod, &[], &mut method_leak_ptr)?;
 
-        if KERN_SUCCESS != kret {
-            println!("Couldn't read timer MSRs (error {:?})", CStr::from_ptr(mach_error_string(kret)));
-            return;
-        }
+        return Ok(method_leak_ptr[1]);
+    }
 
-        println!("PMCR0 is 0x{:X}", rval[0]);
-        println!("CNTKCTL_EL1 is 0x{:X}", rval[1]);
+    /// Leak the current proc pointer
+    unsafe fn current_proc(&self) -> Result<u64, PacmanError> {
+        let mut leak_ptr : [u64; 1] = [0; 1];
+        self.run(PacmanKitOp::LeakCurProc, &[], &mut leak_ptr)?;
 
-        return;
+        return Ok(leak_ptr[0]);
     }
+}
 
+impl KernelGadgets for PacmanKitConnection {
     /**
      * Read a u64 from kernel virtual memory without any IOMemoryDescriptor calls.
      * This *CAN* panic the kernel!
@@ -293,25 +467,12 @@ impl PacmanKitConnection {
      * # Return Value
      * Returns the number of cycles taken if `do_it` was true. Else, returns an undefined value.
      */
-     pub unsafe fn kernel_read_for_timing(&self, addr: u64, do_it: bool) -> Result<u64, KernReturn> {
-        let mut output_cnt = 1;
-        let mut read_out = 0;
+     unsafe fn kernel_read_for_timing(&self, addr: u64, do_it: bool) -> Result<u64, PacmanError> {
+        let mut read_out = [0; 1];
         let args : [u64; 2] = [addr, do_it as u64];
-        let kret = IOConnectCallScalarMethod(
-            self.0,
-            PacmanKitOp::ReadForTiming as u32,
-            args.as_ptr(),
-            2,
-            &mut read_out,
-            &mut output_cnt
-        );
+        self.run(PacmanKitOp::ReadForTiming, &args, &mut read_out)?;
 
-        if KERN_SUCCESS != kret {
-            println!("Couldn't read from kernel memory (error {:?})", CStr::from_ptr(mach_error_string(kret)));
-            return Err(kret);
-        }
-
-        return Ok(read_out - timer::TIMER_OVERHEAD_PCORE);
+        return Ok(read_out[0] - timer::TIMER_OVERHEAD_PCORE);
     }
 
     /**
@@ -325,95 +486,12 @@ impl PacmanKitConnection {
      * # Return Value
      * Returns the number of cycles taken if `do_it` was true. Else, returns an undefined value.
      */
-     pub unsafe fn kernel_exec_for_timing(&self, addr: u64, do_it: bool) -> Result<u64, KernReturn> {
-        let mut output_cnt = 1;
-        let mut read_out = 0;
+     unsafe fn kernel_exec_for_timing(&self, addr: u64, do_it: bool) -> Result<u64, PacmanError> {
+        let mut read_out = [0; 1];
         let args : [u64; 2] = [addr, do_it as u64];
-        let kret = IOConnectCallScalarMethod(
-            self.0,
-            PacmanKitOp::ExecForTiming as u32,
-            args.as_ptr(),
-            2,
-            &mut read_out,
-            &mut output_cnt
-        );
-
-        if KERN_SUCCESS != kret {
-            println!("Couldn't exec from kernel memory (error {:?})", CStr::from_ptr(mach_error_string(kret)));
-            return Err(kret);
-        }
-
-        return Ok(read_out - timer::TIMER_OVERHEAD_PCORE);
-    }
-
-    /**
-     * Returns a pointer to a kernel method that just runs `ret`.
-     */
-     pub unsafe fn leak_retpoline(&self) -> Result<u64, KernReturn> {
-        let mut method_leak_ptr : [u64; 3] = [0; 3];
-        let mut output_cnt = 3;
-        let kret = IOConnectCallScalarMethod(
-            self.0,
-            PacmanKitOp::LeakMethod as u32,
-            core::ptr::null(),
-            0,
-            method_leak_ptr.as_mut_ptr(),
-            &mut output_cnt
-        );
-
-        if KERN_SUCCESS != kret {
-            println!("Couldn't get reveal address of a kernel method (error {:?})", CStr::from_ptr(mach_error_string(kret)));
-            return Err(kret);
-        }
-
-        return Ok(method_leak_ptr[0]);
-    }
-
-    /**
-     * Returns a pointer to the `LIMIT` variable in the PacmanKit kext.
-     */
-     pub unsafe fn leak_limit_location(&self) -> Result<u64, KernReturn> {
-        let mut method_leak_ptr : [u64; 3] = [0; 3];
-        let mut output_cnt = 3;
-        let kret = IOConnectCallScalarMethod(
-            self.0,
-            PacmanKitOp::LeakMethod as u32,
-            core::ptr::null(),
-            0,
-            method_leak_ptr.as_mut_ptr(),
-            &mut output_cnt
-        );
-
-        if KERN_SUCCESS != kret {
-            println!("Couldn't get reveal address of the kext limit (error {:?})", CStr::from_ptr(mach_error_string(kret)));
-            return Err(kret);
-        }
+        self.run(PacmanKitOp::ExecForTiming, &args, &mut read_out)?;
 
-        return Ok(method_leak_ptr[1]);
-    }
-
-    /**
-     * Returns a pointer to the win() method in the PacmanKit kext.
-     */
-     pub unsafe fn leak_win(&self) -> Result<u64, KernReturn> {
-        panic!("This has been deprecated- use leak_retpoline to reveal a region full of `ret`s that can be used");
-        // let mut method_leak_ptr : [u64; 3] = [0; 3];
-        // let mut output_cnt = 3;
-        // let kret = IOConnectCallScalarMethod(
-        //     self.0,
-        //     PacmanKitOp::LeakMethod as u32,
-        //     core::ptr::null(),
-        //     0,
-        //     method_leak_ptr.as_mut_ptr(),
-        //     &mut output_cnt
-        // );
-
-        // if KERN_SUCCESS != kret {
-        //     println!("Couldn't get reveal address of the kext limit (error {:?})", CStr::from_ptr(mach_error_string(kret)));
-        //     return Err(kret);
-        // }
-
-        // return Ok(method_leak_ptr[2]);
+        return Ok(read_out[0] - timer::TIMER_OVERHEAD_PCORE);
     }
 
     /**
@@ -430,21 +508,9 @@ impl PacmanKitConnection {
      * # Return Value
      * Returns Nothing.
      */
-     pub unsafe fn kernel_read_for_spectre(&self, addr: u64, idx: u64) -> Result<(), KernReturn> {
+     unsafe fn kernel_read_for_spectre(&self, addr: u64, idx: u64) -> Result<(), PacmanError> {
         let args : [u64; 2] = [addr, idx];
-        let kret = IOConnectCallScalarMethod(
-            self.0,
-            PacmanKitOp::ReadForSpectre as u32,
-            args.as_ptr(),
-            2,
-            core::ptr::null_mut(),
-            core::ptr::null_mut(),
-        );
-
-        if KERN_SUCCESS != kret {
-            println!("Couldn't read from kernel memory (error {:?})", CStr::from_ptr(mach_error_string(kret)));
-            return Err(kret);
-        }
+        self.run(PacmanKitOp::ReadForSpectre, &args, &mut [])?;
 
         return Ok(());
     }
@@ -463,177 +529,103 @@ impl PacmanKitConnection {
      * # Return Value
      * Returns Nothing.
      */
-     pub unsafe fn kernel_exec_for_spectre(&self, addr: u64, idx: u64) -> Result<(), KernReturn> {
+     unsafe fn kernel_exec_for_spectre(&self, addr: u64, idx: u64) -> Result<(), PacmanError> {
         let args : [u64; 2] = [addr, idx];
-        let kret = IOConnectCallScalarMethod(
-            self.0,
-            PacmanKitOp::ExecForSpectre as u32,
-            args.as_ptr(),
-            2,
-            core::ptr::null_mut(),
-            core::ptr::null_mut(),
-        );
-
-        if KERN_SUCCESS != kret {
-            println!("Couldn't exec from kernel memory (error {:?})", CStr::from_ptr(mach_error_string(kret)));
-            return Err(kret);
-        }
+        self.run(PacmanKitOp::ExecForSpectre, &args, &mut [])?;
 
         return Ok(());
     }
 
-    pub unsafe fn call_service_routine(&self, arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5: u64, arg6: u64) -> Result<u64, KernReturn> {
+    /**
+     * Call PacmanKitService's externalMethod (the victim for the end to end attack).
+     *
+     * The PACMAN attacks call this with `arg1 = 10000` to reach the gadget speculatively and with
+     * `arg1 = 0` to run it non-speculatively.
+     */
+    unsafe fn call_service_routine(&self, arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5: u64, arg6: u64) -> Result<u64, PacmanError> {
         let args : [u64; 6] = [arg1, arg2, arg3, arg4, arg5, arg6];
-        let mut output_cnt = 1;
-        let mut output_val = 0u64;
-
-        let kret = IOConnectCallScalarMethod(
-            self.0,
-            PacmanKitOp::CallServiceRoutine as u32,
-            args.as_ptr(),
-            6,
-            &mut output_val,
-            &mut output_cnt
-        );
+        let mut output_val = [0u64; 1];
 
         // Ignore errors...
-        if KERN_SUCCESS != kret {
-            // println!("Couldn't call service routine {}!", arg1);
-            // return Err(kret);
+        let _ = self.run(PacmanKitOp::CallServiceRoutine, &args, &mut output_val);
+
+        return Ok(output_val[0]);
+    }
+}
+
+impl KernelBatch for PacmanKitConnection {
+    /**
+     * Run `batch` with `PacmanKitOp::Batch` (as few calls as `MAX_BATCH_OPS` allows).
+     *
+     * If the backend can't run batches (an older kext, or any backend without `call_struct`), every
+     * operation is run as its own scalar call instead, and this connection stops trying.
+     */
+    unsafe fn run_batch(&self, batch: &Batch) -> Result<Vec<u64>, PacmanError> {
+        if !self.1.get() {
+            return batch::run_each(self, batch);
         }
 
-        return Ok(output_val);
+        let mut results = Vec::with_capacity(batch.len());
+        for chunk in batch.chunks() {
+            let mut output = vec![0u8; chunk.len() * BATCH_RESULT_SIZE];
+            let kret = self.0.call_struct(PacmanKitOp::Batch, &chunk.encode(), &mut output);
+            if results.is_empty() && (KIO_RETURN_UNSUPPORTED == kret || KIO_RETURN_BAD_ARGUMENT == kret) {
+                self.1.set(false);
+                return batch::run_each(self, batch);
+            }
+            if KERN_SUCCESS != kret {
+                return Err(PacmanError::OperationFailed { op: PacmanKitOp::Batch, kret });
+            }
+            results.extend(chunk.results(&output)?);
+        }
+        return Ok(results);
     }
+}
 
+impl PacOracle for PacmanKitConnection {
     /// Returns the correct PACDA signature from the kernel. This can ONLY be used for testing!
     /// The real attack will need to use brute force to find this. We only use this method to learn
     /// the ground truth for generating plots and tuning the algorithm.
-    pub unsafe fn forge_sign_data(&self, addr: u64, salt: u64) -> Result<u64, KernReturn> {
+    unsafe fn forge_sign_data(&self, addr: u64, salt: u64) -> Result<u64, PacmanError> {
         let args : [u64; 2] = [addr, salt];
-        let mut output_cnt = 1;
-        let mut output_val = 1;
-
-        let kret = IOConnectCallScalarMethod(
-            self.0,
-            PacmanKitOp::ForgeSignData as u32,
-            args.as_ptr(),
-            2,
-            &mut output_val,
-            &mut output_cnt
-        );
+        let mut output_val = [1; 1];
 
-        if KERN_SUCCESS != kret {
-            println!("Couldn't forge PACDA for addr 0x{:X} with salt 0x{:X}", addr, salt);
-            return Err(kret);
-        }
+        self.run(PacmanKitOp::ForgeSignData, &args, &mut output_val)?;
 
-        return Ok(output_val);
+        return Ok(output_val[0]);
     }
 
     /// Returns the correct AUTDA signature from the kernel. This can ONLY be used for testing!
     /// The real attack cannot do this.
-    pub unsafe fn forge_auth_data(&self, addr: u64, salt: u64) -> Result<u64, KernReturn> {
+    unsafe fn forge_auth_data(&self, addr: u64, salt: u64) -> Result<u64, PacmanError> {
         let args : [u64; 2] = [addr, salt];
-        let mut output_cnt = 1;
-        let mut output_val = 1;
-
-        let kret = IOConnectCallScalarMethod(
-            self.0,
-            PacmanKitOp::ForgeAuthData as u32,
-            args.as_ptr(),
-            2,
-            &mut output_val,
-            &mut output_cnt
-        );
+        let mut output_val = [1; 1];
 
-        if KERN_SUCCESS != kret {
-            println!("Couldn't forge AUTDA for addr 0x{:X} with salt 0x{:X}", addr, salt);
-            return Err(kret);
-        }
+        self.run(PacmanKitOp::ForgeAuthData, &args, &mut output_val)?;
 
-        return Ok(output_val);
+        return Ok(output_val[0]);
     }
 
     /// Returns the correct PACIA signature from the kernel. This can ONLY be used for testing!
     /// The real attack will need to use brute force to find this. We only use this method to learn
     /// the ground truth for generating plots and tuning the algorithm.
-    pub unsafe fn forge_sign_inst(&self, addr: u64, salt: u64) -> Result<u64, KernReturn> {
+    unsafe fn forge_sign_inst(&self, addr: u64, salt: u64) -> Result<u64, PacmanError> {
         let args : [u64; 2] = [addr, salt];
-        let mut output_cnt = 1;
-        let mut output_val = 1;
-
-        let kret = IOConnectCallScalarMethod(
-            self.0,
-            PacmanKitOp::ForgeSignInst as u32,
-            args.as_ptr(),
-            2,
-            &mut output_val,
-            &mut output_cnt
-        );
+        let mut output_val = [1; 1];
 
-        if KERN_SUCCESS != kret {
-            println!("Couldn't forge PACIA for addr 0x{:X} with salt 0x{:X}", addr, salt);
-            return Err(kret);
-        }
+        self.run(PacmanKitOp::ForgeSignInst, &args, &mut output_val)?;
 
-        return Ok(output_val);
+        return Ok(output_val[0]);
     }
 
     /// Returns the correct AUTIA signature from the kernel. This can ONLY be used for testing!
     /// The real attack cannot do this.
-    pub unsafe fn forge_auth_inst(&self, addr: u64, salt: u64) -> Result<u64, KernReturn> {
+    unsafe fn forge_auth_inst(&self, addr: u64, salt: u64) -> Result<u64, PacmanError> {
         let args : [u64; 2] = [addr, salt];
-        let mut output_cnt = 1;
-        let mut output_val = 1;
-
-        let kret = IOConnectCallScalarMethod(
-            self.0,
-            PacmanKitOp::ForgeAuthInst as u32,
-            args.as_ptr(),
-            2,
-            &mut output_val,
-            &mut output_cnt
-        );
+        let mut output_val = [1; 1];
 
-        if KERN_SUCCESS != kret {
-            println!("Couldn't forge AUTIA for addr 0x{:X} with salt 0x{:X}", addr, salt);
-            return Err(kret);
-        }
-
-        return Ok(output_val);
-    }
+        self.run(PacmanKitOp::ForgeAuthInst, &args, &mut output_val)?;
 
-    /// Leak the current proc pointer
-    pub unsafe fn current_proc(&self) -> Result<u64, KernReturn> {
-        let mut leak_ptr : [u64; 1] = [0; 1];
-        let mut output_cnt = 1;
-        let kret = IOConnectCallScalarMethod(
-            self.0,
-            PacmanKitOp::LeakCurProc as u32,
-            core::ptr::null(),
-            0,
-            leak_ptr.as_mut_ptr(),
-            &mut output_cnt
-        );
-
-        if KERN_SUCCESS != kret {
-            println!("Couldn't call current_proc() (error {:?})", CStr::from_ptr(mach_error_string(kret)));
-            return Err(kret);
-        }
-
-        return Ok(leak_ptr[0]);
-    }
-}
-
-impl Drop for PacmanKitConnection {
-    /**
-     * Clean up our IOKit connection.
-     */
-    fn drop(&mut self) {
-        println!("Dropping a PacmanKitConnection ({:X}, {:X})", self.0, self.1);
-        unsafe {
-            IOServiceClose(self.0);
-            IOObjectRelease(self.1);
-        }
+        return Ok(output_val[0]);
     }
 }
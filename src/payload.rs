@@ -0,0 +1,211 @@
+/*!
+ * Building code to run out of attacker memory, for evicting and probing the instruction side caches and
+ * branch predictors.
+ *
+ * A `Payload` is the code for a range of addresses, built up here and then copied somewhere executable (with
+ * `retpoline::mk_payload` on a Mac). Every word starts out as `ret`, so jumping anywhere in it returns right
+ * away like a retpoline does, and the builders lay code on top of that:
+ * * `nop_sled`: `nop`s ending in a `ret`, so one call executes (and caches) every line of it.
+ * * `branch_chain`: a `b` at each address to the next one, ending in a `ret`, so one call executes exactly
+ *   those addresses (across cache lines and pages) and nothing in between.
+ * * `jump`: a `b`, or an absolute jump through `JUMP_REG` for targets out of a `b`'s range.
+ * * `pad_to_l1i_set`: `nop`s up to the next address in a given L1I set.
+ * * `btb_conflicts`: a chain of branches `stride` bytes apart, whose addresses only differ above the bits
+ *   branch predictors index with, so they compete for the same entries.
+ *
+ * Nothing here touches the hardware, so the generated code can be checked anywhere.
+ */
+use crate::aarch64::{Inst, NOP, RET};
+use crate::cache;
+
+/// How far apart instruction addresses in the same L1I set are
+pub const L1I_SET_STRIDE : u64 = (cache::L1I_SETS * cache::L1I_LINESIZE) as u64;
+
+/// The register `Payload::jump` clobbers to reach targets out of a `b`'s range (`x16`, the scratch register for veneers)
+pub const JUMP_REG : u8 = 16;
+
+/// The most bytes `Payload::jump` takes (4 moves and a `br`)
+pub const JUMP_MAX_SIZE : u64 = 20;
+
+/**
+ * The code for `[base, base + size)`.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Payload {
+    base: u64,
+    words: Vec<u32>,
+}
+
+impl Payload {
+    /**
+     * `size` bytes of `ret`s at `base` (both 4 byte aligned).
+     */
+    pub fn new(base: u64, size: usize) -> Self {
+        assert!(base.is_multiple_of(4) && size.is_multiple_of(4), "Payloads are made of whole instructions");
+        return Payload { base, words: vec![RET; size / 4] };
+    }
+
+    /// Where the payload starts
+    pub fn base(&self) -> u64 {
+        return self.base;
+    }
+
+    /// Where the payload ends
+    pub fn end(&self) -> u64 {
+        return self.base + self.words.len() as u64 * 4;
+    }
+
+    /// Is `addr` inside the payload?
+    pub fn contains(&self, addr: u64) -> bool {
+        return addr >= self.base && addr < self.end();
+    }
+
+    /// The instruction word at `addr`
+    pub fn word(&self, addr: u64) -> Option<u32> {
+        if !self.contains(addr) || !addr.is_multiple_of(4) {
+            return None;
+        }
+        return Some(self.words[((addr - self.base) / 4) as usize]);
+    }
+
+    /// Every instruction word, from `base` on
+    pub fn words(&self) -> &[u32] {
+        return &self.words;
+    }
+
+    /// The code as little endian bytes
+    pub fn bytes(&self) -> Vec<u8> {
+        return self.words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    }
+
+    /**
+     * Put instruction word `word` at `addr`.
+     * Panics if `addr` isn't an instruction in the payload.
+     */
+    pub fn emit_word(&mut self, addr: u64, word: u32) {
+        assert!(self.contains(addr) && addr.is_multiple_of(4), "0x{:X} isn't an instruction in the payload", addr);
+        self.words[((addr - self.base) / 4) as usize] = word;
+    }
+
+    /**
+     * Put `inst` at `addr`.
+     * Panics if `addr` isn't an instruction in the payload, or `inst` can't be encoded.
+     */
+    pub fn emit(&mut self, addr: u64, inst: Inst) {
+        let word = inst.encode().unwrap_or_else(|| panic!("{:?} can't be encoded", inst));
+        self.emit_word(addr, word);
+    }
+
+    /**
+     * Fill `len` bytes from `addr` with `nop`s, followed by a `ret`.
+     *
+     * # Return Value
+     * The address after the `ret`.
+     */
+    pub fn nop_sled(&mut self, addr: u64, len: usize) -> u64 {
+        let ret = addr + len as u64;
+        for inst in (addr..ret).step_by(4) {
+            self.emit_word(inst, NOP);
+        }
+        self.emit_word(ret, RET);
+        return ret + 4;
+    }
+
+    /**
+     * Chain `addrs` together: each one branches to the next, and the last one returns.
+     * Calling the first executes every one of them, in order (they can be in any order in memory).
+     */
+    pub fn branch_chain(&mut self, addrs: &[u64]) {
+        for pair in addrs.windows(2) {
+            self.emit(pair[0], Inst::B { offset: pair[1].wrapping_sub(pair[0]) as i64, link: false });
+        }
+        if let Some(last) = addrs.last() {
+            self.emit_word(*last, RET);
+        }
+    }
+
+    /**
+     * Jump from `addr` to `target`: a `b` if it's in range, otherwise `target` is moved into `JUMP_REG` and
+     * branched to with a `br`.
+     *
+     * # Return Value
+     * The address after the jump.
+     */
+    pub fn jump(&mut self, addr: u64, target: u64) -> u64 {
+        if let Some(word) = (Inst::B { offset: target.wrapping_sub(addr) as i64, link: false }).encode() {
+            self.emit_word(addr, word);
+            return addr + 4;
+        }
+
+        let mut at = addr;
+        for shift in [0, 16, 32, 48] {
+            self.emit(at, Inst::Mov { rd: JUMP_REG, imm: (target >> shift) as u16, shift, keep: shift != 0 });
+            at += 4;
+        }
+        self.emit(at, Inst::Br { rn: JUMP_REG, link: false });
+        return at + 4;
+    }
+
+    /**
+     * Fill with `nop`s from `addr` up to the first instruction at or after it in L1I set `set`.
+     *
+     * # Return Value
+     * The address in set `set` (where the code to pad out goes).
+     */
+    pub fn pad_to_l1i_set(&mut self, addr: u64, set: u64) -> u64 {
+        let target = next_in_l1i_set(addr, set);
+        for inst in (addr..target).step_by(4) {
+            self.emit_word(inst, NOP);
+        }
+        return target;
+    }
+
+    /**
+     * Chain `count` branches `stride` bytes apart from `addr` (see `branch_chain`).
+     *
+     * With `stride` a power of two above the bits a branch predictor indexes with (and tags with, for a
+     * partially tagged one), every branch maps to the same predictor entries, so running the chain evicts
+     * (or aliases with) a victim branch at the same low address bits.
+     *
+     * # Return Value
+     * The branches' addresses.
+     */
+    pub fn btb_conflicts(&mut self, addr: u64, stride: u64, count: usize) -> Vec<u64> {
+        let addrs = strided(addr, stride, count);
+        self.branch_chain(&addrs);
+        return addrs;
+    }
+}
+
+/**
+ * `count` addresses `stride` bytes apart, starting at `start` (eg. one per cache line, or per page).
+ */
+pub fn strided(start: u64, stride: u64, count: usize) -> Vec<u64> {
+    return (0..count as u64).map(|i| start + i * stride).collect();
+}
+
+/**
+ * The first address at or after `addr` (rounded up to an instruction) in L1I set `set`.
+ */
+pub fn next_in_l1i_set(addr: u64, set: u64) -> u64 {
+    let addr = (addr + 3) & !3;
+    let line_start = addr & !(cache::L1I_LINESIZE as u64 - 1);
+    let in_set = (line_start & !(L1I_SET_STRIDE - 1)) | ((set % cache::L1I_SETS as u64) * cache::L1I_LINESIZE as u64);
+    if cache::get_l1_cache_set_m1(addr) == set % cache::L1I_SETS as u64 {
+        return addr;
+    }
+    if in_set > addr {
+        return in_set;
+    }
+    return in_set + L1I_SET_STRIDE;
+}
+
+/**
+ * `count` line aligned addresses in L1I set `set`, from the first one at or after `start` on.
+ * Chaining them with `Payload::branch_chain` makes an instruction eviction set for that set.
+ */
+pub fn l1i_set_addresses(start: u64, set: u64, count: usize) -> Vec<u64> {
+    let first = next_in_l1i_set(start, set) & !(cache::L1I_LINESIZE as u64 - 1);
+    let first = if first < start { first + L1I_SET_STRIDE } else { first };
+    return strided(first, L1I_SET_STRIDE, count);
+}
@@ -0,0 +1,158 @@
+/*!
+ * What the attacks need from the kernel, independent of how they get it.
+ *
+ * Each trait is one family of PacmanKit operations. `PacmanKitConnection` implements all of them (for the
+ * kext and for every `PacmanKitBackend`), but anything else that can provide them (a simulator, a socket,
+ * a recorded session) can be attacked the same way. Functions ask for the smallest trait they use, and
+ * whole experiments ask for `KernelPrimitives`.
+ *
+ * Every method is `unsafe`: against the real kext they poke at kernel memory and can panic the kernel.
+ * Failures are returned as a `PacmanError` (nothing is printed).
+ */
+use crate::batch::Batch;
+use crate::error::PacmanError;
+
+/**
+ * Arbitrary kernel memory access, and a kernel buffer to use it on.
+ */
+pub trait KernelMemory {
+    /**
+     * Read a u64 from kernel virtual memory.
+     */
+    unsafe fn kernel_read(&self, addr: u64) -> Result<u64, PacmanError>;
+
+    /**
+     * Write a u64 into kernel memory.
+     */
+    unsafe fn kernel_write(&self, addr: u64, val: u64) -> Result<(), PacmanError>;
+
+    /**
+     * Returns a pointer to a kernel memory region mmap'ed by IOMallocAligned to a page size.
+     */
+    unsafe fn kernel_mmap(&self) -> Result<u64, PacmanError>;
+
+    /**
+     * Frees memory allocated by kernel_mmap.
+     */
+    unsafe fn kernel_free(&self) -> Result<(), PacmanError>;
+}
+
+/**
+ * Virtual to physical address translation (for building eviction sets out of physical addresses).
+ */
+pub trait AddressTranslation {
+    /**
+     * Translate a kernel virtual address to its physical address.
+     */
+    unsafe fn kernel_virt_to_phys(&self, addr: u64) -> Result<u64, PacmanError>;
+
+    /**
+     * Translate a user virtual address to its physical address.
+     */
+    unsafe fn user_virt_to_phys(&self, addr: u64) -> Result<u64, PacmanError>;
+}
+
+/**
+ * Addresses of interest in the kernel (the KASLR and heap leaks the attack assumes).
+ */
+pub trait KernelLeaks {
+    /**
+     * Returns the kernel base address (pointer to the macho header of the kernelcache).
+     */
+    unsafe fn get_kernel_base(&self) -> Result<u64, PacmanError>;
+
+    /**
+     * Returns a pointer to this connection's IOUserClient in the kernel.
+     */
+    unsafe fn get_handle_loc(&self) -> Result<u64, PacmanError>;
+
+    /**
+     * Returns a pointer to a kernel method that just runs `ret`.
+     */
+    unsafe fn leak_retpoline(&self) -> Result<u64, PacmanError>;
+
+    /**
+     * Returns a pointer to the `LIMIT` variable in the PacmanKit kext.
+     */
+    unsafe fn leak_limit_location(&self) -> Result<u64, PacmanError>;
+
+    /**
+     * Returns a pointer to the win() method in the PacmanKit kext.
+     */
+    unsafe fn leak_win(&self) -> Result<u64, PacmanError> {
+        panic!("This has been deprecated- use leak_retpoline to reveal a region full of `ret`s that can be used");
+    }
+
+    /**
+     * Leak the current proc pointer.
+     */
+    unsafe fn current_proc(&self) -> Result<u64, PacmanError>;
+}
+
+/**
+ * The kernel gadgets: timed and speculative loads/ executions, and the PACMAN victim.
+ * See `PacmanKitConnection`'s implementation for what each one does.
+ */
+pub trait KernelGadgets {
+    /**
+     * Time a load from a kernel address (if `do_it`). This *CAN* panic the kernel!
+     */
+    unsafe fn kernel_read_for_timing(&self, addr: u64, do_it: bool) -> Result<u64, PacmanError>;
+
+    /**
+     * Time executing a kernel address (if `do_it`). This *CAN* panic the kernel!
+     */
+    unsafe fn kernel_exec_for_timing(&self, addr: u64, do_it: bool) -> Result<u64, PacmanError>;
+
+    /**
+     * Load from a kernel address behind a bounds check on `idx`. This *CAN* panic the kernel!
+     */
+    unsafe fn kernel_read_for_spectre(&self, addr: u64, idx: u64) -> Result<(), PacmanError>;
+
+    /**
+     * Execute a kernel address behind a bounds check on `idx`. This *CAN* panic the kernel!
+     */
+    unsafe fn kernel_exec_for_spectre(&self, addr: u64, idx: u64) -> Result<(), PacmanError>;
+
+    /**
+     * Call PacmanKitService's externalMethod (the victim for the end to end attack).
+     */
+    unsafe fn call_service_routine(&self, arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5: u64, arg6: u64) -> Result<u64, PacmanError>;
+}
+
+/**
+ * The kernel's PAC keys, as an oracle. This can ONLY be used for testing (the real attack bruteforces PACs instead).
+ */
+pub trait PacOracle {
+    /// Returns the correct PACDA signature from the kernel.
+    unsafe fn forge_sign_data(&self, addr: u64, salt: u64) -> Result<u64, PacmanError>;
+
+    /// Returns the correct AUTDA result from the kernel.
+    unsafe fn forge_auth_data(&self, addr: u64, salt: u64) -> Result<u64, PacmanError>;
+
+    /// Returns the correct PACIA signature from the kernel.
+    unsafe fn forge_sign_inst(&self, addr: u64, salt: u64) -> Result<u64, PacmanError>;
+
+    /// Returns the correct AUTIA result from the kernel.
+    unsafe fn forge_auth_inst(&self, addr: u64, salt: u64) -> Result<u64, PacmanError>;
+}
+
+/**
+ * Running a queue of kernel reads, writes and gadget calls in one round-trip (see `batch`).
+ */
+pub trait KernelBatch {
+    /**
+     * Run every operation in `batch` in order, stopping at the first one that fails.
+     *
+     * # Return Value
+     * Each operation's output (0 for operations without one), or the first operation that failed.
+     */
+    unsafe fn run_batch(&self, batch: &Batch) -> Result<Vec<u64>, PacmanError>;
+}
+
+/**
+ * Everything PacmanKit provides. Implemented for anything that implements all of the smaller traits.
+ */
+pub trait KernelPrimitives: KernelMemory + AddressTranslation + KernelLeaks + KernelGadgets + PacOracle + KernelBatch {}
+
+impl<T> KernelPrimitives for T where T: KernelMemory + AddressTranslation + KernelLeaks + KernelGadgets + PacOracle + KernelBatch {}
@@ -0,0 +1,144 @@
+/*!
+ * Kernel struct offsets for each OS build.
+ *
+ * The offsets the PACMAN experiments use (where PacmanKit's helper object and `proc.task` are, what the
+ * syscall branches on, ...) and the salt constants they forge PACs with change from one kernel build to the
+ * next. They live in an offset profile
+ * file (`offsets.toml`, built into the runner) with one `[[profile]]` per OS build, listing the devices it
+ * was checked on:
+ * ```toml
+ * [[profile]]
+ * build = "21D62"
+ * devices = ["MacBookAir10,1"]
+ *
+ * [profile.fields]
+ * "proc.task" = 0x10
+ *
+ * [profile.discriminators]
+ * "proc.task" = 0xa08a
+ *
+ * [profile.gadgets]
+ * "inst_target" = "base+0x26a497c"
+ * ```
+ * Attacks look values up by name (the constants below), and get a `PacmanError::MissingOffset` instead of
+ * a guess when the profile doesn't have one.
+ */
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize};
+use crate::error::PacmanError;
+use crate::kernelcache::symbols::SymbolicAddress;
+
+/// The offset profiles built into the runner
+pub const BUILTIN_PROFILES : &str = include_str!("../offsets.toml");
+
+/// Field: where a PacmanKit user client keeps its helper object (the victim object the PACMAN experiments forge)
+pub const PACMANKIT_HELPER : &str = "pacmankit.helper";
+
+/// Discriminator: the vtable pointer of an IOKit object (blended with the object's address)
+pub const VTABLE_POINTER : &str = "object.vtable";
+
+/// Discriminator: the entries of an IOKit vtable (blended with the entry's address)
+pub const VTABLE_ENTRY : &str = "vtable.entry";
+
+/// Field: `proc.task`, the holder `pacman_real` forges
+pub const PROC_TASK : &str = "proc.task";
+
+/// Field: what `memorystatus_available_memory` branches on before it uses `proc.task`
+pub const PROC_CONDITION : &str = "proc.condition";
+
+/// Field: what `memorystatus_available_memory` loads through `proc.task`
+pub const TASK_TRANSMIT : &str = "task.transmit";
+
+/// Gadget: the `ret` inst prime+probe executes (unless `pacman.inst_target` in the config says otherwise)
+pub const INST_TARGET : &str = "inst_target";
+
+/**
+ * The offsets for one OS build.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OffsetProfile {
+    /// The OS build (`sysctl kern.osversion`, eg. `21D62`)
+    pub build: String,
+
+    /// The devices (`sysctl hw.model`, eg. `MacBookAir10,1`) the offsets were checked on
+    pub devices: Vec<String>,
+
+    /// Offsets of struct fields (`struct.field`)
+    #[serde(default)]
+    pub fields: BTreeMap<String, u64>,
+
+    /// Diversity constants of signed pointers
+    #[serde(default)]
+    pub discriminators: BTreeMap<String, u16>,
+
+    /// Where gadgets are (relative to the kernel base, a kext or a symbol)
+    #[serde(default)]
+    pub gadgets: BTreeMap<String, SymbolicAddress>,
+}
+
+impl OffsetProfile {
+    /// The offset of struct field `name`
+    pub fn field(&self, name: &'static str) -> Result<u64, PacmanError> {
+        return self.fields.get(name).copied().ok_or(PacmanError::MissingOffset(name));
+    }
+
+    /// The diversity constant of the pointer called `name`
+    pub fn discriminator(&self, name: &'static str) -> Result<u64, PacmanError> {
+        return self.discriminators.get(name).map(|val| *val as u64).ok_or(PacmanError::MissingOffset(name));
+    }
+
+    /// Where gadget `name` is
+    pub fn gadget(&self, name: &'static str) -> Result<&SymbolicAddress, PacmanError> {
+        return self.gadgets.get(name).ok_or(PacmanError::MissingOffset(name));
+    }
+}
+
+/**
+ * Every profile in an offset profile file.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OffsetProfiles {
+    #[serde(rename = "profile", default)]
+    pub profiles: Vec<OffsetProfile>,
+}
+
+impl OffsetProfiles {
+    /**
+     * Parse an offset profile file.
+     */
+    pub fn from_toml(text: &str) -> Result<Self, String> {
+        return toml::from_str(text).map_err(|err| err.to_string());
+    }
+
+    /**
+     * Load an offset profile file.
+     */
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+        return OffsetProfiles::from_toml(&text);
+    }
+
+    /**
+     * The profiles built into the runner (`offsets.toml`).
+     */
+    pub fn builtin() -> Self {
+        return OffsetProfiles::from_toml(BUILTIN_PROFILES).expect("offsets.toml is broken");
+    }
+
+    /**
+     * The profile for OS build `build` on `device`.
+     * Returns a description of what's missing if there isn't one.
+     */
+    pub fn find(&self, build: &str, device: &str) -> Result<&OffsetProfile, String> {
+        let for_build : Vec<&OffsetProfile> = self.profiles.iter().filter(|profile| profile.build == build).collect();
+        if for_build.is_empty() {
+            let known : Vec<&str> = self.profiles.iter().map(|profile| profile.build.as_str()).collect();
+            return Err(format!("No offset profile for build {} (there are profiles for {})", build, known.join(", ")));
+        }
+        return for_build.into_iter()
+            .find(|profile| profile.devices.iter().any(|known| known == device))
+            .ok_or_else(|| format!("The offset profile for build {} hasn't been checked on {}", build, device));
+    }
+}
@@ -0,0 +1,397 @@
+/*!
+ * PacmanKit over a socket: the `PacmanKitOp` selectors and their scalar inputs/ outputs, sent over Unix or TCP.
+ *
+ * `connect` returns a regular `PacmanKitConnection` whose backend forwards every call to a server. The server
+ * (`serve`, and the `pacmankit_server` binary) runs each call on a connection of its own: a simulated kernel,
+ * or the kext itself when it runs on a lab Mac. Every socket is its own PacmanKit connection (its own
+ * IOUserClient on the server), so attacks that need a victim connection open two sockets.
+ *
+ * Only the kernel operations cross the socket. The attacker's own timed accesses still run on the client.
+ * The protocol only carries scalar methods (`RemoteBackend` has no `call_struct`), so batches (see `batch`)
+ * are run one scalar call at a time over it.
+ * A call made after the socket breaks fails with `MACH_SEND_INVALID_DEST`, like a call on a dead mach port.
+ *
+ * # Wire Format
+ * All integers are little endian. Both sides send a hello when the socket is opened.
+ * ```text
+ * Hello:    "PACKITRP" (8 bytes) | version: u32
+ * Request:  selector: u32 | num_args: u32 | num_outputs: u32 | arg: u64 (x num_args)
+ * Response: kern_return: i32 | num_outputs: u32 | output: u64 (x num_outputs)
+ * ```
+ * At most `MAX_SCALARS` inputs and outputs are allowed (more inputs than that is a protocol error, which
+ * hangs up). Unknown selectors and requests for too many outputs are answered with `KERN_INVALID_ARGUMENT`
+ * (and no outputs).
+ */
+use std::fmt;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use crate::error::PacmanError;
+use crate::pacmankit::*;
+
+/// Magic bytes every hello starts with
+pub const PROTOCOL_MAGIC : &[u8; 8] = b"PACKITRP";
+
+/// Current protocol version
+pub const PROTOCOL_VERSION : u32 = 1;
+
+/// The most scalar inputs or outputs a request can have (IOKit's limit for scalar methods)
+pub const MAX_SCALARS : usize = 16;
+
+/**
+ * Where a PacmanKit server listens.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Address {
+    /// A Unix socket (`unix:/path/to/socket`)
+    Unix(String),
+
+    /// A TCP socket (`host:port`)
+    Tcp(String),
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("expected a path after unix:".to_string());
+            }
+            return Ok(Address::Unix(path.to_string()));
+        }
+        if !s.contains(':') {
+            return Err(format!("expected unix:<path> or <host>:<port>, got {}", s));
+        }
+        return Ok(Address::Tcp(s.to_string()));
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            Address::Unix(path) => write!(f, "unix:{}", path),
+            Address::Tcp(addr) => write!(f, "{}", addr),
+        };
+    }
+}
+
+/// The two halves of a connected socket
+type Halves = (Box<dyn Read + Send>, Box<dyn Write + Send>);
+
+/// The two halves of a connected socket, buffered
+type BufferedHalves = (BufReader<Box<dyn Read + Send>>, BufWriter<Box<dyn Write + Send>>);
+
+/// Split a TCP socket (requests are tiny, don't wait to batch them)
+fn tcp_halves(stream: TcpStream) -> std::io::Result<Halves> {
+    stream.set_nodelay(true)?;
+    return Ok((Box::new(stream.try_clone()?), Box::new(stream)));
+}
+
+/// Split a Unix socket
+#[cfg(unix)]
+fn unix_halves(stream: UnixStream) -> std::io::Result<Halves> {
+    return Ok((Box::new(stream.try_clone()?), Box::new(stream)));
+}
+
+/// Open a socket to `address`
+fn open(address: &Address) -> std::io::Result<Halves> {
+    return match address {
+        #[cfg(unix)]
+        Address::Unix(path) => unix_halves(UnixStream::connect(path)?),
+        #[cfg(not(unix))]
+        Address::Unix(_) => Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Unix sockets aren't supported here")),
+        Address::Tcp(addr) => tcp_halves(TcpStream::connect(addr)?),
+    };
+}
+
+/// Shorthand for a malformed message error
+fn invalid_data(msg: &str) -> std::io::Error {
+    return std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+}
+
+fn read_u32(input: &mut impl Read) -> std::io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    return Ok(u32::from_le_bytes(bytes));
+}
+
+fn read_u64s(input: &mut impl Read, count: usize) -> std::io::Result<Vec<u64>> {
+    let mut bytes = vec![0u8; 8 * count];
+    input.read_exact(&mut bytes)?;
+    return Ok(bytes.chunks(8).map(|c| u64::from_le_bytes(c.try_into().unwrap())).collect());
+}
+
+/**
+ * Send a hello.
+ */
+pub fn write_hello(out: &mut impl Write) -> std::io::Result<()> {
+    out.write_all(PROTOCOL_MAGIC)?;
+    out.write_all(&PROTOCOL_VERSION.to_le_bytes())?;
+    return out.flush();
+}
+
+/**
+ * Read the other side's hello (checks the magic and version).
+ */
+pub fn read_hello(input: &mut impl Read) -> std::io::Result<()> {
+    let mut magic = [0u8; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != PROTOCOL_MAGIC {
+        return Err(invalid_data("Not a PacmanKit server"));
+    }
+    if read_u32(input)? != PROTOCOL_VERSION {
+        return Err(invalid_data("Unsupported PacmanKit protocol version"));
+    }
+    return Ok(());
+}
+
+/**
+ * One call of a `PacmanKitOp`.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Request {
+    /// The ::externalMethod selector (see `PacmanKitOp`)
+    pub selector: u32,
+
+    /// The scalar inputs
+    pub args: Vec<u64>,
+
+    /// How many scalar outputs the caller expects
+    pub num_outputs: usize,
+}
+
+impl Request {
+    /// Send this request
+    pub fn write_to(&self, out: &mut impl Write) -> std::io::Result<()> {
+        out.write_all(&self.selector.to_le_bytes())?;
+        out.write_all(&(self.args.len() as u32).to_le_bytes())?;
+        out.write_all(&(self.num_outputs as u32).to_le_bytes())?;
+        for arg in &self.args {
+            out.write_all(&arg.to_le_bytes())?;
+        }
+        return out.flush();
+    }
+
+    /**
+     * Read the next request. Returns `Ok(None)` if the client hung up between requests.
+     */
+    pub fn read_from(input: &mut impl Read) -> std::io::Result<Option<Self>> {
+        let mut selector = [0u8; 4];
+        if input.read(&mut selector[..1])? == 0 {
+            return Ok(None);
+        }
+        input.read_exact(&mut selector[1..])?;
+
+        let num_args = read_u32(input)? as usize;
+        let num_outputs = read_u32(input)? as usize;
+        if num_args > MAX_SCALARS {
+            return Err(invalid_data("Too many scalar inputs"));
+        }
+
+        return Ok(Some(Self {
+            selector: u32::from_le_bytes(selector),
+            args: read_u64s(input, num_args)?,
+            num_outputs,
+        }));
+    }
+}
+
+/**
+ * The result of a `Request`.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response {
+    /// The `kern_return_t` of the call
+    pub kret: KernReturn,
+
+    /// The scalar outputs
+    pub output: Vec<u64>,
+}
+
+impl Response {
+    /// Send this response
+    pub fn write_to(&self, out: &mut impl Write) -> std::io::Result<()> {
+        out.write_all(&self.kret.to_le_bytes())?;
+        out.write_all(&(self.output.len() as u32).to_le_bytes())?;
+        for val in &self.output {
+            out.write_all(&val.to_le_bytes())?;
+        }
+        return out.flush();
+    }
+
+    /// Read the response to a request
+    pub fn read_from(input: &mut impl Read) -> std::io::Result<Self> {
+        let kret = read_u32(input)? as KernReturn;
+        let num_outputs = read_u32(input)? as usize;
+        if num_outputs > MAX_SCALARS {
+            return Err(invalid_data("Too many scalar outputs"));
+        }
+        return Ok(Self { kret, output: read_u64s(input, num_outputs)? });
+    }
+}
+
+/**
+ * Run a request on `backend`.
+ */
+pub fn handle_request(backend: &dyn PacmanKitBackend, request: &Request) -> Response {
+    let op = match PacmanKitOp::from_selector(request.selector) {
+        Some(v) => v,
+        None => return Response { kret: KERN_INVALID_ARGUMENT, output: Vec::new() },
+    };
+    if request.num_outputs > MAX_SCALARS {
+        return Response { kret: KERN_INVALID_ARGUMENT, output: Vec::new() };
+    }
+
+    let mut output = vec![0; request.num_outputs];
+    let kret = backend.call(op, &request.args, &mut output);
+    return Response { kret, output };
+}
+
+/**
+ * A PacmanKit server on the other end of a socket.
+ */
+pub struct RemoteBackend {
+    stream: Mutex<BufferedHalves>,
+}
+
+impl PacmanKitBackend for RemoteBackend {
+    fn call(&self, op: PacmanKitOp, args: &[u64], output: &mut [u64]) -> KernReturn {
+        let mut stream = self.stream.lock().unwrap();
+        let (input, out) = &mut *stream;
+
+        let request = Request { selector: op as u32, args: args.to_vec(), num_outputs: output.len() };
+        let response = match request.write_to(out).and_then(|_| Response::read_from(input)) {
+            Ok(v) => v,
+            Err(_) => return MACH_SEND_INVALID_DEST,
+        };
+
+        for (out, val) in output.iter_mut().zip(response.output) {
+            *out = val;
+        }
+        return response.kret;
+    }
+}
+
+/**
+ * Open a new connection to PacmanKit on a server (the remote `PacmanKitConnection::init`).
+ *
+ * # Return Value
+ * Returns a connection with its own IOUserClient, or `PacmanError::Unreachable` if the server couldn't be
+ * reached (or didn't say hello).
+ */
+pub fn connect(address: &Address) -> Result<PacmanKitConnection, PacmanError> {
+    let connected = open(address).and_then(|(input, out)| {
+        let mut input = BufReader::new(input);
+        let mut out = BufWriter::new(out);
+        write_hello(&mut out)?;
+        read_hello(&mut input)?;
+        return Ok((input, out));
+    });
+
+    return match connected {
+        Ok(stream) => Ok(PacmanKitConnection::with_backend(Box::new(RemoteBackend { stream: Mutex::new(stream) }))),
+        Err(err) => Err(PacmanError::Unreachable(err.kind())),
+    };
+}
+
+/**
+ * Answer one client's requests on `backend` until it hangs up.
+ */
+pub fn serve_client(backend: &dyn PacmanKitBackend, input: impl Read, out: impl Write) -> std::io::Result<()> {
+    let mut input = BufReader::new(input);
+    let mut out = BufWriter::new(out);
+    write_hello(&mut out)?;
+    read_hello(&mut input)?;
+
+    while let Some(request) = Request::read_from(&mut input)? {
+        handle_request(backend, &request).write_to(&mut out)?;
+    }
+    return Ok(());
+}
+
+/**
+ * A bound server socket.
+ */
+pub enum Listener {
+    #[cfg(unix)]
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl Listener {
+    /**
+     * Start listening on `address` (an existing Unix socket file is replaced).
+     */
+    pub fn bind(address: &Address) -> std::io::Result<Self> {
+        return match address {
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                let _ = std::fs::remove_file(path);
+                Ok(Listener::Unix(UnixListener::bind(path)?))
+            },
+            #[cfg(not(unix))]
+            Address::Unix(_) => Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Unix sockets aren't supported here")),
+            Address::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+        };
+    }
+
+    /**
+     * Where clients can reach us (with the real port if we bound to port 0).
+     */
+    pub fn address(&self) -> std::io::Result<Address> {
+        return match self {
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let addr = listener.local_addr()?;
+                let path = addr.as_pathname().ok_or_else(|| invalid_data("Unnamed Unix socket"))?;
+                Ok(Address::Unix(path.to_string_lossy().into_owned()))
+            },
+            Listener::Tcp(listener) => Ok(Address::Tcp(listener.local_addr()?.to_string())),
+        };
+    }
+
+    /// Wait for the next client
+    fn accept(&self) -> std::io::Result<Halves> {
+        return match self {
+            #[cfg(unix)]
+            Listener::Unix(listener) => unix_halves(listener.accept()?.0),
+            Listener::Tcp(listener) => tcp_halves(listener.accept()?.0),
+        };
+    }
+}
+
+/**
+ * Serve PacmanKit forever: every client gets its own thread and its own connection from `open_connection`
+ * (eg. `sim::kernel::connect` on a shared simulated kernel, or `PacmanKitConnection::init` on a lab Mac).
+ * Clients we can't accept or open a connection for are hung up on, and the server carries on with the next one.
+ */
+pub fn serve<F>(listener: Listener, open_connection: F) -> ! where
+    F: Fn() -> Result<PacmanKitConnection, PacmanError> + Send + Clone + 'static {
+    loop {
+        let (input, out) = match listener.accept() {
+            Ok(v) => v,
+            Err(err) => {
+                println!("Couldn't accept a client ({})", err);
+                continue;
+            }
+        };
+        let open_connection = open_connection.clone();
+        thread::spawn(move || {
+            let connection = match open_connection() {
+                Ok(v) => v,
+                Err(err) => {
+                    println!("Couldn't open a PacmanKit connection for a new client ({})", err);
+                    return;
+                }
+            };
+            if let Err(err) = serve_client(&connection, input, out) {
+                println!("Dropped a client (error {})", err);
+            }
+        });
+    }
+}
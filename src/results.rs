@@ -0,0 +1,239 @@
+/*!
+ * Structured experiment output.
+ *
+ * Experiments report their raw measurements as `ExperimentRecord`s to a `ResultsSink` instead of
+ * printing Python literals to stdout. Two sinks are provided: JSON Lines (one JSON object per line)
+ * and CSV (one row per record). Both can be loaded straight into a notebook with pandas.
+ */
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use crate::timer;
+
+/// The core we are running on
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+fn current_core() -> u64 {
+    return crate::qos::core_id();
+}
+
+/// The core we are running on
+#[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
+fn current_core() -> u64 {
+    return 0;
+}
+
+/// A timestamp for new records
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub fn current_time() -> u64 {
+    return crate::libdarwin::mach::gettime();
+}
+
+/// A timestamp for new records
+#[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
+pub fn current_time() -> u64 {
+    return std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or(0);
+}
+
+/**
+ * One set of raw measurements produced by an experiment.
+ *
+ * The timer backend, core and timestamp are filled in automatically when the record is created,
+ * so records should be created on the thread (and core) that took the measurements.
+ */
+#[derive(Clone, Debug, Serialize)]
+pub struct ExperimentRecord {
+    /// Which experiment produced this record (eg. `data_evict_reload`).
+    pub experiment: String,
+
+    /// Parameters that distinguish this record from the others in the same experiment.
+    pub params: Map<String, Value>,
+
+    /// Which timer backend took the measurements.
+    pub timer: String,
+
+    /// The core the measurements were taken on (see `qos::core_id`, always 0 off Apple silicon).
+    pub core: u64,
+
+    /// `mach_absolute_time` when the record was created (nanoseconds since the epoch off Apple silicon).
+    pub timestamp: u64,
+
+    /// The raw measurements (latencies or miss counts, depending on the experiment).
+    pub samples: Vec<u64>,
+}
+
+impl ExperimentRecord {
+    /**
+     * Create a new record for a given experiment with no parameters and no samples.
+     */
+    pub fn new(experiment: &str) -> Self {
+        return Self {
+            experiment: experiment.to_string(),
+            params: Map::new(),
+            timer: timer::name().to_string(),
+            core: current_core(),
+            timestamp: current_time(),
+            samples: Vec::new(),
+        };
+    }
+
+    /**
+     * Add a parameter to this record.
+     */
+    pub fn param<V: Into<Value>>(mut self, key: &str, value: V) -> Self {
+        self.params.insert(key.to_string(), value.into());
+        return self;
+    }
+
+    /**
+     * Add a pointer parameter to this record. Pointers are recorded as hex strings since
+     * kernel pointers (and especially signed ones) don't survive a round trip through a double.
+     */
+    pub fn param_ptr(self, key: &str, value: u64) -> Self {
+        return self.param(key, format!("0x{:X}", value));
+    }
+
+    /**
+     * Set the raw samples of this record.
+     */
+    pub fn samples(mut self, samples: &[u64]) -> Self {
+        self.samples = samples.to_vec();
+        return self;
+    }
+}
+
+/**
+ * Somewhere to put experiment results.
+ */
+pub trait ResultsSink {
+    /**
+     * Write one record to the sink.
+     */
+    fn record(&mut self, record: &ExperimentRecord) -> std::io::Result<()>;
+
+    /**
+     * Make sure everything recorded so far has reached the underlying writer.
+     */
+    fn flush(&mut self) -> std::io::Result<()>;
+}
+
+/**
+ * The supported output formats.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OutputFormat {
+    JsonLines,
+    Csv,
+}
+
+impl OutputFormat {
+    /**
+     * Guess the output format from a file name (`.csv` is CSV, everything else is JSON Lines).
+     */
+    pub fn from_path(path: &str) -> Self {
+        if path.to_lowercase().ends_with(".csv") {
+            return OutputFormat::Csv;
+        }
+        return OutputFormat::JsonLines;
+    }
+}
+
+/**
+ * Writes every record as a single line of JSON.
+ */
+pub struct JsonLinesSink<W: Write> {
+    out: W,
+}
+
+impl<W: Write> JsonLinesSink<W> {
+    pub fn new(out: W) -> Self {
+        return Self { out };
+    }
+}
+
+impl<W: Write> ResultsSink for JsonLinesSink<W> {
+    fn record(&mut self, record: &ExperimentRecord) -> std::io::Result<()> {
+        serde_json::to_writer(&mut self.out, record)?;
+        return self.out.write_all(b"\n");
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.out.flush();
+    }
+}
+
+/**
+ * Writes every record as a row of a CSV table.
+ *
+ * The columns are `experiment,timer,core,timestamp,params,samples`. Since every experiment has
+ * different parameters and a different number of samples, `params` is a `;` separated list of
+ * `key=value` pairs and `samples` is a `;` separated list of numbers.
+ */
+pub struct CsvSink<W: Write> {
+    out: W,
+    wrote_header: bool,
+}
+
+impl<W: Write> CsvSink<W> {
+    pub fn new(out: W) -> Self {
+        return Self { out, wrote_header: false };
+    }
+}
+
+/// Quote a CSV field if it needs it (RFC 4180)
+fn csv_field(field: &str) -> String {
+    if field.contains(',') || field.contains('"') || field.contains('\n') {
+        return format!("\"{}\"", field.replace('"', "\"\""));
+    }
+    return field.to_string();
+}
+
+impl<W: Write> ResultsSink for CsvSink<W> {
+    fn record(&mut self, record: &ExperimentRecord) -> std::io::Result<()> {
+        if !self.wrote_header {
+            writeln!(self.out, "experiment,timer,core,timestamp,params,samples")?;
+            self.wrote_header = true;
+        }
+
+        let params : Vec<String> = record.params.iter().map(|(key, value)| {
+            match value {
+                Value::String(s) => format!("{}={}", key, s),
+                other => format!("{}={}", key, other),
+            }
+        }).collect();
+        let samples : Vec<String> = record.samples.iter().map(|s| s.to_string()).collect();
+
+        return writeln!(
+            self.out,
+            "{},{},{},{},{},{}",
+            csv_field(&record.experiment),
+            csv_field(&record.timer),
+            record.core,
+            record.timestamp,
+            csv_field(&params.join(";")),
+            samples.join(";")
+        );
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.out.flush();
+    }
+}
+
+/**
+ * Open a results sink writing to a file.
+ *
+ * # Arguments
+ * * `path`: Where to write the results. Will be truncated if it already exists.
+ * * `format`: Which format to write them in (see `OutputFormat::from_path`).
+ */
+pub fn open_sink(path: &str, format: OutputFormat) -> std::io::Result<Box<dyn ResultsSink>> {
+    let file = BufWriter::new(File::create(path)?);
+    return Ok(match format {
+        OutputFormat::JsonLines => Box::new(JsonLinesSink::new(file)),
+        OutputFormat::Csv => Box::new(CsvSink::new(file)),
+    });
+}
@@ -1,58 +1,27 @@
 /*!
- * Generate a trampoline made of return instructions.
+ * Generate a trampoline made of return instructions (or any other `payload::Payload`).
  */
 
-use crate::*;
 
+use crate::aarch64;
+use crate::cache;
+use crate::error::PacmanError;
+use crate::libdarwin::mach::*;
+use crate::payload::Payload;
 /// The opcode for a return instruction
-pub const RET_INST : u32 = 0xd65f03c0;
-pub const NOP_INST : u32 = 0xd503201f;
+pub const RET_INST : u32 = aarch64::RET;
+pub const NOP_INST : u32 = aarch64::NOP;
 
 /**
  * Fill a page with `ret` instructions, and make it executable.
+ * Panics if `page` is smaller than a page.
  *
  * # Return Value
  * Returns Error on failure, Ok on success.
  */
-pub unsafe fn mk_retpoline_page(page: &mut [u8]) -> Result<(), ()> {
-    if page.len() < cache::PAGE_SIZE {
-        return Err(());
-    }
-
-    let retpoline_ptr = page.as_mut_ptr();
-
-    match mach_vm_protect(
-        mach_task_self(),
-        retpoline_ptr,
-        cache::PAGE_SIZE,
-        0,
-        (VM_PROT_READ | VM_PROT_WRITE)
-    ) {
-        KERN_SUCCESS => {},
-        err => {
-            println!("Error calling mach_vm_protect! Error code 0x{:X} ({:?})", err, std::ffi::CStr::from_ptr(mach_error_string(err)));
-            return Err(());
-        }
-    }
-
-    let page_as_u32 = core::slice::from_raw_parts_mut(retpoline_ptr as *mut u32, cache::PAGE_SIZE / core::mem::size_of::<u32>());
-    page_as_u32.fill(RET_INST);
-
-    match mach_vm_protect(
-        mach_task_self(),
-        retpoline_ptr,
-        cache::PAGE_SIZE,
-        0,
-        (VM_PROT_READ | VM_PROT_EXECUTE)
-    ) {
-        KERN_SUCCESS => {},
-        err => {
-            println!("Error calling mach_vm_protect! Error code 0x{:X} ({:?})", err, std::ffi::CStr::from_ptr(mach_error_string(err)));
-            return Err(());
-        }
-    }
-
-    return Ok(());
+pub unsafe fn mk_retpoline_page(page: &mut [u8]) -> Result<(), PacmanError> {
+    assert!(page.len() >= cache::PAGE_SIZE, "A retpoline page needs {} bytes (got {})", cache::PAGE_SIZE, page.len());
+    return mk_payload(&Payload::new(page.as_mut_ptr() as u64, cache::PAGE_SIZE));
 }
 
 /**
@@ -61,38 +30,45 @@ pub unsafe fn mk_retpoline_page(page: &mut [u8]) -> Result<(), ()> {
  * # Return Value
  * Returns Error on failure, Ok on success.
  */
- pub unsafe fn mk_retpoline_addr(addr: u64, size: usize) -> Result<(), ()> {
-    let retpoline_ptr = addr as *mut u8;
+ pub unsafe fn mk_retpoline_addr(addr: u64, size: usize) -> Result<(), PacmanError> {
+    return mk_payload(&Payload::new(addr, size));
+}
+
+/**
+ * Copy `payload` to where it was built for (memory we own), make it executable, and invalidate the
+ * instruction cache over it (so code that was there before doesn't keep running).
+ *
+ * # Return Value
+ * Returns Error on failure, Ok on success.
+ */
+pub unsafe fn mk_payload(payload: &Payload) -> Result<(), PacmanError> {
+    let payload_ptr = payload.base() as *mut u8;
+    let size = (payload.end() - payload.base()) as usize;
 
     match mach_vm_protect(
         mach_task_self(),
-        retpoline_ptr,
+        payload_ptr,
         size,
         0,
-        (VM_PROT_READ | VM_PROT_WRITE)
+        VM_PROT_READ | VM_PROT_WRITE
     ) {
         KERN_SUCCESS => {},
-        err => {
-            println!("Error calling mach_vm_protect! Error code 0x{:X} ({:?})", err, std::ffi::CStr::from_ptr(mach_error_string(err)));
-            return Err(());
-        }
+        kret => return Err(PacmanError::Mach { call: "mach_vm_protect", kret }),
     }
 
-    let page_as_u32 = core::slice::from_raw_parts_mut(retpoline_ptr as *mut u32, size / core::mem::size_of::<u32>());
-    page_as_u32.fill(RET_INST);
+    let payload_as_u32 = core::slice::from_raw_parts_mut(payload_ptr as *mut u32, payload.words().len());
+    payload_as_u32.copy_from_slice(payload.words());
+    sys_icache_invalidate(payload_ptr, size);
 
     match mach_vm_protect(
         mach_task_self(),
-        retpoline_ptr,
+        payload_ptr,
         size,
         0,
-        (VM_PROT_READ | VM_PROT_EXECUTE)
+        VM_PROT_READ | VM_PROT_EXECUTE
     ) {
         KERN_SUCCESS => {},
-        err => {
-            println!("Error calling mach_vm_protect! Error code 0x{:X} ({:?})", err, std::ffi::CStr::from_ptr(mach_error_string(err)));
-            return Err(());
-        }
+        kret => return Err(PacmanError::Mach { call: "mach_vm_protect", kret }),
     }
 
     return Ok(());
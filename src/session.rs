@@ -0,0 +1,477 @@
+/*!
+ * Recording and replaying PacmanKit sessions.
+ *
+ * A `SessionRecorder` wraps `PacmanKitConnection`s so every call they make (selector, inputs, outputs and
+ * `kern_return_t`, for scalar and structure methods alike) is logged to a session file. A `SessionReplay`
+ * hands out connections that answer the same calls from the file instead of the kernel, so a failed run
+ * (eg. one that panicked the kernel) can be stepped through again offline with exactly the same addresses
+ * and PACs.
+ *
+ * Connections are numbered in the order they were opened: the first connection opened during a replay is
+ * served the calls of the first connection opened during the recording, and so on. Replayed calls have to
+ * match the recording (same selector, inputs and number of outputs). The first one that doesn't ends the
+ * replay: it and every call after it fail with `KERN_FAILURE` (see `SessionReplay::divergence`).
+ *
+ * Only the kernel calls are recorded. Timing done by the attacker itself isn't, so a replayed bruteforce
+ * only follows the recording for as long as it makes the same decisions.
+ *
+ * # File Format
+ * All integers are little endian.
+ * ```text
+ * Header:  "PACKITSN" (8 bytes) | version: u32
+ * Record:  timestamp: u64 | connection: u32 | selector: u32 | kern_return: i32 | structure: u16 |
+ *          num_args: u16 | num_outputs: u16 | arg: u64 (x num_args) | output: u64 (x num_outputs)
+ * ```
+ * Timestamps are nanoseconds since the recording started. `structure` is 1 for structure method calls
+ * (`call_struct`, eg. a `batch`), whose input and output structures are stored as their little endian
+ * u64s. Records are flushed as soon as they are made, so a session survives the experiment crashing. To
+ * survive a kernel panic, record on another machine (`--remote`).
+ */
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use crate::error::PacmanError;
+use crate::pacmankit::*;
+
+/// Magic bytes at the start of every session file
+pub const SESSION_MAGIC : &[u8; 8] = b"PACKITSN";
+
+/// Current session file format version
+pub const SESSION_VERSION : u32 = 2;
+
+/// Bytes in a record before its scalars
+const RECORD_HEADER_SIZE : usize = 26;
+
+/**
+ * One PacmanKit call.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionRecord {
+    /// Nanoseconds since the recording started (when the call returned)
+    pub timestamp: u64,
+
+    /// Which connection made the call (in the order the connections were opened)
+    pub connection: u32,
+
+    /// The `PacmanKitOp` selector
+    pub selector: u32,
+
+    /// What the call returned
+    pub kret: KernReturn,
+
+    /// Was it a structure method call? (`args` and `output` are then the structures' u64s)
+    pub structure: bool,
+
+    /// The scalar inputs
+    pub args: Vec<u64>,
+
+    /// The scalar outputs (as many as the caller asked for)
+    pub output: Vec<u64>,
+}
+
+impl SessionRecord {
+    /**
+     * Write this record to a session file.
+     */
+    pub fn write_to(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let (num_args, num_outputs) : (u16, u16) = match (self.args.len().try_into(), self.output.len().try_into()) {
+            (Ok(args), Ok(outputs)) => (args, outputs),
+            _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Too many scalars for one session record")),
+        };
+
+        let mut buf = Vec::with_capacity(RECORD_HEADER_SIZE + 8 * (self.args.len() + self.output.len()));
+        buf.extend_from_slice(&self.timestamp.to_le_bytes());
+        buf.extend_from_slice(&self.connection.to_le_bytes());
+        buf.extend_from_slice(&self.selector.to_le_bytes());
+        buf.extend_from_slice(&self.kret.to_le_bytes());
+        buf.extend_from_slice(&(self.structure as u16).to_le_bytes());
+        buf.extend_from_slice(&num_args.to_le_bytes());
+        buf.extend_from_slice(&num_outputs.to_le_bytes());
+        for val in self.args.iter().chain(self.output.iter()) {
+            buf.extend_from_slice(&val.to_le_bytes());
+        }
+
+        return out.write_all(&buf);
+    }
+}
+
+/// The shared state of a `SessionRecorder`
+struct RecorderState {
+    out: Box<dyn Write + Send>,
+    start: Instant,
+    next_connection: u32,
+
+    /// Why the first record that couldn't be written wasn't (recording stops there)
+    error: Option<std::io::ErrorKind>,
+}
+
+/**
+ * Records the calls of every connection it wraps to one session file.
+ *
+ * Cloning a recorder gives another handle to the same session.
+ */
+#[derive(Clone)]
+pub struct SessionRecorder(Arc<Mutex<RecorderState>>);
+
+impl SessionRecorder {
+    /**
+     * Start a new session on a writer (writes the file header).
+     */
+    pub fn new(mut out: Box<dyn Write + Send>) -> std::io::Result<Self> {
+        out.write_all(SESSION_MAGIC)?;
+        out.write_all(&SESSION_VERSION.to_le_bytes())?;
+        out.flush()?;
+        return Ok(Self(Arc::new(Mutex::new(RecorderState {
+            out,
+            start: Instant::now(),
+            next_connection: 0,
+            error: None,
+        }))));
+    }
+
+    /**
+     * Start a new session file at `path` (truncating it if it exists).
+     */
+    pub fn create(path: &str) -> std::io::Result<Self> {
+        return Self::new(Box::new(BufWriter::new(File::create(path)?)));
+    }
+
+    /**
+     * Record every call made through `connection` from now on.
+     *
+     * # Return Value
+     * Returns a connection that runs every call on `connection` and records it.
+     */
+    pub fn record(&self, connection: PacmanKitConnection) -> PacmanKitConnection {
+        let mut state = self.0.lock().unwrap();
+        let id = state.next_connection;
+        state.next_connection += 1;
+
+        return PacmanKitConnection::with_backend(Box::new(RecordingBackend {
+            inner: connection,
+            connection: id,
+            recorder: self.clone(),
+        }));
+    }
+
+    /**
+     * Why the session stopped being recorded (`None` if every call so far was recorded).
+     */
+    pub fn error(&self) -> Option<std::io::ErrorKind> {
+        return self.0.lock().unwrap().error;
+    }
+
+    /**
+     * Log one call. A session that can't be written to doesn't stop the experiment (see `error`).
+     */
+    fn log(&self, connection: u32, structure: bool, op: PacmanKitOp, args: &[u64], output: &[u64], kret: KernReturn) {
+        let mut state = self.0.lock().unwrap();
+        if state.error.is_some() {
+            return;
+        }
+        let record = SessionRecord {
+            timestamp: state.start.elapsed().as_nanos() as u64,
+            connection,
+            selector: op as u32,
+            kret,
+            structure,
+            args: args.to_vec(),
+            output: output.to_vec(),
+        };
+
+        let written = record.write_to(&mut state.out).and_then(|_| state.out.flush());
+        if let Err(err) = written {
+            state.error = Some(err.kind());
+        }
+    }
+}
+
+/**
+ * Runs calls on a connection and records them.
+ */
+struct RecordingBackend {
+    inner: PacmanKitConnection,
+    connection: u32,
+    recorder: SessionRecorder,
+}
+
+impl PacmanKitBackend for RecordingBackend {
+    fn call(&self, op: PacmanKitOp, args: &[u64], output: &mut [u64]) -> KernReturn {
+        let kret = self.inner.call(op, args, output);
+        self.recorder.log(self.connection, false, op, args, output, kret);
+        return kret;
+    }
+
+    /// Structures are recorded as u64s, so they have to be a whole number of them
+    fn call_struct(&self, op: PacmanKitOp, input: &[u8], output: &mut [u8]) -> KernReturn {
+        if !input.len().is_multiple_of(8) || !output.len().is_multiple_of(8) {
+            return KERN_INVALID_ARGUMENT;
+        }
+        let kret = self.inner.call_struct(op, input, output);
+        self.recorder.log(self.connection, true, op, &to_words(input), &to_words(output), kret);
+        return kret;
+    }
+}
+
+/// A structure as little endian u64s (any trailing bytes are dropped)
+fn to_words(bytes: &[u8]) -> Vec<u64> {
+    return bytes.chunks_exact(8).map(|c| u64::from_le_bytes(c.try_into().unwrap())).collect();
+}
+
+/// Shorthand for a corrupt session error
+fn invalid_data(msg: &str) -> std::io::Error {
+    return std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+}
+
+/**
+ * Reads `SessionRecord`s back out of a session file.
+ */
+pub struct SessionReader<R: Read> {
+    input: R,
+}
+
+impl SessionReader<BufReader<File>> {
+    /**
+     * Open a session file.
+     */
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        return Self::new(BufReader::new(File::open(path)?));
+    }
+}
+
+impl<R: Read> SessionReader<R> {
+    /**
+     * Start reading a session (checks the file header).
+     */
+    pub fn new(mut input: R) -> std::io::Result<Self> {
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != SESSION_MAGIC {
+            return Err(invalid_data("Not a PacmanKit session"));
+        }
+
+        let mut version = [0u8; 4];
+        input.read_exact(&mut version)?;
+        if u32::from_le_bytes(version) != SESSION_VERSION {
+            return Err(invalid_data("Unsupported session version"));
+        }
+
+        return Ok(Self { input });
+    }
+
+    /**
+     * Read the next record. Returns `Ok(None)` at the end of the session.
+     */
+    pub fn next_record(&mut self) -> std::io::Result<Option<SessionRecord>> {
+        let mut fixed = [0u8; RECORD_HEADER_SIZE];
+
+        // Distinguish a clean end of file from a truncated record
+        let mut read = 0;
+        while read < fixed.len() {
+            let n = self.input.read(&mut fixed[read..])?;
+            if n == 0 {
+                if read == 0 {
+                    return Ok(None);
+                }
+                return Err(invalid_data("Truncated session record"));
+            }
+            read += n;
+        }
+
+        let u32_at = |i: usize| u32::from_le_bytes(fixed[i..i+4].try_into().unwrap());
+        let u16_at = |i: usize| u16::from_le_bytes([fixed[i], fixed[i + 1]]);
+        let num_args = u16_at(22) as usize;
+        let num_outputs = u16_at(24) as usize;
+
+        let mut scalars = vec![0u8; 8 * (num_args + num_outputs)];
+        self.input.read_exact(&mut scalars)?;
+        let mut scalars : Vec<u64> = scalars.chunks(8).map(|c| u64::from_le_bytes(c.try_into().unwrap())).collect();
+        let output = scalars.split_off(num_args);
+
+        return Ok(Some(SessionRecord {
+            timestamp: u64::from_le_bytes(fixed[0..8].try_into().unwrap()),
+            connection: u32_at(8),
+            selector: u32_at(12),
+            kret: u32_at(16) as KernReturn,
+            structure: u16_at(20) != 0,
+            args: scalars,
+            output,
+        }));
+    }
+
+    /**
+     * Read every remaining record.
+     */
+    pub fn read_all(&mut self) -> std::io::Result<Vec<SessionRecord>> {
+        let mut records = Vec::new();
+        while let Some(record) = self.next_record()? {
+            records.push(record);
+        }
+        return Ok(records);
+    }
+}
+
+/**
+ * Where a replay stopped following the recording.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    /// The connection that made the call
+    pub connection: u32,
+
+    /// How many calls that connection had made before this one
+    pub call_index: usize,
+
+    /// What the recording expected instead (`None` if the connection ran past the end of its recording)
+    pub expected: Option<SessionRecord>,
+
+    /// The call that was made
+    pub structure: bool,
+    pub selector: u32,
+    pub args: Vec<u64>,
+    pub num_outputs: usize,
+}
+
+/// The shared state of a `SessionReplay`
+struct ReplayState {
+    /// The calls left to serve for each connection
+    remaining: Vec<VecDeque<SessionRecord>>,
+
+    /// How many calls each connection has been served
+    served: Vec<usize>,
+
+    next_connection: u32,
+    divergence: Option<Divergence>,
+}
+
+/**
+ * Serves the calls of a recorded session back to the connections it hands out.
+ *
+ * Cloning a replay gives another handle to the same replay.
+ */
+#[derive(Clone)]
+pub struct SessionReplay(Arc<Mutex<ReplayState>>);
+
+impl SessionReplay {
+    /**
+     * Replay `records` (in the order they were recorded).
+     */
+    pub fn new(records: Vec<SessionRecord>) -> Self {
+        let num_connections = records.iter().map(|r| r.connection as usize + 1).max().unwrap_or(0);
+        let mut remaining = vec![VecDeque::new(); num_connections];
+        for record in records {
+            remaining[record.connection as usize].push_back(record);
+        }
+
+        return Self(Arc::new(Mutex::new(ReplayState {
+            remaining,
+            served: vec![0; num_connections],
+            next_connection: 0,
+            divergence: None,
+        })));
+    }
+
+    /**
+     * Replay a session file.
+     */
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        return Ok(Self::new(SessionReader::open(path)?.read_all()?));
+    }
+
+    /**
+     * Open the next recorded connection (the replay's `PacmanKitConnection::init`).
+     *
+     * # Return Value
+     * Returns `PacmanError::NotRecorded` if the recording didn't open any more connections.
+     */
+    pub fn connect(&self) -> Result<PacmanKitConnection, PacmanError> {
+        let mut state = self.0.lock().unwrap();
+        let id = state.next_connection;
+        if id as usize >= state.remaining.len() {
+            return Err(PacmanError::NotRecorded(id));
+        }
+        state.next_connection += 1;
+
+        return Ok(PacmanKitConnection::with_backend(Box::new(ReplayBackend {
+            replay: self.clone(),
+            connection: id,
+        })));
+    }
+
+    /**
+     * Where the replay stopped following the recording (`None` if it hasn't).
+     */
+    pub fn divergence(&self) -> Option<Divergence> {
+        return self.0.lock().unwrap().divergence.clone();
+    }
+
+    /**
+     * How many recorded calls haven't been replayed yet.
+     */
+    pub fn remaining(&self) -> usize {
+        return self.0.lock().unwrap().remaining.iter().map(|calls| calls.len()).sum();
+    }
+}
+
+/**
+ * Answers one connection's calls from a `SessionReplay`.
+ */
+struct ReplayBackend {
+    replay: SessionReplay,
+    connection: u32,
+}
+
+impl ReplayBackend {
+    /**
+     * Answer the next call of this connection from the recording (`output` has room for as many u64s as the
+     * caller asked for).
+     */
+    fn serve(&self, structure: bool, op: PacmanKitOp, args: &[u64], output: &mut [u64]) -> KernReturn {
+        let mut state = self.replay.0.lock().unwrap();
+        if state.divergence.is_some() {
+            return KERN_FAILURE;
+        }
+
+        let connection = self.connection as usize;
+        let expected = state.remaining[connection].pop_front();
+        let matches = match &expected {
+            Some(record) => record.structure == structure && record.selector == op as u32 && record.args == args && record.output.len() == output.len(),
+            None => false,
+        };
+
+        if !matches {
+            state.divergence = Some(Divergence {
+                connection: self.connection,
+                call_index: state.served[connection],
+                expected,
+                structure,
+                selector: op as u32,
+                args: args.to_vec(),
+                num_outputs: output.len(),
+            });
+            return KERN_FAILURE;
+        }
+
+        let record = expected.unwrap();
+        output.copy_from_slice(&record.output);
+        state.served[connection] += 1;
+        return record.kret;
+    }
+}
+
+impl PacmanKitBackend for ReplayBackend {
+    fn call(&self, op: PacmanKitOp, args: &[u64], output: &mut [u64]) -> KernReturn {
+        return self.serve(false, op, args, output);
+    }
+
+    fn call_struct(&self, op: PacmanKitOp, input: &[u8], output: &mut [u8]) -> KernReturn {
+        let mut words = vec![0u64; output.len() / 8];
+        let kret = self.serve(true, op, &to_words(input), &mut words);
+        for (bytes, word) in output.chunks_exact_mut(8).zip(words) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        return kret;
+    }
+}
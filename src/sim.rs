@@ -0,0 +1,18 @@
+/*!
+ * Stand-ins for the hardware and the kernel, so the attacks can run (and be tested) anywhere.
+ */
+
+// Pointer authentication with real keys
+pub mod pauth;
+
+// A model of the L2 cache (for timing and the PACMAN gadget's footprint)
+pub mod cache;
+
+// Noise to add to the simulated kernel
+pub mod noise;
+
+// A simulated kernel with PacmanKit loaded (a `PacmanKitBackend`)
+pub mod kernel;
+
+// Synthetic kernelcaches (MH_FILESET) for the offline analysis in `kernelcache`
+pub mod kernelcache;
@@ -0,0 +1,124 @@
+/*!
+ * A model of the M1 L2 cache (the cache every eviction set in the attacks targets).
+ *
+ * The L2 is physically indexed and tagged, shared by data and instructions, and uses LRU replacement
+ * within a set. The L1s aren't modelled: a hit is an L2 hit and a miss goes to DRAM.
+ *
+ * The simulated kernel colours its physical pages (see `PAGE_COLOURS`), so the L2 set of an address is
+ * the same virtually and physically. That's what lets eviction sets built out of virtual address offsets
+ * (`evset::data_pevset`) contend with their targets.
+ */
+use rand::Rng;
+use rand::rngs::StdRng;
+use crate::cache::{L2_LINESIZE, L2_SETS, L2_WAYS, PAGE_SIZE};
+
+/// Latency of a load that hits (as reported by the simulated timer, without the timer overhead)
+pub const LOAD_HIT_LATENCY : u64 = 20;
+
+/// Latency of a load that misses
+pub const LOAD_MISS_LATENCY : u64 = 90;
+
+/// Latency of executing an address that hits
+pub const FETCH_HIT_LATENCY : u64 = 30;
+
+/// Latency of executing an address that misses
+pub const FETCH_MISS_LATENCY : u64 = 120;
+
+/// How many different L2 sets a physical page number decides (the low bits of the PPN have to match the VPN)
+pub const PAGE_COLOURS : u64 = (L2_SETS * L2_LINESIZE / PAGE_SIZE) as u64;
+
+/**
+ * A set associative cache with LRU replacement.
+ */
+#[derive(Clone, Debug)]
+pub struct Cache {
+    /// The lines in every set (line numbers, least recently used first)
+    sets: Vec<Vec<u64>>,
+
+    /// How many lines fit in a set
+    ways: usize,
+}
+
+impl Cache {
+    /// An empty cache shaped like the M1 L2
+    pub fn new() -> Self {
+        return Self {
+            sets: vec![Vec::new(); L2_SETS],
+            ways: L2_WAYS,
+        };
+    }
+
+    /// The line number and set index of a physical address
+    fn locate(paddr: u64) -> (u64, usize) {
+        // Same as `cache::get_cache_set_generic`, without the floating point (this runs for every simulated access)
+        let line = paddr / L2_LINESIZE as u64;
+        return (line, (line % L2_SETS as u64) as usize);
+    }
+
+    /**
+     * Access the line holding `paddr`, filling it on a miss (evicting the least recently used line of its set).
+     *
+     * # Return Value
+     * Returns true on a hit.
+     */
+    pub fn access(&mut self, paddr: u64) -> bool {
+        let (line, set_idx) = Cache::locate(paddr);
+        let set = &mut self.sets[set_idx];
+
+        let hit = match set.iter().position(|l| *l == line) {
+            Some(pos) => {
+                set.remove(pos);
+                true
+            },
+            None => {
+                if set.len() >= self.ways {
+                    set.remove(0);
+                }
+                false
+            }
+        };
+
+        set.push(line);
+        return hit;
+    }
+
+    /// Is the line holding `paddr` cached? (Doesn't update the replacement state)
+    pub fn contains(&self, paddr: u64) -> bool {
+        let (line, set_idx) = Cache::locate(paddr);
+        return self.sets[set_idx].contains(&line);
+    }
+
+    /// Drop the line holding `paddr` (if it's cached)
+    pub fn evict(&mut self, paddr: u64) {
+        let (line, set_idx) = Cache::locate(paddr);
+        self.sets[set_idx].retain(|l| *l != line);
+    }
+
+    /// Drop the least recently used line of the set `paddr` maps to
+    pub fn evict_lru(&mut self, paddr: u64) {
+        let (_, set_idx) = Cache::locate(paddr);
+        if !self.sets[set_idx].is_empty() {
+            self.sets[set_idx].remove(0);
+        }
+    }
+
+    /// Drop every line with probability `rate`
+    pub fn evict_randomly(&mut self, rng: &mut StdRng, rate: f64) {
+        for set in self.sets.iter_mut() {
+            set.retain(|_| !rng.gen_bool(rate));
+        }
+    }
+
+    /// Empty the whole cache
+    pub fn flush(&mut self) {
+        for set in self.sets.iter_mut() {
+            set.clear();
+        }
+    }
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
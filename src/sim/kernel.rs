@@ -0,0 +1,935 @@
+/*!
+ * A simulated kernel with the PacmanKit kext loaded.
+ *
+ * `SimulatedKernel` answers every `PacmanKitOp` the way the kext would, from a fake address space:
+ * * Kernel and user page tables map 16KB virtual pages onto randomly picked physical pages.
+ * * The kernelcache is loaded at a random (KASLR) slide, with PacmanKit's text, `retpoline` region,
+ *   `PacmanKitService` vtable and `LIMIT` variable behind it.
+ * * Every connection gets its own IOUserClient on the kernel heap, with a `PacmanKitService` at
+ *   `HELPER_OFFSET` whose vtable pointer and vtable entry are signed with the kernel's PAC keys.
+ * * `call_service_routine` does the virtual call through that object (authenticating both pointers),
+ *   so a forged vtable redirects it and a badly forged one panics the (simulated) kernel.
+ *
+ * It also models enough of the CPU for the PACMAN gadget to work (see `service_routine`):
+ * * Every load and instruction fetch (kernel or user) goes through a model of the L2 (`sim::cache`).
+ * * The bounds checks on `LIMIT` are predicted by 2-bit counters, trained by every call.
+ * * When a bounds check is mispredicted while `LIMIT` isn't cached, the gadget behind it runs speculatively:
+ *   a pointer that fails authentication is never dereferenced (and leaves no footprint in the cache),
+ *   one that passes is loaded from or branched to (and does).
+ *
+ * The attacker's own timed loads and executions go through the same cache (see `time_access`).
+ *
+ * All of this is perfectly quiet unless a noise model is installed with `set_noise` (see `sim::noise`).
+ *
+ * Connect to it with `connect`, which returns a regular `PacmanKitConnection`. To run the attacks against it
+ * unchanged, `install` it on the current thread: `PacmanKitConnection::init` then connects to it, and the
+ * `Simulated` timer backend times accesses through it. The experiments that need an offset profile get the
+ * simulated kernel's from `offset_profile`.
+ */
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::{Arc, Mutex};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use crate::attacks::pacman::get_salt;
+use crate::batch::{self, Batch, BATCH_RESULT_SIZE};
+use crate::cache::{PAGE_OFFSET_MASK, PAGE_SIZE};
+use crate::error::PacmanError;
+use crate::pacmankit::*;
+use crate::profile::{self, OffsetProfile};
+use crate::sim::cache::{self, Cache, PAGE_COLOURS};
+use crate::sim::noise::NoiseModel;
+use crate::sim::pauth::{self, PacKey};
+use crate::timer;
+
+/// Where the kernelcache would be without a KASLR slide
+pub const KERNEL_BASE_UNSLID : u64 = 0xFFFFFE0007004000;
+
+/// The KASLR slide is a random number of pages below this
+pub const KASLR_SLIDE_MAX : u64 = 0x20000000;
+
+/// Where PacmanKit is loaded relative to the kernel base
+pub const KEXT_OFFSET : u64 = 0x2000000;
+
+/// Where the kernel heap starts (before its own random slide, past the highest the kernelcache can be slid to)
+pub const KERNEL_HEAP_BASE : u64 = 0xFFFFFE0030000000;
+
+/// The kernel heap's slide is a random number of pages below this
+pub const KERNEL_HEAP_SLIDE_MAX : u64 = 0x4000000;
+
+/// How many bytes `GimmeMemory` allocates (the attacks place their fake vtables up to 0x24c940 bytes in)
+pub const GIMME_MEMORY_SIZE : u64 = 0x400000;
+
+/// Size of the region of `ret`s revealed by `LeakMethod`
+pub const RETPOLINE_SIZE : u64 = 0x8000;
+
+/// Size of a PacmanUser IOUserClient object
+pub const IOUSERCLIENT_SIZE : u64 = 0x100;
+
+/// Size of a proc struct
+pub const PROC_SIZE : u64 = 0x600;
+
+/// Size of a task struct
+pub const TASK_SIZE : u64 = 0x800;
+
+/// Offset of the `PacmanKitService` helper in a PacmanUser IOUserClient
+pub const HELPER_OFFSET : u64 = 0xE0;
+
+/// Offset of `proc.task`
+pub const PROC_TASK_OFFSET : u64 = 0x10;
+
+/// Salt constant of a `PacmanKitService` vtable pointer
+pub const VTABLE_PTR_SALT : u64 = 0xd986;
+
+/// Salt constant of a `PacmanKitService` vtable entry
+pub const VTABLE_ENTRY_SALT : u64 = 0xa7d5;
+
+/// Salt constant of `proc.task`
+pub const PROC_TASK_SALT : u64 = 0xa08a;
+
+/// Initial value of the kext's `LIMIT` (`call_service_routine` and the spectre gadgets only run for indexes below it)
+pub const LIMIT_VALUE : u64 = 100;
+
+/// Encoding of AArch64 `ret`
+pub const RET_INSTRUCTION : u32 = 0xD65F03C0;
+
+/// The first 8 bytes of the kernelcache (`MH_MAGIC_64` and `CPU_TYPE_ARM64`)
+pub const MACH_HEADER_START : u64 = 0x100000CFEEDFACF;
+
+/// Where physical memory starts on M1
+pub const DRAM_BASE : u64 = 0x800000000;
+
+/// How many pages of physical memory there are (8GB)
+pub const DRAM_PAGES : u64 = 0x80000;
+
+/// Values reported by `TellMeRegs` (PMCR0, CNTKCTL_EL1)
+pub const TIMER_REGS : [u64; 2] = [0x3003400FF4FF, 0x3];
+
+// Where things live within PacmanKit
+const KEXT_EXTERNAL_METHOD : u64 = 0x100;
+const KEXT_WIN : u64 = 0x200;
+const KEXT_RETPOLINE : u64 = 0x4000;
+const KEXT_VTABLE : u64 = 0xC000;
+const KEXT_LIMIT : u64 = 0x10000;
+
+// The bounds checks on LIMIT (each has its own entry in the branch predictor)
+const KEXT_SERVICE_ROUTINE_CHECK : u64 = 0x140;
+const KEXT_READ_FOR_SPECTRE_CHECK : u64 = 0x180;
+const KEXT_EXEC_FOR_SPECTRE_CHECK : u64 = 0x1C0;
+
+/**
+ * A page table entry.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct PageTableEntry {
+    /// Physical page number
+    ppn: u64,
+
+    /// Can this page be executed?
+    executable: bool,
+}
+
+/**
+ * Which address space does a virtual address belong to?
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum AddressSpace {
+    Kernel,
+    User,
+}
+
+/**
+ * The simulated kernel. See the module documentation.
+ */
+pub struct SimulatedKernel {
+    rng: StdRng,
+
+    /// `APIAKey` (instruction pointers)
+    ia_key: PacKey,
+
+    /// `APDAKey` (data pointers)
+    da_key: PacKey,
+
+    /// Virtual page number -> page table entry, for the kernel
+    kernel_pages: HashMap<u64, PageTableEntry>,
+
+    /// Virtual page number -> page table entry, for the attacker's task
+    user_pages: HashMap<u64, PageTableEntry>,
+
+    /// Physical page number -> contents
+    physical: HashMap<u64, Vec<u8>>,
+
+    kernel_base: u64,
+    external_method: u64,
+    win: u64,
+    retpoline: u64,
+    vtable: u64,
+    limit: u64,
+
+    /// The next free kernel heap address
+    heap_next: u64,
+
+    /// The allocation made by the last `GimmeMemory`
+    gimme_memory: Option<u64>,
+
+    /// Our proc struct
+    proc: u64,
+
+    /// Why the kernel panicked (if it did)
+    panic_message: Option<String>,
+
+    /// Every function `call_service_routine` dispatched to, in order
+    service_calls: Vec<u64>,
+
+    /// The L2 (shared by the kernel and the attacker)
+    cache: Cache,
+
+    /// Branch address -> 2-bit saturating counter (2 and up predicts the bounds check passes)
+    predictor: HashMap<u64, u8>,
+
+    /// What noise to add
+    noise: NoiseModel,
+
+    /// Where the noise comes from (kept apart from `rng` so noise doesn't change the kernel's layout)
+    noise_rng: StdRng,
+
+    /// Accesses until the next interrupt
+    until_interrupt: u64,
+}
+
+impl SimulatedKernel {
+    /**
+     * Boot a new kernel. Everything random (the KASLR slide, the PAC keys, the physical pages)
+     * is derived from `seed`, so the same seed always produces the same kernel.
+     */
+    pub fn new(seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let ia_key = PacKey { lo: rng.gen(), hi: rng.gen() };
+        let da_key = PacKey { lo: rng.gen(), hi: rng.gen() };
+        let kernel_base = KERNEL_BASE_UNSLID + rng.gen_range(0..KASLR_SLIDE_MAX / PAGE_SIZE as u64) * PAGE_SIZE as u64;
+        let heap_next = KERNEL_HEAP_BASE + rng.gen_range(0..KERNEL_HEAP_SLIDE_MAX / PAGE_SIZE as u64) * PAGE_SIZE as u64;
+        let kext = kernel_base + KEXT_OFFSET;
+
+        let mut kernel = Self {
+            rng,
+            ia_key,
+            da_key,
+            kernel_pages: HashMap::new(),
+            user_pages: HashMap::new(),
+            physical: HashMap::new(),
+            kernel_base,
+            external_method: kext + KEXT_EXTERNAL_METHOD,
+            win: kext + KEXT_WIN,
+            retpoline: kext + KEXT_RETPOLINE,
+            vtable: kext + KEXT_VTABLE,
+            limit: kext + KEXT_LIMIT,
+            heap_next,
+            gimme_memory: None,
+            proc: 0,
+            panic_message: None,
+            service_calls: Vec::new(),
+            cache: Cache::new(),
+            predictor: HashMap::new(),
+            noise: NoiseModel::default(),
+            noise_rng: StdRng::seed_from_u64(!seed),
+            until_interrupt: 0,
+        };
+
+        // The kernelcache header
+        kernel.map(AddressSpace::Kernel, kernel_base, PAGE_SIZE as u64, false);
+        kernel.write_u64(kernel_base, MACH_HEADER_START);
+
+        // PacmanKit's text (every instruction is a `ret`, including the retpoline region)
+        kernel.map(AddressSpace::Kernel, kext, KEXT_VTABLE, true);
+        for addr in (kext..kext + KEXT_VTABLE).step_by(4) {
+            kernel.write_u32(addr, RET_INSTRUCTION);
+        }
+
+        // The PacmanKitService vtable (just externalMethod)
+        kernel.map(AddressSpace::Kernel, kernel.vtable, PAGE_SIZE as u64, false);
+        let external_method_signed = pauth::sign(kernel.ia_key, kernel.external_method, get_salt(kernel.vtable, VTABLE_ENTRY_SALT));
+        kernel.write_u64(kernel.vtable, external_method_signed);
+
+        // LIMIT
+        kernel.map(AddressSpace::Kernel, kernel.limit, PAGE_SIZE as u64, false);
+        kernel.write_u64(kernel.limit, LIMIT_VALUE);
+
+        // Our proc and its task
+        kernel.proc = kernel.kalloc(PROC_SIZE);
+        let task = kernel.kalloc(TASK_SIZE);
+        let holder = kernel.proc + PROC_TASK_OFFSET;
+        let task_signed = pauth::sign(kernel.da_key, task, get_salt(holder, PROC_TASK_SALT));
+        kernel.write_u64(holder, task_signed);
+
+        return kernel;
+    }
+
+    /// Where the kernelcache is loaded
+    pub fn kernel_base(&self) -> u64 {
+        return self.kernel_base;
+    }
+
+    /// `PacmanKitService::externalMethod` (what the service routine normally calls)
+    pub fn external_method(&self) -> u64 {
+        return self.external_method;
+    }
+
+    /// The region of `ret`s revealed by `LeakMethod`
+    pub fn retpoline(&self) -> u64 {
+        return self.retpoline;
+    }
+
+    /// Why the kernel panicked, if it did. Every operation fails after a panic.
+    pub fn panic_message(&self) -> Option<&str> {
+        return self.panic_message.as_deref();
+    }
+
+    /// Every function `call_service_routine` dispatched to, in order
+    pub fn service_calls(&self) -> &[u64] {
+        return &self.service_calls;
+    }
+
+    /// The data key signature of `ptr` with a given salt (the ground truth for a data PAC)
+    pub fn sign_data(&self, ptr: u64, salt: u64) -> u64 {
+        return pauth::sign(self.da_key, ptr, salt);
+    }
+
+    /// The instruction key signature of `ptr` with a given salt (the ground truth for an instruction PAC)
+    pub fn sign_inst(&self, ptr: u64, salt: u64) -> u64 {
+        return pauth::sign(self.ia_key, ptr, salt);
+    }
+
+    /**
+     * Time a load from an attacker address (what `timer::time_access` does with the simulated backend).
+     * Unmapped user memory is paged in first, like touching it would.
+     */
+    pub fn time_access(&mut self, addr: u64) -> u64 {
+        let paddr = self.translate_user(addr);
+        let latency = if self.access(paddr) { cache::LOAD_HIT_LATENCY } else { cache::LOAD_MISS_LATENCY };
+        return self.noise.jitter.apply(&mut self.noise_rng, latency);
+    }
+
+    /// Time executing an attacker address (what `timer::time_exec` does with the simulated backend)
+    pub fn time_exec(&mut self, addr: u64) -> u64 {
+        let paddr = self.translate_user(addr);
+        let latency = if self.access(paddr) { cache::FETCH_HIT_LATENCY } else { cache::FETCH_MISS_LATENCY };
+        return self.noise.jitter.apply(&mut self.noise_rng, latency);
+    }
+
+    /// Add noise from now on (see `sim::noise`)
+    pub fn set_noise(&mut self, noise: NoiseModel) {
+        self.noise = noise;
+        self.until_interrupt = noise.interrupt_period;
+    }
+
+    /// The noise being added
+    pub fn noise(&self) -> NoiseModel {
+        return self.noise;
+    }
+
+    /// Is the line holding kernel address `addr` in the cache? (For checking on the gadget from tests)
+    pub fn is_cached(&self, addr: u64) -> bool {
+        return match self.translate(AddressSpace::Kernel, addr) {
+            Some((paddr, _)) => self.cache.contains(paddr),
+            None => false,
+        };
+    }
+
+    /// Where PacmanKit is loaded
+    fn kext(&self) -> u64 {
+        return self.kernel_base + KEXT_OFFSET;
+    }
+
+    /// Stop the kernel
+    fn panic(&mut self, message: String) {
+        if self.panic_message.is_none() {
+            self.panic_message = Some(message);
+        }
+    }
+
+    /// Pick an unused physical page of the same colour as virtual page `vpn` (see `sim::cache`)
+    fn alloc_physical_page(&mut self, vpn: u64) -> u64 {
+        loop {
+            let ppn = (DRAM_BASE / PAGE_SIZE as u64)
+                + self.rng.gen_range(0..DRAM_PAGES / PAGE_COLOURS) * PAGE_COLOURS
+                + vpn % PAGE_COLOURS;
+            if let Entry::Vacant(page) = self.physical.entry(ppn) {
+                page.insert(vec![0; PAGE_SIZE]);
+                return ppn;
+            }
+        }
+    }
+
+    /// The page table of an address space
+    fn page_table(&mut self, space: AddressSpace) -> &mut HashMap<u64, PageTableEntry> {
+        return match space {
+            AddressSpace::Kernel => &mut self.kernel_pages,
+            AddressSpace::User => &mut self.user_pages,
+        };
+    }
+
+    /// Map fresh physical pages over `[addr, addr + size)`
+    fn map(&mut self, space: AddressSpace, addr: u64, size: u64, executable: bool) {
+        let first = pauth::strip(addr) / PAGE_SIZE as u64;
+        let last = (pauth::strip(addr) + size - 1) / PAGE_SIZE as u64;
+        for vpn in first..=last {
+            let ppn = self.alloc_physical_page(vpn);
+            self.page_table(space).insert(vpn, PageTableEntry { ppn, executable });
+        }
+    }
+
+    /// Unmap `[addr, addr + size)` and release the physical pages behind it
+    fn unmap(&mut self, space: AddressSpace, addr: u64, size: u64) {
+        let first = pauth::strip(addr) / PAGE_SIZE as u64;
+        let last = (pauth::strip(addr) + size - 1) / PAGE_SIZE as u64;
+        for vpn in first..=last {
+            if let Some(entry) = self.page_table(space).remove(&vpn) {
+                self.physical.remove(&entry.ppn);
+            }
+        }
+    }
+
+    /// Walk the page tables. Addresses are used with their PAC stripped (like the kext does).
+    fn translate(&self, space: AddressSpace, addr: u64) -> Option<(u64, PageTableEntry)> {
+        let addr = pauth::strip(addr);
+        let table = match space {
+            AddressSpace::Kernel => &self.kernel_pages,
+            AddressSpace::User => &self.user_pages,
+        };
+        let entry = *table.get(&(addr / PAGE_SIZE as u64))?;
+        return Some((entry.ppn * PAGE_SIZE as u64 + (addr & PAGE_OFFSET_MASK as u64), entry));
+    }
+
+    /// Which address space does `addr` belong in?
+    fn space_of(addr: u64) -> AddressSpace {
+        if crate::pac::is_kernel_pointer(addr) {
+            return AddressSpace::Kernel;
+        }
+        return AddressSpace::User;
+    }
+
+    /// Translate a user address, mapping the page on first use (user memory is demand paged)
+    fn translate_user(&mut self, addr: u64) -> u64 {
+        if self.translate(AddressSpace::User, addr).is_none() {
+            self.map(AddressSpace::User, addr, 1, false);
+        }
+        return self.translate(AddressSpace::User, addr).unwrap().0;
+    }
+
+    /// Read a byte of kernel memory
+    fn read_u8(&self, addr: u64) -> Option<u8> {
+        let (paddr, _) = self.translate(AddressSpace::Kernel, addr)?;
+        let page = self.physical.get(&(paddr / PAGE_SIZE as u64))?;
+        return Some(page[(paddr & PAGE_OFFSET_MASK as u64) as usize]);
+    }
+
+    /// Write a byte of kernel memory
+    fn write_u8(&mut self, addr: u64, val: u8) -> Option<()> {
+        let (paddr, _) = self.translate(AddressSpace::Kernel, addr)?;
+        let page = self.physical.get_mut(&(paddr / PAGE_SIZE as u64))?;
+        page[(paddr & PAGE_OFFSET_MASK as u64) as usize] = val;
+        return Some(());
+    }
+
+    /// Read a u64 of kernel memory (`None` if any of it isn't mapped)
+    fn read_u64(&self, addr: u64) -> Option<u64> {
+        if (addr & PAGE_OFFSET_MASK as u64) <= (PAGE_SIZE - 8) as u64 {
+            let (paddr, _) = self.translate(AddressSpace::Kernel, addr)?;
+            let page = self.physical.get(&(paddr / PAGE_SIZE as u64))?;
+            let offset = (paddr & PAGE_OFFSET_MASK as u64) as usize;
+            return Some(u64::from_le_bytes(page[offset..offset + 8].try_into().unwrap()));
+        }
+
+        // Crosses a page boundary
+        let mut bytes = [0u8; 8];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.read_u8(addr.wrapping_add(i as u64))?;
+        }
+        return Some(u64::from_le_bytes(bytes));
+    }
+
+    /// Write a u64 of kernel memory (`None` if any of it isn't mapped)
+    fn write_u64(&mut self, addr: u64, val: u64) -> Option<()> {
+        for i in 0..8 {
+            self.read_u8(addr.wrapping_add(i as u64))?;
+        }
+        for (i, byte) in val.to_le_bytes().iter().enumerate() {
+            self.write_u8(addr.wrapping_add(i as u64), *byte)?;
+        }
+        return Some(());
+    }
+
+    /// Write a u32 of kernel memory (instructions)
+    fn write_u32(&mut self, addr: u64, val: u32) -> Option<()> {
+        for (i, byte) in val.to_le_bytes().iter().enumerate() {
+            self.write_u8(addr.wrapping_add(i as u64), *byte)?;
+        }
+        return Some(());
+    }
+
+    /// Allocate zeroed kernel memory (page aligned if it's at least a page)
+    fn kalloc(&mut self, size: u64) -> u64 {
+        let align = if size >= PAGE_SIZE as u64 { PAGE_SIZE as u64 } else { 0x10 };
+        let addr = (self.heap_next + align - 1) & !(align - 1);
+        self.heap_next = addr + size;
+
+        // Map whatever pages of the allocation aren't already
+        let first = addr / PAGE_SIZE as u64;
+        let last = (addr + size - 1) / PAGE_SIZE as u64;
+        for vpn in first..=last {
+            if !self.kernel_pages.contains_key(&vpn) {
+                self.map(AddressSpace::Kernel, vpn * PAGE_SIZE as u64, PAGE_SIZE as u64, false);
+            }
+        }
+        return addr;
+    }
+
+    /**
+     * Create a new PacmanUser IOUserClient (with its PacmanKitService helper).
+     * Returns the address of the IOUserClient.
+     */
+    fn new_user_client(&mut self) -> u64 {
+        let user_client = self.kalloc(IOUSERCLIENT_SIZE);
+        let helper = user_client + HELPER_OFFSET;
+        let vtable_signed = pauth::sign(self.da_key, self.vtable, get_salt(helper, VTABLE_PTR_SALT));
+        self.write_u64(helper, vtable_signed);
+        return user_client;
+    }
+
+    /**
+     * Access the cache line holding `paddr` (every simulated load and fetch ends up here).
+     * This is where spurious evictions and interrupts happen.
+     *
+     * # Return Value
+     * Returns true on a hit.
+     */
+    fn access(&mut self, paddr: u64) -> bool {
+        if self.noise.interrupt_period != 0 {
+            self.until_interrupt = self.until_interrupt.saturating_sub(1);
+            if self.until_interrupt == 0 {
+                self.cache.evict_randomly(&mut self.noise_rng, self.noise.interrupt_eviction_rate);
+                self.until_interrupt = self.noise.interrupt_period;
+            }
+        }
+
+        let hit = self.cache.access(paddr);
+        if self.noise.spurious_eviction_rate > 0.0 && self.noise_rng.gen_bool(self.noise.spurious_eviction_rate) {
+            self.cache.evict_lru(paddr);
+        }
+        return hit;
+    }
+
+    /// Bring the line holding kernel address `addr` into the cache (if it's mapped)
+    fn touch(&mut self, addr: u64) -> bool {
+        return match self.translate(AddressSpace::Kernel, addr) {
+            Some((paddr, _)) => self.access(paddr),
+            None => false,
+        };
+    }
+
+    /**
+     * Load from `addr` (what a non-speculative load in the kext does). Panics the kernel if `addr` isn't mapped.
+     */
+    fn kernel_load(&mut self, addr: u64) -> u64 {
+        if !pauth::is_canonical(addr) {
+            self.panic(format!("Kernel data abort (translation fault) at 0x{:X}", addr));
+            return 0;
+        }
+        return match self.read_u64(addr) {
+            Some(v) => {
+                self.touch(addr);
+                v
+            },
+            None => {
+                self.panic(format!("Kernel data abort at 0x{:X}", addr));
+                0
+            }
+        };
+    }
+
+    /**
+     * Branch to `addr` (what a non-speculative call in the kext does). Panics the kernel if `addr` isn't executable.
+     */
+    fn kernel_branch(&mut self, addr: u64) {
+        if !pauth::is_canonical(addr) {
+            self.panic(format!("Kernel instruction abort (translation fault) at 0x{:X}", addr));
+            return;
+        }
+        match self.translate(AddressSpace::Kernel, addr) {
+            Some((paddr, entry)) if entry.executable => { self.access(paddr); },
+            _ => self.panic(format!("Kernel instruction abort at 0x{:X}", addr)),
+        }
+    }
+
+    /**
+     * Load from `addr` speculatively: faults are suppressed (the load just doesn't happen),
+     * so a pointer that failed authentication never reaches the cache.
+     *
+     * # Return Value
+     * Returns the value loaded, or `None` if the load faulted.
+     */
+    fn speculative_load(&mut self, addr: u64) -> Option<u64> {
+        if !pauth::is_canonical(addr) {
+            return None;
+        }
+        let val = self.read_u64(addr)?;
+        self.touch(addr);
+        return Some(val);
+    }
+
+    /// Branch to `addr` speculatively: fetches the target (if it's executable) and nothing else
+    fn speculative_branch(&mut self, addr: u64) {
+        if !pauth::is_canonical(addr) {
+            return;
+        }
+        if let Some((paddr, entry)) = self.translate(AddressSpace::Kernel, addr) {
+            if entry.executable {
+                self.access(paddr);
+            }
+        }
+    }
+
+    /**
+     * Evaluate the bounds check `idx < LIMIT` at kext address `branch`.
+     *
+     * # Return Value
+     * Returns (the architectural outcome, whether the check was mispredicted as passing with a
+     * speculation window open). The window is only open if `LIMIT` had to come from memory.
+     */
+    fn bounds_check(&mut self, branch: u64, idx: u64) -> (bool, bool) {
+        let window_open = !self.is_cached(self.limit);
+        let counter = *self.predictor.get(&branch).unwrap_or(&0);
+        let mut predicted_in_bounds = counter >= 2;
+        if self.noise.mistrain_rate > 0.0 && self.noise_rng.gen_bool(self.noise.mistrain_rate) {
+            predicted_in_bounds = !predicted_in_bounds;
+        }
+
+        let in_bounds = idx < self.kernel_load(self.limit);
+        self.predictor.insert(branch, if in_bounds { (counter + 1).min(3) } else { counter.saturating_sub(1) });
+
+        return (in_bounds, !in_bounds && predicted_in_bounds && window_open);
+    }
+
+    /**
+     * `PacmanKitService::externalMethod`, called through the helper object of `user_client`:
+     * authenticate the vtable pointer and the vtable entry, then call it (if `idx` is below `LIMIT`).
+     *
+     * This is the PACMAN gadget. If the bounds check is mispredicted, the virtual call runs speculatively:
+     * the vtable is only loaded from if the vtable pointer authenticates (a footprint on the vtable's line),
+     * and the entry is only fetched if the entry authenticates too (a footprint on the target's line).
+     */
+    fn service_routine(&mut self, user_client: u64, idx: u64) -> u64 {
+        let helper = user_client + HELPER_OFFSET;
+        let (in_bounds, mispredicted) = self.bounds_check(self.kext() + KEXT_SERVICE_ROUTINE_CHECK, idx);
+
+        if mispredicted {
+            if let Some(vtable_signed) = self.speculative_load(helper) {
+                let vtable = pauth::auth(self.da_key, vtable_signed, get_salt(helper, VTABLE_PTR_SALT));
+                if let Some(entry_signed) = self.speculative_load(vtable) {
+                    let entry = pauth::auth(self.ia_key, entry_signed, get_salt(vtable, VTABLE_ENTRY_SALT));
+                    self.speculative_branch(entry);
+                }
+            }
+        }
+
+        if !in_bounds {
+            return 0;
+        }
+
+        let vtable_signed = self.kernel_load(helper);
+        let vtable = pauth::auth(self.da_key, vtable_signed, get_salt(helper, VTABLE_PTR_SALT));
+        let entry_signed = self.kernel_load(vtable);
+        if self.panic_message.is_some() {
+            return 0;
+        }
+
+        let entry = pauth::auth(self.ia_key, entry_signed, get_salt(vtable, VTABLE_ENTRY_SALT));
+        self.kernel_branch(entry);
+        if self.panic_message.is_some() {
+            return 0;
+        }
+
+        self.service_calls.push(entry);
+        return 0;
+    }
+
+    /**
+     * Run one PacmanKit selector on behalf of the connection owning `user_client`.
+     * See `PacmanKitBackend::call`.
+     */
+    fn run_op(&mut self, user_client: u64, op: PacmanKitOp, args: &[u64], output: &mut [u64]) -> KernReturn {
+        if self.panic_message.is_some() {
+            return KERN_FAILURE;
+        }
+
+        if self.noise.call_failure_rate > 0.0 && self.noise_rng.gen_bool(self.noise.call_failure_rate) {
+            return KERN_RESOURCE_SHORTAGE;
+        }
+
+        let (num_args, num_outputs) = match op {
+            PacmanKitOp::KernelBase => (0, 1),
+            PacmanKitOp::Read => (1, 1),
+            PacmanKitOp::Write => (2, 0),
+            PacmanKitOp::KernelVirt2Phys => (1, 1),
+            PacmanKitOp::UserVirt2Phys => (1, 1),
+            PacmanKitOp::IOUserClientLeak => (0, 1),
+            PacmanKitOp::GimmeMemory => (0, 1),
+            PacmanKitOp::FreeMemory => (0, 0),
+            PacmanKitOp::TellMeRegs => (0, 2),
+            PacmanKitOp::ReadForTiming => (2, 1),
+            PacmanKitOp::ExecForTiming => (2, 1),
+            PacmanKitOp::LeakMethod => (0, 3),
+            PacmanKitOp::ReadForSpectre => (2, 0),
+            PacmanKitOp::ExecForSpectre => (2, 0),
+            PacmanKitOp::CallServiceRoutine => (6, 1),
+            PacmanKitOp::ForgeSignData => (2, 1),
+            PacmanKitOp::ForgeAuthData => (2, 1),
+            PacmanKitOp::ForgeSignInst => (2, 1),
+            PacmanKitOp::ForgeAuthInst => (2, 1),
+            PacmanKitOp::LeakCurProc => (0, 1),
+
+            // Only takes a structure (see `run_batch`)
+            PacmanKitOp::Batch => return KERN_INVALID_ARGUMENT,
+        };
+
+        if args.len() != num_args || output.len() < num_outputs {
+            return KERN_INVALID_ARGUMENT;
+        }
+
+        match op {
+            PacmanKitOp::KernelBase => output[0] = self.kernel_base,
+
+            PacmanKitOp::Read => {
+                output[0] = match self.read_u64(args[0]) {
+                    Some(v) => v,
+                    None => return KERN_INVALID_ADDRESS,
+                };
+                self.touch(args[0]);
+            },
+
+            PacmanKitOp::Write => {
+                if self.write_u64(args[0], args[1]).is_none() {
+                    return KERN_INVALID_ADDRESS;
+                }
+                self.touch(args[0]);
+            },
+
+            PacmanKitOp::KernelVirt2Phys => {
+                output[0] = match self.translate(AddressSpace::Kernel, args[0]) {
+                    Some((paddr, _)) => paddr,
+                    None => return KERN_INVALID_ADDRESS,
+                };
+            },
+
+            PacmanKitOp::UserVirt2Phys => {
+                if SimulatedKernel::space_of(args[0]) != AddressSpace::User {
+                    return KERN_INVALID_ADDRESS;
+                }
+                output[0] = self.translate_user(args[0]);
+            },
+
+            PacmanKitOp::IOUserClientLeak => output[0] = user_client,
+
+            PacmanKitOp::GimmeMemory => {
+                let addr = self.kalloc(GIMME_MEMORY_SIZE);
+                self.gimme_memory = Some(addr);
+                output[0] = addr;
+            },
+
+            PacmanKitOp::FreeMemory => {
+                if let Some(addr) = self.gimme_memory.take() {
+                    self.unmap(AddressSpace::Kernel, addr, GIMME_MEMORY_SIZE);
+                }
+            },
+
+            PacmanKitOp::TellMeRegs => output[..2].copy_from_slice(&TIMER_REGS),
+
+            PacmanKitOp::ReadForTiming => {
+                output[0] = timer::TIMER_OVERHEAD_PCORE;
+                if args[1] != 0 {
+                    let latency = if self.is_cached(args[0]) { cache::LOAD_HIT_LATENCY } else { cache::LOAD_MISS_LATENCY };
+                    output[0] += self.noise.jitter.apply(&mut self.noise_rng, latency);
+                    self.kernel_load(args[0]);
+                }
+            },
+
+            PacmanKitOp::ExecForTiming => {
+                output[0] = timer::TIMER_OVERHEAD_PCORE;
+                if args[1] != 0 {
+                    let latency = if self.is_cached(args[0]) { cache::FETCH_HIT_LATENCY } else { cache::FETCH_MISS_LATENCY };
+                    output[0] += self.noise.jitter.apply(&mut self.noise_rng, latency);
+                    self.kernel_branch(args[0]);
+                }
+            },
+
+            PacmanKitOp::LeakMethod => output[..3].copy_from_slice(&[self.retpoline, self.limit, self.win]),
+
+            PacmanKitOp::ReadForSpectre => {
+                let (in_bounds, mispredicted) = self.bounds_check(self.kext() + KEXT_READ_FOR_SPECTRE_CHECK, args[1]);
+                if in_bounds {
+                    self.kernel_load(args[0]);
+                }
+                else if mispredicted {
+                    self.speculative_load(args[0]);
+                }
+            },
+
+            PacmanKitOp::ExecForSpectre => {
+                let (in_bounds, mispredicted) = self.bounds_check(self.kext() + KEXT_EXEC_FOR_SPECTRE_CHECK, args[1]);
+                if in_bounds {
+                    self.kernel_branch(args[0]);
+                }
+                else if mispredicted {
+                    self.speculative_branch(args[0]);
+                }
+            },
+
+            PacmanKitOp::CallServiceRoutine => output[0] = self.service_routine(user_client, args[0]),
+
+            PacmanKitOp::ForgeSignData => output[0] = pauth::sign(self.da_key, args[0], args[1]),
+            PacmanKitOp::ForgeAuthData => output[0] = pauth::auth(self.da_key, args[0], args[1]),
+            PacmanKitOp::ForgeSignInst => output[0] = pauth::sign(self.ia_key, args[0], args[1]),
+            PacmanKitOp::ForgeAuthInst => output[0] = pauth::auth(self.ia_key, args[0], args[1]),
+
+            PacmanKitOp::LeakCurProc => output[0] = self.proc,
+
+            PacmanKitOp::Batch => unreachable!(),
+        }
+
+        if self.panic_message.is_some() {
+            return KERN_FAILURE;
+        }
+
+        return KERN_SUCCESS;
+    }
+
+    /**
+     * Run a `PacmanKitOp::Batch` structure input on behalf of the connection owning `user_client`
+     * (every operation goes through `run_op`, just like the scalar calls would). See `batch`.
+     */
+    fn run_batch(&mut self, user_client: u64, input: &[u8], output: &mut [u8]) -> KernReturn {
+        let batch = match Batch::decode(input) {
+            Some(v) => v,
+            None => return KERN_INVALID_ARGUMENT,
+        };
+        if output.len() < batch.len() * BATCH_RESULT_SIZE {
+            return KERN_INVALID_ARGUMENT;
+        }
+
+        for (index, (op, times)) in batch.ops().iter().enumerate() {
+            let mut out = [0u64; 1];
+            let mut kret = KERN_SUCCESS;
+            for _ in 0..*times {
+                kret = self.run_op(user_client, op.op(), &op.args(), &mut out[..op.num_outputs()]);
+                if KERN_SUCCESS != kret && op.stops_on_failure() {
+                    break;
+                }
+            }
+            batch::write_result(output, index, kret, out[0]);
+            if KERN_SUCCESS != kret && op.stops_on_failure() {
+                break;
+            }
+        }
+
+        return KERN_SUCCESS;
+    }
+}
+
+/**
+ * A connection to a simulated kernel (owns one IOUserClient in it).
+ */
+struct SimulatedConnection {
+    kernel: Arc<Mutex<SimulatedKernel>>,
+    user_client: u64,
+}
+
+impl PacmanKitBackend for SimulatedConnection {
+    fn call(&self, op: PacmanKitOp, args: &[u64], output: &mut [u64]) -> KernReturn {
+        return self.kernel.lock().unwrap().run_op(self.user_client, op, args, output);
+    }
+
+    fn call_struct(&self, op: PacmanKitOp, input: &[u8], output: &mut [u8]) -> KernReturn {
+        if PacmanKitOp::Batch != op {
+            return KERN_INVALID_ARGUMENT;
+        }
+        return self.kernel.lock().unwrap().run_batch(self.user_client, input, output);
+    }
+}
+
+/**
+ * Open a new connection to PacmanKit in a simulated kernel (the simulated `PacmanKitConnection::init`).
+ *
+ * # Return Value
+ * Returns `PacmanError::OpenFailed` if the kernel has panicked, a connection with its own IOUserClient otherwise.
+ */
+pub fn connect(kernel: &Arc<Mutex<SimulatedKernel>>) -> Result<PacmanKitConnection, PacmanError> {
+    let user_client = {
+        let mut kernel = kernel.lock().unwrap();
+        if kernel.panic_message.is_some() {
+            return Err(PacmanError::OpenFailed(KERN_FAILURE));
+        }
+        kernel.new_user_client()
+    };
+
+    return Ok(PacmanKitConnection::with_backend(Box::new(SimulatedConnection {
+        kernel: kernel.clone(),
+        user_client,
+    })));
+}
+
+/**
+ * The offset profile of the simulated kernel (what `offsets.toml` would say about it).
+ */
+pub fn offset_profile() -> OffsetProfile {
+    let mut sim = OffsetProfile { build: "simulated".to_string(), ..Default::default() };
+    sim.fields.insert(profile::PACMANKIT_HELPER.to_string(), HELPER_OFFSET);
+    sim.fields.insert(profile::PROC_TASK.to_string(), PROC_TASK_OFFSET);
+    sim.discriminators.insert(profile::VTABLE_POINTER.to_string(), VTABLE_PTR_SALT as u16);
+    sim.discriminators.insert(profile::VTABLE_ENTRY.to_string(), VTABLE_ENTRY_SALT as u16);
+    sim.discriminators.insert(profile::PROC_TASK.to_string(), PROC_TASK_SALT as u16);
+    return sim;
+}
+
+thread_local! {
+    /// The simulated kernel installed on this thread (if any)
+    static INSTALLED : RefCell<Option<Arc<Mutex<SimulatedKernel>>>> = const { RefCell::new(None) };
+}
+
+/**
+ * Make `kernel` the machine this thread runs on: `PacmanKitConnection::init` connects to it and the
+ * `Simulated` timer backend times accesses with it (until `uninstall` is called).
+ */
+pub fn install(kernel: &Arc<Mutex<SimulatedKernel>>) {
+    INSTALLED.with(|installed| *installed.borrow_mut() = Some(kernel.clone()));
+}
+
+/// Stop using the simulated kernel installed on this thread
+pub fn uninstall() {
+    INSTALLED.with(|installed| *installed.borrow_mut() = None);
+}
+
+/// The simulated kernel installed on this thread (if any)
+pub fn installed() -> Option<Arc<Mutex<SimulatedKernel>>> {
+    return INSTALLED.with(|installed| installed.borrow().clone());
+}
+
+/// Run `f` on the simulated kernel installed on this thread. Panics if there isn't one.
+fn with_installed<T>(f: impl FnOnce(&mut SimulatedKernel) -> T) -> T {
+    let kernel = match installed() {
+        Some(v) => v,
+        None => panic!("No simulated kernel is installed on this thread (see sim::kernel::install)"),
+    };
+    let mut kernel = kernel.lock().unwrap();
+    return f(&mut kernel);
+}
+
+/// Time a load from `addr` on the installed simulated kernel (the `Simulated` timer backend)
+pub fn time_access(addr: u64) -> u64 {
+    return with_installed(|kernel| kernel.time_access(addr));
+}
+
+/// Time executing `addr` on the installed simulated kernel (the `Simulated` timer backend)
+pub fn time_exec(addr: u64) -> u64 {
+    return with_installed(|kernel| kernel.time_exec(addr));
+}
@@ -0,0 +1,377 @@
+/*!
+ * Building synthetic kernelcaches, to run the offline analysis on without a real one.
+ *
+ * `KernelcacheBuilder` lays out an MH_FILESET the way the kernelcaches on Apple silicon are laid out, only
+ * simpler: every Mach-O header and segment starts on its own 16KB page, and the file is mapped 1:1 (a file
+ * offset is always its address minus the base address). Addresses are handed out as kexts and segments are
+ * added, so symbols and pointers can refer to them straight away. `build` writes the headers, the symbol
+ * tables and the chained fixups (`DYLD_CHAINED_PTR_64_KERNEL_CACHE`) for everything that was added.
+ */
+use crate::kernelcache::macho::*;
+
+/// Every header and segment starts on a page of this size
+pub const KERNELCACHE_PAGE_SIZE : u64 = 0x4000;
+
+/// `VM_PROT_READ`
+pub const VM_PROT_READ : u32 = 0x1;
+/// `VM_PROT_WRITE`
+pub const VM_PROT_WRITE : u32 = 0x2;
+/// `VM_PROT_EXECUTE`
+pub const VM_PROT_EXECUTE : u32 = 0x4;
+
+/// `CPU_TYPE_ARM64`
+pub const CPU_TYPE_ARM64 : u32 = 0x0100000C;
+
+/// The bundle id of the kernel itself (built as MH_EXECUTE, every other entry is MH_KEXT_BUNDLE)
+pub const KERNEL_BUNDLE_ID : &str = "com.apple.kernel";
+
+struct SegmentImage {
+    name: String,
+    vmaddr: u64,
+    initprot: u32,
+    data: Vec<u8>,
+
+    /// (name, address, size)
+    sections: Vec<(String, u64, u64)>,
+}
+
+struct KextImage {
+    id: String,
+
+    /// Where its Mach-O header is
+    vmaddr: u64,
+    segments: Vec<SegmentImage>,
+    symbols: Vec<(String, u64)>,
+}
+
+/**
+ * A kernelcache under construction.
+ */
+pub struct KernelcacheBuilder {
+    base: u64,
+
+    /// Where the next page goes
+    next: u64,
+    kexts: Vec<KextImage>,
+    fixups: Vec<Fixup>,
+}
+
+fn page_align(len: u64) -> u64 {
+    return len.div_ceil(KERNELCACHE_PAGE_SIZE).max(1) * KERNELCACHE_PAGE_SIZE;
+}
+
+fn put_u16(out: &mut Vec<u8>, val: u16) {
+    out.extend_from_slice(&val.to_le_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, val: u32) {
+    out.extend_from_slice(&val.to_le_bytes());
+}
+
+fn put_u64(out: &mut Vec<u8>, val: u64) {
+    out.extend_from_slice(&val.to_le_bytes());
+}
+
+/// `name` as a fixed 16 byte field
+fn put_name(out: &mut Vec<u8>, name: &str) {
+    let mut field = [0u8; 16];
+    field[..name.len().min(16)].copy_from_slice(&name.as_bytes()[..name.len().min(16)]);
+    out.extend_from_slice(&field);
+}
+
+/// A `mach_header_64` with `filetype` followed by `cmds`
+fn mach_header(filetype: u32, cmds: &[Vec<u8>]) -> Vec<u8> {
+    let mut out = Vec::new();
+    put_u32(&mut out, MH_MAGIC_64);
+    put_u32(&mut out, CPU_TYPE_ARM64);
+    put_u32(&mut out, 2);
+    put_u32(&mut out, filetype);
+    put_u32(&mut out, cmds.len() as u32);
+    put_u32(&mut out, cmds.iter().map(|cmd| cmd.len() as u32).sum());
+    put_u32(&mut out, 0);
+    put_u32(&mut out, 0);
+    for cmd in cmds {
+        out.extend_from_slice(cmd);
+    }
+    return out;
+}
+
+/// An `LC_SEGMENT_64` (mapped 1:1 from the file) and its sections
+fn segment_command(base: u64, name: &str, vmaddr: u64, size: u64, initprot: u32, sections: &[(String, u64, u64)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    put_u32(&mut out, LC_SEGMENT_64);
+    put_u32(&mut out, 72 + 80 * sections.len() as u32);
+    put_name(&mut out, name);
+    put_u64(&mut out, vmaddr);
+    put_u64(&mut out, size);
+    put_u64(&mut out, vmaddr - base);
+    put_u64(&mut out, size);
+    put_u32(&mut out, initprot);
+    put_u32(&mut out, initprot);
+    put_u32(&mut out, sections.len() as u32);
+    put_u32(&mut out, 0);
+    for (sectname, addr, sect_size) in sections {
+        put_name(&mut out, sectname);
+        put_name(&mut out, name);
+        put_u64(&mut out, *addr);
+        put_u64(&mut out, *sect_size);
+        put_u32(&mut out, (addr - base) as u32);
+        // align, reloff, nreloc, flags, reserved1-3
+        for _ in 0..7 {
+            put_u32(&mut out, 0);
+        }
+    }
+    return out;
+}
+
+/// A format 8 chained pointer for `fixup`, with `next` strides to the next one
+fn encode_fixup(base: u64, fixup: &Fixup, next: u64) -> u64 {
+    let mut raw = (fixup.target - base) & 0x3FFFFFFF;
+    if let Some(auth) = fixup.auth {
+        raw |= (auth.diversity as u64) << 32;
+        raw |= (auth.addr_div as u64) << 48;
+        raw |= (auth.key as u64) << 49;
+        raw |= 1 << 63;
+    }
+    return raw | (next << 51);
+}
+
+impl KernelcacheBuilder {
+    /**
+     * An empty kernelcache whose fileset header is at `base`.
+     */
+    pub fn new(base: u64) -> Self {
+        return KernelcacheBuilder { base, next: base + KERNELCACHE_PAGE_SIZE, kexts: Vec::new(), fixups: Vec::new() };
+    }
+
+    /**
+     * Add a kext (or the kernel, for `KERNEL_BUNDLE_ID`) with bundle id `id`.
+     *
+     * # Return Value
+     * The kext's index, for adding segments and symbols to it.
+     */
+    pub fn kext(&mut self, id: &str) -> usize {
+        self.kexts.push(KextImage { id: id.to_string(), vmaddr: self.next, segments: Vec::new(), symbols: Vec::new() });
+        self.next += KERNELCACHE_PAGE_SIZE;
+        return self.kexts.len() - 1;
+    }
+
+    /// Where kext `kext`'s Mach-O header is
+    pub fn kext_address(&self, kext: usize) -> u64 {
+        return self.kexts[kext].vmaddr;
+    }
+
+    /**
+     * Add a segment holding `data` to kext `kext`.
+     *
+     * # Return Value
+     * Where the segment starts.
+     */
+    pub fn segment(&mut self, kext: usize, name: &str, initprot: u32, data: &[u8]) -> u64 {
+        let vmaddr = self.next;
+        self.kexts[kext].segments.push(SegmentImage {
+            name: name.to_string(),
+            vmaddr,
+            initprot,
+            data: data.to_vec(),
+            sections: Vec::new(),
+        });
+        self.next += page_align(data.len() as u64);
+        return vmaddr;
+    }
+
+    /**
+     * Add a section covering `size` bytes at `addr` to segment `segname` of kext `kext`.
+     */
+    pub fn section(&mut self, kext: usize, segname: &str, sectname: &str, addr: u64, size: u64) {
+        let seg = self.kexts[kext].segments.iter_mut().find(|seg| seg.name == segname).expect("No such segment");
+        seg.sections.push((sectname.to_string(), addr, size));
+    }
+
+    /**
+     * Define symbol `name` at `addr` in kext `kext`.
+     */
+    pub fn symbol(&mut self, kext: usize, name: &str, addr: u64) {
+        self.kexts[kext].symbols.push((name.to_string(), addr));
+    }
+
+    /**
+     * Store a pointer to `target` (signed as `auth` says) at `location`, which must be 4 byte aligned and in a segment.
+     */
+    pub fn pointer(&mut self, location: u64, target: u64, auth: Option<PtrAuth>) {
+        self.fixups.push(Fixup { location, target, auth });
+    }
+
+    /**
+     * Write out the kernelcache.
+     */
+    pub fn build(&self) -> Vec<u8> {
+        let base = self.base;
+        let linkedit = self.next;
+        let mut out = vec![0u8; (linkedit - base) as usize];
+
+        for kext in &self.kexts {
+            for seg in &kext.segments {
+                let offset = (seg.vmaddr - base) as usize;
+                out[offset..offset + seg.data.len()].copy_from_slice(&seg.data);
+            }
+        }
+
+        // Every segment in the kernelcache, the same order the fileset lists them in
+        let mut segments = vec![("__TEXT".to_string(), base, KERNELCACHE_PAGE_SIZE, VM_PROT_READ)];
+        for kext in &self.kexts {
+            segments.push(("__TEXT".to_string(), kext.vmaddr, KERNELCACHE_PAGE_SIZE, VM_PROT_READ));
+            for seg in &kext.segments {
+                segments.push((seg.name.clone(), seg.vmaddr, page_align(seg.data.len() as u64), seg.initprot));
+            }
+        }
+
+        // Chain the pointers within each page of each segment
+        let mut fixups = self.fixups.clone();
+        fixups.sort_by_key(|fixup| fixup.location);
+        let mut seg_infos = Vec::new();
+        for (_, vmaddr, size, _) in &segments {
+            let page_count = (size / KERNELCACHE_PAGE_SIZE) as usize;
+            let mut page_starts = vec![DYLD_CHAINED_PTR_START_NONE; page_count];
+            let in_segment : Vec<&Fixup> = fixups.iter().filter(|fixup| fixup.location >= *vmaddr && fixup.location < vmaddr + size).collect();
+            for (i, fixup) in in_segment.iter().enumerate() {
+                let page = (fixup.location - vmaddr) / KERNELCACHE_PAGE_SIZE;
+                let next = match in_segment.get(i + 1) {
+                    Some(next) if (next.location - vmaddr) / KERNELCACHE_PAGE_SIZE == page => (next.location - fixup.location) / 4,
+                    _ => 0,
+                };
+                if page_starts[page as usize] == DYLD_CHAINED_PTR_START_NONE {
+                    page_starts[page as usize] = ((fixup.location - vmaddr) % KERNELCACHE_PAGE_SIZE) as u16;
+                }
+                let offset = (fixup.location - base) as usize;
+                out[offset..offset + 8].copy_from_slice(&encode_fixup(base, fixup, next).to_le_bytes());
+            }
+            if in_segment.is_empty() {
+                seg_infos.push(None);
+            }
+            else {
+                seg_infos.push(Some((vmaddr - base, page_starts)));
+            }
+        }
+
+        // __LINKEDIT: the chained fixups, then each kext's symbol table and strings
+        let mut linkedit_data = Vec::new();
+        let fixups_offset = (linkedit - base) as u32;
+        {
+            let starts_offset = 28u32;
+            put_u32(&mut linkedit_data, 0);
+            put_u32(&mut linkedit_data, starts_offset);
+            for _ in 0..5 {
+                put_u32(&mut linkedit_data, 0);
+            }
+
+            let mut starts = Vec::new();
+            put_u32(&mut starts, seg_infos.len() as u32);
+            let mut seg_info_bytes = Vec::new();
+            let mut seg_info_offsets = Vec::new();
+            for seg_info in &seg_infos {
+                match seg_info {
+                    None => seg_info_offsets.push(0),
+                    Some((segment_offset, page_starts)) => {
+                        seg_info_offsets.push(4 + 4 * seg_infos.len() as u32 + seg_info_bytes.len() as u32);
+                        put_u32(&mut seg_info_bytes, 22 + 2 * page_starts.len() as u32);
+                        put_u16(&mut seg_info_bytes, KERNELCACHE_PAGE_SIZE as u16);
+                        put_u16(&mut seg_info_bytes, DYLD_CHAINED_PTR_64_KERNEL_CACHE);
+                        put_u64(&mut seg_info_bytes, *segment_offset);
+                        put_u32(&mut seg_info_bytes, 0);
+                        put_u16(&mut seg_info_bytes, page_starts.len() as u16);
+                        for start in page_starts {
+                            put_u16(&mut seg_info_bytes, *start);
+                        }
+                        while seg_info_bytes.len() % 8 != 0 {
+                            seg_info_bytes.push(0);
+                        }
+                    },
+                }
+            }
+            for offset in seg_info_offsets {
+                put_u32(&mut starts, offset);
+            }
+            linkedit_data.extend_from_slice(&starts);
+            linkedit_data.extend_from_slice(&seg_info_bytes);
+        }
+        let fixups_size = linkedit_data.len() as u32;
+
+        let mut symtabs = Vec::new();
+        for kext in &self.kexts {
+            while linkedit_data.len() % 8 != 0 {
+                linkedit_data.push(0);
+            }
+            let symoff = (linkedit - base) as u32 + linkedit_data.len() as u32;
+            let mut strings = vec![0u8];
+            let mut nlists = Vec::new();
+            for (name, addr) in &kext.symbols {
+                put_u32(&mut nlists, strings.len() as u32);
+                // N_SECT | N_EXT
+                nlists.push(0x0F);
+                nlists.push(1);
+                put_u16(&mut nlists, 0);
+                put_u64(&mut nlists, *addr);
+                strings.extend_from_slice(name.as_bytes());
+                strings.push(0);
+            }
+            let stroff = symoff + nlists.len() as u32;
+            linkedit_data.extend_from_slice(&nlists);
+            linkedit_data.extend_from_slice(&strings);
+            symtabs.push((symoff, kext.symbols.len() as u32, stroff, strings.len() as u32));
+        }
+        let linkedit_size = page_align(linkedit_data.len() as u64);
+        out.extend_from_slice(&linkedit_data);
+        out.resize((linkedit - base + linkedit_size) as usize, 0);
+
+        // Each kext's header
+        for (kext, (symoff, nsyms, stroff, strsize)) in self.kexts.iter().zip(symtabs) {
+            let mut cmds = vec![segment_command(base, "__TEXT", kext.vmaddr, KERNELCACHE_PAGE_SIZE, VM_PROT_READ, &[])];
+            for seg in &kext.segments {
+                cmds.push(segment_command(base, &seg.name, seg.vmaddr, page_align(seg.data.len() as u64), seg.initprot, &seg.sections));
+            }
+            let mut symtab = Vec::new();
+            put_u32(&mut symtab, LC_SYMTAB);
+            put_u32(&mut symtab, 24);
+            put_u32(&mut symtab, symoff);
+            put_u32(&mut symtab, nsyms);
+            put_u32(&mut symtab, stroff);
+            put_u32(&mut symtab, strsize);
+            cmds.push(symtab);
+
+            let filetype = if kext.id == KERNEL_BUNDLE_ID { MH_EXECUTE } else { MH_KEXT_BUNDLE };
+            let header = mach_header(filetype, &cmds);
+            let offset = (kext.vmaddr - base) as usize;
+            out[offset..offset + header.len()].copy_from_slice(&header);
+        }
+
+        // The fileset's header
+        let mut cmds : Vec<Vec<u8>> = segments.iter()
+            .map(|(name, vmaddr, size, initprot)| segment_command(base, name, *vmaddr, *size, *initprot, &[]))
+            .collect();
+        cmds.push(segment_command(base, "__LINKEDIT", linkedit, linkedit_size, VM_PROT_READ, &[]));
+        for kext in &self.kexts {
+            let mut entry = Vec::new();
+            let cmdsize = (32 + kext.id.len() as u32 + 1).div_ceil(8) * 8;
+            put_u32(&mut entry, LC_FILESET_ENTRY);
+            put_u32(&mut entry, cmdsize);
+            put_u64(&mut entry, kext.vmaddr);
+            put_u64(&mut entry, kext.vmaddr - base);
+            put_u32(&mut entry, 32);
+            put_u32(&mut entry, 0);
+            entry.extend_from_slice(kext.id.as_bytes());
+            entry.resize(cmdsize as usize, 0);
+            cmds.push(entry);
+        }
+        let mut chained_fixups = Vec::new();
+        put_u32(&mut chained_fixups, LC_DYLD_CHAINED_FIXUPS);
+        put_u32(&mut chained_fixups, 16);
+        put_u32(&mut chained_fixups, fixups_offset);
+        put_u32(&mut chained_fixups, fixups_size);
+        cmds.push(chained_fixups);
+
+        let header = mach_header(MH_FILESET, &cmds);
+        out[..header.len()].copy_from_slice(&header);
+        return out;
+    }
+}
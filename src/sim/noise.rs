@@ -0,0 +1,281 @@
+/*!
+ * Noise for the simulated kernel, so the attacks can be checked against something less perfect than a quiet model.
+ *
+ * A `NoiseModel` is installed on a kernel with `SimulatedKernel::set_noise`. Every knob defaults to off:
+ * * `jitter`: Added to every timing the simulator reports (attacker timers and PacmanKit's timed accesses).
+ * * `spurious_eviction_rate`: Chance that an access also evicts the least recently used line of its set
+ *   (someone else touching the same set).
+ * * `interrupt_period`/ `interrupt_eviction_rate`: Every so many accesses an "interrupt" runs and evicts
+ *   that fraction of every line in the cache.
+ * * `mistrain_rate`: Chance a bounds check is predicted the other way from what its counter says.
+ * * `call_failure_rate`: Chance a PacmanKit call fails (with `KERN_RESOURCE_SHORTAGE`) before doing anything.
+ *
+ * The noise is drawn from its own seeded generator, so a noisy kernel is as deterministic as a quiet one.
+ *
+ * `noise_tolerance` measures how much noise each `DecisionStatistic` survives before the bruteforce picks the wrong PAC.
+ */
+use std::sync::{Arc, Mutex};
+use rand::Rng;
+use rand::rngs::StdRng;
+use crate::attacks::decision::{BruteforceDecider, DecisionStatistic};
+use crate::attacks::pacman::{find_victim_objects, get_salt, pacman_direct, GadgetParams};
+use crate::error::PacmanError;
+use crate::evset;
+use crate::pac::{self, PAC_BITMASK};
+use crate::pacmankit::PacmanKitConnection;
+use crate::primitives::*;
+use crate::sim::kernel::{self, SimulatedKernel};
+use crate::timer::{self, TimerBackend};
+
+/**
+ * How timer readings are perturbed.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Jitter {
+    /// Timings are exact
+    None,
+
+    /// Add between 0 and `max` cycles (uniformly)
+    Uniform { max: u64 },
+
+    /// Add normally distributed cycles (negative values can make a reading faster, down to 0)
+    Normal { stddev: f64 },
+
+    /// Add `size` cycles to a `rate` fraction of readings (the rest are exact)
+    Outliers { rate: f64, size: u64 },
+}
+
+impl Jitter {
+    /// Perturb a latency
+    pub fn apply(&self, rng: &mut StdRng, latency: u64) -> u64 {
+        return match *self {
+            Jitter::None => latency,
+            Jitter::Uniform { max } => latency + rng.gen_range(0..=max),
+            Jitter::Normal { stddev } => {
+                // Box-Muller
+                let u1 : f64 = 1.0 - rng.gen::<f64>();
+                let u2 : f64 = rng.gen();
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * core::f64::consts::PI * u2).cos();
+                (latency as f64 + z * stddev).round().max(0.0) as u64
+            },
+            Jitter::Outliers { rate, size } => {
+                if rng.gen_bool(rate.clamp(0.0, 1.0)) { latency + size } else { latency }
+            },
+        };
+    }
+
+    /// The same distribution, `level` times as wide (or as frequent)
+    pub fn scaled(&self, level: f64) -> Self {
+        return match *self {
+            Jitter::None => Jitter::None,
+            Jitter::Uniform { max } => Jitter::Uniform { max: (max as f64 * level).round() as u64 },
+            Jitter::Normal { stddev } => Jitter::Normal { stddev: stddev * level },
+            Jitter::Outliers { rate, size } => Jitter::Outliers { rate: (rate * level).min(1.0), size },
+        };
+    }
+}
+
+/**
+ * Every source of noise in the simulator. See the module documentation.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct NoiseModel {
+    /// Added to every reported latency
+    pub jitter: Jitter,
+
+    /// Chance an access evicts the least recently used line of its set
+    pub spurious_eviction_rate: f64,
+
+    /// How many accesses between interrupts (0 for none)
+    pub interrupt_period: u64,
+
+    /// Fraction of the cache's lines every interrupt evicts
+    pub interrupt_eviction_rate: f64,
+
+    /// Chance a bounds check is predicted the wrong way
+    pub mistrain_rate: f64,
+
+    /// Chance a PacmanKit call fails
+    pub call_failure_rate: f64,
+}
+
+/// No noise at all (what a simulated kernel starts with)
+pub const QUIET : NoiseModel = NoiseModel {
+    jitter: Jitter::None,
+    spurious_eviction_rate: 0.0,
+    interrupt_period: 0,
+    interrupt_eviction_rate: 0.0,
+    mistrain_rate: 0.0,
+    call_failure_rate: 0.0,
+};
+
+/// Roughly what a busy M1 looks like to the attack (level 1 for `NoiseModel::scaled`)
+pub const REALISTIC : NoiseModel = NoiseModel {
+    jitter: Jitter::Normal { stddev: 6.0 },
+    spurious_eviction_rate: 0.002,
+    interrupt_period: 20000,
+    interrupt_eviction_rate: 0.1,
+    mistrain_rate: 0.02,
+    call_failure_rate: 0.001,
+};
+
+impl Default for NoiseModel {
+    fn default() -> Self {
+        return QUIET;
+    }
+}
+
+impl NoiseModel {
+    /**
+     * Turn every knob up (or down) by `level`: jitter gets wider, and every rate is multiplied by `level`
+     * (capped at 1). Interrupts keep their period. `level` 0 is quiet.
+     */
+    pub fn scaled(&self, level: f64) -> Self {
+        let scale = |rate: f64| (rate * level).clamp(0.0, 1.0);
+        return Self {
+            jitter: self.jitter.scaled(level),
+            spurious_eviction_rate: scale(self.spurious_eviction_rate),
+            interrupt_period: if level > 0.0 { self.interrupt_period } else { 0 },
+            interrupt_eviction_rate: scale(self.interrupt_eviction_rate),
+            mistrain_rate: scale(self.mistrain_rate),
+            call_failure_rate: scale(self.call_failure_rate),
+        };
+    }
+}
+
+/**
+ * Run a shortened bruteforce of the data gadget on a fresh simulated kernel with `noise`: the correct PAC for
+ * the vtable pointer is hidden among `num_guesses - 1` wrong ones, and the guesses are decided on with `statistic`.
+ * The kernel is set up quietly, the noise starts with the bruteforce.
+ *
+ * # Arguments
+ * * `seed`: Seed of the simulated kernel (and its noise).
+ * * `noise`: The noise to add.
+ * * `statistic`: The decision strategy.
+ * * `params`: The data gadget parameters to bruteforce with.
+ * * `limit_evset_size`: How many addresses to evict LIMIT with.
+ * * `num_guesses`: How many PACs to try.
+ * * `memory_region`: The region to build eviction sets in (see `attacks::pacman::end_to_end`).
+ *
+ * # Return Value
+ * Returns true if the correct PAC was picked (false if a kernel call kept failing even with retries).
+ */
+pub unsafe fn bruteforce_survives(
+    seed: u64,
+    noise: NoiseModel,
+    statistic: DecisionStatistic,
+    params: &GadgetParams,
+    limit_evset_size: usize,
+    num_guesses: usize,
+    memory_region: &mut [u8],
+) -> bool {
+    let kernel = Arc::new(Mutex::new(SimulatedKernel::new(seed)));
+    kernel::install(&kernel);
+    timer::set_backend(TimerBackend::Simulated);
+
+    let survived = noisy_bruteforce(&kernel, noise, statistic, params, limit_evset_size, num_guesses, memory_region);
+
+    kernel.lock().unwrap().set_noise(QUIET);
+    kernel::uninstall();
+    return survived.unwrap_or(false);
+}
+
+/**
+ * The bruteforce of `bruteforce_survives`, against the installed simulated `kernel`.
+ */
+unsafe fn noisy_bruteforce(
+    kernel: &Arc<Mutex<SimulatedKernel>>,
+    noise: NoiseModel,
+    statistic: DecisionStatistic,
+    params: &GadgetParams,
+    limit_evset_size: usize,
+    num_guesses: usize,
+    memory_region: &mut [u8],
+) -> Result<bool, PacmanError> {
+    let handle = PacmanKitConnection::init()?;
+    let victim_handle = PacmanKitConnection::init()?;
+    let (_victim_user_client, victim_object, victim_vtable, _victim_vtable_entry) = find_victim_objects(&victim_handle, &kernel::offset_profile())?;
+    let new_vtable = handle.kernel_mmap()? | PAC_BITMASK;
+    let correct = kernel.lock().unwrap().sign_data(new_vtable, get_salt(victim_object, kernel::VTABLE_PTR_SALT));
+
+    // Setup evsets for LIMIT and the new vtable
+    let limit_va = handle.leak_limit_location()?;
+    let limit_evset = evset::data_pevset(limit_va, handle.kernel_virt_to_phys(limit_va)?, memory_region);
+    let limit_evset_chosen = evset::choose_evset(&limit_evset, limit_evset_size, "the LIMIT eviction set")?;
+    let mut limit_indexes : Vec<usize> = (0..limit_evset_chosen.len()).collect();
+
+    let new_vtable_evset = evset::data_pevset(new_vtable, handle.kernel_virt_to_phys(new_vtable)?, memory_region);
+    let new_vtable_evset_chosen = evset::choose_evset(&new_vtable_evset, params.evset_size, "the forged pointer eviction set")?;
+    let mut new_vtable_indexes : Vec<usize> = (0..new_vtable_evset_chosen.len()).collect();
+
+    let try_speculative = || {
+        let _ = victim_handle.call_service_routine(10000, 0, 0, 0, 0, 0);
+    };
+
+    let try_nonspeculative = || {
+        let _ = victim_handle.call_service_routine(0, 0, 0, 0, 0, 0);
+    };
+
+    // Hide the correct PAC in the middle (the first guess sets the baseline)
+    let mut guesses : Vec<u64> = pac::iterate_pacs(new_vtable).filter(|guess| *guess != correct).take(num_guesses - 1).collect();
+    guesses.insert(guesses.len() / 2, correct);
+
+    kernel.lock().unwrap().set_noise(noise);
+    let mut decider = BruteforceDecider::with_statistic(params.evset_size, params.how_far_above_average, statistic);
+    let mut run = |guess: u64, num_iters: usize| {
+        return pacman_direct(
+            &handle,
+            victim_object,
+            victim_vtable,
+            guess,
+            num_iters,
+            params,
+            timer::time_access,
+            try_speculative,
+            try_nonspeculative,
+            &new_vtable_evset_chosen,
+            &mut new_vtable_indexes,
+            &limit_evset_chosen,
+            &mut limit_indexes,
+            None,
+        );
+    };
+
+    for guess in guesses {
+        let samples = run(guess, params.num_iters)?;
+        decider.scan(guess, &samples);
+    }
+    for guess in decider.potential_matches().to_vec() {
+        let samples = run(guess, params.num_final_iters)?;
+        decider.inspect(guess, &samples);
+    }
+
+    return Ok(decider.decide() == Some(correct));
+}
+
+/**
+ * Measure how much noise a decision strategy tolerates: run `bruteforce_survives` with `REALISTIC` noise scaled
+ * by each of `levels` (in increasing order) until one picks the wrong PAC.
+ * See `bruteforce_survives` for the other arguments.
+ *
+ * # Return Value
+ * Returns the highest level that still picked the correct PAC, or `None` if the first one didn't.
+ */
+pub unsafe fn noise_tolerance(
+    seed: u64,
+    statistic: DecisionStatistic,
+    levels: &[f64],
+    params: &GadgetParams,
+    limit_evset_size: usize,
+    num_guesses: usize,
+    memory_region: &mut [u8],
+) -> Option<f64> {
+    let mut tolerated = None;
+    for level in levels {
+        if !bruteforce_survives(seed, REALISTIC.scaled(*level), statistic, params, limit_evset_size, num_guesses, memory_region) {
+            break;
+        }
+        tolerated = Some(*level);
+    }
+    return tolerated;
+}
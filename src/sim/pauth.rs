@@ -0,0 +1,125 @@
+/*!
+ * Pointer authentication as done by the simulated kernel.
+ *
+ * A PAC is a keyed MAC (SipHash-2-4) of the stripped pointer and its modifier (the salt), folded down
+ * to 16 bits and stored in the upper bits of the pointer (see `pac::encode_pac`). This isn't the
+ * algorithm M1 uses, but like the real thing a PAC can't be computed without the key, and only one
+ * PAC out of every `MAX_PAC + 1` authenticates.
+ */
+use crate::pac::{self, PAC, PAC_BITMASK};
+
+/// Bits 53 and 54 of a pointer are replaced with this error code when an A key fails to authenticate it
+pub const AUTH_ERROR_KEY_A : u64 = 0b01 << 53;
+
+/// Mask of the bits the AUT error code goes into
+pub const AUTH_ERROR_MASK : u64 = 0b11 << 53;
+
+/**
+ * A 128 bit PAC key (eg. `APIAKey_EL1`).
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PacKey {
+    pub lo: u64,
+    pub hi: u64,
+}
+
+/// One SipRound
+fn sipround(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13);
+    v[1] ^= v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16);
+    v[3] ^= v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21);
+    v[3] ^= v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17);
+    v[1] ^= v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+/// SipHash-2-4 of a sequence of little endian u64s
+fn siphash(key: PacKey, words: &[u64]) -> u64 {
+    let mut v = [
+        key.lo ^ 0x736f6d6570736575,
+        key.hi ^ 0x646f72616e646f6d,
+        key.lo ^ 0x6c7967656e657261,
+        key.hi ^ 0x7465646279746573,
+    ];
+
+    for word in words {
+        v[3] ^= word;
+        sipround(&mut v);
+        sipround(&mut v);
+        v[0] ^= word;
+    }
+
+    let last = ((words.len() as u64) * 8) << 56;
+    v[3] ^= last;
+    sipround(&mut v);
+    sipround(&mut v);
+    v[0] ^= last;
+
+    v[2] ^= 0xFF;
+    for _ in 0..4 {
+        sipround(&mut v);
+    }
+
+    return v[0] ^ v[1] ^ v[2] ^ v[3];
+}
+
+/**
+ * Remove the PAC from a pointer (what `XPACI`/ `XPACD` do).
+ */
+pub fn strip(ptr: u64) -> u64 {
+    if pac::is_kernel_pointer(ptr) {
+        return ptr | PAC_BITMASK;
+    }
+    return ptr & !PAC_BITMASK;
+}
+
+/**
+ * Is `ptr` canonical (has no PAC and no AUT error code in it)?
+ */
+pub fn is_canonical(ptr: u64) -> bool {
+    return strip(ptr) == ptr;
+}
+
+/**
+ * Compute the PAC of a pointer.
+ *
+ * # Arguments
+ * * `key`: The key to sign with.
+ * * `ptr`: The pointer to sign (its upper bits are ignored).
+ * * `modifier`: The salt (see `attacks::pacman::get_salt`).
+ */
+pub fn compute_pac(key: PacKey, ptr: u64, modifier: u64) -> PAC {
+    let mac = siphash(key, &[strip(ptr), modifier]);
+    return (mac ^ (mac >> 16) ^ (mac >> 32) ^ (mac >> 48)) as PAC;
+}
+
+/**
+ * Sign a pointer (`PACIA`/ `PACDA`).
+ */
+pub fn sign(key: PacKey, ptr: u64, modifier: u64) -> u64 {
+    let stripped = strip(ptr);
+    return pac::encode_pac(compute_pac(key, stripped, modifier), stripped);
+}
+
+/**
+ * Authenticate a pointer (`AUTIA`/ `AUTDA`).
+ *
+ * # Return Value
+ * Returns the stripped pointer if the PAC was correct. Otherwise returns the stripped pointer with an
+ * error code in bits 53 and 54, which is not canonical and so faults when used.
+ */
+pub fn auth(key: PacKey, ptr: u64, modifier: u64) -> u64 {
+    let stripped = strip(ptr);
+    if sign(key, stripped, modifier) == ptr {
+        return stripped;
+    }
+    return (stripped & !AUTH_ERROR_MASK) | AUTH_ERROR_KEY_A;
+}
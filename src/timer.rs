@@ -0,0 +1,182 @@
+/*!
+ * Timer backend selection.
+ *
+ * Everything is timed through this module, which forwards to one of the timer backends:
+ * * `timer_msr`: The PMC0 cycle counter (requires the PACMAN patch to XNU).
+ * * `timer_multithread`: A counter thread (see `counter.rs`).
+ * * `sim::kernel`: The cache of the simulated kernel installed on this thread (see `sim::kernel::install`).
+ *
+ * The backend is picked once at startup with `set_backend`.
+ *
+ * The first two need Apple silicon running macOS. Everywhere else the simulated backend is the default (and the only one that works).
+ */
+use core::str::FromStr;
+use core::sync::atomic::{AtomicU8, Ordering};
+use crate::sim;
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+use crate::timer_msr;
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+use crate::timer_multithread;
+
+// PacmanKit always reports kernel timings with the MSR timer, regardless of the backend in use
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub use crate::timer_msr::{TIMER_OVERHEAD_PCORE, TIMER_OVERHEAD_ECORE};
+
+/// See `timer_msr::TIMER_OVERHEAD_PCORE` (the simulated kernel reports its timings the same way)
+#[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
+pub const TIMER_OVERHEAD_PCORE : u64 = 56;
+/// See `timer_msr::TIMER_OVERHEAD_ECORE`
+#[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
+pub const TIMER_OVERHEAD_ECORE : u64 = 52;
+
+/**
+ * The available timer backends.
+ */
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TimerBackend {
+    /// The PMC0 cycle counter (see `timer_msr`)
+    Msr = 0,
+
+    /// The counter thread (see `timer_multithread`)
+    Multithread = 1,
+
+    /// The simulated kernel's cache (see `sim::kernel`)
+    Simulated = 2,
+}
+
+impl TimerBackend {
+    /// Name of this timer backend (reported alongside experiment results)
+    pub fn name(&self) -> &'static str {
+        return match self {
+            TimerBackend::Msr => "msr",
+            TimerBackend::Multithread => "multithread",
+            TimerBackend::Simulated => "simulated",
+        };
+    }
+}
+
+impl FromStr for TimerBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s {
+            "msr" => Ok(TimerBackend::Msr),
+            "multithread" => Ok(TimerBackend::Multithread),
+            "simulated" => Ok(TimerBackend::Simulated),
+            _ => Err(format!("Unknown timer backend '{}' (expected msr, multithread or simulated)", s)),
+        };
+    }
+}
+
+/// The backend currently in use (a `TimerBackend` discriminant)
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+static BACKEND : AtomicU8 = AtomicU8::new(TimerBackend::Msr as u8);
+
+/// The backend currently in use (a `TimerBackend` discriminant)
+#[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
+static BACKEND : AtomicU8 = AtomicU8::new(TimerBackend::Simulated as u8);
+
+/**
+ * Select the timer backend. Should be done once before any measurements are taken.
+ * The multithread backend requires the counter thread to be running (see `counter::counter_thread`).
+ */
+pub fn set_backend(backend: TimerBackend) {
+    BACKEND.store(backend as u8, Ordering::Relaxed);
+}
+
+/// The timer backend currently in use
+#[inline(always)]
+pub fn backend() -> TimerBackend {
+    return match BACKEND.load(Ordering::Relaxed) {
+        1 => TimerBackend::Multithread,
+        2 => TimerBackend::Simulated,
+        _ => TimerBackend::Msr,
+    };
+}
+
+/// Name of the timer backend currently in use
+pub fn name() -> &'static str {
+    return backend().name();
+}
+
+/// The hardware backends can't be used here
+#[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
+fn needs_apple_silicon(backend: TimerBackend) -> u64 {
+    panic!("The {} timer needs Apple silicon running macOS", backend.name());
+}
+
+/// Returns the time to access a given address with the current backend
+#[inline(always)]
+pub unsafe fn time_access(addr: u64) -> u64 {
+    return match backend() {
+        #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+        TimerBackend::Msr => timer_msr::time_access(addr),
+        #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+        TimerBackend::Multithread => timer_multithread::time_access(addr),
+        TimerBackend::Simulated => sim::kernel::time_access(addr),
+        #[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
+        other => needs_apple_silicon(other),
+    };
+}
+
+/// Returns the time to write to a given address with the current backend
+#[inline(always)]
+pub unsafe fn time_store(addr: u64) -> u64 {
+    return match backend() {
+        #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+        TimerBackend::Msr => timer_msr::time_store(addr),
+        #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+        TimerBackend::Multithread => timer_multithread::time_store(addr),
+        TimerBackend::Simulated => sim::kernel::time_access(addr),
+        #[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
+        other => needs_apple_silicon(other),
+    };
+}
+
+/// Returns the time to execute a given address with the current backend
+#[inline(always)]
+pub unsafe fn time_exec(addr: u64) -> u64 {
+    return match backend() {
+        #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+        TimerBackend::Msr => timer_msr::time_exec(addr),
+        #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+        TimerBackend::Multithread => timer_multithread::time_exec(addr),
+        TimerBackend::Simulated => sim::kernel::time_exec(addr),
+        #[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
+        other => needs_apple_silicon(other),
+    };
+}
+
+/// Returns the constant time offset associated with performing measurements with the current backend
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub fn timer_overhead() -> u64 {
+    return match backend() {
+        TimerBackend::Msr => timer_msr::timer_overhead(),
+        TimerBackend::Multithread => timer_multithread::timer_overhead(),
+        TimerBackend::Simulated => 0,
+    };
+}
+
+/// Reports the time for a cache miss with the current backend (see `timer_msr::time_miss`)
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub fn time_miss(untouched_page: &mut [u8]) -> u64 {
+    return match backend() {
+        TimerBackend::Msr => timer_msr::time_miss(untouched_page),
+        TimerBackend::Multithread => timer_multithread::time_miss(untouched_page),
+        TimerBackend::Simulated => sim::kernel::time_access(&untouched_page[0] as *const u8 as u64),
+    };
+}
+
+/// Reports the time for a cache hit with the current backend (see `timer_msr::time_hit`)
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub fn time_hit(page: &mut [u8]) -> u64 {
+    return match backend() {
+        TimerBackend::Msr => timer_msr::time_hit(page),
+        TimerBackend::Multithread => timer_multithread::time_hit(page),
+        TimerBackend::Simulated => {
+            sim::kernel::time_access(&page[0] as *const u8 as u64);
+            sim::kernel::time_access(&page[0] as *const u8 as u64)
+        },
+    };
+}
@@ -4,7 +4,7 @@
  * Requires PACMAN patch to XNU for this to work.
  */
 
-use crate::*;
+use crate::cache;
 use core::arch::asm;
 
 /// The overhead of doing timer measurements with a NOP
@@ -12,6 +12,7 @@ use core::arch::asm;
 /// Set this to 0 to ignore. This value will be different depending on if
 /// you are running on a P or E core.
 pub const TIMER_OVERHEAD_PCORE : u64 = 56;
+/// Same as `TIMER_OVERHEAD_PCORE` except for the E core
 pub const TIMER_OVERHEAD_ECORE : u64 = 52;
 
 /**
@@ -108,7 +109,6 @@ pub unsafe fn time_access(addr: u64) -> u64 {
 pub fn timer_overhead() -> u64 {
     let t1 : u64;
     let t2 : u64;
-    let val_out : u64;
     unsafe {
         asm!{
             "isb",
@@ -2,7 +2,8 @@
  * It's timer_msr except using the counter.rs multithreaded counter as a timer.
  */
 
-use crate::*;
+use crate::cache;
+use crate::counter;
 use core::arch::asm;
 
 /// TODO: Remove these from here
@@ -11,7 +12,9 @@ use core::arch::asm;
 // multithread timer has lots of variance and isn't super precise. We keep these equal to the value in timer_msr.rs
 // so that when doing timing with the multithreaded timer in userspace, the kernel overhead is still taken care of.
 // This will be resolved when we refactor the code.
+/// See `timer_msr::TIMER_OVERHEAD_PCORE`
 pub const TIMER_OVERHEAD_PCORE : u64 = 56;
+/// See `timer_msr::TIMER_OVERHEAD_ECORE`
 pub const TIMER_OVERHEAD_ECORE : u64 = 52;
 
 /**
@@ -32,7 +35,7 @@ pub unsafe fn time_access(addr: u64) -> u64 {
         "dsb sy",
         val_out = out(reg) _,
         addr = in(reg) addr,
-        cnt_addr = in(reg) &mut counter::CTR as *mut u64 as u64,
+        cnt_addr = in(reg) core::ptr::addr_of_mut!(counter::CTR) as u64,
         t1 = out(reg) t1,
         t2 = out(reg) t2,
     }
@@ -58,7 +61,7 @@ pub unsafe fn time_access(addr: u64) -> u64 {
         "dsb sy",
         val_in = in(reg) val_in,
         addr = in(reg) addr,
-        cnt_addr = in(reg) &mut counter::CTR as *mut u64 as u64,
+        cnt_addr = in(reg) core::ptr::addr_of_mut!(counter::CTR) as u64,
         t1 = out(reg) t1,
         t2 = out(reg) t2,
     }
@@ -82,7 +85,7 @@ pub unsafe fn time_access(addr: u64) -> u64 {
         "isb",
         "dsb sy",
         addr = in(reg) addr,
-        cnt_addr = in(reg) &mut counter::CTR as *mut u64 as u64,
+        cnt_addr = in(reg) core::ptr::addr_of_mut!(counter::CTR) as u64,
         t1 = out(reg) t1,
         t2 = out(reg) t2,
     }
@@ -96,7 +99,6 @@ pub unsafe fn time_access(addr: u64) -> u64 {
 pub fn timer_overhead() -> u64 {
     let t1 : u64;
     let t2 : u64;
-    let val_out : u64;
     unsafe {
         asm!{
             "isb",
@@ -106,7 +108,7 @@ pub fn timer_overhead() -> u64 {
             "isb",
             "ldr {t2}, [{cnt_addr}]",
             "isb",
-            cnt_addr = in(reg) &mut counter::CTR as *mut u64 as u64,
+            cnt_addr = in(reg) core::ptr::addr_of_mut!(counter::CTR) as u64,
             t1 = out(reg) t1,
             t2 = out(reg) t2,
         }
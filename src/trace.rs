@@ -0,0 +1,412 @@
+/*!
+ * Raw trace recording and offline replay of PACMAN trials.
+ *
+ * Every `pacman_try_one` invocation can be recorded to a compact binary trace. A trace can later be
+ * replayed through the classifier, threshold and bruteforce decision logic (`attacks::decision`)
+ * on any machine, so the decision algorithm can be tuned against real M1 data without re-running
+ * the attack.
+ *
+ * # File Format
+ * All integers are little endian.
+ * ```text
+ * Header:  "PACTRACE" (8 bytes) | version: u32
+ * Record:  timestamp: u64 | holder: u64 | guess: u64 | limit_evict_cycles: u64 |
+ *          phase: u8 | label: u8 | num_probes: u16 | probe latency: u32 (x num_probes)
+ * ```
+ * Probe latencies are saturated to `u32::MAX` (a measurement that large is a miss either way).
+ */
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use crate::attacks::decision::*;
+
+/// Magic bytes at the start of every trace file
+pub const TRACE_MAGIC : &[u8; 8] = b"PACTRACE";
+
+/// Current trace file format version
+pub const TRACE_VERSION : u32 = 1;
+
+/**
+ * Which part of the attack produced a trial?
+ */
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TracePhase {
+    /// `pacman_differentiate`
+    Differentiate = 0,
+
+    /// The first pass of `pacman_bruteforce` over every PAC
+    Scan = 1,
+
+    /// The second pass of `pacman_bruteforce` over the potential matches
+    Final = 2,
+}
+
+/**
+ * Do we know whether the guess of a trial was correct?
+ */
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TraceLabel {
+    Unknown = 0,
+    Correct = 1,
+    Incorrect = 2,
+}
+
+/**
+ * One `pacman_try_one` invocation.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceRecord {
+    /// `mach_absolute_time` after the trial completed
+    pub timestamp: u64,
+
+    /// The address holding the pointer under test
+    pub holder: u64,
+
+    /// The pointer (with guessed PAC) under test
+    pub guess: u64,
+
+    /// Total latency of evicting LIMIT
+    pub limit_evict_cycles: u64,
+
+    pub phase: TracePhase,
+    pub label: TraceLabel,
+
+    /// Probe latencies of the forge eviction set, in probe order
+    pub probe_latencies: Vec<u64>,
+}
+
+/**
+ * Writes `TraceRecord`s to a trace file.
+ *
+ * The phase and label are properties of the recorder (rather than passed with every trial) as the
+ * code running trials doesn't know which phase of the attack it is part of.
+ */
+pub struct TraceRecorder {
+    out: Box<dyn Write>,
+    phase: TracePhase,
+    label: TraceLabel,
+}
+
+impl TraceRecorder {
+    /**
+     * Start a new trace on a writer (writes the file header).
+     */
+    pub fn new(mut out: Box<dyn Write>) -> std::io::Result<Self> {
+        out.write_all(TRACE_MAGIC)?;
+        out.write_all(&TRACE_VERSION.to_le_bytes())?;
+        return Ok(Self {
+            out,
+            phase: TracePhase::Scan,
+            label: TraceLabel::Unknown,
+        });
+    }
+
+    /**
+     * Start a new trace file at `path` (truncating it if it exists).
+     */
+    pub fn create(path: &str) -> std::io::Result<Self> {
+        return Self::new(Box::new(BufWriter::new(File::create(path)?)));
+    }
+
+    /// Set the phase and label of every trial recorded from now on
+    pub fn set_phase(&mut self, phase: TracePhase, label: TraceLabel) {
+        self.phase = phase;
+        self.label = label;
+    }
+
+    /**
+     * Record one trial.
+     *
+     * # Arguments
+     * * `timestamp`: When the trial completed.
+     * * `holder`: The address holding the pointer under test.
+     * * `guess`: The pointer under test.
+     * * `limit_evict_cycles`: Total latency of evicting LIMIT.
+     * * `probe_latencies`: Probe latencies of the forge eviction set.
+     */
+    pub fn record(&mut self, timestamp: u64, holder: u64, guess: u64, limit_evict_cycles: u64, probe_latencies: &[u64]) -> std::io::Result<()> {
+        let num_probes : u16 = match probe_latencies.len().try_into() {
+            Ok(v) => v,
+            Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Too many probes for one trace record")),
+        };
+
+        let mut buf = Vec::with_capacity(36 + 4 * probe_latencies.len());
+        buf.extend_from_slice(&timestamp.to_le_bytes());
+        buf.extend_from_slice(&holder.to_le_bytes());
+        buf.extend_from_slice(&guess.to_le_bytes());
+        buf.extend_from_slice(&limit_evict_cycles.to_le_bytes());
+        buf.push(self.phase as u8);
+        buf.push(self.label as u8);
+        buf.extend_from_slice(&num_probes.to_le_bytes());
+        for latency in probe_latencies {
+            let latency_u32 = (*latency).min(u32::MAX as u64) as u32;
+            buf.extend_from_slice(&latency_u32.to_le_bytes());
+        }
+
+        return self.out.write_all(&buf);
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        return self.out.flush();
+    }
+}
+
+/// Shorthand for a corrupt trace error
+fn invalid_data(msg: &str) -> std::io::Error {
+    return std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+}
+
+/**
+ * Reads `TraceRecord`s back out of a trace file.
+ */
+pub struct TraceReader<R: Read> {
+    input: R,
+}
+
+impl TraceReader<BufReader<File>> {
+    /**
+     * Open a trace file.
+     */
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        return Self::new(BufReader::new(File::open(path)?));
+    }
+}
+
+impl<R: Read> TraceReader<R> {
+    /**
+     * Start reading a trace (checks the file header).
+     */
+    pub fn new(mut input: R) -> std::io::Result<Self> {
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != TRACE_MAGIC {
+            return Err(invalid_data("Not a PACMAN trace"));
+        }
+
+        let mut version = [0u8; 4];
+        input.read_exact(&mut version)?;
+        if u32::from_le_bytes(version) != TRACE_VERSION {
+            return Err(invalid_data("Unsupported trace version"));
+        }
+
+        return Ok(Self { input });
+    }
+
+    /**
+     * Read the next record. Returns `Ok(None)` at the end of the trace.
+     */
+    pub fn next_record(&mut self) -> std::io::Result<Option<TraceRecord>> {
+        let mut fixed = [0u8; 36];
+
+        // Distinguish a clean end of file from a truncated record
+        let mut read = 0;
+        while read < fixed.len() {
+            let n = self.input.read(&mut fixed[read..])?;
+            if n == 0 {
+                if read == 0 {
+                    return Ok(None);
+                }
+                return Err(invalid_data("Truncated trace record"));
+            }
+            read += n;
+        }
+
+        let u64_at = |i: usize| u64::from_le_bytes(fixed[i..i+8].try_into().unwrap());
+        let phase = match fixed[32] {
+            0 => TracePhase::Differentiate,
+            1 => TracePhase::Scan,
+            2 => TracePhase::Final,
+            _ => return Err(invalid_data("Unknown trace phase")),
+        };
+        let label = match fixed[33] {
+            0 => TraceLabel::Unknown,
+            1 => TraceLabel::Correct,
+            2 => TraceLabel::Incorrect,
+            _ => return Err(invalid_data("Unknown trace label")),
+        };
+        let num_probes = u16::from_le_bytes([fixed[34], fixed[35]]) as usize;
+
+        let mut probes = vec![0u8; 4 * num_probes];
+        self.input.read_exact(&mut probes)?;
+
+        return Ok(Some(TraceRecord {
+            timestamp: u64_at(0),
+            holder: u64_at(8),
+            guess: u64_at(16),
+            limit_evict_cycles: u64_at(24),
+            phase,
+            label,
+            probe_latencies: probes.chunks(4).map(|c| u32::from_le_bytes(c.try_into().unwrap()) as u64).collect(),
+        }));
+    }
+
+    /**
+     * Read every remaining record.
+     */
+    pub fn read_all(&mut self) -> std::io::Result<Vec<TraceRecord>> {
+        let mut records = Vec::new();
+        while let Some(record) = self.next_record()? {
+            records.push(record);
+        }
+        return Ok(records);
+    }
+}
+
+/**
+ * Consecutive trials of the same guess (what `pacman_direct`/ `pacman_indirect` would return).
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceGroup {
+    pub phase: TracePhase,
+    pub label: TraceLabel,
+    pub holder: u64,
+    pub guess: u64,
+
+    /// Miss count of every trial in the group
+    pub samples: Vec<u64>,
+}
+
+/**
+ * Group consecutive records of the same phase/ holder/ guess together and classify every trial.
+ *
+ * # Arguments
+ * * `records`: The recorded trials, in the order they were recorded.
+ * * `miss_latency`: What probe latency constitutes a cache miss? (Need not match the recording!)
+ */
+pub fn group_records(records: &[TraceRecord], miss_latency: u64) -> Vec<TraceGroup> {
+    let mut groups : Vec<TraceGroup> = Vec::new();
+
+    for record in records {
+        let misses = count_misses(&record.probe_latencies, miss_latency);
+
+        if let Some(last) = groups.last_mut() {
+            if last.phase == record.phase && last.label == record.label && last.holder == record.holder && last.guess == record.guess {
+                last.samples.push(misses);
+                continue;
+            }
+        }
+
+        groups.push(TraceGroup {
+            phase: record.phase,
+            label: record.label,
+            holder: record.holder,
+            guess: record.guess,
+            samples: vec![misses],
+        });
+    }
+
+    return groups;
+}
+
+/**
+ * The outcome of replaying a trace.
+ */
+#[derive(Clone, Debug)]
+pub struct ReplayReport {
+    /// Every group of trials in the trace
+    pub groups: Vec<TraceGroup>,
+
+    /// The decision logic after seeing every scan/ final group (`None` if there were none)
+    pub decider: Option<BruteforceDecider>,
+
+    /// Potential matches with no final-phase trials in the trace (scan trials were inspected instead)
+    pub inspected_from_scan: Vec<u64>,
+}
+
+/**
+ * Replay a recorded trace through the bruteforce decision logic.
+ *
+ * Scan groups are fed to a fresh `BruteforceDecider` in recorded order. The potential matches it
+ * picks are then inspected using the final-phase trials of the trace. A different decision
+ * algorithm may pick potential matches the recorded run never inspected- those are inspected
+ * using their scan trials instead (and listed in `inspected_from_scan`).
+ *
+ * # Arguments
+ * * `records`: The recorded trials.
+ * * `miss_latency`: What probe latency constitutes a cache miss?
+ * * `how_far_above_average`: See `BruteforceDecider`.
+ */
+pub fn replay(records: &[TraceRecord], miss_latency: u64, how_far_above_average: f64) -> ReplayReport {
+    let groups = group_records(records, miss_latency);
+    let evset_size = records.iter().map(|r| r.probe_latencies.len()).max().unwrap_or(0);
+
+    let mut inspected_from_scan = Vec::new();
+    let mut decider = None;
+
+    if groups.iter().any(|g| g.phase == TracePhase::Scan) {
+        let mut bruteforce = BruteforceDecider::new(evset_size, how_far_above_average);
+
+        for group in groups.iter().filter(|g| g.phase == TracePhase::Scan) {
+            bruteforce.scan(group.guess, &group.samples);
+        }
+
+        for potential_match in bruteforce.potential_matches().to_vec() {
+            let final_samples : Vec<u64> = groups.iter()
+                .filter(|g| g.phase == TracePhase::Final && g.guess == potential_match)
+                .flat_map(|g| g.samples.iter().cloned())
+                .collect();
+
+            if !final_samples.is_empty() {
+                bruteforce.inspect(potential_match, &final_samples);
+                continue;
+            }
+
+            let scan_samples : Vec<u64> = groups.iter()
+                .filter(|g| g.phase == TracePhase::Scan && g.guess == potential_match)
+                .flat_map(|g| g.samples.iter().cloned())
+                .collect();
+            bruteforce.inspect(potential_match, &scan_samples);
+            inspected_from_scan.push(potential_match);
+        }
+
+        decider = Some(bruteforce);
+    }
+
+    return ReplayReport {
+        groups,
+        decider,
+        inspected_from_scan,
+    };
+}
+
+/**
+ * Replay a trace file and print a summary.
+ *
+ * # Arguments
+ * * `path`: The trace file to replay.
+ * * `miss_latency`: What probe latency constitutes a cache miss?
+ * * `how_far_above_average`: See `BruteforceDecider`.
+ */
+pub fn replay_file(path: &str, miss_latency: u64, how_far_above_average: f64) -> std::io::Result<ReplayReport> {
+    let records = TraceReader::open(path)?.read_all()?;
+    println!("Replaying {} trials from {}", records.len(), path);
+
+    let report = replay(&records, miss_latency, how_far_above_average);
+
+    for group in report.groups.iter().filter(|g| g.phase == TracePhase::Differentiate) {
+        let marker = match group.label {
+            TraceLabel::Correct => "[*]",
+            TraceLabel::Incorrect => "[x]",
+            TraceLabel::Unknown => "[?]",
+        };
+        if let Some(stats) = SampleStats::from_samples(&group.samples) {
+            println!("{} 0x{:X}: {}, {}, {}, {}, {}", marker, group.guess, stats.min, stats.median, stats.max, stats.avg, stats.total);
+        }
+    }
+
+    if let Some(decider) = &report.decider {
+        println!("The number to beat is {:?} misses", decider.threshold());
+        println!("Found {} potential matches", decider.potential_matches().len());
+        for guess in &report.inspected_from_scan {
+            println!("No final trials recorded for 0x{:X}, inspected its scan trials instead", guess);
+        }
+        println!("{:?}", decider.final_matches());
+        match decider.decide() {
+            Some(answer) => println!("Final answer: 0x{:X}", answer),
+            None => println!("No final answer"),
+        }
+    }
+
+    return Ok(report);
+}
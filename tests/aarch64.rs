@@ -0,0 +1,90 @@
+/*!
+ * Decoding and encoding AArch64 instructions.
+ */
+
+use pacman::aarch64::*;
+use pacman::kernelcache::macho::PtrAuthKey;
+
+#[test]
+fn branches() {
+    assert_eq!(decode(0x54000102), Inst::BCond { cond: Cond(2), offset: 0x20 });
+    assert_eq!(Cond(2).name(), "hs");
+    assert_eq!(decode(0xB5000089), Inst::Cbz { rt: 9, nonzero: true, offset: 0x10 });
+    assert_eq!(decode(0x36000043), Inst::Tbz { rt: 3, bit: 0, nonzero: false, offset: 8 });
+    assert_eq!(decode(0x17FFFFFF), Inst::B { offset: -4, link: false });
+    assert_eq!(decode(0x94000010), Inst::B { offset: 0x40, link: true });
+    assert_eq!(decode(0xD63F0100), Inst::Br { rn: 8, link: true });
+    assert_eq!(decode(0xD65F03C0), Inst::Ret { rn: 30, key: None });
+    assert_eq!(decode(0xD65F0FFF), Inst::Ret { rn: 30, key: Some(PtrAuthKey::IB) });
+    assert_eq!(decode(0x54000102).branch_target(0x1000), Some(0x1020));
+}
+
+#[test]
+fn pointer_authentication() {
+    assert_eq!(decode(0xDAC11A30), Inst::Aut { key: PtrAuthKey::DA, rd: 16, modifier: Some(17) });
+    assert_eq!(decode(0xDAC11628), Inst::Aut { key: PtrAuthKey::IB, rd: 8, modifier: Some(17) });
+    assert_eq!(decode(0xDAC13BF0), Inst::Aut { key: PtrAuthKey::DA, rd: 16, modifier: None });
+    assert_eq!(decode(0xDAC10A30), Inst::Pac { key: PtrAuthKey::DA, rd: 16, modifier: Some(17) });
+    assert_eq!(decode(0xD73F0911), Inst::BrAuth { key: PtrAuthKey::IA, rn: 8, modifier: Some(17), link: true });
+    assert_eq!(decode(0xD63F091F), Inst::BrAuth { key: PtrAuthKey::IA, rn: 8, modifier: None, link: true });
+    assert_eq!(decode(0xD61F0D1F), Inst::BrAuth { key: PtrAuthKey::IB, rn: 8, modifier: None, link: false });
+}
+
+#[test]
+fn loads_moves_and_compares() {
+    assert_eq!(decode(0xF9419E68), Inst::Ldr { rt: 8, rn: 19, offset: 0x338, size: 8 });
+    assert_eq!(decode(0xF85F8028), Inst::Ldr { rt: 8, rn: 1, offset: -8, size: 8 });
+    assert_eq!(decode(0xB9400020), Inst::Ldr { rt: 0, rn: 1, offset: 0, size: 4 });
+    assert_eq!(decode(0xF8626820), Inst::LdrReg { rt: 0, rn: 1, rm: 2, size: 8 });
+    assert_eq!(decode(0xF9000820), Inst::Str { rt: 0, rn: 1, offset: 0x10, size: 8 });
+    assert_eq!(decode(0xF2FB30D1), Inst::Mov { rd: 17, imm: 0xd986, shift: 48, keep: true });
+    assert_eq!(decode(0xD2824691), Inst::Mov { rd: 17, imm: 0x1234, shift: 0, keep: false });
+    assert_eq!(decode(0xAA0003F1), Inst::MovReg { rd: 17, rm: 0 });
+    assert_eq!(decode(0x91004000), Inst::AddImm { rd: 0, rn: 0, imm: 0x10, negative: false });
+    assert_eq!(decode(0xEB01011F), Inst::CmpReg { rn: 8, rm: 1 });
+    assert_eq!(decode(0xF100051F), Inst::CmpImm { rn: 8, imm: 1 });
+    assert_eq!(decode(0xD503201F), Inst::Barrier);
+
+    // Undecoded instructions are assumed to write their Rd
+    assert_eq!(decode(0x8B020020), Inst::Other(0x8B020020));
+    assert_eq!(decode(0x8B020020).dest(), Some(0));
+    assert_eq!(decode(0xEB01011F).dest(), None);
+}
+
+#[test]
+fn encodings() {
+    // Every word the decoding tests start from encodes back byte for byte
+    for word in [
+        0x54000102, 0xB5000089, 0x36000043, 0x17FFFFFF, 0x94000010, 0xD63F0100, 0xD65F03C0, 0xD65F0FFF,
+        0xDAC11A30, 0xDAC11628, 0xDAC13BF0, 0xDAC10A30, 0xD73F0911, 0xD63F091F, 0xD61F0D1F,
+        0xF9419E68, 0xF85F8028, 0xB9400020, 0xF8626820, 0xF9000820,
+        0xF2FB30D1, 0xD2824691, 0xAA0003F1, 0x91004000, 0xEB01011F, 0xF100051F, 0x8B020020,
+    ] {
+        assert_eq!(decode(word).encode(), Some(word), "0x{:08X}", word);
+    }
+
+    assert_eq!(Inst::Br { rn: 16, link: false }.encode(), Some(0xD61F0200));
+    assert_eq!(Inst::AddImm { rd: XZR, rn: XZR, imm: 0x10, negative: true }.encode(), Some(0xD10043FF));
+    assert_eq!(Inst::AddImm { rd: 0, rn: 1, imm: 0x1000, negative: false }.encode(), Some(0x91400420));
+    assert_eq!(Inst::Ldr { rt: 0, rn: 1, offset: 1, size: 1 }.encode(), Some(0x39400420));
+    // Unaligned offsets fall back to ldur
+    assert_eq!(Inst::Ldr { rt: 0, rn: 1, offset: 1, size: 8 }.encode(), Some(0xF8401020));
+
+    assert_eq!(encode_all(&[Inst::Barrier]), None);
+    assert_eq!(encode_all(&[decode(RET), Inst::Other(NOP)]).unwrap(), vec![0xC0, 0x03, 0x5F, 0xD6, 0x1F, 0x20, 0x03, 0xD5]);
+}
+
+#[test]
+fn unencodable() {
+    assert_eq!(Inst::B { offset: 2, link: false }.encode(), None);
+    assert_eq!(Inst::B { offset: 1 << 27, link: false }.encode(), None);
+    assert_eq!(Inst::B { offset: -(1 << 27), link: false }.encode(), Some(0x16000000));
+    assert_eq!(Inst::BCond { cond: Cond(0), offset: 1 << 20 }.encode(), None);
+    assert_eq!(Inst::Tbz { rt: 0, bit: 64, nonzero: false, offset: 0 }.encode(), None);
+    assert_eq!(Inst::BrAuth { key: PtrAuthKey::DA, rn: 8, modifier: None, link: true }.encode(), None);
+    assert_eq!(Inst::Ret { rn: 1, key: Some(PtrAuthKey::IA) }.encode(), None);
+    assert_eq!(Inst::Mov { rd: 0, imm: 1, shift: 8, keep: false }.encode(), None);
+    assert_eq!(Inst::AddImm { rd: 0, rn: 0, imm: 0x1001, negative: false }.encode(), None);
+    assert_eq!(Inst::Ldr { rt: 0, rn: 1, offset: 0, size: 3 }.encode(), None);
+    assert_eq!(Inst::Str { rt: 0, rn: 1, offset: -0x200, size: 8 }.encode(), None);
+}
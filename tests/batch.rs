@@ -0,0 +1,124 @@
+/*!
+ * Batches of kernel operations, run natively by the simulated kernel and one call at a time by everything else.
+ */
+
+mod common;
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use pacman::attacks::pacman::{find_victim_objects, BatchedTraining, Training, TRAINING_CALLS};
+use pacman::batch::*;
+use pacman::error::PacmanError;
+use pacman::pacmankit::{KernReturn, PacmanKitBackend, PacmanKitConnection, PacmanKitOp, KERN_INVALID_ADDRESS, KIO_RETURN_UNSUPPORTED};
+use pacman::primitives::*;
+use pacman::sim::kernel::{self, SimulatedKernel};
+
+/// Counts the scalar and structure calls made through it
+struct Counting {
+    inner: PacmanKitConnection,
+    structs: bool,
+    calls: Rc<Cell<usize>>,
+}
+
+impl PacmanKitBackend for Counting {
+    fn call(&self, op: PacmanKitOp, args: &[u64], output: &mut [u64]) -> KernReturn {
+        self.calls.set(self.calls.get() + 1);
+        self.inner.call(op, args, output)
+    }
+
+    fn call_struct(&self, op: PacmanKitOp, input: &[u8], output: &mut [u8]) -> KernReturn {
+        if !self.structs {
+            return KIO_RETURN_UNSUPPORTED;
+        }
+        self.calls.set(self.calls.get() + 1);
+        self.inner.call_struct(op, input, output)
+    }
+}
+
+/// A connection to `kernel` that counts its calls (and can run batches if `structs`)
+fn counting(kernel: &Arc<Mutex<SimulatedKernel>>, structs: bool) -> (PacmanKitConnection, Rc<Cell<usize>>) {
+    let calls = Rc::new(Cell::new(0));
+    let inner = kernel::connect(kernel).unwrap();
+    let connection = PacmanKitConnection::with_backend(Box::new(Counting { inner, structs, calls: calls.clone() }));
+    (connection, calls)
+}
+
+#[test]
+fn encode_and_decode() {
+    let mut batch = Batch::new();
+    batch.write(0xFFFFFE0000004000, 0x41).read(0xFFFFFE0000004000).call_service_routine([1, 2, 3, 4, 5, 6], 12);
+    batch.push(BatchOp::ExecForSpectre { addr: 0xFFFFFE0000008000, idx: 0x50 }, 1);
+
+    let bytes = batch.encode();
+    assert_eq!(bytes.len(), 4 * BATCH_ENTRY_SIZE);
+    assert_eq!(Batch::decode(&bytes), Some(batch));
+    assert_eq!(Batch::decode(&bytes[1..]), None);
+
+    // Only the batchable selectors
+    let mut not_batchable = bytes[..BATCH_ENTRY_SIZE].to_vec();
+    not_batchable[0] = PacmanKitOp::GimmeMemory as u8;
+    assert_eq!(Batch::decode(&not_batchable), None);
+
+    let long = Batch::decode(&bytes.repeat(MAX_BATCH_OPS)).unwrap();
+    assert_eq!(long.chunks().iter().map(|chunk| chunk.len()).collect::<Vec<_>>(), vec![MAX_BATCH_OPS; 4]);
+}
+
+#[test]
+fn batches_are_one_call_when_supported() {
+    let kernel = common::boot(80);
+    let (batched, batched_calls) = counting(&kernel, true);
+    let (scalar, scalar_calls) = counting(&kernel, false);
+
+    unsafe {
+        let buffer = batched.kernel_mmap().unwrap();
+        batched_calls.set(0);
+
+        let mut batch = Batch::new();
+        batch.write(buffer, 0x41).write(buffer + 8, 0x42).read(buffer).read(buffer + 8);
+        assert_eq!(batched.run_batch(&batch), Ok(vec![0, 0, 0x41, 0x42]));
+        assert_eq!(batched_calls.get(), 1);
+
+        // Falls back to a call per operation
+        assert_eq!(scalar.run_batch(&batch), Ok(vec![0, 0, 0x41, 0x42]));
+        assert_eq!(scalar_calls.get(), 4);
+    }
+}
+
+#[test]
+fn batches_stop_at_the_first_failure() {
+    let kernel = common::boot(81);
+
+    for structs in [true, false] {
+        let (handle, _) = counting(&kernel, structs);
+        unsafe {
+            let buffer = handle.kernel_mmap().unwrap();
+            let mut batch = Batch::new();
+            batch.write(buffer, 0x41).read(8).write(buffer, 0x42);
+            assert_eq!(handle.run_batch(&batch), Err(PacmanError::OperationFailed { op: PacmanKitOp::Read, kret: KERN_INVALID_ADDRESS }));
+            assert_eq!(handle.kernel_read(buffer).unwrap(), 0x41);
+        }
+    }
+}
+
+#[test]
+fn batched_training_is_one_round_trip() {
+    let kernel = common::boot(82);
+    let handle = kernel::connect(&kernel).unwrap();
+    let (victim_handle, calls) = counting(&kernel, true);
+
+    unsafe {
+        let (_, object, vtable, _) = find_victim_objects(&victim_handle, &kernel::offset_profile()).unwrap();
+        calls.set(0);
+
+        let training = BatchedTraining::service_routine(&victim_handle);
+        (&training).train(&handle, object, vtable, 0x4141).unwrap();
+        assert_eq!(calls.get(), 1);
+        assert_eq!(handle.kernel_read(object).unwrap(), 0x4141);
+        handle.kernel_write(object, vtable).unwrap();
+    }
+
+    let kernel = kernel.lock().unwrap();
+    assert_eq!(kernel.service_calls().len(), TRAINING_CALLS as usize);
+    assert!(kernel.service_calls().iter().all(|call| *call == kernel.external_method()));
+}
@@ -0,0 +1,37 @@
+/*!
+ * Cache address math.
+ */
+
+use pacman::cache::*;
+
+const ADDRS : [u64; 4] = [0, 0x8_0123_4567, 0xFFFF_FE00_0712_3450, 0x1_FFFF_FFFF];
+
+#[test]
+fn generic_split_reassembles() {
+    for addr in ADDRS {
+        let tag = get_cache_tag_generic(addr);
+        let set = get_cache_set_generic(addr);
+        let offset = get_cache_offset_generic(addr);
+
+        assert!(set < L2_SETS as u64);
+        assert!(offset < L2_LINESIZE as u64);
+        assert_eq!((tag << 20) | (set << 7) | offset, addr);
+    }
+}
+
+#[test]
+fn m1_agrees_with_generic() {
+    for addr in ADDRS {
+        assert_eq!(get_cache_tag_m1(addr), get_cache_tag_generic(addr) & 0xFFFFFFFFFFF);
+        assert_eq!(get_cache_set_m1(addr) & (L2_SETS as u64 - 1), get_cache_set_generic(addr));
+        assert_eq!(get_cache_offset_m1(addr), get_cache_offset_generic(addr));
+    }
+}
+
+#[test]
+fn l1_set_wraps_every_l1_way() {
+    let base = 0x1234_5640u64;
+    let way_size = (L1I_SETS * L1I_LINESIZE) as u64;
+    assert_eq!(get_l1_cache_set_m1(base), get_l1_cache_set_m1(base + way_size));
+    assert_ne!(get_l1_cache_set_m1(base), get_l1_cache_set_m1(base + L1I_LINESIZE as u64));
+}
@@ -0,0 +1,27 @@
+/*!
+ * What the tests against the simulated kernel share.
+ */
+#![allow(dead_code)]
+
+use std::sync::{Arc, Mutex};
+use pacman::evset;
+use pacman::sim::kernel::{self, SimulatedKernel};
+use pacman::timer::{self, TimerBackend};
+
+/// Big enough for 16 eviction set candidates of anything in the simulated kernel
+pub const REGION_SIZE : usize = 0x70000000;
+
+/// Boot a simulated kernel and run everything on this thread against it
+pub fn boot(seed: u64) -> Arc<Mutex<SimulatedKernel>> {
+    let kernel = Arc::new(Mutex::new(SimulatedKernel::new(seed)));
+    kernel::install(&kernel);
+    timer::set_backend(TimerBackend::Simulated);
+    kernel
+}
+
+/// A memory region for eviction sets (aligned like `memory::alloc_memory_region`'s, never touched)
+pub fn memory_region(backing: &mut Vec<u8>) -> &mut [u8] {
+    *backing = vec![0u8; REGION_SIZE + evset::STRIDE];
+    let misalignment = backing.as_ptr() as usize % evset::STRIDE;
+    &mut backing[evset::STRIDE - misalignment..][..REGION_SIZE]
+}
@@ -0,0 +1,50 @@
+/*!
+ * Loading and validating experiment configs.
+ */
+
+use pacman::config::AttackConfig;
+
+#[test]
+fn empty_config_is_the_default() {
+    assert_eq!(AttackConfig::from_toml("").unwrap(), AttackConfig::default());
+    assert!(AttackConfig::default().validate().is_ok());
+}
+
+#[test]
+fn defaults_roundtrip() {
+    let text = toml::to_string(&AttackConfig::default()).unwrap();
+    assert_eq!(AttackConfig::from_toml(&text).unwrap(), AttackConfig::default());
+}
+
+#[test]
+fn partial_config_overrides_only_what_it_mentions() {
+    let config = AttackConfig::from_toml("
+        [pacman.data]
+        miss_latency = 110
+
+        [prime_probe.inst]
+        num_iters = 3
+    ").unwrap();
+
+    let default = AttackConfig::default();
+    assert_eq!(config.pacman.data.miss_latency, 110);
+    assert_eq!(config.pacman.data.num_iters, default.pacman.data.num_iters);
+    assert_eq!(config.pacman.inst, default.pacman.inst);
+    assert_eq!(config.prime_probe.inst.num_iters, 3);
+    assert_eq!(config.evict_reload, default.evict_reload);
+}
+
+#[test]
+fn unknown_parameters_are_rejected() {
+    assert!(AttackConfig::from_toml("[pacman.data]\nmiss_latncy = 110").is_err());
+    assert!(AttackConfig::from_toml("[pacmn]").is_err());
+}
+
+#[test]
+fn validate_names_the_section() {
+    let config = AttackConfig::from_toml("[pacman.inst]\nnum_trials = 1").unwrap();
+    assert!(config.validate().unwrap_err().starts_with("pacman.inst:"));
+
+    let config = AttackConfig::from_toml("[prime_probe.data]\nnum_iters = 0").unwrap();
+    assert!(config.validate().unwrap_err().starts_with("prime_probe.data:"));
+}
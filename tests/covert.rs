@@ -0,0 +1,95 @@
+/*!
+ * The covert channel: its codes and framing, and a message sent between two threads through the simulated cache.
+ */
+
+mod common;
+
+use pacman::config::AttackConfig;
+use pacman::covert::channel::*;
+use pacman::covert::ecc::Ecc;
+use pacman::covert::frame::*;
+use pacman::evset;
+use pacman::results::JsonLinesSink;
+
+const BYTES : &[u8] = b"PACMAN";
+
+#[test]
+fn codes_correct_single_errors() {
+    let bits = bytes_to_bits(BYTES);
+    for ecc in [Ecc::Hamming, Ecc::Repetition { n: 3 }] {
+        let mut coded = ecc.encode(&bits);
+        assert_eq!(coded.len(), ecc.coded_len(bits.len()));
+        assert_eq!(ecc.decode(&coded), (bits.clone(), 0));
+
+        // One error in each of the first two codewords
+        coded[1] = !coded[1];
+        coded[9] = !coded[9];
+        assert_eq!(ecc.decode(&coded), (bits.clone(), 2), "{}", ecc);
+    }
+    assert_eq!(bits_to_bytes(&bits), BYTES);
+}
+
+#[test]
+fn ecc_names() {
+    for text in ["none", "repeat:5", "hamming"] {
+        assert_eq!(text.parse::<Ecc>().unwrap().to_string(), text);
+    }
+    assert!("repeat:2".parse::<Ecc>().is_err());
+    assert!("golay".parse::<Ecc>().is_err());
+}
+
+/// Run `bits` through a deframer, returning everything it found
+fn deframe(bits: &[bool], ecc: Ecc) -> Vec<Deframed> {
+    let mut deframer = Deframer::new(ecc);
+    bits.iter().filter_map(|bit| deframer.push(*bit)).collect()
+}
+
+#[test]
+fn frames_are_found_in_noise() {
+    let mut bits = vec![false, true, false, false, true];
+    bits.extend(encode_frame(3, BYTES, Ecc::Hamming));
+    bits.extend([false; 4]);
+    bits.extend(encode_frame(4, &BYTES[..2], Ecc::Hamming));
+
+    // A bit of the sync word and a bit of the body are wrong
+    bits[6] = !bits[6];
+    bits[60] = !bits[60];
+    let frames = deframe(&bits, Ecc::Hamming);
+    assert_eq!(frames, vec![
+        Deframed::Frame(Frame { seq: 3, payload: BYTES.to_vec(), corrected: 1 }),
+        Deframed::Frame(Frame { seq: 4, payload: BYTES[..2].to_vec(), corrected: 0 }),
+    ]);
+}
+
+#[test]
+fn checksum_catches_what_ecc_misses() {
+    let mut bits = encode_frame(1, BYTES, Ecc::None);
+    bits[SYNC.len() + 8 * HEADER_SIZE + 3] ^= true;
+    assert_eq!(deframe(&bits, Ecc::None), vec![Deframed::BadChecksum { seq: 1 }]);
+}
+
+#[test]
+fn channel_config() {
+    let config = AttackConfig::from_toml("[covert]\necc = \"repeat:3\"").unwrap();
+    assert_eq!(config.covert.ecc, Ecc::Repetition { n: 3 });
+    assert!(config.validate().is_ok());
+
+    let config = AttackConfig::from_toml("[covert]\nframe_size = 1\nmessage_size = 300").unwrap();
+    assert!(config.validate().unwrap_err().starts_with("covert:"));
+}
+
+#[test]
+fn message_gets_through_the_simulated_cache() {
+    common::boot(50);
+
+    let config = ChannelConfig { message_size: 8, frame_size: 4, slot_micros: 10000, ..ChannelConfig::default() };
+    let mut memory_region = vec![0u8; (config.receiver_evset_size + config.sender_evset_size + 1) * evset::STRIDE];
+    let mut sink = JsonLinesSink::new(Vec::new());
+    let report = covert_channel(&mut memory_region, &config, &mut sink).unwrap();
+
+    // The threads share the machine with everything else, so a frame can be lost now and then,
+    // but the ones that arrive must be right
+    assert!(report.raw_bit_error_rate() < 0.05, "{}", report);
+    assert!(report.frames_received > 0, "{}", report);
+    assert_eq!(report.message_bit_errors, (report.frames_sent - report.frames_received) * config.frame_size * 8, "{}", report);
+}
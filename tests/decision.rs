@@ -0,0 +1,84 @@
+/*!
+ * The PACMAN decision logic.
+ */
+
+use pacman::attacks::decision::*;
+
+#[test]
+fn misses_are_strictly_above_the_latency() {
+    assert_eq!(count_misses(&[10, 42, 43, 100], 42), 2);
+    assert_eq!(count_misses(&[], 42), 0);
+}
+
+#[test]
+fn sample_stats() {
+    assert_eq!(SampleStats::from_samples(&[]), None);
+
+    let stats = SampleStats::from_samples(&[7, 1, 5, 3, 9, 0]).unwrap();
+    assert_eq!(stats.min, 3);
+    assert_eq!(stats.median, 5);
+    assert_eq!(stats.max, 7);
+    assert_eq!(stats.total, 25);
+    assert!((stats.avg - 25.0 / 6.0).abs() < 1e-9);
+
+    let stats = SampleStats::from_samples(&[4]).unwrap();
+    assert_eq!((stats.min, stats.median, stats.max, stats.total), (4, 4, 4, 4));
+}
+
+#[test]
+fn bruteforce_picks_the_loudest_match() {
+    let mut decider = BruteforceDecider::new(12, 4.0);
+
+    // The first guess sets the baseline
+    assert!(!decider.scan(0x100, &[1, 1, 1, 1]));
+    assert_eq!(decider.threshold(), Some(5.0));
+    assert!(!decider.threshold_capped());
+
+    assert!(!decider.scan(0x101, &[2, 2, 2, 2]));
+    assert!(decider.scan(0x102, &[3, 3, 3, 3]));
+    assert!(decider.scan(0x103, &[8, 8, 8, 8]));
+    assert_eq!(decider.potential_matches(), &[0x102, 0x103]);
+
+    assert!(!decider.inspect(0x102, &[2, 2, 2, 2]));
+    assert!(decider.inspect(0x103, &[6, 6, 6, 6]));
+    assert_eq!(decider.final_matches(), &[(24, 0x103)]);
+    assert_eq!(decider.decide(), Some(0x103));
+}
+
+#[test]
+fn bruteforce_prefers_more_misses() {
+    let mut decider = BruteforceDecider::new(12, 4.0);
+    decider.scan(0, &[0, 0]);
+    decider.inspect(1, &[5, 5]);
+    decider.inspect(2, &[9, 9]);
+    decider.inspect(3, &[6, 6]);
+    assert_eq!(decider.decide(), Some(2));
+}
+
+#[test]
+fn bruteforce_threshold_is_capped() {
+    let mut decider = BruteforceDecider::new(12, 4.0);
+    decider.scan(0, &[10, 10, 10]);
+    assert_eq!(decider.threshold(), Some(12.0));
+    assert!(decider.threshold_capped());
+}
+
+#[test]
+fn bruteforce_without_matches() {
+    let mut decider = BruteforceDecider::new(12, 4.0);
+    assert!(!decider.inspect(0, &[12]));
+    assert_eq!(decider.decide(), None);
+}
+
+#[test]
+fn median_ignores_a_noisy_measurement() {
+    // A few interrupted measurements push the average of a wrong guess over the threshold, but not its median
+    let noisy = [0, 0, 0, 0, 0, 12, 12, 12];
+    let mut average = BruteforceDecider::with_statistic(12, 4.0, DecisionStatistic::Average);
+    let mut median = BruteforceDecider::with_statistic(12, 4.0, DecisionStatistic::Median);
+    for decider in [&mut average, &mut median] {
+        decider.scan(0, &[0; 8]);
+    }
+    assert!(average.scan(1, &noisy));
+    assert!(!median.scan(1, &noisy));
+}
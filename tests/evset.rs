@@ -0,0 +1,109 @@
+/*!
+ * Eviction set construction.
+ */
+
+use pacman::aarch64::{decode, Inst, RET};
+use pacman::cache::{self, L1D_WAYS, L1I_WAYS};
+use pacman::error::PacmanError;
+use pacman::evset::*;
+use pacman::payload::{Payload, JUMP_REG};
+
+#[test]
+fn choose_evset_picks_distinct_candidates() {
+    let candidates : Vec<u64> = (0..64).map(|i| 0x1000 * i).collect();
+    let mut chosen = choose_evset(&candidates, 12, "test").unwrap();
+    assert_eq!(chosen.len(), 12);
+    assert!(chosen.iter().all(|c| candidates.contains(c)));
+
+    chosen.sort();
+    chosen.dedup();
+    assert_eq!(chosen.len(), 12);
+}
+
+#[test]
+fn choose_evset_needs_enough_candidates() {
+    assert_eq!(choose_evset(&[0, 1, 2], 4, "test"), Err(PacmanError::TooFewCandidates { name: "test", size: 4, found: 3 }));
+}
+
+#[test]
+fn data_evset_shares_an_l1_set() {
+    let mut region = vec![0u8; 0x100000];
+    let base = &region[0] as *const u8;
+    let evset = data_evset(base, &mut region);
+
+    assert_eq!(evset.len(), L1D_WAYS);
+    for entry in &evset {
+        assert_eq!(cache::get_l1_cache_set_m1(*entry as u64), cache::get_l1_cache_set_m1(base as u64));
+    }
+}
+
+#[test]
+fn inst_evset_size() {
+    let mut region = vec![0u32; 0x100000];
+    let base = &region[0] as *const u32;
+    assert_eq!(inst_evset(base, &mut region).len(), L1I_WAYS);
+}
+
+#[test]
+fn data_pevset_candidates_are_stride_apart() {
+    let target = 0x1234u64;
+    let mut region = vec![0u8; 3 * STRIDE];
+    let base = &region[0] as *const u8 as u64;
+    let evset = data_pevset(target, target, &mut region);
+
+    assert_eq!(evset.len(), 3);
+    for (i, entry) in evset.iter().enumerate() {
+        assert_eq!(*entry, base + (i * STRIDE) as u64 + target);
+    }
+}
+
+/// Follow the code in `payloads` from `entry` to its `ret`, returning every line it runs through
+fn run_chain(payloads: &[Payload], entry: u64) -> Vec<u64> {
+    let word = |addr: u64| payloads.iter().find_map(|payload| payload.word(addr)).expect("Ran off the payloads");
+    let mut lines = vec![entry];
+    let mut pc = entry;
+    let mut jump_reg = 0u64;
+    loop {
+        match decode(word(pc)) {
+            Inst::Ret { .. } => return lines,
+            Inst::B { offset, link: false } => pc = pc.wrapping_add(offset as u64),
+            Inst::Mov { rd: JUMP_REG, imm, shift, keep } => {
+                let kept = if keep { jump_reg & !(0xFFFF << shift) } else { 0 };
+                jump_reg = kept | (imm as u64) << shift;
+                pc += 4;
+                continue;
+            },
+            Inst::Br { rn: JUMP_REG, link: false } => pc = jump_reg,
+            inst => panic!("Unexpected {:?} at 0x{:X}", inst, pc),
+        }
+        lines.push(pc);
+    }
+}
+
+#[test]
+fn inst_chain_runs_through_every_line() {
+    let base = 0x100000000u64 + 0x1234;
+    // The second hop is too far for a `b`, the others aren't
+    let evset = [base, base + 3 * STRIDE as u64, base + STRIDE as u64, base + 0x40];
+    let chain = InstChain::new(&evset);
+    let lines : Vec<u64> = evset.iter().map(|addr| addr & !0x3F).collect();
+    assert_eq!(chain.lines, lines);
+    assert_eq!(chain.entry(), base - 0x34);
+    assert_eq!(chain.probe(1), lines[1] + CHAIN_PROBE_OFFSET);
+
+    // One page per line (the last two share one)
+    let payloads = chain.payloads();
+    assert_eq!(payloads.len(), 3);
+    assert_eq!(run_chain(&payloads, chain.entry()), lines);
+
+    // Every line can also be probed on its own
+    for i in 0..lines.len() {
+        assert_eq!(payloads.iter().find_map(|payload| payload.word(chain.probe(i))), Some(RET));
+    }
+}
+
+#[test]
+#[should_panic]
+fn inst_chain_needs_distinct_lines() {
+    InstChain::new(&[0x1000, 0x1008]);
+}
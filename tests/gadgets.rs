@@ -0,0 +1,140 @@
+/*!
+ * Finding PACMAN gadgets in code.
+ */
+
+use pacman::kernelcache::gadgets::*;
+use pacman::kernelcache::macho::{MachO, PtrAuthKey};
+use pacman::sim::kernel::KERNEL_BASE_UNSLID;
+use pacman::sim::kernelcache::*;
+
+fn code(insts: &[u32]) -> Vec<u8> {
+    insts.iter().flat_map(|inst| inst.to_le_bytes()).collect()
+}
+
+/// A data gadget: the bounds check loads its limit, and the vtable pointer is blended with 0xd986
+const DATA_GADGET : [u32; 9] = [
+    0xF9419E68, // ldr x8, [x19, #0x338]
+    0xEB01011F, // cmp x8, x1
+    0x54000102, // b.hs #0x20
+    0xF9400810, // ldr x16, [x0, #0x10]
+    0xAA0003F1, // mov x17, x0
+    0xF2FB30D1, // movk x17, #0xd986, lsl #48
+    0xDAC11A30, // autda x16, x17
+    0xF9400200, // ldr x0, [x16]
+    0xD65F03C0, // ret
+];
+
+/// Two instruction gadgets: one with a zero modifier, one whose condition and pointer come from registers (which
+/// both branches also reach when they're taken)
+const INST_GADGETS : [u32; 8] = [
+    0xF9400049, // ldr x9, [x2]
+    0xB5000089, // cbnz x9, #0x10
+    0xF9400028, // ldr x8, [x1]
+    0xD63F091F, // blraaz x8
+    0x36000043, // tbz w3, #0, #8
+    0xD2824691, // mov x17, #0x1234
+    0xDAC11628, // autib x8, x17
+    0xD63F0100, // blr x8
+];
+
+#[test]
+fn data_gadget() {
+    let gadgets = scan(&code(&DATA_GADGET), 0x1000);
+    assert_eq!(gadgets, vec![Gadget {
+        kind: GadgetKind::Data,
+        key: PtrAuthKey::DA,
+        branch: 0x1008,
+        path: 0x100C,
+        auth: 0x1018,
+        transmit: 0x101C,
+        condition: Source::Load { base: 19, offset: 0x338 },
+        pointer: 16,
+        holder: Source::Load { base: 0, offset: 0x10 },
+        discriminator: Discriminator::Blend { addr: 0, constant: 0xd986 },
+    }]);
+    assert_eq!(gadgets[0].distance(), 5);
+}
+
+#[test]
+fn inst_gadgets() {
+    let gadgets = scan(&code(&INST_GADGETS), 0);
+    assert_eq!(gadgets.len(), 4);
+
+    assert_eq!((gadgets[0].kind, gadgets[0].key), (GadgetKind::Inst, PtrAuthKey::IA));
+    assert_eq!(gadgets[0].condition, Source::Load { base: 2, offset: 0 });
+    assert_eq!(gadgets[0].holder, Source::Load { base: 1, offset: 0 });
+    assert_eq!(gadgets[0].discriminator, Discriminator::Zero);
+    assert_eq!(gadgets[0].auth, gadgets[0].transmit);
+
+    // cbnz taken
+    assert_eq!((gadgets[1].branch, gadgets[1].path, gadgets[1].key), (0x4, 0x14, PtrAuthKey::IB));
+    assert_eq!(gadgets[1].condition, Source::Load { base: 2, offset: 0 });
+
+    assert_eq!((gadgets[2].kind, gadgets[2].key), (GadgetKind::Inst, PtrAuthKey::IB));
+    assert_eq!(gadgets[2].condition, Source::Register(3));
+    assert_eq!(gadgets[2].holder, Source::Register(8));
+    assert_eq!(gadgets[2].discriminator, Discriminator::Value(Source::Constant(0x1234)));
+    assert!(gadgets[0].score() > gadgets[2].score());
+
+    // tbz taken skips the mov, so the modifier is whatever x17 held
+    assert_eq!((gadgets[3].branch, gadgets[3].path), (0x10, 0x18));
+    assert_eq!(gadgets[3].discriminator, Discriminator::Value(Source::Register(17)));
+}
+
+#[test]
+fn taken_path_gadget() {
+    let insts = [
+        0xF9400028, // ldr x8, [x1]
+        0xF9400049, // ldr x9, [x2]
+        0xB4000069, // cbz x9, #0xC
+        0xD65F03C0, // ret
+        0xF9400068, // ldr x8, [x3]
+        0xD63F091F, // blraaz x8
+    ];
+    assert_eq!(scan(&code(&insts), 0x1000), vec![Gadget {
+        kind: GadgetKind::Inst,
+        key: PtrAuthKey::IA,
+        branch: 0x1008,
+        path: 0x1014,
+        auth: 0x1014,
+        transmit: 0x1014,
+        condition: Source::Load { base: 2, offset: 0 },
+        pointer: 8,
+        // From before the branch: the load just before the target is on another path
+        holder: Source::Load { base: 1, offset: 0 },
+        discriminator: Discriminator::Zero,
+    }]);
+}
+
+#[test]
+fn not_gadgets() {
+    // The authenticated pointer is overwritten before it's used
+    assert!(scan(&code(&[0x54000040, 0xDAC11A30, 0xAA0003F0, 0xF9400200]), 0).is_empty());
+    // The path returns before it's used
+    assert!(scan(&code(&[0x54000040, 0xDAC11A30, 0xD65F03C0, 0xF9400200]), 0).is_empty());
+    // Loading through a pointer authenticated with an instruction key isn't a data gadget
+    assert!(scan(&code(&[0x54000040, 0xDAC11628, 0xF9400100]), 0).is_empty());
+}
+
+#[test]
+fn ranks_gadgets_across_a_kernelcache() {
+    let mut builder = KernelcacheBuilder::new(KERNEL_BASE_UNSLID);
+    let kernel = builder.kext(KERNEL_BUNDLE_ID);
+    let kext = builder.kext("com.apple.iokit.IOSCSIArchitectureModelFamily");
+    builder.segment(kernel, "__TEXT_EXEC", VM_PROT_READ | VM_PROT_EXECUTE, &code(&INST_GADGETS));
+    let text = builder.segment(kext, "__TEXT_EXEC", VM_PROT_READ | VM_PROT_EXECUTE, &code(&DATA_GADGET));
+    // Not executable, so never scanned
+    builder.segment(kext, "__DATA_CONST", VM_PROT_READ, &code(&DATA_GADGET));
+    let bytes = builder.build();
+
+    let gadgets = scan_kernelcache(&MachO::parse(&bytes).unwrap()).unwrap();
+    let found : Vec<(&str, GadgetKind)> = gadgets.iter().map(|(id, gadget)| (id.as_str(), gadget.kind)).collect();
+    assert_eq!(found, vec![
+        (KERNEL_BUNDLE_ID, GadgetKind::Inst),
+        ("com.apple.iokit.IOSCSIArchitectureModelFamily", GadgetKind::Data),
+        (KERNEL_BUNDLE_ID, GadgetKind::Inst),
+        (KERNEL_BUNDLE_ID, GadgetKind::Inst),
+        (KERNEL_BUNDLE_ID, GadgetKind::Inst),
+    ]);
+    assert_eq!(gadgets[1].1.branch, text + 8);
+}
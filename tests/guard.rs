@@ -0,0 +1,100 @@
+/*!
+ * Guards putting the simulated kernel back the way it was, including when the experiment panics.
+ */
+
+mod common;
+
+use std::panic::{self, AssertUnwindSafe};
+use pacman::attacks::pacman::find_victim_objects;
+use pacman::guard::{KernelAllocation, KernelRestore};
+use pacman::pac::PAC_BITMASK;
+use pacman::pacmankit;
+use pacman::primitives::*;
+use pacman::sim::kernel;
+use pacman::sim::noise::{self, NoiseModel};
+use common::boot;
+
+#[test]
+fn allocations_are_freed_on_drop() {
+    let kernel = boot(70);
+    let handle = kernel::connect(&kernel).unwrap();
+
+    unsafe {
+        let addr = {
+            let memory = KernelAllocation::new(&handle).unwrap();
+            handle.kernel_write(memory.addr(), 0x41).unwrap();
+            memory.addr()
+        };
+        assert!(handle.kernel_read(addr).is_err());
+
+        let memory = KernelAllocation::new(&handle).unwrap();
+        let addr = memory.addr();
+        memory.free().unwrap();
+        assert!(handle.kernel_read(addr).is_err());
+    }
+}
+
+#[test]
+fn holders_are_restored_in_reverse_order() {
+    let kernel = boot(71);
+    let handle = kernel::connect(&kernel).unwrap();
+
+    unsafe {
+        let (_, object, vtable, _) = find_victim_objects(&handle, &kernel::offset_profile()).unwrap();
+        let buffer = handle.kernel_mmap().unwrap();
+        handle.kernel_write(buffer, 0x41).unwrap();
+
+        let mut holders = KernelRestore::new(&handle);
+        holders.write(object, 0x4242).unwrap();
+        holders.write(object | PAC_BITMASK, 0x4343).unwrap();
+        holders.write(buffer, 0x4444).unwrap();
+        assert_eq!(holders.original(object), Some(vtable));
+        assert_eq!(holders.original(buffer), Some(0x41));
+
+        holders.restore().unwrap();
+        assert_eq!(handle.kernel_read(object).unwrap(), vtable);
+        assert_eq!(handle.kernel_read(buffer).unwrap(), 0x41);
+    }
+}
+
+#[test]
+fn guards_ride_out_failing_kernel_calls() {
+    let kernel = boot(73);
+    kernel.lock().unwrap().set_noise(NoiseModel { call_failure_rate: 0.3, ..noise::QUIET });
+    let handle = kernel::connect(&kernel).unwrap();
+
+    unsafe {
+        let memory = KernelAllocation::new(&handle).unwrap();
+        let mut holders = KernelRestore::new(&handle);
+        for i in 0..32 {
+            holders.write(memory.addr() + 8 * i, 0x4141).unwrap();
+        }
+        holders.restore().unwrap();
+        for i in 0..32 {
+            assert_eq!(pacmankit::retry(|| handle.kernel_read(memory.addr() + 8 * i)).unwrap(), 0);
+        }
+    }
+}
+
+#[test]
+fn a_panicking_experiment_leaves_the_kernel_intact() {
+    let kernel = boot(72);
+    let handle = kernel::connect(&kernel).unwrap();
+    let victim_handle = kernel::connect(&kernel).unwrap();
+    let (_, object, vtable, _) = unsafe { find_victim_objects(&victim_handle, &kernel::offset_profile()).unwrap() };
+
+    // Point the victim at a fake vtable and die before cleaning up
+    let result = panic::catch_unwind(AssertUnwindSafe(|| unsafe {
+        let memory = KernelAllocation::new(&handle).unwrap();
+        let mut victim = KernelRestore::new(&handle);
+        victim.write(object, memory.addr() | PAC_BITMASK).unwrap();
+        panic!("The experiment failed");
+    }));
+    assert!(result.is_err());
+
+    unsafe {
+        assert_eq!(handle.kernel_read(object).unwrap(), vtable);
+        victim_handle.call_service_routine(0, 0, 0, 0, 0, 0).unwrap();
+    }
+    assert_eq!(kernel.lock().unwrap().panic_message(), None);
+}
@@ -0,0 +1,107 @@
+/*!
+ * Parsing kernelcaches: kexts, segments, symbols and chained fixups.
+ */
+
+use pacman::kernelcache::macho::*;
+use pacman::sim::kernel::KERNEL_BASE_UNSLID;
+use pacman::sim::kernelcache::*;
+
+const BASE : u64 = KERNEL_BASE_UNSLID;
+
+/// A kernel with a vtable of signed pointers into a kext's text
+fn kernelcache() -> (Vec<u8>, u64, u64) {
+    let mut builder = KernelcacheBuilder::new(BASE);
+    let kernel = builder.kext(KERNEL_BUNDLE_ID);
+    let kext = builder.kext("com.apple.iokit.IOSCSIArchitectureModelFamily");
+
+    let text = builder.segment(kext, "__TEXT_EXEC", VM_PROT_READ | VM_PROT_EXECUTE, &[0xC0, 0x03, 0x5F, 0xD6].repeat(0x10));
+    builder.section(kext, "__TEXT_EXEC", "__text", text, 0x40);
+    builder.symbol(kext, "_scsi_method", text + 0x20);
+
+    let data = builder.segment(kernel, "__DATA_CONST", VM_PROT_READ, &[0; 0x5000]);
+    builder.symbol(kernel, "_vtable", data + 0x10);
+    builder.pointer(data + 0x10, text, Some(PtrAuth { key: PtrAuthKey::IA, diversity: 0xa7d5, addr_div: true }));
+    builder.pointer(data + 0x18, text + 0x20, Some(PtrAuth { key: PtrAuthKey::DA, diversity: 0xd986, addr_div: false }));
+    builder.pointer(data + 0x20, data, None);
+    // The next page of the segment gets its own chain
+    builder.pointer(data + 0x4008, text + 4, Some(PtrAuth { key: PtrAuthKey::IB, diversity: 0x1234, addr_div: true }));
+
+    (builder.build(), text, data)
+}
+
+#[test]
+fn kexts_segments_and_sections() {
+    let (bytes, text, data) = kernelcache();
+    let kernelcache = MachO::parse(&bytes).unwrap();
+    assert_eq!(kernelcache.filetype, MH_FILESET);
+    assert_eq!(kernelcache.base_address(), BASE);
+    let ids : Vec<&str> = kernelcache.fileset_entries.iter().map(|entry| entry.id.as_str()).collect();
+    assert_eq!(ids, vec![KERNEL_BUNDLE_ID, "com.apple.iokit.IOSCSIArchitectureModelFamily"]);
+
+    let kext = kernelcache.entry("com.apple.iokit.IOSCSIArchitectureModelFamily").unwrap();
+    assert_eq!(kext.filetype, MH_KEXT_BUNDLE);
+    let exec = kext.segment("__TEXT_EXEC").unwrap();
+    assert_eq!(exec.vmaddr, text);
+    assert!(exec.is_executable());
+    assert_eq!(kext.section("__TEXT_EXEC", "__text").unwrap().size, 0x40);
+    assert_eq!(kext.read_u32(text + 8), Some(0xD65F03C0));
+
+    let (entry, _) = kernelcache.entry_containing(data + 0x100).unwrap();
+    assert_eq!(entry.id, KERNEL_BUNDLE_ID);
+    assert_eq!(kernelcache.entry(KERNEL_BUNDLE_ID).unwrap().filetype, MH_EXECUTE);
+    assert!(kernelcache.entry("com.apple.nope").is_err());
+}
+
+#[test]
+fn symbols() {
+    let (bytes, text, data) = kernelcache();
+    let kernelcache = MachO::parse(&bytes).unwrap();
+    let kernel = kernelcache.entry(KERNEL_BUNDLE_ID).unwrap();
+    let kext = kernelcache.entry("com.apple.iokit.IOSCSIArchitectureModelFamily").unwrap();
+
+    assert_eq!(kernel.symbol("_vtable").unwrap(), Some(data + 0x10));
+    assert_eq!(kernel.symbol("_scsi_method").unwrap(), None);
+    assert_eq!(kext.symbol("_scsi_method").unwrap(), Some(text + 0x20));
+    assert!(kext.symbols().unwrap()[0].external);
+}
+
+#[test]
+fn chained_fixups() {
+    let (bytes, text, data) = kernelcache();
+    let fixups = MachO::parse(&bytes).unwrap().chained_fixups().unwrap();
+    assert_eq!(fixups, vec![
+        Fixup { location: data + 0x10, target: text, auth: Some(PtrAuth { key: PtrAuthKey::IA, diversity: 0xa7d5, addr_div: true }) },
+        Fixup { location: data + 0x18, target: text + 0x20, auth: Some(PtrAuth { key: PtrAuthKey::DA, diversity: 0xd986, addr_div: false }) },
+        Fixup { location: data + 0x20, target: data, auth: None },
+        Fixup { location: data + 0x4008, target: text + 4, auth: Some(PtrAuth { key: PtrAuthKey::IB, diversity: 0x1234, addr_div: true }) },
+    ]);
+
+    // Address diversity blends the location in, like the kernel does
+    assert_eq!(fixups[0].auth.unwrap().salt(data + 0x10), ((data + 0x10) & 0xFFFFFFFFFFFF) | (0xa7d5 << 48));
+    assert_eq!(fixups[1].auth.unwrap().salt(data + 0x18), 0xd986);
+}
+
+#[test]
+fn decode_userspace_and_kernel_formats() {
+    // An auth rebase (key DB, diversity 0x42, address diversity) with the next pointer 3 strides on
+    let raw = (1u64 << 63) | (3 << 51) | (3 << 49) | (1 << 48) | (0x42 << 32) | 0x1000;
+    let (fixup, next, stride) = decode_chained_ptr(DYLD_CHAINED_PTR_ARM64E, 0x100000000, 0x100008000, raw).unwrap();
+    assert_eq!((fixup.target, next, stride), (0x100001000, 3, 8));
+    assert_eq!(fixup.auth, Some(PtrAuth { key: PtrAuthKey::DB, diversity: 0x42, addr_div: true }));
+
+    // Plain rebases are addresses in userspace and offsets in the kernel
+    let raw = (0x80u64 << 43) | 0x100004000;
+    assert_eq!(decode_chained_ptr(DYLD_CHAINED_PTR_ARM64E, 0x100000000, 0, raw).unwrap().0.target, 0x8000000100004000);
+    assert_eq!(decode_chained_ptr(DYLD_CHAINED_PTR_ARM64E_KERNEL, BASE, 0, 0x4000).unwrap().0.target, BASE + 0x4000);
+
+    // Binds and unknown formats are errors
+    assert!(decode_chained_ptr(DYLD_CHAINED_PTR_ARM64E, 0, 0, 1 << 62).is_err());
+    assert!(decode_chained_ptr(2, 0, 0, 0).is_err());
+}
+
+#[test]
+fn rejects_garbage() {
+    assert!(MachO::parse(b"definitely not a kernelcache").is_err());
+    let (bytes, _, _) = kernelcache();
+    assert!(MachO::parse(&bytes[..0x40]).is_err());
+}
@@ -0,0 +1,94 @@
+/*!
+ * The simulated kernel's noise model, and how the PACMAN bruteforce holds up under it.
+ */
+
+mod common;
+
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use pacman::attacks::decision::DecisionStatistic;
+use pacman::attacks::pacman::GadgetParams;
+use pacman::pacmankit;
+use pacman::primitives::KernelMemory;
+use pacman::sim::kernel;
+use pacman::sim::noise::{self, Jitter, NoiseModel};
+use common::memory_region;
+
+/// How many PACs each noisy bruteforce tries
+const NUM_GUESSES : usize = 128;
+
+/// Data gadget parameters short enough to run a few bruteforces per test
+fn params() -> GadgetParams {
+    let mut params = pacman::attacks::pacman::DATA_PARAMS;
+    params.num_iters = 4;
+    params.num_final_iters = 16;
+    params
+}
+
+#[test]
+fn jitter_is_deterministic_and_bounded() {
+    let jitter = Jitter::Uniform { max: 10 };
+    let mut a = StdRng::seed_from_u64(1);
+    let mut b = StdRng::seed_from_u64(1);
+    let first : Vec<u64> = (0..100).map(|_| jitter.apply(&mut a, 50)).collect();
+    let second : Vec<u64> = (0..100).map(|_| jitter.apply(&mut b, 50)).collect();
+    assert_eq!(first, second);
+    assert!(first.iter().all(|t| (50..=60).contains(t)));
+    assert!(first.iter().any(|t| *t != first[0]));
+
+    let mut rng = StdRng::seed_from_u64(2);
+    assert!((0..100).all(|_| Jitter::Normal { stddev: 100.0 }.apply(&mut rng, 10) < 1000));
+    assert!((0..100).all(|_| Jitter::None.apply(&mut rng, 10) == 10));
+}
+
+#[test]
+fn scaling_to_zero_is_quiet() {
+    let quiet = noise::REALISTIC.scaled(0.0);
+    assert_eq!(quiet.interrupt_period, 0);
+    assert_eq!(quiet.call_failure_rate, 0.0);
+    assert_eq!(quiet.jitter, Jitter::Normal { stddev: 0.0 });
+    assert_eq!(noise::REALISTIC.scaled(1.0), noise::REALISTIC);
+    assert_eq!(NoiseModel::default(), noise::QUIET);
+}
+
+#[test]
+fn failed_kernel_calls_are_retried() {
+    let kernel = common::boot(20);
+    kernel.lock().unwrap().set_noise(NoiseModel { call_failure_rate: 0.3, ..noise::QUIET });
+    let handle = kernel::connect(&kernel).unwrap();
+
+    unsafe {
+        let addr = pacmankit::retry(|| handle.kernel_mmap()).unwrap();
+        let failures = (0..100).filter(|i| handle.kernel_write(addr, *i).is_err()).count();
+        assert!(failures > 0);
+
+        for i in 0..100 {
+            pacmankit::retry(|| handle.kernel_write(addr, i)).unwrap();
+            assert_eq!(pacmankit::retry(|| handle.kernel_read(addr)).unwrap(), i);
+        }
+    }
+}
+
+#[test]
+fn bruteforce_survives_realistic_noise() {
+    let mut backing = Vec::new();
+    let memory_region = memory_region(&mut backing);
+    unsafe {
+        assert!(noise::bruteforce_survives(21, noise::REALISTIC, DecisionStatistic::Average, &params(), 16, NUM_GUESSES, memory_region));
+    }
+    assert!(kernel::installed().is_none());
+}
+
+#[test]
+fn decision_strategies_tolerate_realistic_noise() {
+    let mut backing = Vec::new();
+    let memory_region = memory_region(&mut backing);
+    let levels : Vec<f64> = (1..=24).map(|level| level as f64).collect();
+
+    for statistic in DecisionStatistic::ALL {
+        let tolerated = unsafe {
+            noise::noise_tolerance(22, statistic, &levels, &params(), 16, NUM_GUESSES, memory_region)
+        };
+        assert!(tolerated.is_some(), "{:?} picked the wrong PAC under realistic noise (tolerated {:?}x)", statistic, tolerated);
+    }
+}
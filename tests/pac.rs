@@ -0,0 +1,57 @@
+/*!
+ * PAC encoding and the PAC iterator.
+ */
+
+use pacman::pac::*;
+use pacman::attacks::pacman::get_salt;
+
+const USER_PTR : u64 = 0x0000_0001_0203_4560;
+const KERNEL_PTR : u64 = 0xFFFF_FE00_0712_3450;
+
+#[test]
+fn encode_extract_roundtrip() {
+    for pac in [0u16, 1, 0x00FF, 0xFF00, 0x1234, MAX_PAC] {
+        assert_eq!(extract_pac(encode_pac(pac, USER_PTR)), pac);
+        assert_eq!(extract_pac(encode_pac(pac, KERNEL_PTR)), pac);
+    }
+}
+
+#[test]
+fn encode_keeps_pointer_bits() {
+    let signed = encode_pac(0xABCD, USER_PTR);
+    assert_eq!(signed & !PAC_BITMASK, USER_PTR);
+    assert!(!is_kernel_pointer(signed));
+
+    let signed = encode_pac(0xABCD, KERNEL_PTR);
+    assert_eq!(signed & !PAC_BITMASK, KERNEL_PTR & !PAC_BITMASK);
+    assert!(is_kernel_pointer(signed));
+}
+
+#[test]
+fn is_kernel_pointer_checks_bit_55() {
+    assert!(is_kernel_pointer(KERNEL_PTR));
+    assert!(is_kernel_pointer(1 << 55));
+    assert!(!is_kernel_pointer(USER_PTR));
+    assert!(!is_kernel_pointer(!(1u64 << 55)));
+}
+
+#[test]
+fn iterate_every_pac() {
+    for ptr in [USER_PTR, KERNEL_PTR] {
+        let guesses : Vec<u64> = iterate_pacs(ptr).collect();
+        assert_eq!(guesses.len(), MAX_PAC as usize + 1);
+
+        for (expected, guess) in guesses.iter().enumerate() {
+            assert_eq!(extract_pac(*guess) as usize, expected);
+            assert_eq!(guess & !PAC_BITMASK, ptr & !PAC_BITMASK);
+            assert_eq!(is_kernel_pointer(*guess), is_kernel_pointer(ptr));
+        }
+    }
+}
+
+#[test]
+fn salt_goes_in_the_upper_bits() {
+    let salt = get_salt(KERNEL_PTR, 0xd986);
+    assert_eq!(salt >> 48, 0xd986);
+    assert_eq!(salt & 0x0000_FFFF_FFFF_FFFF, (KERNEL_PTR | PAC_BITMASK) & 0x0000_FFFF_FFFF_FFFF);
+}
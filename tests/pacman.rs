@@ -0,0 +1,213 @@
+/*!
+ * The PACMAN attack against the simulated kernel's gadget, from a single guess up to forging a whole vtable.
+ */
+
+mod common;
+
+use pacman::attacks::decision::count_misses;
+use pacman::attacks::pacman::*;
+use pacman::error::PacmanError;
+use pacman::evset;
+use pacman::pac::{self, PAC_BITMASK};
+use pacman::pacmankit::PacmanKitConnection;
+use pacman::primitives::*;
+use pacman::results::JsonLinesSink;
+use pacman::sim::kernel;
+use pacman::timer;
+use common::{boot, memory_region};
+
+/// Quick parameters (the simulated gadget has no noise)
+fn config() -> PacmanConfig {
+    let mut config = PacmanConfig::default();
+    config.data.num_iters = 1;
+    config.data.num_final_iters = 4;
+    config.inst.num_iters = 1;
+    config.inst.num_final_iters = 4;
+    config.limit_evset_size = 16;
+    config
+}
+
+/// Eviction sets for LIMIT and `target` (the chosen addresses and their indexes)
+unsafe fn evsets(handle: &PacmanKitConnection, target: u64, size: usize, memory_region: &mut [u8]) -> (Vec<u64>, Vec<usize>, Vec<u64>, Vec<usize>) {
+    let limit = handle.leak_limit_location().unwrap();
+    let limit_candidates = evset::data_pevset(limit, handle.kernel_virt_to_phys(limit).unwrap(), memory_region);
+    let limit_evset = evset::choose_evset(&limit_candidates, config().limit_evset_size, "LIMIT").unwrap();
+
+    let forge_candidates = evset::data_pevset(target, handle.kernel_virt_to_phys(target).unwrap(), memory_region);
+    let forge_evset = evset::choose_evset(&forge_candidates, size, "the target").unwrap();
+
+    let limit_indexes = (0..limit_evset.len()).collect();
+    let forge_indexes = (0..forge_evset.len()).collect();
+    (forge_evset, forge_indexes, limit_evset, limit_indexes)
+}
+
+#[test]
+fn try_one_sees_only_the_correct_pac() {
+    let kernel = boot(10);
+    let mut backing = Vec::new();
+    let memory_region = memory_region(&mut backing);
+    let params = config().data;
+
+    unsafe {
+        let handle = PacmanKitConnection::init().unwrap();
+        let victim_handle = PacmanKitConnection::init().unwrap();
+        let (_, victim_object, victim_vtable, _) = find_victim_objects(&victim_handle, &kernel::offset_profile()).unwrap();
+        let new_vtable = handle.kernel_mmap().unwrap() | PAC_BITMASK;
+        let correct = handle.forge_sign_data(new_vtable, get_salt(victim_object, kernel::VTABLE_PTR_SALT)).unwrap();
+        let incorrect = pac::encode_pac(pac::extract_pac(correct) ^ 0x1234, correct);
+
+        let (forge_evset, mut forge_indexes, limit_evset, mut limit_indexes) = evsets(&handle, new_vtable, params.evset_size, memory_region);
+        let try_speculative = || { victim_handle.call_service_routine(10000, 0, 0, 0, 0, 0).unwrap(); };
+        let try_nonspeculative = || { victim_handle.call_service_routine(0, 0, 0, 0, 0, 0).unwrap(); };
+
+        let mut misses = Vec::new();
+        for guess in [incorrect, correct, incorrect, correct] {
+            let mut times = vec![0; params.evset_size];
+            pacman_try_one(
+                &handle, victim_object, victim_vtable, guess,
+                timer::time_access, try_speculative, try_nonspeculative,
+                &forge_evset, &mut forge_indexes, &limit_evset, &mut limit_indexes,
+                &mut times,
+            ).unwrap();
+            misses.push(count_misses(&times, params.miss_latency));
+        }
+        assert_eq!(misses, vec![0, params.evset_size as u64, 0, params.evset_size as u64]);
+    }
+
+    // Only the training calls ever really ran
+    let kernel = kernel.lock().unwrap();
+    assert_eq!(kernel.panic_message(), None);
+    assert!(kernel.service_calls().iter().all(|call| *call == kernel.external_method()));
+}
+
+#[test]
+fn no_footprint_without_a_speculation_window() {
+    let kernel = boot(11);
+    let mut backing = Vec::new();
+    let memory_region = memory_region(&mut backing);
+    let params = config().data;
+
+    unsafe {
+        let handle = PacmanKitConnection::init().unwrap();
+        let victim_handle = PacmanKitConnection::init().unwrap();
+        let (_, victim_object, victim_vtable, _) = find_victim_objects(&victim_handle, &kernel::offset_profile()).unwrap();
+        let new_vtable = handle.kernel_mmap().unwrap() | PAC_BITMASK;
+        let correct = handle.forge_sign_data(new_vtable, get_salt(victim_object, kernel::VTABLE_PTR_SALT)).unwrap();
+        let (forge_evset, mut forge_indexes, _, _) = evsets(&handle, new_vtable, params.evset_size, memory_region);
+
+        // LIMIT is never evicted, so it's always cached when the bounds check runs
+        let mut times = vec![0; params.evset_size];
+        pacman_try_one(
+            &handle, victim_object, victim_vtable, correct,
+            timer::time_access,
+            || { victim_handle.call_service_routine(10000, 0, 0, 0, 0, 0).unwrap(); },
+            || { victim_handle.call_service_routine(0, 0, 0, 0, 0, 0).unwrap(); },
+            &forge_evset, &mut forge_indexes, &[], &mut [],
+            &mut times,
+        ).unwrap();
+        assert_eq!(count_misses(&times, params.miss_latency), 0);
+    }
+
+    assert_eq!(kernel.lock().unwrap().panic_message(), None);
+}
+
+#[test]
+fn bruteforce_finds_the_vtable_pointer() {
+    let kernel = boot(12);
+    let mut backing = Vec::new();
+    let memory_region = memory_region(&mut backing);
+    let params = config().data;
+    let mut sink = JsonLinesSink::new(std::io::sink());
+
+    unsafe {
+        let handle = PacmanKitConnection::init().unwrap();
+        let victim_handle = PacmanKitConnection::init().unwrap();
+        let (_, victim_object, victim_vtable, _) = find_victim_objects(&victim_handle, &kernel::offset_profile()).unwrap();
+        let new_vtable = handle.kernel_mmap().unwrap() | PAC_BITMASK;
+        let (forge_evset, mut forge_indexes, limit_evset, mut limit_indexes) = evsets(&handle, new_vtable, params.evset_size, memory_region);
+
+        let found = pacman_bruteforce(
+            &handle,
+            &params,
+            PacmanAttackTarget::Direct(DirectTarget { holder: victim_object, train_ptr: victim_vtable, guess_ptr: new_vtable }),
+            timer::time_access,
+            || { victim_handle.call_service_routine(10000, 0, 0, 0, 0, 0).unwrap(); },
+            || { victim_handle.call_service_routine(0, 0, 0, 0, 0, 0).unwrap(); },
+            &forge_evset, &mut forge_indexes, &limit_evset, &mut limit_indexes,
+            "bruteforce_test",
+            &mut sink,
+            None,
+        );
+
+        let oracle = kernel.lock().unwrap().sign_data(new_vtable, get_salt(victim_object, kernel::VTABLE_PTR_SALT));
+        assert_eq!(found, Ok(Some(oracle)));
+    }
+}
+
+/// A results file that can't be written to
+struct FullDisk;
+
+impl std::io::Write for FullDisk {
+    fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+        Err(std::io::ErrorKind::StorageFull.into())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn bruteforce_stops_when_results_cant_be_recorded() {
+    let _kernel = boot(14);
+    let mut backing = Vec::new();
+    let memory_region = memory_region(&mut backing);
+    let params = config().data;
+    let mut sink = JsonLinesSink::new(FullDisk);
+
+    unsafe {
+        let handle = PacmanKitConnection::init().unwrap();
+        let victim_handle = PacmanKitConnection::init().unwrap();
+        let (_, victim_object, victim_vtable, _) = find_victim_objects(&victim_handle, &kernel::offset_profile()).unwrap();
+        let new_vtable = handle.kernel_mmap().unwrap() | PAC_BITMASK;
+        let (forge_evset, mut forge_indexes, limit_evset, mut limit_indexes) = evsets(&handle, new_vtable, params.evset_size, memory_region);
+
+        let found = pacman_bruteforce(
+            &handle,
+            &params,
+            PacmanAttackTarget::Direct(DirectTarget { holder: victim_object, train_ptr: victim_vtable, guess_ptr: new_vtable }),
+            timer::time_access,
+            || { victim_handle.call_service_routine(10000, 0, 0, 0, 0, 0).unwrap(); },
+            || { victim_handle.call_service_routine(0, 0, 0, 0, 0, 0).unwrap(); },
+            &forge_evset, &mut forge_indexes, &limit_evset, &mut limit_indexes,
+            "bruteforce_test",
+            &mut sink,
+            None,
+        );
+        assert_eq!(found, Err(PacmanError::Io(std::io::ErrorKind::StorageFull)));
+    }
+}
+
+#[test]
+fn end_to_end_forges_the_vtable_and_its_entry() {
+    let kernel = boot(13);
+    let mut backing = Vec::new();
+    let memory_region = memory_region(&mut backing);
+    let mut sink = JsonLinesSink::new(std::io::sink());
+
+    // Fails if either bruteforce comes up with the wrong answer
+    unsafe {
+        let handle = PacmanKitConnection::init().unwrap();
+        let victim_handle = PacmanKitConnection::init().unwrap();
+        end_to_end(&handle, &victim_handle, memory_region, &config(), &kernel::offset_profile(), &mut sink, None).unwrap();
+
+        // And then the victim got its real vtable back
+        let (_, victim_object, victim_vtable, _) = find_victim_objects(&victim_handle, &kernel::offset_profile()).unwrap();
+        assert_eq!(handle.kernel_read(victim_object).unwrap(), victim_vtable);
+    }
+
+    // The final (real) call went through the forged vtable to win()
+    let kernel = kernel.lock().unwrap();
+    assert_eq!(kernel.panic_message(), None);
+    assert_eq!(kernel.service_calls().last(), Some(&(kernel.retpoline() + 0x30c0)));
+}
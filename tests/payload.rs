@@ -0,0 +1,95 @@
+/*!
+ * Generating instruction cache and branch predictor eviction code.
+ */
+
+use pacman::aarch64::{decode, Inst, NOP, RET};
+use pacman::cache::{self, L1I_LINESIZE, PAGE_SIZE};
+use pacman::payload::*;
+
+const BASE : u64 = 0x100000000;
+
+#[test]
+fn starts_as_a_retpoline() {
+    let payload = Payload::new(BASE, 0x100);
+    assert_eq!(payload.end(), BASE + 0x100);
+    assert!(payload.words().iter().all(|word| *word == RET));
+    assert_eq!(&payload.bytes()[..4], &[0xC0, 0x03, 0x5F, 0xD6]);
+    assert_eq!(payload.word(BASE + 0x100), None);
+}
+
+#[test]
+fn nop_sled() {
+    let mut payload = Payload::new(BASE, 0x100);
+    assert_eq!(payload.nop_sled(BASE + 0x10, 0x40), BASE + 0x54);
+    assert_eq!(payload.word(BASE + 0xC), Some(RET));
+    assert!((BASE + 0x10..BASE + 0x50).step_by(4).all(|addr| payload.word(addr) == Some(NOP)));
+    assert_eq!(payload.word(BASE + 0x50), Some(RET));
+}
+
+#[test]
+fn branch_chain_across_lines_and_pages() {
+    let mut payload = Payload::new(BASE, 4 * PAGE_SIZE);
+    let lines = strided(BASE, L1I_LINESIZE as u64, 3);
+    payload.branch_chain(&lines);
+    assert_eq!(payload.word(BASE), Some(0x14000010));
+    assert_eq!(payload.word(BASE + 0x40), Some(0x14000010));
+    assert_eq!(payload.word(BASE + 0x80), Some(RET));
+
+    // Backwards, a page at a time
+    let pages = [BASE + 3 * PAGE_SIZE as u64, BASE + 2 * PAGE_SIZE as u64, BASE + 8];
+    payload.branch_chain(&pages);
+    assert_eq!(decode(payload.word(pages[0]).unwrap()), Inst::B { offset: -(PAGE_SIZE as i64), link: false });
+    assert_eq!(decode(payload.word(pages[1]).unwrap()).branch_target(pages[1]), Some(BASE + 8));
+    assert_eq!(payload.word(BASE + 8), Some(RET));
+}
+
+#[test]
+fn l1i_set_alignment() {
+    let mut payload = Payload::new(BASE, 2 * L1I_SET_STRIDE as usize);
+
+    // Padding ends exactly on the set asked for
+    let target = payload.pad_to_l1i_set(BASE + 4, 3);
+    assert_eq!(target, BASE + 3 * L1I_LINESIZE as u64);
+    assert_eq!(cache::get_l1_cache_set_m1(target), 3);
+    assert_eq!(payload.word(BASE), Some(RET));
+    assert_eq!(payload.word(BASE + 4), Some(NOP));
+    assert_eq!(payload.word(target), Some(RET));
+
+    // Already in the set, or past it in this stride
+    assert_eq!(next_in_l1i_set(target + 8, 3), target + 8);
+    assert_eq!(next_in_l1i_set(target + L1I_LINESIZE as u64, 3), target + L1I_SET_STRIDE);
+
+    let evset = l1i_set_addresses(BASE + 1, 0, 4);
+    assert_eq!(evset[0], BASE + L1I_SET_STRIDE);
+    assert!(evset.iter().all(|addr| cache::get_l1_cache_set_m1(*addr) == 0 && addr % L1I_LINESIZE as u64 == 0));
+}
+
+#[test]
+fn btb_conflicts() {
+    let mut payload = Payload::new(BASE, 0x40000);
+    let branches = payload.btb_conflicts(BASE + 0x24, 0x10000, 4);
+    assert_eq!(branches, vec![BASE + 0x24, BASE + 0x10024, BASE + 0x20024, BASE + 0x30024]);
+    assert!(branches.iter().all(|addr| addr & 0xFFFF == 0x24));
+    assert_eq!(payload.word(BASE + 0x24), Some(0x14004000));
+    assert_eq!(payload.word(BASE + 0x30024), Some(RET));
+}
+
+#[test]
+fn jumps_near_and_far() {
+    let mut payload = Payload::new(BASE, 0x100);
+    assert_eq!(payload.jump(BASE, BASE + 0x80), BASE + 4);
+    assert_eq!(payload.word(BASE), Some(0x14000020));
+
+    // Out of range of a `b`, so through x16
+    let far = 0xFFFFFE0007004000;
+    assert_eq!(payload.jump(BASE + 0x10, far), BASE + 0x10 + JUMP_MAX_SIZE);
+    let words : Vec<u32> = (0..5).map(|i| payload.word(BASE + 0x10 + i * 4).unwrap()).collect();
+    assert_eq!(words, vec![0xD2880010, 0xF2A0E010, 0xF2DFC010, 0xF2FFFFF0, 0xD61F0200]);
+}
+
+#[test]
+#[should_panic]
+fn out_of_range_branch() {
+    let mut payload = Payload::new(BASE, 0x100);
+    payload.emit(BASE, Inst::B { offset: 1 << 28, link: false });
+}
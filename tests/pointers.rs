@@ -0,0 +1,57 @@
+/*!
+ * Listing the signed pointers of a kernelcache.
+ */
+
+use pacman::attacks::pacman::get_salt;
+use pacman::kernelcache::macho::{MachO, PtrAuth, PtrAuthKey};
+use pacman::kernelcache::pointers::*;
+use pacman::sim::kernel::{KERNEL_BASE_UNSLID, VTABLE_ENTRY_SALT, PROC_TASK_SALT};
+use pacman::sim::kernelcache::*;
+
+const VTABLE : &str = "__ZTV16PacmanKitService";
+
+/// A kext with a vtable of 3 methods (IA, blended with 0xa7d5), and a kernel with a DA pointer and a plain one
+fn kernelcache() -> (Vec<u8>, u64, u64) {
+    let mut builder = KernelcacheBuilder::new(KERNEL_BASE_UNSLID);
+    let kernel = builder.kext(KERNEL_BUNDLE_ID);
+    let kext = builder.kext("com.apple.PacmanKit");
+    let text = builder.segment(kext, "__TEXT_EXEC", VM_PROT_READ | VM_PROT_EXECUTE, &[0; 0x100]);
+    let vtable = builder.segment(kext, "__DATA_CONST", VM_PROT_READ, &[0; 0x100]);
+    builder.symbol(kext, VTABLE, vtable);
+    for i in 0..3 {
+        let entry = PtrAuth { key: PtrAuthKey::IA, diversity: VTABLE_ENTRY_SALT as u16, addr_div: true };
+        builder.pointer(vtable + 0x10 + i * 8, text + i * 0x40, Some(entry));
+    }
+
+    let data = builder.segment(kernel, "__DATA", VM_PROT_READ | VM_PROT_WRITE, &[0; 0x100]);
+    builder.symbol(kernel, "_kernproc", data);
+    builder.pointer(data + 0x10, data + 0x80, Some(PtrAuth { key: PtrAuthKey::DA, diversity: PROC_TASK_SALT as u16, addr_div: true }));
+    builder.pointer(data + 0x18, data, None);
+    (builder.build(), vtable, data)
+}
+
+#[test]
+fn lists_every_signed_pointer() {
+    let (bytes, vtable, data) = kernelcache();
+    let pointers = signed_pointers(&MachO::parse(&bytes).unwrap()).unwrap();
+    assert_eq!(pointers.len(), 4);
+
+    let task = pointers.iter().find(|ptr| ptr.location == data + 0x10).unwrap();
+    assert_eq!(task.kext, KERNEL_BUNDLE_ID);
+    assert_eq!(task.symbol, Some(("_kernproc".to_string(), 0x10)));
+    assert_eq!((task.key, task.diversity, task.addr_div), (PtrAuthKey::DA, 0xa08a, true));
+    assert_eq!(task.salt(), get_salt(data + 0x10, PROC_TASK_SALT));
+
+    let entries = pointers_in(&pointers, VTABLE);
+    let offsets : Vec<u64> = entries.iter().map(|ptr| ptr.symbol.as_ref().unwrap().1).collect();
+    assert_eq!(offsets, vec![0x10, 0x18, 0x20]);
+    assert!(entries.iter().all(|ptr| ptr.kext == "com.apple.PacmanKit" && ptr.diversity == 0xa7d5));
+    assert_eq!(entries[1].salt(), get_salt(vtable + 0x18, VTABLE_ENTRY_SALT));
+}
+
+#[test]
+fn counts_diversities() {
+    let (bytes, _, _) = kernelcache();
+    let pointers = signed_pointers(&MachO::parse(&bytes).unwrap()).unwrap();
+    assert_eq!(diversities(&pointers), vec![((PtrAuthKey::IA, 0xa7d5), 3), ((PtrAuthKey::DA, 0xa08a), 1)]);
+}
@@ -0,0 +1,123 @@
+/*!
+ * The attacks only need the `primitives` traits, not a `PacmanKitConnection`.
+ */
+
+mod common;
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use pacman::attacks::pacman::*;
+use pacman::error::PacmanError;
+use pacman::pacmankit::{PacmanKitConnection, PacmanKitOp, KERN_INVALID_ADDRESS};
+use pacman::primitives::*;
+use pacman::sim::kernel;
+
+/// Kernel memory as a map of u64s, with a single user client at `USER_CLIENT`
+#[derive(Default)]
+struct FakeKernel {
+    memory: RefCell<HashMap<u64, u64>>,
+}
+
+const USER_CLIENT : u64 = 0xFFFFFE0011110000;
+
+/// What the fake kernel says when `op` has nothing to return
+fn nothing_there(op: PacmanKitOp) -> PacmanError {
+    PacmanError::OperationFailed { op, kret: KERN_INVALID_ADDRESS }
+}
+
+impl KernelMemory for FakeKernel {
+    unsafe fn kernel_read(&self, addr: u64) -> Result<u64, PacmanError> {
+        self.memory.borrow().get(&addr).copied().ok_or(nothing_there(PacmanKitOp::Read))
+    }
+
+    unsafe fn kernel_write(&self, addr: u64, val: u64) -> Result<(), PacmanError> {
+        self.memory.borrow_mut().insert(addr, val);
+        Ok(())
+    }
+
+    unsafe fn kernel_mmap(&self) -> Result<u64, PacmanError> {
+        Ok(0xFFFFFE0022220000)
+    }
+
+    unsafe fn kernel_free(&self) -> Result<(), PacmanError> {
+        Ok(())
+    }
+}
+
+impl KernelLeaks for FakeKernel {
+    unsafe fn get_kernel_base(&self) -> Result<u64, PacmanError> {
+        Ok(0xFFFFFE0007004000)
+    }
+
+    unsafe fn get_handle_loc(&self) -> Result<u64, PacmanError> {
+        Ok(USER_CLIENT)
+    }
+
+    unsafe fn leak_retpoline(&self) -> Result<u64, PacmanError> {
+        Err(nothing_there(PacmanKitOp::LeakMethod))
+    }
+
+    unsafe fn leak_limit_location(&self) -> Result<u64, PacmanError> {
+        Err(nothing_there(PacmanKitOp::LeakMethod))
+    }
+
+    unsafe fn current_proc(&self) -> Result<u64, PacmanError> {
+        Err(nothing_there(PacmanKitOp::LeakCurProc))
+    }
+}
+
+/// Counts the writes made through it to a real connection
+struct CountingWrites<'a> {
+    inner: &'a PacmanKitConnection,
+    writes: Cell<usize>,
+}
+
+impl KernelMemory for CountingWrites<'_> {
+    unsafe fn kernel_read(&self, addr: u64) -> Result<u64, PacmanError> {
+        self.inner.kernel_read(addr)
+    }
+
+    unsafe fn kernel_write(&self, addr: u64, val: u64) -> Result<(), PacmanError> {
+        self.writes.set(self.writes.get() + 1);
+        self.inner.kernel_write(addr, val)
+    }
+
+    unsafe fn kernel_mmap(&self) -> Result<u64, PacmanError> {
+        self.inner.kernel_mmap()
+    }
+
+    unsafe fn kernel_free(&self) -> Result<(), PacmanError> {
+        self.inner.kernel_free()
+    }
+}
+
+#[test]
+fn victim_objects_from_any_kernel_memory() {
+    let fake = FakeKernel::default();
+    unsafe {
+        fake.kernel_write(USER_CLIENT + kernel::HELPER_OFFSET, 0x1234).unwrap();
+        fake.kernel_write(0x1234, 0x5678).unwrap();
+        assert_eq!(find_victim_objects(&fake, &kernel::offset_profile()).unwrap(), (USER_CLIENT, USER_CLIENT + kernel::HELPER_OFFSET, 0x1234, 0x5678));
+    }
+}
+
+#[test]
+fn try_one_only_writes_through_the_primitive() {
+    let kernel = common::boot(30);
+    let handle = kernel::connect(&kernel).unwrap();
+    let victim_handle = kernel::connect(&kernel).unwrap();
+    let counting = CountingWrites { inner: &handle, writes: Cell::new(0) };
+
+    unsafe {
+        let (_, victim_object, victim_vtable, _) = find_victim_objects(&victim_handle, &kernel::offset_profile()).unwrap();
+        let mut times = [0; 4];
+        pacman_try_one(
+            &counting, victim_object, victim_vtable, victim_vtable,
+            |_| 0, || {}, || {},
+            &[0; 4], &mut [0, 1, 2, 3], &[], &mut [],
+            &mut times,
+        ).unwrap();
+        assert_eq!(counting.writes.get(), 3);
+        assert_eq!(handle.kernel_read(victim_object).unwrap(), victim_vtable);
+    }
+}
@@ -0,0 +1,64 @@
+/*!
+ * Looking up kernel offsets by OS build.
+ */
+
+use pacman::error::PacmanError;
+use pacman::profile::*;
+use pacman::sim::kernel::{self, PROC_TASK_OFFSET, PROC_TASK_SALT};
+
+const PROFILES : &str = r#"
+[[profile]]
+build = "21D62"
+devices = ["MacBookAir10,1"]
+
+[profile.fields]
+"proc.task" = 0x10
+
+[[profile]]
+build = "21E230"
+devices = ["MacBookAir10,1", "Macmini9,1"]
+
+[profile.fields]
+"proc.task" = 0x18
+"#;
+
+#[test]
+fn finds_the_profile_for_a_build_and_device() {
+    let profiles = OffsetProfiles::from_toml(PROFILES).unwrap();
+    assert_eq!(profiles.find("21D62", "MacBookAir10,1").unwrap().field(PROC_TASK), Ok(0x10));
+    assert_eq!(profiles.find("21E230", "Macmini9,1").unwrap().field(PROC_TASK), Ok(0x18));
+
+    let err = profiles.find("22A380", "MacBookAir10,1").unwrap_err();
+    assert!(err.contains("22A380") && err.contains("21D62, 21E230"), "{}", err);
+    let err = profiles.find("21D62", "Macmini9,1").unwrap_err();
+    assert!(err.contains("Macmini9,1"), "{}", err);
+}
+
+#[test]
+fn missing_offsets_are_errors() {
+    let profiles = OffsetProfiles::from_toml(PROFILES).unwrap();
+    let profile = profiles.find("21D62", "MacBookAir10,1").unwrap();
+    assert_eq!(profile.field(PROC_CONDITION), Err(PacmanError::MissingOffset(PROC_CONDITION)));
+    assert_eq!(profile.discriminator(PROC_TASK), Err(PacmanError::MissingOffset(PROC_TASK)));
+    assert_eq!(PacmanError::MissingOffset(PROC_CONDITION).to_string(), "The offset profile for this OS build has no proc.condition");
+
+    // Typos aren't silently ignored
+    assert!(OffsetProfiles::from_toml("[[profile]]\nbuild = \"21D62\"\ndevices = []\nfeilds = {}").is_err());
+}
+
+#[test]
+fn builtin_profiles_have_what_the_experiments_need() {
+    let builtin = OffsetProfiles::builtin();
+    let profile = builtin.find("21D62", "MacBookAir10,1").unwrap();
+    assert_eq!(profile.field(PROC_TASK), Ok(PROC_TASK_OFFSET));
+    assert_eq!(profile.discriminator(PROC_TASK), Ok(PROC_TASK_SALT));
+
+    // The simulated kernel is laid out like 21D62
+    let sim = kernel::offset_profile();
+    assert_eq!(profile.field(PACMANKIT_HELPER), sim.field(PACMANKIT_HELPER));
+    assert_eq!(profile.discriminator(VTABLE_POINTER), sim.discriminator(VTABLE_POINTER));
+    assert_eq!(profile.discriminator(VTABLE_ENTRY), sim.discriminator(VTABLE_ENTRY));
+    assert!(profile.field(PROC_CONDITION).is_ok());
+    assert!(profile.field(TASK_TRANSMIT).is_ok());
+    assert!(profile.gadget(INST_TARGET).is_ok());
+}
@@ -0,0 +1,134 @@
+/*!
+ * PacmanKit over a socket, against a local server for a simulated kernel.
+ */
+
+mod common;
+
+use std::io::{BufReader, Cursor};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use pacman::error::PacmanError;
+use pacman::pacmankit::{KERN_INVALID_ARGUMENT, KERN_SUCCESS, MACH_SEND_INVALID_DEST, PacmanKitOp};
+use pacman::primitives::*;
+use pacman::remote::{self, Address, Listener, Request, Response};
+use pacman::sim::kernel::{self, SimulatedKernel};
+use pacman::tests::test_forge_pacs;
+
+/// Serve `kernel` on `address` from a background thread, returning where it ended up listening
+fn start_server(kernel: &Arc<Mutex<SimulatedKernel>>, address: &str) -> Address {
+    let listener = Listener::bind(&address.parse().unwrap()).unwrap();
+    let address = listener.address().unwrap();
+    let kernel = kernel.clone();
+    thread::spawn(move || remote::serve(listener, move || kernel::connect(&kernel)));
+    address
+}
+
+#[test]
+fn addresses() {
+    assert_eq!("unix:/tmp/pacmankit.sock".parse(), Ok(Address::Unix("/tmp/pacmankit.sock".to_string())));
+    assert_eq!("10.0.0.2:7878".parse(), Ok(Address::Tcp("10.0.0.2:7878".to_string())));
+    assert!("unix:".parse::<Address>().is_err());
+    assert!("lab-mac".parse::<Address>().is_err());
+    assert_eq!(Address::Unix("/tmp/x".to_string()).to_string(), "unix:/tmp/x");
+}
+
+#[test]
+fn messages_roundtrip() {
+    let request = Request { selector: PacmanKitOp::Write as u32, args: vec![0xFFFFFE0000001000, 0x41], num_outputs: 0 };
+    let mut bytes = Vec::new();
+    request.write_to(&mut bytes).unwrap();
+    assert_eq!(bytes.len(), 12 + 16);
+
+    let mut input = Cursor::new(bytes);
+    assert_eq!(Request::read_from(&mut input).unwrap(), Some(request));
+    assert_eq!(Request::read_from(&mut input).unwrap(), None);
+
+    let response = Response { kret: KERN_INVALID_ARGUMENT, output: vec![1, 2, 3] };
+    let mut bytes = Vec::new();
+    response.write_to(&mut bytes).unwrap();
+    assert_eq!(Response::read_from(&mut Cursor::new(bytes)).unwrap(), response);
+}
+
+#[test]
+fn remote_connections_behave_like_local_ones() {
+    let kernel = common::boot(40);
+    let address = start_server(&kernel, "127.0.0.1:0");
+    let handle = remote::connect(&address).unwrap();
+    let victim_handle = remote::connect(&address).unwrap();
+
+    unsafe {
+        assert_eq!(handle.get_kernel_base().unwrap(), kernel.lock().unwrap().kernel_base());
+
+        // Each socket is its own user client
+        assert_ne!(handle.get_handle_loc().unwrap(), victim_handle.get_handle_loc().unwrap());
+
+        let buffer = handle.kernel_mmap().unwrap();
+        handle.kernel_write(buffer, 0x4141).unwrap();
+        assert_eq!(victim_handle.kernel_read(buffer).unwrap(), 0x4141);
+
+        // Forges a vtable for the victim and calls win() through it
+        test_forge_pacs(&handle, &victim_handle, &kernel::offset_profile()).unwrap();
+    }
+
+    let kernel = kernel.lock().unwrap();
+    assert_eq!(kernel.panic_message(), None);
+    assert_eq!(kernel.service_calls().last(), Some(&(kernel.retpoline() + 0x30c0)));
+}
+
+#[test]
+fn unix_sockets() {
+    let path = std::env::temp_dir().join(format!("pacmankit-test-{}.sock", std::process::id()));
+    let kernel = common::boot(41);
+    let address = start_server(&kernel, &format!("unix:{}", path.display()));
+    let handle = remote::connect(&address).unwrap();
+
+    unsafe {
+        assert_eq!(handle.leak_retpoline().unwrap(), kernel.lock().unwrap().retpoline());
+    }
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn lost_servers_are_errors() {
+    let nowhere = Address::Unix("/nonexistent/pacmankit.sock".to_string());
+    assert_eq!(remote::connect(&nowhere).err(), Some(PacmanError::Unreachable(std::io::ErrorKind::NotFound)));
+
+    // A server that says hello and hangs up
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = Address::Tcp(listener.local_addr().unwrap().to_string());
+    let server = thread::spawn(move || {
+        let (mut out, _) = listener.accept().unwrap();
+        remote::write_hello(&mut out).unwrap();
+        remote::read_hello(&mut BufReader::new(out.try_clone().unwrap())).unwrap();
+    });
+    let handle = remote::connect(&address).unwrap();
+    server.join().unwrap();
+
+    unsafe {
+        assert_eq!(handle.get_kernel_base(), Err(PacmanError::OperationFailed { op: PacmanKitOp::KernelBase, kret: MACH_SEND_INVALID_DEST }));
+    }
+}
+
+#[test]
+fn unknown_selectors_are_refused() {
+    let kernel = common::boot(42);
+    let address = match start_server(&kernel, "127.0.0.1:0") {
+        Address::Tcp(addr) => addr,
+        other => panic!("Expected a TCP address, got {}", other),
+    };
+
+    let mut out = TcpStream::connect(address).unwrap();
+    let mut input = BufReader::new(out.try_clone().unwrap());
+    remote::write_hello(&mut out).unwrap();
+    remote::read_hello(&mut input).unwrap();
+
+    Request { selector: 0x99, args: vec![], num_outputs: 1 }.write_to(&mut out).unwrap();
+    assert_eq!(Response::read_from(&mut input).unwrap(), Response { kret: KERN_INVALID_ARGUMENT, output: vec![] });
+
+    // The connection is still usable
+    Request { selector: PacmanKitOp::KernelBase as u32, args: vec![], num_outputs: 1 }.write_to(&mut out).unwrap();
+    let response = Response::read_from(&mut input).unwrap();
+    assert_eq!(response.kret, KERN_SUCCESS);
+    assert_eq!(response.output, vec![kernel.lock().unwrap().kernel_base()]);
+}
@@ -0,0 +1,134 @@
+/*!
+ * Recording PacmanKit calls against a simulated kernel, and replaying them without one.
+ */
+
+mod common;
+
+use pacman::attacks::pacman::find_victim_objects;
+use pacman::batch::Batch;
+use pacman::error::PacmanError;
+use pacman::pacmankit::{PacmanKitConnection, PacmanKitOp, KERN_FAILURE, KERN_INVALID_ADDRESS};
+use pacman::primitives::*;
+use pacman::session::*;
+use pacman::sim::kernel;
+use pacman::tests::test_forge_pacs;
+
+/// A session file for this test
+fn session_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("pacmankit-{}-{}.session", name, std::process::id()));
+    path.to_str().unwrap().to_string()
+}
+
+/// What `end_to_end` works out before it starts bruteforcing: the victim objects and a forged vtable pointer
+unsafe fn addresses_and_pacs(handle: &PacmanKitConnection, victim_handle: &PacmanKitConnection) -> Vec<u64> {
+    let (user_client, victim_object, victim_vtable, victim_method) = find_victim_objects(victim_handle, &kernel::offset_profile()).unwrap();
+    let buffer = handle.kernel_mmap().unwrap();
+    let forged = handle.forge_sign_data(buffer, victim_object).unwrap();
+    test_forge_pacs(handle, victim_handle, &kernel::offset_profile()).unwrap();
+    vec![user_client, victim_object, victim_vtable, victim_method, buffer, forged]
+}
+
+#[test]
+fn record_and_read_back() {
+    let records = vec![
+        SessionRecord { timestamp: 1, connection: 0, selector: 0, kret: 0, structure: false, args: vec![], output: vec![0xFFFFFE0007004000] },
+        SessionRecord { timestamp: 2, connection: 1, selector: 2, kret: KERN_INVALID_ADDRESS, structure: false, args: vec![0x41, 0x42], output: vec![] },
+        SessionRecord { timestamp: 3, connection: 1, selector: 0x14, kret: 0, structure: true, args: vec![1, 2, 3, 4, 5, 6, 7, 8], output: vec![0, 0x41] },
+    ];
+    let mut bytes = SESSION_MAGIC.to_vec();
+    bytes.extend_from_slice(&SESSION_VERSION.to_le_bytes());
+    for record in &records {
+        record.write_to(&mut bytes).unwrap();
+    }
+
+    assert_eq!(SessionReader::new(&bytes[..]).unwrap().read_all().unwrap(), records);
+    assert!(SessionReader::new(&bytes[..bytes.len() - 1]).unwrap().read_all().is_err());
+    assert!(SessionReader::new(&b"definitely not a session"[..]).is_err());
+}
+
+#[test]
+fn replay_reproduces_the_recorded_run() {
+    let path = session_path("replay");
+
+    let recorded = {
+        let kernel = common::boot(50);
+        let recorder = SessionRecorder::create(&path).unwrap();
+        let handle = recorder.record(kernel::connect(&kernel).unwrap());
+        let victim_handle = recorder.record(kernel::connect(&kernel).unwrap());
+        unsafe { addresses_and_pacs(&handle, &victim_handle) }
+    };
+
+    // No kernel this time
+    let replay = SessionReplay::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let handle = replay.connect().unwrap();
+    let victim_handle = replay.connect().unwrap();
+    assert_eq!(replay.connect().err(), Some(PacmanError::NotRecorded(2)));
+
+    let replayed = unsafe { addresses_and_pacs(&handle, &victim_handle) };
+    assert_eq!(replayed, recorded);
+    assert_eq!(replay.divergence(), None);
+    assert_eq!(replay.remaining(), 0);
+}
+
+#[test]
+fn batches_are_recorded_as_sent() {
+    let path = session_path("batch");
+
+    let kernel = common::boot(52);
+    let recorder = SessionRecorder::create(&path).unwrap();
+    let handle = recorder.record(kernel::connect(&kernel).unwrap());
+    let (buffer, recorded) = unsafe {
+        let buffer = handle.kernel_mmap().unwrap();
+        (buffer, handle.run_batch(Batch::new().write(buffer, 0x41).read(buffer)).unwrap())
+    };
+
+    // One structure call, not a scalar call per operation
+    let records = SessionReader::open(&path).unwrap().read_all().unwrap();
+    assert_eq!(records.len(), 2);
+    assert!(records[1].structure);
+    assert_eq!(records[1].selector, PacmanKitOp::Batch as u32);
+
+    let replay = SessionReplay::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let handle = replay.connect().unwrap();
+    unsafe {
+        assert_eq!(handle.kernel_mmap(), Ok(buffer));
+        assert_eq!(handle.run_batch(Batch::new().write(buffer, 0x41).read(buffer)), Ok(recorded));
+    }
+    assert_eq!(replay.divergence(), None);
+}
+
+#[test]
+fn diverging_ends_the_replay() {
+    let path = session_path("diverge");
+
+    let kernel = common::boot(51);
+    let recorder = SessionRecorder::create(&path).unwrap();
+    let handle = recorder.record(kernel::connect(&kernel).unwrap());
+    let buffer = unsafe {
+        let buffer = handle.kernel_mmap().unwrap();
+        handle.kernel_write(buffer, 0x4141).unwrap();
+        handle.kernel_read(buffer).unwrap();
+        buffer
+    };
+
+    let replay = SessionReplay::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let handle = replay.connect().unwrap();
+
+    unsafe {
+        assert_eq!(handle.kernel_mmap(), Ok(buffer));
+        assert_eq!(handle.kernel_write(buffer, 0x4242).map_err(|err| err.kret()), Err(Some(KERN_FAILURE)));
+
+        // Everything after the divergence fails too, even calls that were recorded
+        assert_eq!(handle.kernel_read(buffer).map_err(|err| err.kret()), Err(Some(KERN_FAILURE)));
+    }
+
+    let divergence = replay.divergence().unwrap();
+    assert_eq!(divergence.connection, 0);
+    assert_eq!(divergence.call_index, 1);
+    assert_eq!(divergence.selector, PacmanKitOp::Write as u32);
+    assert_eq!(divergence.args, vec![buffer, 0x4242]);
+    assert_eq!(divergence.expected.unwrap().args, vec![buffer, 0x4141]);
+}
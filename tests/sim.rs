@@ -0,0 +1,168 @@
+/*!
+ * The simulated kernel, driven through regular `PacmanKitConnection`s.
+ */
+
+mod common;
+
+use pacman::attacks::pacman::{find_victim_objects, get_salt};
+use pacman::cache::{PAGE_OFFSET_MASK, PAGE_SIZE};
+use pacman::pac::{self, PAC_BITMASK};
+use pacman::error::PacmanError;
+use pacman::pacmankit::{PacmanKitOp, KERN_FAILURE, KERN_INVALID_ADDRESS};
+use pacman::primitives::*;
+use pacman::sim::kernel;
+use pacman::sim::pauth::{self, PacKey};
+use pacman::tests::test_forge_pacs;
+use common::boot;
+
+#[test]
+fn pauth_roundtrip() {
+    let key = PacKey { lo: 0x0123456789ABCDEF, hi: 0xFEDCBA9876543210 };
+    let ptr = 0xFFFFFE0012345678;
+    let salt = get_salt(0xFFFFFE00ABCDEF00, 0xd986);
+
+    let signed = pauth::sign(key, ptr, salt);
+    assert_eq!(signed | PAC_BITMASK, ptr);
+    assert_eq!(pauth::auth(key, signed, salt), ptr);
+
+    // Wrong PAC, wrong salt and wrong key all fail
+    let forged = pac::encode_pac(pac::extract_pac(signed) ^ 1, ptr);
+    assert!(!pauth::is_canonical(pauth::auth(key, forged, salt)));
+    assert!(!pauth::is_canonical(pauth::auth(key, signed, salt ^ 0x10)));
+    assert!(!pauth::is_canonical(pauth::auth(PacKey { lo: 1, hi: 2 }, signed, salt)));
+}
+
+#[test]
+fn same_seed_same_kernel() {
+    let a = boot(7);
+    let b = boot(7);
+    let c = boot(8);
+    assert_eq!(a.lock().unwrap().kernel_base(), b.lock().unwrap().kernel_base());
+    assert_eq!(a.lock().unwrap().sign_data(0xFFFFFE0000001000, 1), b.lock().unwrap().sign_data(0xFFFFFE0000001000, 1));
+    assert_ne!(a.lock().unwrap().sign_data(0xFFFFFE0000001000, 1), c.lock().unwrap().sign_data(0xFFFFFE0000001000, 1));
+}
+
+#[test]
+fn memory_and_translation() {
+    let kernel = boot(1);
+    let handle = kernel::connect(&kernel).unwrap();
+
+    unsafe {
+        let kernel_base = handle.get_kernel_base().unwrap();
+        assert_eq!(kernel_base, kernel.lock().unwrap().kernel_base());
+        assert_eq!(handle.kernel_read(kernel_base).unwrap(), kernel::MACH_HEADER_START);
+
+        let pa = handle.kernel_virt_to_phys(kernel_base + 0x123).unwrap();
+        assert!(pa >= kernel::DRAM_BASE);
+        assert_eq!(pa & PAGE_OFFSET_MASK as u64, 0x123);
+
+        // User memory is paged in on first use and stays put
+        let user = 0x16f000123u64;
+        let user_pa = handle.user_virt_to_phys(user).unwrap();
+        assert_eq!(handle.user_virt_to_phys(user).unwrap(), user_pa);
+        assert_eq!(user_pa & PAGE_OFFSET_MASK as u64, user & PAGE_OFFSET_MASK as u64);
+        assert_ne!(handle.user_virt_to_phys(user + PAGE_SIZE as u64).unwrap() & !(PAGE_OFFSET_MASK as u64), user_pa & !(PAGE_OFFSET_MASK as u64));
+
+        // Nothing lives right below the kernel
+        assert_eq!(handle.kernel_read(kernel_base - 8), Err(PacmanError::OperationFailed { op: PacmanKitOp::Read, kret: KERN_INVALID_ADDRESS }));
+        assert_eq!(handle.kernel_virt_to_phys(kernel_base - 8), Err(PacmanError::OperationFailed { op: PacmanKitOp::KernelVirt2Phys, kret: KERN_INVALID_ADDRESS }));
+        assert_eq!(handle.kernel_read(kernel_base - 8).unwrap_err().to_string(), "PacmanKit Read failed: (os/kern) invalid address (0x1)");
+    }
+}
+
+#[test]
+fn gimme_memory_and_free() {
+    let kernel = boot(2);
+    let handle = kernel::connect(&kernel).unwrap();
+
+    unsafe {
+        let mem = handle.kernel_mmap().unwrap();
+        assert_eq!(mem & PAGE_OFFSET_MASK as u64, 0);
+
+        let last = mem + kernel::GIMME_MEMORY_SIZE - 8;
+        handle.kernel_write(last, 0x4141414141414141).unwrap();
+        assert_eq!(handle.kernel_read(last).unwrap(), 0x4141414141414141);
+
+        handle.kernel_free().unwrap();
+        assert_eq!(handle.kernel_read(last).map_err(|err| err.kret()), Err(Some(KERN_INVALID_ADDRESS)));
+    }
+}
+
+#[test]
+fn leaks() {
+    let kernel = boot(3);
+    let handle = kernel::connect(&kernel).unwrap();
+    let other = kernel::connect(&kernel).unwrap();
+
+    unsafe {
+        assert_ne!(handle.get_handle_loc().unwrap(), other.get_handle_loc().unwrap());
+
+        let limit = handle.leak_limit_location().unwrap();
+        assert_eq!(handle.kernel_read(limit).unwrap(), kernel::LIMIT_VALUE);
+        assert_eq!(handle.leak_retpoline().unwrap(), kernel.lock().unwrap().retpoline());
+
+        // proc.task is signed with the data key
+        let holder = handle.current_proc().unwrap() + kernel::PROC_TASK_OFFSET;
+        let task = handle.kernel_read(holder).unwrap();
+        let salt = get_salt(holder, kernel::PROC_TASK_SALT);
+        assert_eq!(handle.forge_auth_data(task, salt).unwrap(), task | PAC_BITMASK);
+    }
+}
+
+#[test]
+fn victim_objects_are_signed() {
+    let kernel = boot(4);
+    let victim_handle = kernel::connect(&kernel).unwrap();
+
+    unsafe {
+        let (user_client, object, vtable, entry) = find_victim_objects(&victim_handle, &kernel::offset_profile()).unwrap();
+        assert_eq!(object, user_client + kernel::HELPER_OFFSET);
+
+        let vtable_salt = get_salt(object, kernel::VTABLE_PTR_SALT);
+        assert_eq!(victim_handle.forge_auth_data(vtable, vtable_salt).unwrap(), vtable | PAC_BITMASK);
+
+        let entry_salt = get_salt(vtable | PAC_BITMASK, kernel::VTABLE_ENTRY_SALT);
+        assert_eq!(victim_handle.forge_auth_inst(entry, entry_salt).unwrap(), kernel.lock().unwrap().external_method());
+    }
+}
+
+#[test]
+fn forged_vtable_redirects_the_victim() {
+    let kernel = boot(5);
+    let handle = kernel::connect(&kernel).unwrap();
+    let victim_handle = kernel::connect(&kernel).unwrap();
+
+    unsafe {
+        test_forge_pacs(&handle, &victim_handle, &kernel::offset_profile()).unwrap();
+    }
+
+    let kernel = kernel.lock().unwrap();
+    assert_eq!(kernel.panic_message(), None);
+    assert_eq!(kernel.service_calls(), &[kernel.external_method(), kernel.retpoline() + 0x30c0]);
+}
+
+#[test]
+fn bad_pac_panics() {
+    let kernel = boot(6);
+    let handle = kernel::connect(&kernel).unwrap();
+    let victim_handle = kernel::connect(&kernel).unwrap();
+
+    unsafe {
+        let (_, object, _, _) = find_victim_objects(&victim_handle, &kernel::offset_profile()).unwrap();
+        let new_vtable = handle.kernel_mmap().unwrap();
+        let signed = handle.forge_sign_data(new_vtable, get_salt(object, kernel::VTABLE_PTR_SALT)).unwrap();
+        let forged = pac::encode_pac(pac::extract_pac(signed) ^ 0x8000, signed);
+        handle.kernel_write(object, forged).unwrap();
+
+        // Out of bounds (the vtable isn't used)...
+        victim_handle.call_service_routine(10000, 0, 0, 0, 0, 0).unwrap();
+        assert_eq!(kernel.lock().unwrap().panic_message(), None);
+
+        // ...and in bounds
+        victim_handle.call_service_routine(0, 0, 0, 0, 0, 0).unwrap();
+        assert!(kernel.lock().unwrap().panic_message().is_some());
+        assert!(handle.kernel_read(object).is_err());
+    }
+
+    assert_eq!(kernel::connect(&kernel).err(), Some(PacmanError::OpenFailed(KERN_FAILURE)));
+}
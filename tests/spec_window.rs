@@ -0,0 +1,72 @@
+/*!
+ * The speculation window experiment's generated gadget, settings and window estimate.
+ */
+
+use pacman::aarch64::{decode, Inst, RET};
+use pacman::attacks::spec_window::*;
+use pacman::config::AttackConfig;
+
+const BASE : u64 = 0x100000000;
+
+#[test]
+fn gadget_chains_every_op_into_the_transmit() {
+    let payload = window_payload(BASE, WindowOp::Load, 3);
+    assert_eq!(payload.end(), BASE + (3 + WINDOW_OVERHEAD as u64) * 4);
+
+    let insts : Vec<Inst> = payload.words().iter().map(|word| decode(*word)).collect();
+    assert_eq!(insts[0], Inst::Ldr { rt: 9, rn: 1, offset: 0, size: 8 });
+    assert_eq!(insts[1], Inst::CmpReg { rn: 0, rm: 9 });
+    for inst in &insts[3..6] {
+        assert_eq!(*inst, Inst::Ldr { rt: CHAIN_REG, rn: CHAIN_REG, offset: 0, size: 8 });
+    }
+    assert_eq!(insts[6], Inst::LdrReg { rt: 10, rn: 3, rm: CHAIN_REG, size: 8 });
+    assert_eq!(payload.words()[7], RET);
+
+    // The bounds check skips straight to the ret
+    match insts[2] {
+        Inst::BCond { cond, offset } => {
+            assert_eq!(cond.name(), "hs");
+            assert_eq!(payload.word(BASE + 8 + offset as u64), Some(RET));
+        },
+        other => panic!("{:?} isn't the bounds check", other),
+    }
+}
+
+#[test]
+fn alu_chain_ends_where_it_started() {
+    let payload = window_payload(BASE, WindowOp::Alu, 4);
+    let ops : Vec<Inst> = payload.words()[3..7].iter().map(|word| decode(*word)).collect();
+    assert_eq!(ops[0], Inst::AddImm { rd: CHAIN_REG, rn: CHAIN_REG, imm: 1, negative: false });
+    assert_eq!(ops[1], Inst::AddImm { rd: CHAIN_REG, rn: CHAIN_REG, imm: 1, negative: true });
+    assert_eq!(WindowOp::Alu.chain_end(0, 4), WindowOp::Alu.chain_start(0));
+    assert_eq!(WindowOp::Alu.chain_end(0, 5), 1);
+    assert_eq!(WindowOp::Load.chain_end(BASE, 5), BASE);
+}
+
+/// A result with `speculated` out of 10 trials leaking
+fn result(count: usize, speculated: usize) -> WindowResult {
+    WindowResult { core: CoreType::P, source: ConditionSource::Dram, op: WindowOp::Load, count, samples: vec![0; 10], speculated }
+}
+
+#[test]
+fn window_is_the_last_count_before_it_stops_leaking() {
+    assert_eq!(window_size(&[result(16, 9), result(0, 10), result(32, 6), result(48, 2), result(64, 7)], 0.5), Some(32));
+    assert_eq!(window_size(&[result(0, 4), result(8, 9)], 0.5), None);
+    assert_eq!(window_size(&[result(0, 10), result(8, 10)], 1.0), Some(8));
+}
+
+#[test]
+fn window_config() {
+    let config = AttackConfig::from_toml("
+        [spec_window]
+        cores = [\"e\"]
+        sources = [\"l2\"]
+    ").unwrap();
+    assert_eq!(config.spec_window.cores, vec![CoreType::E]);
+    assert_eq!(config.spec_window.sources, vec![ConditionSource::L2]);
+    assert!(config.validate().is_ok());
+
+    let config = AttackConfig::from_toml(&format!("[spec_window]\ncounts = [0, {}]", WINDOW_MAX_OPS + 1)).unwrap();
+    assert!(config.validate().unwrap_err().starts_with("spec_window:"));
+    assert!(AttackConfig::from_toml("[spec_window]\ncores = [\"big\"]").is_err());
+}
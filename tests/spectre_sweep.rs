@@ -0,0 +1,109 @@
+/*!
+ * The Spectre-v1 harness: its settings, and a sweep over the simulated kernel's bounds checks.
+ */
+
+mod common;
+
+use pacman::attacks::spectre::SpectreParams;
+use pacman::attacks::spectre_sweep::*;
+use pacman::config::AttackConfig;
+use pacman::error::PacmanError;
+use pacman::evset;
+use pacman::pacmankit::PacmanKitConnection;
+use pacman::profile::PACMANKIT_HELPER;
+use pacman::results::JsonLinesSink;
+use pacman::sim::kernel;
+use common::{boot, memory_region};
+
+#[test]
+fn schedules() {
+    for text in ["consecutive:4", "interleaved:8:2", "alternating:6:3"] {
+        assert_eq!(text.parse::<TrainingSchedule>().unwrap().to_string(), text);
+    }
+    assert!("interleaved:8:0".parse::<TrainingSchedule>().is_err());
+    assert!("consecutive".parse::<TrainingSchedule>().is_err());
+    assert!("backwards:4".parse::<TrainingSchedule>().is_err());
+
+    let calls = |text: &str| text.parse::<TrainingSchedule>().unwrap().calls();
+    assert_eq!(calls("consecutive:3"), vec![true, true, true]);
+    assert!(calls("consecutive:0").is_empty());
+    assert_eq!(calls("interleaved:5:2"), vec![true, true, false, true, true, false, true]);
+    assert_eq!(calls("alternating:4:2"), vec![true, true, false, false, true, true]);
+}
+
+#[test]
+fn evictions() {
+    for text in ["none", "evset:50", "flush"] {
+        assert_eq!(text.parse::<ConditionEviction>().unwrap().to_string(), text);
+    }
+    assert!("evset:0".parse::<ConditionEviction>().is_err());
+    assert!(format!("evset:{}", evset::EVSET_SIZE_MAX + 1).parse::<ConditionEviction>().is_err());
+    assert!("evict".parse::<ConditionEviction>().is_err());
+}
+
+#[test]
+fn sweep_config() {
+    let config = AttackConfig::from_toml("
+        [spectre_sweep]
+        gadgets = [\"service_routine\"]
+        schedules = [\"interleaved:32:4\"]
+        oracles = []
+    ").unwrap();
+    assert_eq!(config.spectre_sweep.gadgets, vec![SpectreGadget::ServiceRoutine]);
+    assert_eq!(config.spectre_sweep.schedules, vec![TrainingSchedule::Interleaved { count: 32, every: 4 }]);
+    assert_eq!(config.spectre_sweep.evictions, SweepConfig::default().evictions);
+    assert!(config.validate().unwrap_err().starts_with("spectre_sweep:"));
+
+    assert!(AttackConfig::from_toml("[spectre_sweep]\nschedules = [\"consecutive:x\"]").is_err());
+    let config = AttackConfig::from_toml("[spectre_sweep]\nexec_target_offset = 0x4002").unwrap();
+    assert!(config.validate().unwrap_err().contains("exec_target_offset"));
+}
+
+#[test]
+fn sweep_speculates_only_with_limit_evicted() {
+    let _kernel = boot(48);
+    let mut backing = Vec::new();
+    let memory_region = memory_region(&mut backing);
+    let config = SweepConfig {
+        num_trials: 4,
+        schedules: vec![TrainingSchedule::Consecutive { count: 16 }],
+        evictions: vec![ConditionEviction::None, ConditionEviction::Evset { size: 16 }],
+        ..SweepConfig::default()
+    };
+    let mut sink = JsonLinesSink::new(Vec::new());
+
+    let results = unsafe {
+        let handle = PacmanKitConnection::init().unwrap();
+        spectre_sweep(&handle, memory_region, &config, Some(&kernel::offset_profile()), &mut sink).unwrap()
+    };
+
+    // Every gadget, eviction and oracle
+    assert_eq!(results.len(), 3 * 2 * 2);
+    for result in &results {
+        let expected = if result.eviction == ConditionEviction::None { 0 } else { config.num_trials };
+        assert_eq!(result.speculated, expected, "{}", result);
+    }
+}
+
+#[test]
+fn spectre_presets() {
+    let _kernel = boot(49);
+    let mut backing = Vec::new();
+    let memory_region = memory_region(&mut backing);
+    let params = SpectreParams { num_trials: 4, limit_evset_size: 16, training_calls: 16 };
+    let mut sink = JsonLinesSink::new(Vec::new());
+
+    unsafe {
+        let handle = PacmanKitConnection::init().unwrap();
+        for gadget in [SpectreGadget::Read, SpectreGadget::Exec] {
+            let config = params.sweep(gadget, &SweepConfig::default());
+            let results = spectre_sweep(&handle, memory_region, &config, None, &mut sink).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!((results[0].gadget, results[0].speculated), (gadget, params.num_trials), "{}", results[0]);
+        }
+
+        // The service routine is the only gadget that needs an offset profile
+        let config = params.sweep(SpectreGadget::ServiceRoutine, &SweepConfig::default());
+        assert_eq!(spectre_sweep(&handle, memory_region, &config, None, &mut sink), Err(PacmanError::MissingOffset(PACMANKIT_HELPER)));
+    }
+}
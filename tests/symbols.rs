@@ -0,0 +1,97 @@
+/*!
+ * Symbolic kernel addresses: parsing them, resolving them against a kernelcache and sliding them.
+ */
+
+use pacman::kernelcache::macho::MachO;
+use pacman::kernelcache::symbols::*;
+use pacman::sim::kernel::{KERNEL_BASE_UNSLID, SimulatedKernel};
+use pacman::sim::kernelcache::*;
+
+const SCSI : &str = "com.apple.iokit.IOSCSIArchitectureModelFamily";
+
+/// A kernel and a kext, each with a symbol in its text (and where the kext is)
+fn symbols() -> (KernelSymbols, u64, u64, u64) {
+    let mut builder = KernelcacheBuilder::new(KERNEL_BASE_UNSLID);
+    let kernel = builder.kext(KERNEL_BUNDLE_ID);
+    let kext = builder.kext(SCSI);
+
+    let kernel_text = builder.segment(kernel, "__TEXT_EXEC", VM_PROT_READ | VM_PROT_EXECUTE, &[0; 0x100]);
+    builder.symbol(kernel, "_proc_task", kernel_text + 0x40);
+    let kext_text = builder.segment(kext, "__TEXT_EXEC", VM_PROT_READ | VM_PROT_EXECUTE, &[0; 0x100]);
+    builder.symbol(kext, "_scsi_method", kext_text + 0x20);
+
+    let bytes = builder.build();
+    let symbols = KernelSymbols::from_kernelcache(&MachO::parse(&bytes).unwrap()).unwrap();
+    (symbols, kernel_text, builder.kext_address(kext), kext_text)
+}
+
+#[test]
+fn parse_and_display() {
+    for text in ["base+0x26a497c", "IOSCSIArchitectureModelFamily+0x1234", "_proc_task", "com.apple.kernel!_proc_task-0x10"] {
+        assert_eq!(text.parse::<SymbolicAddress>().unwrap().to_string(), text);
+    }
+    assert_eq!("base+0x10".parse::<SymbolicAddress>().unwrap(), SymbolicAddress::base(0x10));
+    assert_eq!("kext!_sym+0x8".parse::<SymbolicAddress>().unwrap().anchor, Anchor::KextSymbol { kext: "kext".to_string(), symbol: "_sym".to_string() });
+    assert!("+0x10".parse::<SymbolicAddress>().is_err());
+    assert!("base+0xzz".parse::<SymbolicAddress>().is_err());
+}
+
+#[test]
+fn offsets_from_base() {
+    let (symbols, kernel_text, _, _) = symbols();
+    let base = symbols.base_address();
+    assert_eq!(base, KERNEL_BASE_UNSLID);
+
+    // Base relative addresses don't need a kernelcache, anything else does
+    assert_eq!(SymbolicAddress::base(0x26a497c).offset_from_base(None).unwrap(), 0x26a497c);
+    let proc_task : SymbolicAddress = "_proc_task+0x4".parse().unwrap();
+    assert!(proc_task.offset_from_base(None).is_err());
+    assert_eq!(proc_task.offset_from_base(Some(&symbols)).unwrap(), kernel_text + 0x44 - base);
+}
+
+#[test]
+fn resolve() {
+    let (symbols, kernel_text, kext_base, kext_text) = symbols();
+    let resolve = |text: &str| symbols.resolve(&text.parse().unwrap());
+
+    // Kexts by bundle id or its last component, symbols in any kext or a specific one
+    assert_eq!(resolve("base+0x100").unwrap(), KERNEL_BASE_UNSLID + 0x100);
+    assert_eq!(resolve(&format!("{}+0x10", SCSI)).unwrap(), kext_base + 0x10);
+    assert_eq!(resolve("IOSCSIArchitectureModelFamily+0x10").unwrap(), kext_base + 0x10);
+    assert_eq!(resolve("_scsi_method+0x4").unwrap(), kext_text + 0x24);
+    assert_eq!(resolve("kernel!_proc_task-0x8").unwrap(), kernel_text + 0x38);
+
+    assert!(resolve("_nope").is_err());
+    assert!(resolve("IOSCSIArchitectureModelFamily!_proc_task").is_err());
+    assert!(resolve("com.apple.nope!_scsi_method").is_err());
+}
+
+#[test]
+fn symbolicate() {
+    let (symbols, kernel_text, _, kext_text) = symbols();
+    assert_eq!(symbols.symbolicate(kext_text + 0x28).to_string(), format!("{}!_scsi_method+0x8", SCSI));
+    assert_eq!(symbols.symbolicate(kernel_text + 0x40).to_string(), "com.apple.kernel!_proc_task");
+
+    // Before any symbol it's relative to the kext, outside every kext it's relative to the base
+    assert_eq!(symbols.symbolicate(kext_text).anchor, Anchor::Name(SCSI.to_string()));
+    assert_eq!(symbols.symbolicate(KERNEL_BASE_UNSLID - 0x10).to_string(), "base-0x10");
+
+    // Symbolicating then resolving gets back the same address
+    let addr = symbols.symbolicate(kext_text + 0x30);
+    assert_eq!(symbols.resolve(&addr).unwrap(), kext_text + 0x30);
+}
+
+#[test]
+fn slide_from_leaked_base() {
+    let (symbols, _, _, kext_text) = symbols();
+    let kernel_base = SimulatedKernel::new(3).kernel_base();
+    let live = symbols.live(kernel_base);
+    assert_eq!(live.slide, kernel_base - KERNEL_BASE_UNSLID);
+    assert_eq!(live.slide % KERNELCACHE_PAGE_SIZE, 0);
+
+    let method = live.resolve(&"_scsi_method".parse().unwrap()).unwrap();
+    assert_eq!(method, kext_text + 0x20 + live.slide);
+    assert_eq!(live.resolve(&SymbolicAddress::base(0)).unwrap(), kernel_base);
+    assert_eq!(live.unslide(method), kext_text + 0x20);
+    assert_eq!(live.symbolicate(method + 4).to_string(), format!("{}!_scsi_method+0x4", SCSI));
+}
@@ -0,0 +1,110 @@
+/*!
+ * Recording and replaying traces.
+ */
+
+use pacman::trace::*;
+
+const MISS : u64 = 100;
+const HIT : u64 = 10;
+
+/// Probe latencies with `misses` misses out of 4
+fn probes(misses: usize) -> Vec<u64> {
+    (0..4).map(|i| if i < misses { MISS } else { HIT }).collect()
+}
+
+#[test]
+fn record_and_read_back() {
+    let path = std::env::temp_dir().join(format!("pacman-trace-{}.bin", std::process::id()));
+    let path = path.to_str().unwrap();
+
+    let mut recorder = TraceRecorder::create(path).unwrap();
+    recorder.set_phase(TracePhase::Differentiate, TraceLabel::Correct);
+    recorder.record(1, 0xAAAA, 0xBBBB, 1234, &probes(3)).unwrap();
+    recorder.set_phase(TracePhase::Scan, TraceLabel::Unknown);
+    recorder.record(2, 0xAAAA, 0xCCCC, 5678, &probes(1)).unwrap();
+    recorder.flush().unwrap();
+    drop(recorder);
+
+    let records = TraceReader::open(path).unwrap().read_all().unwrap();
+    std::fs::remove_file(path).unwrap();
+
+    assert_eq!(records, vec![
+        TraceRecord {
+            timestamp: 1,
+            holder: 0xAAAA,
+            guess: 0xBBBB,
+            limit_evict_cycles: 1234,
+            phase: TracePhase::Differentiate,
+            label: TraceLabel::Correct,
+            probe_latencies: probes(3),
+        },
+        TraceRecord {
+            timestamp: 2,
+            holder: 0xAAAA,
+            guess: 0xCCCC,
+            limit_evict_cycles: 5678,
+            phase: TracePhase::Scan,
+            label: TraceLabel::Unknown,
+            probe_latencies: probes(1),
+        },
+    ]);
+}
+
+#[test]
+fn garbage_is_not_a_trace() {
+    assert!(TraceReader::new(&b"definitely not a trace"[..]).is_err());
+}
+
+/// Build a record for a bruteforce trial
+fn trial(phase: TracePhase, guess: u64, misses: usize) -> TraceRecord {
+    TraceRecord {
+        timestamp: 0,
+        holder: 0xAAAA,
+        guess,
+        limit_evict_cycles: 0,
+        phase,
+        label: TraceLabel::Unknown,
+        probe_latencies: probes(misses),
+    }
+}
+
+#[test]
+fn group_consecutive_trials() {
+    let records = vec![
+        trial(TracePhase::Scan, 1, 0),
+        trial(TracePhase::Scan, 1, 1),
+        trial(TracePhase::Scan, 2, 4),
+        trial(TracePhase::Scan, 1, 2),
+    ];
+    let groups = group_records(&records, 50);
+
+    assert_eq!(groups.len(), 3);
+    assert_eq!((groups[0].guess, groups[0].samples.clone()), (1, vec![0, 1]));
+    assert_eq!((groups[1].guess, groups[1].samples.clone()), (2, vec![4]));
+    assert_eq!((groups[2].guess, groups[2].samples.clone()), (1, vec![2]));
+
+    // Classification happens at replay time
+    assert_eq!(group_records(&records, 5)[0].samples, vec![4, 4]);
+}
+
+#[test]
+fn replay_finds_the_answer() {
+    let mut records = Vec::new();
+    for guess in 0..8u64 {
+        let misses = if guess == 5 || guess == 6 { 4 } else { 0 };
+        for _ in 0..4 {
+            records.push(trial(TracePhase::Scan, guess, misses));
+        }
+    }
+
+    // Only guess 5 was measured again
+    for _ in 0..8 {
+        records.push(trial(TracePhase::Final, 5, 4));
+    }
+
+    let report = replay(&records, 50, 2.0);
+    let decider = report.decider.unwrap();
+    assert_eq!(decider.potential_matches(), &[5, 6]);
+    assert_eq!(report.inspected_from_scan, vec![6]);
+    assert_eq!(decider.decide(), Some(5));
+}